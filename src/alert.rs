@@ -0,0 +1,175 @@
+//! Alerts published when no relay accepts receipts, by `clnzapper_alert_threshold`
+//!
+//! After that many receipts in a row were accepted by none of their relays, the
+//! zapper publishes a kind 1 note signed with its receipt key to the
+//! `clnzapper_alert_relays`, and another once a receipt is accepted again. Anyone
+//! following the key on those relays hears about it without running monitoring of
+//! their own. The alert relays should be separate from the zapper's relays, which
+//! are the ones failing. Zap requests for an unexpected recipient are alerted to the
+//! same relays with `clnzapper_recipient_mismatch=alert`.
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::{debug, warn};
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind};
+
+use crate::config::Config;
+use crate::relay::broadcast_zap_note;
+use crate::relay_url::RelayUrl;
+
+/// Hashtag alerts are published with, so they can be found
+const ALERT_HASHTAG: &str = "zapper-alert";
+
+/// A change worth telling the operator about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertChange {
+    /// This many receipts in a row were accepted by no relay
+    Failing(u64),
+    /// A receipt was accepted after this many that weren't
+    Recovered(u64),
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    /// Receipts in a row accepted by no relay
+    consecutive: u64,
+    /// Whether an alert went out that hasn't been followed by a recovery
+    alerting: bool,
+}
+
+/// Counts receipts no relay accepted
+#[derive(Debug)]
+pub struct Alerter {
+    threshold: u64,
+    relays: Vec<RelayUrl>,
+    failures: Mutex<Failures>,
+}
+
+impl Alerter {
+    pub fn new(threshold: u64, relays: Vec<RelayUrl>) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            relays,
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    /// Count a receipt broadcast `accepted` relays took, returning what to alert about
+    pub fn record(&self, accepted: usize) -> Option<AlertChange> {
+        let mut failures = self.failures.lock().expect("Lock not poisoned");
+        if accepted > 0 {
+            let failed = std::mem::take(&mut failures.consecutive);
+            return std::mem::take(&mut failures.alerting)
+                .then_some(AlertChange::Recovered(failed));
+        }
+
+        failures.consecutive += 1;
+        if failures.consecutive >= self.threshold && !failures.alerting {
+            failures.alerting = true;
+            return Some(AlertChange::Failing(failures.consecutive));
+        }
+        None
+    }
+
+    /// Count the broadcast and publish an alert in the background if it changed anything
+    pub fn observe(&self, accepted: usize, keys: &Keys, config: &Config) {
+        let Some(change) = self.record(accepted) else {
+            return;
+        };
+        publish(alert_content(change), keys, self.relays.clone(), config);
+    }
+}
+
+/// Publish an alert note with `content` to `relays` in the background
+pub fn publish(content: String, keys: &Keys, relays: Vec<RelayUrl>, config: &Config) {
+    let note = match alert_note(keys, content) {
+        Ok(note) => note,
+        Err(err) => {
+            warn!("Error while creating alert: {err}");
+            return;
+        }
+    };
+
+    warn!("{}", note.content);
+    let config = config.clone();
+    tokio::spawn(async move {
+        debug!("Publishing alert {}", note.id.to_hex());
+        match broadcast_zap_note(&relays, note, &config).await {
+            Ok(0) => warn!("No alert relay accepted the alert"),
+            Ok(_) => (),
+            Err(err) => warn!("Error while publishing alert: {err}"),
+        }
+    });
+}
+
+fn alert_content(change: AlertChange) -> String {
+    match change {
+        AlertChange::Failing(failed) => {
+            format!("cln-zapper alert: no relay accepted the last {failed} zap receipts")
+        }
+        AlertChange::Recovered(failed) => format!(
+            "cln-zapper recovered: relays accept zap receipts again, after {failed} that no relay accepted"
+        ),
+    }
+}
+
+fn alert_note(keys: &Keys, content: String) -> Result<Event> {
+    let tags = [Tag::Generic(
+        TagKind::Custom("t".to_string()),
+        vec![ALERT_HASHTAG.to_string()],
+    )];
+
+    Ok(EventBuilder::new(Kind::TextNote, content, &tags).to_event(keys)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nostr::ClientMessage;
+
+    use super::*;
+    use crate::relay::tests::mock_relay;
+
+    #[test]
+    fn test_alert_threshold() {
+        let alerter = Alerter::new(3, vec![]);
+        assert_eq!(alerter.record(0), None);
+        assert_eq!(alerter.record(0), None);
+        assert_eq!(alerter.record(0), Some(AlertChange::Failing(3)));
+        // Alerted once until it recovers
+        assert_eq!(alerter.record(0), None);
+        assert_eq!(alerter.record(2), Some(AlertChange::Recovered(4)));
+        assert_eq!(alerter.record(1), None);
+
+        // A success in between starts the count again
+        assert_eq!(alerter.record(0), None);
+        assert_eq!(alerter.record(0), None);
+        assert_eq!(alerter.record(1), None);
+        assert_eq!(alerter.record(0), None);
+    }
+
+    #[tokio::test]
+    async fn test_alert_published() {
+        let (relay, received) = mock_relay(None, 1);
+        let alerter = Alerter::new(1, vec![relay]);
+        let keys = Keys::generate();
+
+        alerter.observe(0, &keys, &Config::default());
+
+        let msg =
+            tokio::task::spawn_blocking(move || received.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap()
+                .unwrap();
+        let ClientMessage::Event(note) = ClientMessage::from_json(msg).unwrap() else {
+            panic!("Expected an event");
+        };
+        assert_eq!(note.pubkey, keys.public_key());
+        assert_eq!(
+            note.content,
+            "cln-zapper alert: no relay accepted the last 1 zap receipts"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Checks of a zap request's amount against the paid invoice
+//!
+//! The receipt always carries the invoice's bolt11, so it reflects the requested
+//! amount. What can be configured is which invoice amount the zap request's
+//! `amount` tag must equal:
+//! * `requested` (default): `amount_msat`, the amount the invoice was created for
+//! * `received`: `amount_received_msat`, what the payer actually paid
+//!
+//! CLN accepts overpayments, so these can differ. Zaps can also be rejected when
+//! the received amount deviates from the requested one by more than a percentage.
+//!
+//! A zap paid in multiple parts (MPP) settles as one paid invoice, with
+//! `amount_received_msat` the sum of the parts. That is at least the requested
+//! amount and can be a little over it when the payer's parts don't split it
+//! exactly, which matters only with `received` or a deviation of `0`.
+//!
+//! `clnzapper_amount_mismatch` decides what a zap failing these checks gets:
+//! * `skip` (default): no receipt, logged at info
+//! * `reject`: no receipt, logged as a warning and counted apart from skipped zaps,
+//!   under `amount-rejected` in `zapper-status`
+//! * `warn_and_broadcast`: the payment did happen, so the receipt is still
+//!   published, with the mismatch logged as a warning and counted
+//!
+//! Separately, `clnzapper_sanity_min_msat` and `clnzapper_sanity_max_msat` bound the
+//! invoice amounts themselves, whatever the zap request asked for. An invoice outside
+//! them, more likely a misconfigured LNURL server than a real zap, gets no receipt.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::WaitanyinvoiceResponse;
+use log::Level;
+
+use crate::skip::SkipReason;
+
+/// Invoice amount a zap request's amount tag is compared against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AmountField {
+    /// Amount the invoice was created for
+    #[default]
+    Requested,
+    /// Amount actually received
+    Received,
+}
+
+impl FromStr for AmountField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "requested" => Ok(Self::Requested),
+            "received" => Ok(Self::Received),
+            _ => Err(anyhow!(
+                "Invalid amount field {s}, expected requested or received"
+            )),
+        }
+    }
+}
+
+/// Value of `clnzapper_amount_mismatch`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    #[default]
+    Skip,
+    WarnAndBroadcast,
+    Reject,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "warn_and_broadcast" => Ok(Self::WarnAndBroadcast),
+            "reject" => Ok(Self::Reject),
+            _ => Err(anyhow!(
+                "Invalid amount mismatch policy {s}, expected skip, warn_and_broadcast or reject"
+            )),
+        }
+    }
+}
+
+impl MismatchPolicy {
+    /// Level a mismatch is logged at
+    pub fn log_level(&self) -> Level {
+        match self {
+            Self::Skip => Level::Info,
+            Self::WarnAndBroadcast | Self::Reject => Level::Warn,
+        }
+    }
+
+    /// Why a zap with a mismatch gets no receipt, `None` if it still gets one
+    pub fn skip_reason(&self) -> Option<SkipReason> {
+        match self {
+            Self::Skip => Some(SkipReason::AmountMismatch),
+            Self::Reject => Some(SkipReason::AmountRejected),
+            Self::WarnAndBroadcast => None,
+        }
+    }
+}
+
+/// Check the zap request amount is consistent with the paid invoice
+pub fn check_zap_amount(
+    zap_request_amount: Option<u64>,
+    invoice: &WaitanyinvoiceResponse,
+    field: AmountField,
+    max_deviation_pct: Option<u64>,
+) -> Result<()> {
+    let requested = invoice.amount_msat.map(|a| a.msat());
+    let received = invoice.amount_received_msat.map(|a| a.msat());
+
+    if let (Some(max_deviation_pct), Some(requested), Some(received)) =
+        (max_deviation_pct, requested, received)
+    {
+        let deviation = requested.abs_diff(received) as u128 * 100;
+        if deviation > requested as u128 * max_deviation_pct as u128 {
+            return Err(anyhow!(
+                "Received amount {received} differs from invoice amount {requested} by more than {max_deviation_pct}%"
+            ));
+        }
+    }
+
+    let invoice_amount = match field {
+        AmountField::Requested => requested,
+        AmountField::Received => received,
+    };
+
+    // If there is an amount tag present in zap request check it matches invoice
+    if let (Some(zap_request_amount), Some(invoice_amount)) = (zap_request_amount, invoice_amount) {
+        if zap_request_amount != invoice_amount {
+            return Err(anyhow!(
+                "Zap request amount {zap_request_amount} does not equal invoice amount {invoice_amount}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Bounds paid invoice amounts must fall within, unbounded where unset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SanityRange {
+    pub min_msat: Option<u64>,
+    pub max_msat: Option<u64>,
+}
+
+impl SanityRange {
+    /// Check the invoice's requested and received amounts are within the range
+    pub fn check(&self, invoice: &WaitanyinvoiceResponse) -> Result<()> {
+        let amounts = [
+            ("Invoice amount", invoice.amount_msat),
+            ("Received amount", invoice.amount_received_msat),
+        ];
+        for (name, amount) in amounts {
+            let Some(amount) = amount.map(|a| a.msat()) else {
+                continue;
+            };
+            if let Some(min) = self.min_msat.filter(|min| amount < *min) {
+                return Err(anyhow!("{name} {amount} msat is below the minimum {min}"));
+            }
+            if let Some(max) = self.max_msat.filter(|max| amount > *max) {
+                return Err(anyhow!("{name} {amount} msat is above the maximum {max}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cln_rpc::primitives::Amount;
+
+    use super::*;
+    use crate::tests::{test_invoice, ZAP_REQ};
+
+    fn paid(requested: u64, received: u64) -> WaitanyinvoiceResponse {
+        let mut invoice = test_invoice(ZAP_REQ);
+        invoice.amount_msat = Some(Amount::from_msat(requested));
+        invoice.amount_received_msat = Some(Amount::from_msat(received));
+        invoice
+    }
+
+    #[test]
+    fn test_exact_payment() {
+        let invoice = paid(50000, 50000);
+        for field in [AmountField::Requested, AmountField::Received] {
+            assert!(check_zap_amount(Some(50000), &invoice, field, Some(0)).is_ok());
+            assert!(check_zap_amount(Some(40000), &invoice, field, None).is_err());
+        }
+        // No amount tag means nothing to compare
+        assert!(check_zap_amount(None, &invoice, AmountField::Requested, None).is_ok());
+    }
+
+    #[test]
+    fn test_overpaid() {
+        let invoice = paid(50000, 60000);
+
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Requested, None).is_ok());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Received, None).is_err());
+        assert!(check_zap_amount(Some(60000), &invoice, AmountField::Received, None).is_ok());
+
+        // 20% over
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Requested, Some(20)).is_ok());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Requested, Some(10)).is_err());
+    }
+
+    #[test]
+    fn test_multi_part_payment() {
+        // Parts summing to the amount
+        let invoice = paid(50000, 50000);
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::default(), None).is_ok());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Received, Some(0)).is_ok());
+
+        // Parts summing to a few msat over
+        let invoice = paid(50000, 50003);
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::default(), Some(1)).is_ok());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Received, None).is_err());
+    }
+
+    #[test]
+    fn test_underpaid() {
+        let invoice = paid(50000, 45000);
+
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Requested, None).is_ok());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Received, None).is_err());
+        assert!(check_zap_amount(Some(50000), &invoice, AmountField::Requested, Some(5)).is_err());
+    }
+
+    #[test]
+    fn test_sanity_range() {
+        let range = SanityRange {
+            min_msat: Some(1000),
+            max_msat: Some(1_000_000),
+        };
+
+        assert!(range.check(&paid(50000, 50000)).is_ok());
+        assert!(range.check(&paid(1000, 1000)).is_ok());
+        assert!(range.check(&paid(999, 999)).is_err());
+        assert!(range.check(&paid(2_000_000, 2_000_000)).is_err());
+        // An overpayment past the maximum counts too
+        assert!(range.check(&paid(50000, 5_000_000)).is_err());
+
+        assert!(SanityRange::default().check(&paid(1, u64::MAX / 2)).is_ok());
+    }
+
+    #[test]
+    fn test_amount_field_from_str() {
+        assert_eq!(
+            AmountField::from_str("received").unwrap(),
+            AmountField::Received
+        );
+        assert!(AmountField::from_str("paid").is_err());
+    }
+
+    #[test]
+    fn test_mismatch_policy_from_str() {
+        assert_eq!(
+            MismatchPolicy::from_str("warn_and_broadcast").unwrap(),
+            MismatchPolicy::WarnAndBroadcast
+        );
+        assert_eq!(
+            MismatchPolicy::from_str("reject").unwrap(),
+            MismatchPolicy::Reject
+        );
+        assert!(MismatchPolicy::from_str("warn").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Optional off-relay store of every zap receipt published
+//!
+//! `clnzapper_archive` is either a directory, where each receipt is written as
+//! `<event id>.json`, or an `http://` or `https://` endpoint each receipt is POSTed
+//! to as JSON. Archived receipts are complete signed events, so they can be
+//! rebroadcast later.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use nostr::{Event, Url};
+use rustls::{ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio::sync::Semaphore;
+
+/// How long an upload to an archive endpoint may take
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Receipts being archived at once, past which more are dropped rather than queued
+pub const MAX_PENDING: usize = 64;
+
+/// Where receipts are archived
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Archive {
+    /// Directory of `<event id>.json` files
+    Dir(PathBuf),
+    /// Endpoint receipts are POSTed to, over TLS if https
+    Http(Url),
+}
+
+impl FromStr for Archive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            let url = Url::parse(s)?;
+            if url.host_str().is_none() {
+                return Err(anyhow!("Archive endpoint {s} has no host"));
+            }
+            return Ok(Self::Http(url));
+        }
+        if s.is_empty() {
+            return Err(anyhow!("Archive path is empty"));
+        }
+        Ok(Self::Dir(PathBuf::from(s)))
+    }
+}
+
+impl Archive {
+    /// Store the receipt
+    pub async fn store(&self, zap_note: &Event) -> Result<()> {
+        match self {
+            Self::Dir(dir) => write_file(dir, zap_note).await,
+            // The upload is blocking, like relay sends, so keep it off the async workers
+            Self::Http(url) => {
+                let (url, body) = (url.clone(), zap_note.as_json());
+                tokio::task::spawn_blocking(move || upload(&url, &body)).await?
+            }
+        }
+    }
+
+    /// Store the receipt in the background, holding one of `pending`'s permits until done.
+    /// Returns false, dropping the receipt, if none are free
+    pub fn spawn_store(&self, zap_note: &Event, pending: &Arc<Semaphore>) -> bool {
+        let Ok(permit) = pending.clone().try_acquire_owned() else {
+            warn!(
+                "Not archiving zap note {}: {MAX_PENDING} receipts are already being archived",
+                zap_note.id.to_hex()
+            );
+            return false;
+        };
+        let (archive, zap_note) = (self.clone(), zap_note.clone());
+        tokio::spawn(async move {
+            if let Err(err) = archive.store(&zap_note).await {
+                warn!("Error while archiving zap note: {err}");
+            }
+            drop(permit);
+        });
+        true
+    }
+}
+
+impl Archive {
+    /// Write every archived receipt to `out` as newline delimited JSON, oldest first,
+    /// returning how many were written
+    pub async fn export(&self, out: &Path) -> Result<usize> {
+        let dir = match self {
+            Self::Dir(dir) => dir,
+            Self::Http(url) => {
+                return Err(anyhow!(
+                "Receipts archived to {url} can't be read back, export needs a directory archive"
+            ))
+            }
+        };
+
+        let mut zap_notes = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            match Event::from_json(tokio::fs::read_to_string(&path).await?) {
+                Ok(zap_note) => zap_notes.push(zap_note),
+                Err(err) => warn!("Not exporting {}: {err}", path.display()),
+            }
+        }
+        zap_notes.sort_by_key(|zap_note| (zap_note.created_at, zap_note.id));
+
+        let mut lines = String::new();
+        for zap_note in &zap_notes {
+            lines.push_str(&zap_note.as_json());
+            lines.push('\n');
+        }
+        let tmp = out.with_extension("tmp");
+        tokio::fs::write(&tmp, lines).await?;
+        tokio::fs::rename(&tmp, out).await?;
+
+        Ok(zap_notes.len())
+    }
+}
+
+async fn write_file(dir: &Path, zap_note: &Event) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    // Write then rename so a crash never leaves a partial receipt behind
+    let path = dir.join(format!("{}.json", zap_note.id.to_hex()));
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, zap_note.as_json()).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+
+    Ok(())
+}
+
+/// Roots https archive endpoints are checked against, those tungstenite trusts for relays
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+/// POST `body` to the endpoint, blocking for at most `UPLOAD_TIMEOUT` per connect, read or write
+fn upload(url: &Url, body: &str) -> Result<()> {
+    let host = url.host_str().expect("Checked when parsed");
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("No port for archive endpoint {url}"))?;
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{path}?{query}");
+    }
+
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Archive endpoint host {host} resolved to no addresses"))?;
+    let socket = TcpStream::connect_timeout(&addr, UPLOAD_TIMEOUT)?;
+    socket.set_read_timeout(Some(UPLOAD_TIMEOUT))?;
+    socket.set_write_timeout(Some(UPLOAD_TIMEOUT))?;
+    let mut stream: Box<dyn ReadWrite> = match url.scheme() {
+        "https" => {
+            let server_name = ServerName::try_from(host)
+                .map_err(|_| anyhow!("Invalid archive endpoint host {host}"))?;
+            let tls = ClientConnection::new(tls_config(), server_name)?;
+            Box::new(rustls::StreamOwned::new(tls, socket))
+        }
+        _ => Box::new(socket),
+    };
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // Only the status line matters, and servers don't always close TLS cleanly after it
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.windows(2).any(|window| window == b"\r\n") {
+        match stream.read(&mut buf)? {
+            0 => break,
+            read => response.extend_from_slice(&buf[..read]),
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| anyhow!("Malformed response from {url}"))?;
+
+    if !status.starts_with('2') {
+        return Err(anyhow!("Archive endpoint {url} responded {status}"));
+    }
+
+    Ok(())
+}
+
+/// A plain or TLS stream to an archive endpoint
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+#[cfg(test)]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn zap_note() -> Event {
+        EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_archive_from_str() {
+        assert_eq!(
+            Archive::from_str("./test/archive").unwrap(),
+            Archive::Dir(PathBuf::from("./test/archive"))
+        );
+        assert!(matches!(
+            Archive::from_str("http://localhost:8000/receipts").unwrap(),
+            Archive::Http(_)
+        ));
+        assert!(matches!(
+            Archive::from_str("https://archive.example/receipts").unwrap(),
+            Archive::Http(_)
+        ));
+        assert!(Archive::from_str("https://").is_err());
+        assert!(Archive::from_str("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_archive_dir() {
+        let dir = PathBuf::from("./test/archive");
+        let zap_note = zap_note();
+
+        Archive::Dir(dir.clone()).store(&zap_note).await.unwrap();
+
+        let stored =
+            std::fs::read_to_string(dir.join(format!("{}.json", zap_note.id.to_hex()))).unwrap();
+        assert_eq!(Event::from_json(stored).unwrap(), zap_note);
+    }
+
+    #[tokio::test]
+    async fn test_archive_full_dropped() {
+        let dir = PathBuf::from("./test/archive_full");
+        std::fs::remove_dir_all(&dir).ok();
+        let archive = Archive::Dir(dir.clone());
+        let pending = Arc::new(Semaphore::new(1));
+        let held = pending.clone().try_acquire_owned().unwrap();
+
+        let dropped = zap_note();
+        assert!(!archive.spawn_store(&dropped, &pending));
+
+        drop(held);
+        let stored = zap_note();
+        assert!(archive.spawn_store(&stored, &pending));
+        // The permit is released once the receipt is written
+        let _permit = tokio::time::timeout(Duration::from_secs(5), pending.acquire())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(dir.join(format!("{}.json", stored.id.to_hex())).exists());
+        assert!(!dir.join(format!("{}.json", dropped.id.to_hex())).exists());
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let dir = PathBuf::from("./test/export");
+        std::fs::remove_dir_all(&dir).ok();
+        let archive = Archive::Dir(dir.join("receipts"));
+        let zap_notes = [zap_note(), zap_note()];
+        for zap_note in &zap_notes {
+            archive.store(zap_note).await.unwrap();
+        }
+        // Not a receipt
+        std::fs::write(dir.join("receipts/notes.txt"), "").unwrap();
+
+        let out = dir.join("receipts.jsonl");
+        assert_eq!(archive.export(&out).await.unwrap(), 2);
+
+        let exported: Vec<Event> = std::fs::read_to_string(&out)
+            .unwrap()
+            .lines()
+            .map(|line| Event::from_json(line).unwrap())
+            .collect();
+        assert_eq!(exported.len(), 2);
+        assert!(zap_notes.iter().all(|zap_note| exported.contains(zap_note)));
+
+        let endpoint = Archive::from_str("http://localhost:8000/receipts").unwrap();
+        assert!(endpoint.export(&out).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_archive_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/receipts", listener.local_addr().unwrap());
+        let zap_note = zap_note();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let mut read = 0;
+            // Read until the whole body has arrived
+            while !String::from_utf8_lossy(&request[..read]).ends_with('}') {
+                read += stream.read(&mut request[read..]).await.unwrap();
+            }
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).to_string()
+        });
+
+        Archive::from_str(&url)
+            .unwrap()
+            .store(&zap_note)
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /receipts HTTP/1.1\r\n"));
+        assert!(request.ends_with(&zap_note.as_json()));
+    }
+
+    #[tokio::test]
+    async fn test_archive_https() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://localhost:{}/receipts",
+            listener.local_addr().unwrap().port()
+        );
+
+        // Not a TLS server, so the upload fails once it has sent its ClientHello
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut record_type = [0; 1];
+            stream.read_exact(&mut record_type).await.unwrap();
+            record_type[0]
+        });
+
+        let stored = Archive::from_str(&url).unwrap().store(&zap_note()).await;
+        // A TLS handshake record
+        assert_eq!(server.await.unwrap(), 0x16);
+        assert!(stored.is_err());
+    }
+}
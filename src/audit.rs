@@ -0,0 +1,108 @@
+//! Optional audit attestations of published zap receipts
+//!
+//! With `clnzapper_audit_nsec` and `clnzapper_audit_relay` set, every receipt gets
+//! a second event signed by the audit key that references it, published only to
+//! the audit relay. The receipt itself and where it is published are unchanged.
+
+use anyhow::{anyhow, Result};
+use nostr::{Event, EventBuilder, Keys, Kind, Tag};
+
+use crate::config::Config;
+use crate::keys;
+use crate::relay::broadcast_zap_note;
+use crate::relay_url::RelayUrl;
+
+/// Kind of attestation events, not assigned by any NIP
+pub const ATTESTATION_KIND: u64 = 9739;
+
+/// Signs and publishes attestations of receipts
+#[derive(Clone, Debug)]
+pub struct Auditor {
+    /// Keys attestations are signed with
+    keys: Keys,
+    /// Internal relay attestations are published to
+    relay: RelayUrl,
+}
+
+impl Auditor {
+    /// Build from the audit options, both or neither of which must be set
+    pub fn from_options(nsec: Option<String>, relay: Option<String>) -> Result<Option<Self>> {
+        match (nsec, relay) {
+            (Some(nsec), Some(relay)) => Ok(Some(Self {
+                keys: keys::load("clnzapper_audit_nsec", &nsec)?,
+                relay: RelayUrl::parse(&relay)?,
+            })),
+            (None, None) => Ok(None),
+            _ => Err(anyhow!(
+                "clnzapper_audit_nsec and clnzapper_audit_relay must be set together"
+            )),
+        }
+    }
+
+    /// Attestation of the receipt signed by the audit key
+    pub fn attestation(&self, zap_note: &Event) -> Result<Event> {
+        let tags = vec![
+            Tag::Event(zap_note.id, None, None),
+            Tag::PubKey(zap_note.pubkey, None),
+        ];
+
+        Ok(EventBuilder::new(Kind::Custom(ATTESTATION_KIND), "", &tags).to_event(&self.keys)?)
+    }
+
+    /// Publish the attestation to the audit relay only
+    pub async fn publish(&self, attestation: Event, config: &Config) -> Result<()> {
+        broadcast_zap_note(std::slice::from_ref(&self.relay), attestation, config).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::tests::mock_relay;
+    use crate::tests::{test_keys, ZAP_REQ};
+
+    #[test]
+    fn test_from_options() {
+        assert!(Auditor::from_options(None, None).unwrap().is_none());
+        assert!(Auditor::from_options(
+            Some("505fd02741816952ec9a70204221acdd8458906d3e1e0604fef033876c811a8f".to_string()),
+            Some("wss://audit.internal".to_string())
+        )
+        .unwrap()
+        .is_some());
+        assert!(Auditor::from_options(None, Some("wss://audit.internal".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attestation() {
+        let (relay, received) = mock_relay(None, 1);
+        let auditor = Auditor {
+            keys: Keys::generate(),
+            relay,
+        };
+        let zap_note = crate::create_zap_note(
+            &test_keys(),
+            crate::decode_zap_req(ZAP_REQ).unwrap(),
+            crate::tests::test_invoice(ZAP_REQ),
+            &[],
+            false,
+            crate::clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+
+        let attestation = auditor.attestation(&zap_note).unwrap();
+        attestation.verify().unwrap();
+        assert_eq!(attestation.kind, Kind::Custom(ATTESTATION_KIND));
+        assert_eq!(attestation.pubkey, auditor.keys.public_key());
+        assert!(attestation
+            .tags
+            .contains(&Tag::Event(zap_note.id, None, None)));
+
+        auditor
+            .publish(attestation.clone(), &Config::default())
+            .await
+            .unwrap();
+        assert!(received.recv().unwrap().contains(&attestation.id.to_hex()));
+    }
+}
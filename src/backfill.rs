@@ -0,0 +1,131 @@
+//! Where a node with no pay index file yet starts, by `clnzapper_backfill`
+//!
+//! On the first start, such as right after installing, there is no last pay index
+//! to resume from, so which paid invoices get receipts is a choice:
+//! * `all` (the default) starts from the beginning and issues receipts for every
+//!   zap ever paid on the node, e.g. when migrating from another zapper. Pair it
+//!   with `clnzapper_catchup_rate` to not flood relays
+//! * `none` starts after the newest paid invoice, so only new zaps get receipts
+//! * a pay index starts after that index
+//!
+//! Once the pay index file exists it is resumed from and this has no effect.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::requests::ListinvoicesRequest;
+
+use crate::cln::Rpc;
+
+/// Value of `clnzapper_backfill`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backfill {
+    #[default]
+    All,
+    None,
+    /// Start after this pay index
+    After(u64),
+}
+
+impl FromStr for Backfill {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(Self::All),
+            "none" => Ok(Self::None),
+            _ => s
+                .parse()
+                .map(Self::After)
+                .map_err(|_| anyhow!("Invalid backfill {s}, expected all, none or a pay index")),
+        }
+    }
+}
+
+impl Backfill {
+    /// Pay index a node without a pay index file starts after
+    pub async fn start_index(self, socket: PathBuf, rpc_timeout: Option<Duration>) -> Result<u64> {
+        match self {
+            Self::All => Ok(0),
+            Self::After(pay_index) => Ok(pay_index),
+            Self::None => {
+                let mut rpc = Rpc::connect(socket, rpc_timeout).await?;
+                let request = ListinvoicesRequest {
+                    label: None,
+                    invstring: None,
+                    payment_hash: None,
+                    offer_id: None,
+                };
+                let invoices = rpc.call(request, Duration::ZERO).await?.invoices;
+                Ok(invoices
+                    .iter()
+                    .filter_map(|invoice| invoice.pay_index)
+                    .max()
+                    .unwrap_or(0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::source::tests::listed;
+
+    #[test]
+    fn test_parse_backfill() {
+        assert_eq!("all".parse::<Backfill>().unwrap(), Backfill::All);
+        assert_eq!("none".parse::<Backfill>().unwrap(), Backfill::None);
+        assert_eq!("42".parse::<Backfill>().unwrap(), Backfill::After(42));
+        assert!("some".parse::<Backfill>().is_err());
+        assert!("-1".parse::<Backfill>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start_index() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-backfill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lightning-rpc");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Answers listinvoices with invoices paid up to pay index 7
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(request) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                let invoices = json!([
+                    listed("a", "paid", Some(7)),
+                    listed("b", "paid", Some(3)),
+                    listed("unpaid", "unpaid", None),
+                ]);
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": { "invoices": invoices },
+                });
+                write
+                    .write_all(format!("{response}\n\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let start = |backfill: Backfill| backfill.start_index(path.clone(), None);
+        assert_eq!(start(Backfill::All).await.unwrap(), 0);
+        assert_eq!(start(Backfill::After(5)).await.unwrap(), 5);
+        assert_eq!(start(Backfill::None).await.unwrap(), 7);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,95 @@
+//! Refusing zaps from abusive payers, by `clnzapper_payer_blocklist`
+//!
+//! The blocklist is comma separated npub or hex pubkeys, or `file:<path>` of a file
+//! with one per line, where blank lines and lines starting with `#` are ignored.
+//! A zap request signed by a blocklisted pubkey gets no receipt. Anonymous zaps are
+//! signed with a throwaway key, so they can't be blocked this way. The blocklist is
+//! read again on SIGHUP.
+
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use nostr::key::FromPkStr;
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::Keys;
+
+use crate::ZapRequestInfo;
+
+/// Parse `clnzapper_payer_blocklist`, reading the file it names if it starts with `file:`
+pub fn parse(value: &str) -> Result<HashSet<XOnlyPublicKey>> {
+    let list = match value.strip_prefix("file:") {
+        Some(path) => std::fs::read_to_string(path).map_err(|err| {
+            let msg = format!("Could not read clnzapper_payer_blocklist {path}: {err}");
+            anyhow::Error::new(err).context(msg)
+        })?,
+        None => value.replace(',', "\n"),
+    };
+
+    list.lines()
+        .map(str::trim)
+        .filter(|pubkey| !pubkey.is_empty() && !pubkey.starts_with('#'))
+        .map(|pubkey| {
+            Keys::from_pk_str(pubkey)
+                .map(|keys| keys.public_key())
+                .map_err(|err| anyhow!("Invalid blocklisted pubkey {pubkey}: {err}"))
+        })
+        .collect()
+}
+
+/// Payers whose zap requests get no receipt, replaced on reload
+#[derive(Debug, Default)]
+pub struct PayerBlocklist {
+    pubkeys: RwLock<HashSet<XOnlyPublicKey>>,
+}
+
+impl PayerBlocklist {
+    pub fn new(pubkeys: HashSet<XOnlyPublicKey>) -> Self {
+        Self {
+            pubkeys: RwLock::new(pubkeys),
+        }
+    }
+
+    /// Whether the zap request was signed by a blocklisted payer
+    pub fn is_blocked(&self, zap: &ZapRequestInfo) -> bool {
+        self.pubkeys
+            .read()
+            .expect("Lock not poisoned")
+            .contains(&zap.zap_request.pubkey)
+    }
+
+    pub fn replace(&self, pubkeys: HashSet<XOnlyPublicKey>) {
+        *self.pubkeys.write().expect("Lock not poisoned") = pubkeys;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_zap_req;
+    use crate::tests::ZAP_REQ;
+
+    #[test]
+    fn test_parse() {
+        let zap = decode_zap_req(ZAP_REQ).unwrap();
+        let author = zap.zap_request.pubkey;
+        let other = Keys::generate().public_key();
+
+        let blocklist = parse(&format!("{author}, {other}")).unwrap();
+        assert_eq!(blocklist.len(), 2);
+        let payer_blocklist = PayerBlocklist::new(blocklist.clone());
+        assert!(payer_blocklist.is_blocked(&zap));
+        payer_blocklist.replace(parse(&other.to_string()).unwrap());
+        assert!(!payer_blocklist.is_blocked(&zap));
+        assert!(parse("npub1nope").is_err());
+
+        let path = std::env::temp_dir().join(format!("clnzapper-blocklist-{}", std::process::id()));
+        std::fs::write(&path, format!("# spammers\n{author}\n\n{other}\n")).unwrap();
+        assert_eq!(
+            parse(&format!("file:{}", path.display())).unwrap(),
+            blocklist
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse(&format!("file:{}", path.display())).is_err());
+    }
+}
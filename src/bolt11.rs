@@ -0,0 +1,188 @@
+//! Just enough bolt11 decoding to read an invoice's network and description hash
+
+use anyhow::{anyhow, Result};
+
+const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Words of the timestamp at the start of the data part
+const TIMESTAMP_WORDS: usize = 7;
+
+/// Words of the signature at the end of the data part
+const SIGNATURE_WORDS: usize = 104;
+
+/// Tagged field type of the description hash
+const DESCRIPTION_HASH: u8 = 23;
+
+/// The `h` field of a bolt11 invoice, checking its bech32 checksum
+pub fn description_hash(bolt11: &str) -> Result<Option<[u8; 32]>> {
+    let bolt11 = bolt11.to_lowercase();
+    let (hrp, data) = bolt11
+        .rsplit_once('1')
+        .ok_or_else(|| anyhow!("Invoice is not bech32"))?;
+
+    let words = data
+        .chars()
+        .map(|c| {
+            CHARSET
+                .find(c)
+                .map(|i| i as u8)
+                .ok_or_else(|| anyhow!("Invalid bech32 character {c}"))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+
+    if words.len() < TIMESTAMP_WORDS + SIGNATURE_WORDS + 6 {
+        return Err(anyhow!("Invoice too short"));
+    }
+    if polymod(
+        &hrp_expand(hrp)
+            .chain(words.iter().copied())
+            .collect::<Vec<u8>>(),
+    ) != 1
+    {
+        return Err(anyhow!("Invalid invoice checksum"));
+    }
+
+    let mut fields = &words[TIMESTAMP_WORDS..words.len() - 6 - SIGNATURE_WORDS];
+    while fields.len() >= 3 {
+        let kind = fields[0];
+        let len = fields[1] as usize * 32 + fields[2] as usize;
+        let value = fields
+            .get(3..3 + len)
+            .ok_or_else(|| anyhow!("Invoice field overruns the data"))?;
+
+        if kind == DESCRIPTION_HASH && len == 52 {
+            let bytes = to_bytes(value);
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes[..32]);
+            return Ok(Some(hash));
+        }
+        fields = &fields[3 + len..];
+    }
+
+    Ok(None)
+}
+
+/// Network of a bolt11 invoice, from its prefix
+pub fn network(bolt11: &str) -> Option<&'static str> {
+    let bolt11 = bolt11.to_lowercase();
+    // Longest prefixes first, lnbcrt also starts with lnbc
+    [
+        ("lnbcrt", "regtest"),
+        ("lntbs", "signet"),
+        ("lntb", "testnet"),
+        ("lnsb", "simnet"),
+        ("lnbc", "bitcoin"),
+    ]
+    .into_iter()
+    .find_map(|(prefix, network)| bolt11.starts_with(prefix).then_some(network))
+}
+
+fn hrp_expand(hrp: &str) -> impl Iterator<Item = u8> + '_ {
+    hrp.bytes()
+        .map(|b| b >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    values.iter().fold(1, |chk, v| {
+        let top = chk >> 25;
+        let chk = ((chk & 0x1ffffff) << 5) ^ *v as u32;
+        GEN.iter()
+            .enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(chk, |chk, (_, g)| chk ^ g)
+    })
+}
+
+/// Regroup 5 bit words into bytes, dropping the padding
+fn to_bytes(words: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for word in words {
+        acc = ((acc << 5) | *word as u32) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use nostr::hashes::{sha256, Hash};
+
+    use super::*;
+    use crate::tests::{test_invoice, ZAP_REQ};
+
+    /// Unsigned but well formed invoice committing to the description
+    pub fn fake_bolt11(description: &str) -> String {
+        let hash = sha256::Hash::hash(description.as_bytes());
+        let mut words = vec![0; TIMESTAMP_WORDS];
+        words.extend([DESCRIPTION_HASH, 1, 20]);
+        words.extend(to_words(hash.as_ref()));
+        words.extend([0; SIGNATURE_WORDS]);
+
+        let mut values: Vec<u8> = hrp_expand("lnbc").chain(words.iter().copied()).collect();
+        values.extend([0; 6]);
+        let checksum = polymod(&values) ^ 1;
+        words.extend((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+
+        let data: String = words
+            .iter()
+            .map(|w| CHARSET.as_bytes()[*w as usize] as char)
+            .collect();
+        format!("lnbc1{data}")
+    }
+
+    fn to_words(bytes: &[u8]) -> Vec<u8> {
+        let mut words = Vec::new();
+        let (mut acc, mut bits) = (0u32, 0);
+        for byte in bytes {
+            acc = ((acc << 8) | *byte as u32) & 0xfff;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                words.push(((acc >> bits) & 31) as u8);
+            }
+        }
+        if bits > 0 {
+            words.push(((acc << (5 - bits)) & 31) as u8);
+        }
+        words
+    }
+
+    #[test]
+    fn test_network() {
+        assert_eq!(network("lnbc500n1pjq7u7j"), Some("bitcoin"));
+        assert_eq!(network("LNBCRT1pjq"), Some("regtest"));
+        assert_eq!(network("lntb1u1pjq"), Some("testnet"));
+        assert_eq!(network("lntbs1u1pjq"), Some("signet"));
+        assert_eq!(network("lni1qqg"), None);
+    }
+
+    #[test]
+    fn test_description_hash() {
+        let bolt11 = test_invoice(ZAP_REQ).bolt11.unwrap();
+        let hash = description_hash(&bolt11).unwrap().unwrap();
+        assert_eq!(
+            hex::encode(hash),
+            "c2e66523cc6b42ccc4a1ec689f3db5273be4902904b21c11002c4f961f59b37d"
+        );
+
+        // Flipping a character breaks the checksum
+        let mut corrupt = bolt11.clone();
+        corrupt.replace_range(20..21, if &bolt11[20..21] == "q" { "p" } else { "q" });
+        assert!(description_hash(&corrupt).is_err());
+        assert!(description_hash("lnbc1placeholder").is_err());
+
+        let hash = description_hash(&fake_bolt11(ZAP_REQ)).unwrap().unwrap();
+        assert_eq!(
+            hex::encode(hash),
+            sha256::Hash::hash(ZAP_REQ.as_bytes()).to_string()
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cln_rpc::model::WaitanyinvoiceResponse;
+use tokio::time::Instant;
+
+/// Paces receipts for invoices paid before the plugin started
+///
+/// Catching up after downtime can produce a burst of receipts relays dislike,
+/// so these are spaced out to `rate` per second. Invoices paid while running
+/// are never delayed.
+#[derive(Debug)]
+pub struct CatchupPacer {
+    /// Minimum time between catch-up receipts, `None` if unlimited
+    interval: Option<Duration>,
+    /// Unix time the plugin started
+    started_at: u64,
+    /// Earliest the next catch-up receipt may go out
+    next: Instant,
+}
+
+impl CatchupPacer {
+    pub fn new(rate: Option<u64>) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self::with_start(rate, started_at)
+    }
+
+    fn with_start(rate: Option<u64>, started_at: u64) -> Self {
+        Self {
+            interval: rate
+                .filter(|rate| *rate > 0)
+                .map(|rate| Duration::from_nanos(1_000_000_000 / rate)),
+            started_at,
+            next: Instant::now(),
+        }
+    }
+
+    /// Invoice was paid before we started, so its receipt is catch-up work
+    pub fn is_catchup(&self, invoice: &WaitanyinvoiceResponse) -> bool {
+        invoice
+            .paid_at
+            .map(|paid_at| paid_at < self.started_at)
+            .unwrap_or(false)
+    }
+
+    /// Wait until the receipt for this invoice may be broadcast
+    pub async fn wait(&mut self, invoice: &WaitanyinvoiceResponse) {
+        let interval = match self.interval {
+            Some(interval) if self.is_catchup(invoice) => interval,
+            _ => return,
+        };
+
+        tokio::time::sleep_until(self.next).await;
+        self.next = Instant::now() + interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cln_rpc::model::WaitanyinvoiceStatus;
+    use cln_rpc::primitives::Sha256;
+
+    use super::*;
+
+    fn invoice(paid_at: u64) -> WaitanyinvoiceResponse {
+        WaitanyinvoiceResponse {
+            label: "label".to_string(),
+            description: "".to_string(),
+            payment_hash: Sha256::from_str(
+                "83f34c56502833b28dc64b382ef8462c2f5edb19c427fd5456d46bfc5c35914b",
+            )
+            .unwrap(),
+            status: WaitanyinvoiceStatus::PAID,
+            expires_at: 0,
+            amount_msat: None,
+            bolt11: None,
+            bolt12: None,
+            pay_index: Some(1),
+            amount_received_msat: None,
+            paid_at: Some(paid_at),
+            payment_preimage: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_catchup_paced() {
+        let mut pacer = CatchupPacer::with_start(Some(20), 1000);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            pacer.wait(&invoice(999)).await;
+        }
+        // First goes out immediately, the next two 50ms apart
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Live invoices are never delayed
+        let start = Instant::now();
+        for _ in 0..3 {
+            pacer.wait(&invoice(1000)).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_catchup_unlimited() {
+        let mut pacer = CatchupPacer::with_start(None, 1000);
+        assert!(pacer.is_catchup(&invoice(999)));
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            pacer.wait(&invoice(999)).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
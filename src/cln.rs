@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::IntoRequest;
+use cln_rpc::ClnRpc;
+use log::warn;
+
+/// CLN rpc client with a ceiling on how long a call may take
+///
+/// A call can hang forever if the socket gets wedged. With `clnzapper_rpc_timeout`
+/// set, a call taking longer fails and the connection is dropped, so the next call
+/// reconnects instead of reading a late reply meant for the call given up on.
+pub struct Rpc {
+    socket: PathBuf,
+    client: Option<ClnRpc>,
+    timeout: Option<Duration>,
+}
+
+impl Rpc {
+    pub async fn connect(socket: PathBuf, timeout: Option<Duration>) -> Result<Self> {
+        let client = Some(ClnRpc::new(&socket).await?);
+        Ok(Self {
+            socket,
+            client,
+            timeout,
+        })
+    }
+
+    /// Call lightningd, allowing `wait` on top of the ceiling for calls that wait by design
+    pub async fn call<R>(&mut self, request: R, wait: Duration) -> Result<R::Response>
+    where
+        R: IntoRequest,
+    {
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => self.client.insert(ClnRpc::new(&self.socket).await?),
+        };
+
+        let Some(timeout) = self.timeout else {
+            return Ok(client.call_typed(request).await?);
+        };
+        match tokio::time::timeout(timeout + wait, client.call_typed(request)).await {
+            Ok(response) => Ok(response?),
+            Err(_) => {
+                self.client = None;
+                warn!(
+                    "CLN rpc call took longer than {}s, reconnecting",
+                    (timeout + wait).as_secs()
+                );
+                Err(anyhow!("CLN rpc call timed out"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cln_rpc::model::ListinvoicesRequest;
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stalled_call_times_out() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-rpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lightning-rpc");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Accepts connections and never answers
+        let accepted = tokio::spawn(async move {
+            let mut streams = vec![];
+            for _ in 0..2 {
+                streams.push(listener.accept().await.unwrap().0);
+            }
+            streams
+        });
+
+        let mut rpc = Rpc::connect(path, Some(Duration::from_millis(100)))
+            .await
+            .unwrap();
+        let request = || ListinvoicesRequest {
+            label: None,
+            invstring: None,
+            payment_hash: None,
+            offer_id: None,
+        };
+
+        let start = tokio::time::Instant::now();
+        assert!(rpc.call(request(), Duration::ZERO).await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(rpc.client.is_none());
+
+        // The next call reconnects
+        assert!(rpc.call(request(), Duration::ZERO).await.is_err());
+        assert_eq!(accepted.await.unwrap().len(), 2);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,78 @@
+//! Receipt timestamps, by `clnzapper_max_clock_skew`
+//!
+//! A receipt is created at its invoice's `paid_at`, as NIP-57 asks, which comes from
+//! the node's clock. A node clock far off, or an invoice paid long before the zapper
+//! got to it, would give a `created_at` relays reject as too far in the future or
+//! past, so it is clamped to within `clnzapper_max_clock_skew` seconds of the local
+//! clock. Invoices without a `paid_at` get the local time.
+
+use log::{info, warn};
+use nostr::Timestamp;
+
+/// Seconds `created_at` may be from the local clock when `clnzapper_max_clock_skew` is not set
+pub const DEFAULT_MAX_SKEW: u64 = 900;
+
+/// When the receipt of an invoice paid at `paid_at` is created, clamped to within
+/// `max_skew` of `now`
+pub fn receipt_created_at(paid_at: Option<u64>, now: Timestamp, max_skew: u64) -> Timestamp {
+    let now = now.as_u64();
+    let Some(paid_at) = paid_at else {
+        return Timestamp::from(now);
+    };
+
+    let (earliest, latest) = (now.saturating_sub(max_skew), now.saturating_add(max_skew));
+    if paid_at > latest {
+        warn!("Invoice paid_at {paid_at} is ahead of the local clock, {now}, creating its receipt at {latest}");
+        Timestamp::from(latest)
+    } else if paid_at < earliest {
+        info!("Invoice paid_at {paid_at} is more than {max_skew}s ago, creating its receipt at {earliest}");
+        Timestamp::from(earliest)
+    } else {
+        Timestamp::from(paid_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamped() {
+        let now = Timestamp::from(1_700_000_000);
+        let created_at = |paid_at| receipt_created_at(paid_at, now, 900).as_u64();
+
+        assert_eq!(created_at(Some(1_700_000_000 - 60)), 1_700_000_000 - 60);
+        assert_eq!(
+            created_at(Some(1_700_000_000 + 86_400)),
+            1_700_000_000 + 900
+        );
+        assert_eq!(created_at(Some(1_600_000_000)), 1_700_000_000 - 900);
+        assert_eq!(created_at(None), 1_700_000_000);
+
+        // No skew allowed always takes the local clock
+        assert_eq!(
+            receipt_created_at(Some(1_700_000_060), now, 0).as_u64(),
+            1_700_000_000
+        );
+    }
+
+    #[test]
+    fn test_receipt_of_future_invoice() {
+        let mut invoice = crate::tests::test_invoice(crate::tests::ZAP_REQ);
+        let now = Timestamp::now().as_u64();
+        invoice.paid_at = Some(now + 10 * 86_400);
+
+        let zap_note = crate::create_zap_note(
+            &crate::tests::test_keys(),
+            crate::decode_zap_req(crate::tests::ZAP_REQ).unwrap(),
+            invoice,
+            &[],
+            false,
+            DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        let created_at = zap_note.created_at.as_u64();
+        assert!(created_at >= now + DEFAULT_MAX_SKEW);
+        assert!(created_at <= Timestamp::now().as_u64() + DEFAULT_MAX_SKEW);
+    }
+}
@@ -0,0 +1,107 @@
+//! Coalescing retried zaps, by `clnzapper_coalesce_window_secs`
+//!
+//! A wallet retrying a payment can pay two invoices carrying the same zap request,
+//! which would otherwise each get a receipt. Within the window of the first invoice
+//! of a zap request being paid, later invoices for it are skipped as coalesced. The
+//! window runs on the invoices' `paid_at`, so retries read together while catching
+//! up after a restart are still coalesced, and invoices paid far apart never are.
+//! Only zap requests seen since startup are remembered, and only for the window:
+//! this catches retries, not replays across restarts. A zap request whose receipt
+//! was never published, because it was skipped, failed or no relay accepted it, is
+//! forgotten again so a retry can still get one.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use cln_rpc::model::WaitanyinvoiceResponse;
+use nostr::{EventId, Timestamp};
+
+/// Zap requests seen within the window, by when their first invoice was paid
+#[derive(Debug)]
+pub struct Coalescer {
+    window: Duration,
+    /// Unix time in seconds the first invoice of each zap request was paid
+    seen: Mutex<HashMap<EventId, u64>>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether an invoice for zap request `id` was paid within the window of
+    /// `paid_at`, in unix seconds, remembering it if not
+    pub fn is_retry(&self, id: EventId, paid_at: u64) -> bool {
+        let window = self.window.as_secs();
+        let mut seen = self.seen.lock().expect("Lock not poisoned");
+        seen.retain(|_, first| first.saturating_add(window) > paid_at);
+        match seen.get(&id) {
+            Some(first) if first.abs_diff(paid_at) < window => true,
+            _ => {
+                seen.insert(id, paid_at);
+                false
+            }
+        }
+    }
+
+    /// Forget the invoice for zap request `id` paid at `paid_at`, so a retry within
+    /// its window isn't coalesced with it
+    pub fn forget(&self, id: EventId, paid_at: u64) {
+        let window = self.window.as_secs();
+        let mut seen = self.seen.lock().expect("Lock not poisoned");
+        if seen
+            .get(&id)
+            .is_some_and(|first| first.abs_diff(paid_at) < window)
+        {
+            seen.remove(&id);
+        }
+    }
+}
+
+/// When the invoice was paid in unix seconds, now if it doesn't say
+pub fn paid_at(invoice: &WaitanyinvoiceResponse) -> u64 {
+    invoice.paid_at.unwrap_or_else(|| Timestamp::now().as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::hashes::{sha256, Hash};
+
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let coalescer = Coalescer::new(Duration::from_secs(60));
+        let id = EventId::all_zeros();
+        let other = EventId::from(sha256::Hash::hash(b"other"));
+        let start = 1_700_000_000;
+
+        assert!(!coalescer.is_retry(id, start));
+        assert!(coalescer.is_retry(id, start + 30));
+        assert!(!coalescer.is_retry(other, start + 30));
+
+        // Counted from the first invoice's payment, not the retries
+        assert!(!coalescer.is_retry(id, start + 60));
+        // Read out of order while catching up, paid just before
+        assert!(coalescer.is_retry(id, start + 50));
+    }
+
+    #[test]
+    fn test_forget() {
+        let coalescer = Coalescer::new(Duration::from_secs(60));
+        let id = EventId::all_zeros();
+        let start = 1_700_000_000;
+
+        assert!(!coalescer.is_retry(id, start));
+        // An invoice from a later window leaves this one remembered
+        coalescer.forget(id, start + 90);
+        assert!(coalescer.is_retry(id, start + 30));
+
+        coalescer.forget(id, start);
+        assert!(!coalescer.is_retry(id, start + 30));
+    }
+}
@@ -0,0 +1,158 @@
+//! Zap comments cleaned up before the zapper shows them anywhere
+//!
+//! The comment is the content of the zap request, text chosen by whoever paid.
+//! The receipt carries the zap request untouched, as NIP-57 requires, but logs and
+//! RPC output only ever get the filtered comment, receipts shown there included.
+
+use nostr::Event;
+use serde_json::Value;
+
+/// Characters the comment is cut to when `clnzapper_comment_max_len` is not set
+pub const DEFAULT_COMMENT_MAX_LEN: usize = 280;
+
+/// How zap comments are filtered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommentFilter {
+    /// Most characters kept
+    pub max_len: usize,
+    /// Replace anything that looks like a link with `[link]`
+    pub strip_urls: bool,
+}
+
+impl Default for CommentFilter {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_COMMENT_MAX_LEN,
+            strip_urls: false,
+        }
+    }
+}
+
+impl CommentFilter {
+    /// The comment safe to show, `None` if nothing is left of it
+    pub fn apply(&self, comment: &str) -> Option<String> {
+        // Control characters could forge log lines or drive terminals, bidi overrides
+        // could make the text read differently from what it is
+        let cleaned: String = comment
+            .chars()
+            .filter_map(|c| match c {
+                '\n' | '\r' | '\t' => Some(' '),
+                c if c.is_control() || is_bidi_control(c) => None,
+                c => Some(c),
+            })
+            .collect();
+
+        let words = cleaned.split_whitespace().map(|word| {
+            if self.strip_urls && looks_like_url(word) {
+                "[link]"
+            } else {
+                word
+            }
+        });
+        let cleaned = words.collect::<Vec<&str>>().join(" ");
+        if cleaned.is_empty() {
+            return None;
+        }
+
+        if cleaned.chars().count() <= self.max_len {
+            return Some(cleaned);
+        }
+        let mut cut: String = cleaned.chars().take(self.max_len).collect();
+        cut.push('…');
+        Some(cut)
+    }
+
+    /// The receipt as JSON with the comment of the zap request in its description
+    /// filtered. Its signature no longer matches, so it is only fit to show.
+    pub fn redact_receipt(&self, receipt: &Event) -> Value {
+        let mut json = serde_json::json!(receipt);
+        let tags = json["tags"].as_array_mut().into_iter().flatten();
+        for tag in tags.filter(|tag| tag[0] == "description") {
+            let Some(mut zap_request) = tag[1]
+                .as_str()
+                .and_then(|s| serde_json::from_str::<Value>(s).ok())
+            else {
+                continue;
+            };
+            let comment = zap_request["content"].as_str().and_then(|c| self.apply(c));
+            zap_request["content"] = Value::from(comment.unwrap_or_default());
+            tag[1] = Value::from(zap_request.to_string());
+        }
+        json
+    }
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+fn looks_like_url(word: &str) -> bool {
+    let word = word.to_lowercase();
+    word.contains("://") || word.starts_with("www.") || word.starts_with("lnurl")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_filter() {
+        let filter = CommentFilter::default();
+        assert_eq!(filter.apply("Great post!"), Some("Great post!".to_string()));
+        assert_eq!(
+            filter.apply("fake\n[ERROR] log line\u{1b}[2J"),
+            Some("fake [ERROR] log line[2J".to_string())
+        );
+        assert_eq!(filter.apply("abc\u{202e}fed"), Some("abcfed".to_string()));
+        assert_eq!(filter.apply(" \u{0} \n"), None);
+
+        let long = "z".repeat(1000);
+        let cut = filter.apply(&long).unwrap();
+        assert_eq!(cut.chars().count(), DEFAULT_COMMENT_MAX_LEN + 1);
+        assert!(cut.ends_with('…'));
+    }
+
+    #[test]
+    fn test_redact_receipt() {
+        use nostr::{EventBuilder, Keys, Kind, Tag};
+
+        let keys = Keys::generate();
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "hi\nhttps://scam.example", &[])
+            .to_event(&keys)
+            .unwrap();
+        let receipt = EventBuilder::new(
+            Kind::ZapReceipt,
+            "",
+            &[Tag::Description(zap_request.as_json())],
+        )
+        .to_event(&keys)
+        .unwrap();
+
+        let filter = CommentFilter {
+            strip_urls: true,
+            ..CommentFilter::default()
+        };
+        let redacted = filter.redact_receipt(&receipt);
+        assert_eq!(redacted["id"], receipt.id.to_hex());
+        let description: Value =
+            serde_json::from_str(redacted["tags"][0][1].as_str().unwrap()).unwrap();
+        assert_eq!(description["content"], "hi [link]");
+        assert_eq!(description["id"], zap_request.id.to_hex());
+    }
+
+    #[test]
+    fn test_comment_urls() {
+        let filter = CommentFilter {
+            strip_urls: true,
+            ..CommentFilter::default()
+        };
+        assert_eq!(
+            filter.apply("free sats at https://scam.example now"),
+            Some("free sats at [link] now".to_string())
+        );
+        assert_eq!(
+            CommentFilter::default().apply("see https://nostr.com"),
+            Some("see https://nostr.com".to_string())
+        );
+    }
+}
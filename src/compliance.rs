@@ -0,0 +1,153 @@
+//! How strictly zap requests are held to NIP-57
+//!
+//! Both modes require exactly one `p` tag, at most one `e` tag, and an `amount`
+//! tag, if present, equal to the invoice amount.
+//!
+//! `strict` additionally requires:
+//! * the zap request to be of kind 9734 with a valid signature
+//! * an `amount` tag
+//! * a `relays` tag
+//! * the invoice's description hash to commit to the zap request
+//!
+//! `lenient` (the default) publishes receipts without those checks.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::WaitanyinvoiceResponse;
+use nostr::hashes::{sha256, Hash};
+use nostr::Kind;
+
+use crate::{bolt11, ZapRequestInfo};
+
+/// Value of `clnzapper_compliance_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ComplianceMode {
+    Strict,
+    #[default]
+    Lenient,
+}
+
+impl FromStr for ComplianceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(anyhow!(
+                "Invalid compliance mode {s}, expected strict or lenient"
+            )),
+        }
+    }
+}
+
+/// Check the zap request against the checks only `strict` enables
+pub fn check(
+    mode: ComplianceMode,
+    zap: &ZapRequestInfo,
+    invoice: &WaitanyinvoiceResponse,
+) -> Result<()> {
+    if mode == ComplianceMode::Lenient {
+        return Ok(());
+    }
+
+    let zap_request = &zap.zap_request;
+    if zap_request.kind != Kind::ZapRequest {
+        return Err(anyhow!(
+            "Zap request is of kind {}",
+            zap_request.kind.as_u64()
+        ));
+    }
+    zap_request
+        .verify()
+        .map_err(|err| anyhow!("Invalid zap request signature: {err}"))?;
+
+    if zap.amount.is_none() {
+        return Err(anyhow!("Zap request has no amount tag"));
+    }
+    if zap.relays.is_empty() {
+        return Err(anyhow!("Zap request has no relays tag"));
+    }
+
+    let bolt11 = invoice
+        .bolt11
+        .as_deref()
+        .ok_or_else(|| anyhow!("No bolt 11"))?;
+    let description_hash = bolt11::description_hash(bolt11)?
+        .ok_or_else(|| anyhow!("Invoice has no description hash"))?;
+    let zap_request_hash = sha256::Hash::hash(invoice.description.as_bytes());
+    if description_hash.as_slice() != AsRef::<[u8]>::as_ref(&zap_request_hash) {
+        return Err(anyhow!(
+            "Invoice description hash does not match the zap request"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cln_rpc::primitives::Amount;
+    use nostr::{EventBuilder, Keys, Tag, UncheckedUrl};
+
+    use super::*;
+    use crate::bolt11::tests::fake_bolt11;
+    use crate::decode_zap_req;
+    use crate::tests::{test_invoice, test_keys, ZAP_REQ};
+
+    /// Zap request meeting every strict check, and its invoice
+    fn compliant() -> (String, WaitanyinvoiceResponse) {
+        let tags = [
+            Tag::PubKey(test_keys().public_key(), None),
+            Tag::Relays(vec![UncheckedUrl::from("wss://nos.lol")]),
+            Tag::Amount(21000),
+        ];
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+
+        let mut invoice = test_invoice(&zap_request);
+        invoice.bolt11 = Some(fake_bolt11(&zap_request));
+        invoice.amount_msat = Some(Amount::from_msat(21000));
+        (zap_request, invoice)
+    }
+
+    #[test]
+    fn test_compliance_modes() {
+        let (zap_request, invoice) = compliant();
+        let zap = decode_zap_req(&zap_request).unwrap();
+        assert!(check(ComplianceMode::Strict, &zap, &invoice).is_ok());
+        assert!(check(ComplianceMode::Lenient, &zap, &invoice).is_ok());
+
+        // The fixture has no amount tag and its invoice does not commit to it
+        let zap = decode_zap_req(ZAP_REQ).unwrap();
+        let invoice = test_invoice(ZAP_REQ);
+        assert!(check(ComplianceMode::Strict, &zap, &invoice).is_err());
+        assert!(check(ComplianceMode::Lenient, &zap, &invoice).is_ok());
+    }
+
+    #[test]
+    fn test_strict_checks() {
+        let (zap_request, invoice) = compliant();
+
+        // Invoice for a different description
+        let mut other = invoice.clone();
+        other.bolt11 = Some(fake_bolt11("other"));
+        let zap = decode_zap_req(&zap_request).unwrap();
+        assert!(check(ComplianceMode::Strict, &zap, &other).is_err());
+        assert!(check(ComplianceMode::Lenient, &zap, &other).is_ok());
+
+        // Wrong kind
+        let mut zap = decode_zap_req(&zap_request).unwrap();
+        zap.zap_request.kind = Kind::TextNote;
+        assert!(check(ComplianceMode::Strict, &zap, &invoice).is_err());
+        assert!(check(ComplianceMode::Lenient, &zap, &invoice).is_ok());
+
+        // No relays
+        let mut zap = decode_zap_req(&zap_request).unwrap();
+        zap.relays.clear();
+        assert!(check(ComplianceMode::Strict, &zap, &invoice).is_err());
+    }
+}
@@ -0,0 +1,787 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cln_plugin::options::Value;
+use log::Level;
+use nostr::secp256k1::XOnlyPublicKey;
+use tokio::sync::Semaphore;
+
+use crate::alert::Alerter;
+use crate::amount::{AmountField, MismatchPolicy, SanityRange};
+use crate::archive::{self, Archive};
+use crate::audit::Auditor;
+use crate::backfill::Backfill;
+use crate::blocklist::{self, PayerBlocklist};
+use crate::clock;
+use crate::coalesce::Coalescer;
+use crate::comment::{CommentFilter, DEFAULT_COMMENT_MAX_LEN};
+use crate::compliance::ComplianceMode;
+use crate::index_batch::Batching;
+use crate::inflight::DEFAULT_MAX_INFLIGHT_ZAPS;
+use crate::metrics::Metrics;
+use crate::migrate::IndexConflict;
+use crate::nip65::Nip65Markers;
+use crate::output::ReceiptOutput;
+use crate::published::{Retention, CAPACITY};
+use crate::recipient::{self, MismatchAction, MismatchAlerts};
+use crate::relay::{
+    parse_relay_headers, LogRelayOrder, RelayHeaders, RelaySchemePolicy,
+    DEFAULT_PER_ZAP_CONCURRENCY,
+};
+use crate::relay_auth;
+use crate::relay_kinds::RelayKinds;
+use crate::relay_url::RelayUrl;
+use crate::reload::Reloadable;
+use crate::republish;
+use crate::send_buffer::{Overflow, SendBuffers};
+use crate::source::{SourceKind, DEFAULT_POLL_INTERVAL};
+use crate::status_file;
+use crate::syslog::{self, Facility, Severity, SyslogSink};
+use crate::totals::{ZapTotals, DEFAULT_MAX_RECIPIENTS};
+use crate::zapped_event::{self, MissingEvent, ZappedEventCheck};
+use crate::DEFAULT_MAX_RECEIPT_TAGS;
+
+/// Longest `clnzapper_startup_grace` allowed, in seconds
+const MAX_STARTUP_GRACE: u64 = 300;
+
+/// Network marked on receipts with `clnzapper_network_tag`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkTag {
+    /// Taken from the invoice's prefix
+    FromInvoice,
+    /// Set explicitly
+    Fixed(String),
+}
+
+/// Zapper settings read from the plugin options
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Extra websocket handshake headers per relay
+    pub relay_headers: RelayHeaders,
+    /// Pay index file of a previous install to take over, none if not migrating
+    pub pay_index_migrate_from: Option<PathBuf>,
+    /// Which index wins when migrating onto an existing one
+    pub pay_index_conflict: IndexConflict,
+    /// Kinds relays are known to accept
+    pub relay_kinds: Arc<RelayKinds>,
+    /// Max receipts per second for invoices paid before startup, `None` if unlimited
+    pub catchup_rate: Option<u64>,
+    /// Where a node without a pay index file starts
+    pub backfill: Backfill,
+    /// Max relays contacted at once when broadcasting a single zap
+    pub per_zap_concurrency: usize,
+    /// Invoice amount the zap request's amount is checked against
+    pub amount_field: AmountField,
+    /// What a zap failing the amount checks gets
+    pub amount_mismatch: MismatchPolicy,
+    /// Max percent the received amount may differ from the invoice amount, `None` if unchecked
+    pub max_amount_deviation_pct: Reloadable<Option<u64>>,
+    /// Bounds invoice amounts must fall within for a receipt
+    pub sanity_range: Reloadable<SanityRange>,
+    /// Where every published receipt is also stored, `None` if not archived
+    pub archive: Option<Archive>,
+    /// Permits for receipts being archived, so a stalled archive can't pile up tasks
+    pub archive_pending: Arc<Semaphore>,
+    /// File the ids of published receipts are kept in, none to only keep them in memory
+    pub published_log: Option<PathBuf>,
+    /// Which published receipt ids are kept
+    pub published_retention: Retention,
+    /// Signer of receipt attestations, `None` if not auditing
+    pub auditor: Option<Auditor>,
+    /// Whether the dry run `zapper-simulate` method is enabled
+    pub simulate: bool,
+    /// Seconds the invoice stream may make no progress before it is restarted, `None` if unwatched
+    pub watchdog_timeout: Option<u64>,
+    /// Whether receipts carry a relays tag of the relays they are published to
+    pub relays_tag: bool,
+    /// Whether receipts carry the sha256 of their description in a description_hash tag
+    pub description_hash_tag: bool,
+    /// Whether receipts carry a client tag naming the zapper and its version
+    pub client_tag: bool,
+    /// Whether zap requests may have several e tags, all copied into the receipt
+    pub copy_e_tags: bool,
+    /// Whether private zaps for the receipt key are decrypted
+    pub decrypt_private_zaps: bool,
+    /// How strictly zap requests are held to NIP-57
+    pub compliance_mode: ComplianceMode,
+    /// Seconds a zap request may be created before its payment, unchecked if unset
+    pub max_zap_request_age: Reloadable<Option<u64>>,
+    /// Seconds to wait at startup for the default relays to accept connections, `None` to skip
+    pub startup_grace: Option<u64>,
+    /// Network tag added to receipts, `None` if not tagged
+    pub network_tag: Option<NetworkTag>,
+    /// Whether receipts are requested back from relays that accepted them
+    pub verify_delivery: bool,
+    /// How zapped events are looked up before publishing, none if they aren't
+    pub zapped_event_check: Option<ZappedEventCheck>,
+    /// Where paid invoices come from
+    pub invoice_source: SourceKind,
+    /// Seconds between polls once caught up
+    pub poll_interval: u64,
+    /// Max zaps being published at once
+    pub max_inflight_zaps: usize,
+    /// Whether the saved pay index only advances past invoices done with
+    pub index_after_publish: bool,
+    /// When pay index writes are written, every time if `None`
+    pub index_batching: Option<Batching>,
+    /// Seconds a CLN rpc call may take, beyond any wait it asks for
+    pub rpc_timeout: Option<u64>,
+    /// How relays are listed in logs
+    pub log_relay_order: LogRelayOrder,
+    /// Label prefix of the invoices receipts are issued for, all if unset
+    pub label_prefix: Reloadable<Option<String>>,
+    /// How zap comments are cleaned up before being shown
+    pub comment_filter: Reloadable<CommentFilter>,
+    /// Most optional tags in a receipt, and relays in its relays tag
+    pub max_receipt_tags: usize,
+    /// Seconds after payment receipts carry an expiration tag for, none if unset
+    pub receipt_ttl_secs: Option<u64>,
+    /// Rpc sockets of other nodes to read paid invoices from
+    pub extra_rpc_sockets: Vec<PathBuf>,
+    /// Rpc socket of a standby node read while ours is down
+    pub fallback_rpc_socket: Option<PathBuf>,
+    /// Sign receipts without random aux data
+    pub deterministic_signatures: bool,
+    /// Seconds a receipt's `created_at` may be from the local clock
+    pub max_clock_skew: u64,
+    /// What to do with a relay listed as both ws and wss
+    pub relay_scheme_policy: RelaySchemePolicy,
+    /// Offsets after the first publish to publish each receipt again at
+    pub republish_intervals: Vec<Duration>,
+    /// Seconds between published summaries, none if unset
+    pub summary_interval: Option<u64>,
+    /// File the zapper's status is written to, none if not written
+    pub status_file: Option<PathBuf>,
+    /// Config file hot reloadable options are read from on SIGHUP, lightningd's if unset
+    pub reload_file: Option<PathBuf>,
+    /// Seconds between writes of the status file
+    pub status_file_interval: u64,
+    /// Seconds between reloading the receipt key
+    pub key_reload_interval: Option<u64>,
+    /// Relays summaries are published to, the default relays if empty
+    pub summary_relays: Vec<RelayUrl>,
+    /// Where receipts are streamed as NDJSON, `None` if not streamed
+    pub receipt_output: Option<ReceiptOutput>,
+    /// Level invoices whose description isn't a zap request are logged at
+    pub non_zap_log_level: Level,
+    /// Level the full JSON of receipts is logged at, `None` if never logged
+    pub event_json_log_level: Option<Level>,
+    /// Per-relay queues events are sent through, `None` to send directly
+    pub send_buffers: Option<Arc<SendBuffers>>,
+    /// Publishes alerts when no relay accepts receipts, `None` if not alerting
+    pub alerter: Option<Arc<Alerter>>,
+    /// Relays alerts are published to
+    pub alert_relays: Vec<RelayUrl>,
+    /// Recipients zap requests may be for, any if empty
+    pub recipient_pubkeys: Reloadable<Vec<XOnlyPublicKey>>,
+    /// Payers whose zap requests get no receipt
+    pub payer_blocklist: Arc<PayerBlocklist>,
+    /// Zap requests read recently, to skip retries of, by `clnzapper_coalesce_window_secs`
+    pub coalescer: Option<Arc<Coalescer>>,
+    /// What a zap request for another recipient does besides getting no receipt
+    pub recipient_mismatch: MismatchAction,
+    /// Limits alerts of zap requests for another recipient
+    pub mismatch_alerts: Arc<MismatchAlerts>,
+    /// Address `/metrics` is served on, `None` if not served
+    pub metrics_addr: Option<SocketAddr>,
+    /// Counters served on `/metrics`, `None` if not counted
+    pub metrics: Option<Arc<Metrics>>,
+    /// Rolling zap totals per recipient, `None` if not kept
+    pub zap_totals: Option<Arc<ZapTotals>>,
+    /// Where issued receipts are recorded in syslog, by `clnzapper_syslog`
+    pub syslog: Option<Arc<SyslogSink>>,
+    /// Address `/livez` and `/readyz` are served on, `None` if not served
+    pub health_addr: Option<SocketAddr>,
+    /// Seconds receipts are randomly held for at most before publishing, none if unset
+    pub publish_jitter: Option<u64>,
+    /// Most relays a receipt is published to, unlimited if unset
+    pub max_total_relays: Reloadable<Option<usize>>,
+    /// The operator's relay every receipt also goes to, outside the caps and policies
+    pub inbox_relay: Option<RelayUrl>,
+    /// Whether receipts also go to the recipient's NIP-65 read relays
+    pub nip65_relays: bool,
+    /// Which relays of the recipient's NIP-65 list receipts go to
+    pub nip65_markers: Nip65Markers,
+    /// Seconds after which NIP-65 relay lists are fetched again, never if unset
+    pub nip65_refresh: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            relay_headers: RelayHeaders::new(),
+            pay_index_migrate_from: None,
+            pay_index_conflict: IndexConflict::default(),
+            relay_kinds: Arc::new(RelayKinds::default()),
+            catchup_rate: None,
+            backfill: Backfill::default(),
+            per_zap_concurrency: DEFAULT_PER_ZAP_CONCURRENCY,
+            amount_field: AmountField::default(),
+            amount_mismatch: MismatchPolicy::default(),
+            max_amount_deviation_pct: Reloadable::default(),
+            sanity_range: Reloadable::default(),
+            archive: None,
+            archive_pending: Arc::new(Semaphore::new(archive::MAX_PENDING)),
+            published_log: None,
+            published_retention: Retention::default(),
+            auditor: None,
+            simulate: false,
+            watchdog_timeout: None,
+            relays_tag: false,
+            description_hash_tag: false,
+            client_tag: false,
+            copy_e_tags: false,
+            decrypt_private_zaps: false,
+            compliance_mode: ComplianceMode::default(),
+            max_zap_request_age: Reloadable::default(),
+            startup_grace: None,
+            network_tag: None,
+            verify_delivery: false,
+            zapped_event_check: None,
+            invoice_source: SourceKind::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            max_inflight_zaps: DEFAULT_MAX_INFLIGHT_ZAPS,
+            index_after_publish: false,
+            index_batching: None,
+            rpc_timeout: None,
+            log_relay_order: LogRelayOrder::default(),
+            label_prefix: Reloadable::default(),
+            comment_filter: Reloadable::default(),
+            max_receipt_tags: DEFAULT_MAX_RECEIPT_TAGS,
+            receipt_ttl_secs: None,
+            extra_rpc_sockets: vec![],
+            fallback_rpc_socket: None,
+            deterministic_signatures: false,
+            max_clock_skew: clock::DEFAULT_MAX_SKEW,
+            relay_scheme_policy: RelaySchemePolicy::default(),
+            republish_intervals: vec![],
+            summary_interval: None,
+            status_file: None,
+            reload_file: None,
+            status_file_interval: status_file::DEFAULT_INTERVAL,
+            key_reload_interval: None,
+            summary_relays: vec![],
+            receipt_output: None,
+            non_zap_log_level: Level::Debug,
+            event_json_log_level: Some(Level::Trace),
+            send_buffers: None,
+            alerter: None,
+            alert_relays: vec![],
+            recipient_pubkeys: Reloadable::default(),
+            payer_blocklist: Arc::new(PayerBlocklist::default()),
+            coalescer: None,
+            recipient_mismatch: MismatchAction::default(),
+            mismatch_alerts: Arc::new(MismatchAlerts::default()),
+            metrics_addr: None,
+            metrics: None,
+            zap_totals: None,
+            syslog: None,
+            health_addr: None,
+            publish_jitter: None,
+            max_total_relays: Reloadable::default(),
+            inbox_relay: None,
+            nip65_relays: false,
+            nip65_markers: Nip65Markers::default(),
+            nip65_refresh: Some(3600),
+        }
+    }
+}
+
+impl Config {
+    /// Build the config from plugin options, looked up by name
+    pub fn from_options<F>(option: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<Value>,
+    {
+        let mut relay_headers = match option("clnzapper_relay_headers") {
+            Some(Value::String(headers)) => parse_relay_headers(&headers)?,
+            _ => RelayHeaders::new(),
+        };
+        if let Some(auth) = string_option(&option, "clnzapper_relay_auth") {
+            relay_auth::apply(&auth, &mut relay_headers)?;
+        }
+
+        let pay_index_migrate_from =
+            string_option(&option, "clnzapper_pay_index_migrate_from").map(PathBuf::from);
+        let pay_index_conflict = match option("clnzapper_pay_index_conflict") {
+            Some(Value::String(conflict)) => conflict.parse()?,
+            _ => IndexConflict::default(),
+        };
+
+        let relay_kinds = Arc::new(RelayKinds::parse(
+            string_option(&option, "clnzapper_relay_kinds").as_deref(),
+            matches!(
+                option("clnzapper_learn_relay_kinds"),
+                Some(Value::Boolean(true))
+            ),
+        )?);
+
+        let catchup_rate = int_option(&option, "clnzapper_catchup_rate")?.filter(|rate| *rate > 0);
+
+        let per_zap_concurrency = match int_option(&option, "clnzapper_per_zap_concurrency")? {
+            Some(0) => return Err(anyhow!("clnzapper_per_zap_concurrency must be at least 1")),
+            Some(concurrency) => concurrency as usize,
+            None => DEFAULT_PER_ZAP_CONCURRENCY,
+        };
+
+        let amount_field = match option("clnzapper_amount_field") {
+            Some(Value::String(field)) => field.parse()?,
+            _ => AmountField::default(),
+        };
+
+        let amount_mismatch = match option("clnzapper_amount_mismatch") {
+            Some(Value::String(policy)) => policy.parse()?,
+            _ => MismatchPolicy::default(),
+        };
+
+        let max_amount_deviation_pct = int_option(&option, "clnzapper_max_amount_deviation_pct")?;
+        let sanity_range = SanityRange {
+            min_msat: int_option(&option, "clnzapper_sanity_min_msat")?,
+            max_msat: int_option(&option, "clnzapper_sanity_max_msat")?,
+        };
+        if let (Some(min), Some(max)) = (sanity_range.min_msat, sanity_range.max_msat) {
+            if min > max {
+                return Err(anyhow!(
+                    "clnzapper_sanity_min_msat {min} is above clnzapper_sanity_max_msat {max}"
+                ));
+            }
+        }
+
+        let archive = match option("clnzapper_archive") {
+            Some(Value::String(archive)) => Some(archive.parse()?),
+            _ => None,
+        };
+
+        let published_log = string_option(&option, "clnzapper_published_log").map(PathBuf::from);
+        let published_retention = Retention {
+            keep: match int_option(&option, "clnzapper_published_keep")? {
+                Some(keep) => usize::try_from(keep)?,
+                None => CAPACITY,
+            },
+            max_age: int_option(&option, "clnzapper_published_keep_days")?
+                .filter(|days| *days > 0)
+                .map(|days| days * 24 * 60 * 60),
+        };
+
+        let auditor = Auditor::from_options(
+            string_option(&option, "clnzapper_audit_nsec"),
+            string_option(&option, "clnzapper_audit_relay"),
+        )?;
+
+        let simulate = matches!(option("clnzapper_simulate"), Some(Value::Boolean(true)));
+
+        let watchdog_timeout =
+            int_option(&option, "clnzapper_watchdog_timeout")?.filter(|timeout| *timeout > 0);
+
+        let relays_tag = matches!(option("clnzapper_relays_tag"), Some(Value::Boolean(true)));
+        let client_tag = matches!(option("clnzapper_client_tag"), Some(Value::Boolean(true)));
+        let description_hash_tag = matches!(
+            option("clnzapper_description_hash_tag"),
+            Some(Value::Boolean(true))
+        );
+        let copy_e_tags = matches!(option("clnzapper_copy_e_tags"), Some(Value::Boolean(true)));
+        let decrypt_private_zaps = matches!(
+            option("clnzapper_decrypt_private_zaps"),
+            Some(Value::Boolean(true))
+        );
+
+        let max_zap_request_age = int_option(&option, "clnzapper_max_zap_request_age")?;
+
+        let compliance_mode = match option("clnzapper_compliance_mode") {
+            Some(Value::String(mode)) => mode.parse()?,
+            _ => ComplianceMode::default(),
+        };
+
+        let startup_grace = match int_option(&option, "clnzapper_startup_grace")? {
+            Some(grace) if grace > MAX_STARTUP_GRACE => {
+                return Err(anyhow!(
+                    "clnzapper_startup_grace must be at most {MAX_STARTUP_GRACE} seconds"
+                ))
+            }
+            grace => grace.filter(|grace| *grace > 0),
+        };
+
+        let network_tag =
+            string_option(&option, "clnzapper_network_tag").map(|network| match network.as_str() {
+                "auto" => NetworkTag::FromInvoice,
+                _ => NetworkTag::Fixed(network),
+            });
+
+        let verify_delivery = matches!(
+            option("clnzapper_verify_delivery"),
+            Some(Value::Boolean(true))
+        );
+
+        let zapped_event_timeout =
+            match int_option(&option, "clnzapper_verify_zapped_event_timeout")? {
+                Some(0) => {
+                    return Err(anyhow!(
+                        "clnzapper_verify_zapped_event_timeout must be positive"
+                    ))
+                }
+                Some(timeout) => timeout,
+                None => zapped_event::DEFAULT_TIMEOUT,
+            };
+        let zapped_event_check = match option("clnzapper_verify_zapped_event") {
+            Some(Value::Boolean(true)) => Some(ZappedEventCheck {
+                timeout: Duration::from_secs(zapped_event_timeout),
+                missing: match option("clnzapper_zapped_event_missing") {
+                    Some(Value::String(missing)) => missing.parse()?,
+                    _ => MissingEvent::default(),
+                },
+            }),
+            _ => None,
+        };
+
+        let invoice_source = match option("clnzapper_invoice_source") {
+            Some(Value::String(source)) => source.parse()?,
+            _ => SourceKind::default(),
+        };
+
+        let poll_interval = match int_option(&option, "clnzapper_poll_interval")? {
+            Some(0) => return Err(anyhow!("clnzapper_poll_interval must be at least 1")),
+            Some(interval) => interval,
+            None => DEFAULT_POLL_INTERVAL,
+        };
+
+        let max_inflight_zaps = match int_option(&option, "clnzapper_max_inflight_zaps")? {
+            Some(0) => return Err(anyhow!("clnzapper_max_inflight_zaps must be at least 1")),
+            Some(max) => max as usize,
+            None => DEFAULT_MAX_INFLIGHT_ZAPS,
+        };
+
+        let index_after_publish = matches!(
+            option("clnzapper_index_after_publish"),
+            Some(Value::Boolean(true))
+        );
+        let index_batching = match (
+            int_option(&option, "clnzapper_index_batch_size")?,
+            int_option(&option, "clnzapper_index_batch_ms")?.filter(|ms| *ms > 0),
+        ) {
+            (Some(0), _) => return Err(anyhow!("clnzapper_index_batch_size must be positive")),
+            (None | Some(1), None) => None,
+            (size, ms) => Some(Batching {
+                size: size.unwrap_or(u64::MAX),
+                interval: ms.map(Duration::from_millis),
+            }),
+        };
+        let rpc_timeout =
+            int_option(&option, "clnzapper_rpc_timeout")?.filter(|timeout| *timeout > 0);
+
+        let log_relay_order = match option("clnzapper_log_relay_order") {
+            Some(Value::String(order)) => order.parse()?,
+            _ => LogRelayOrder::default(),
+        };
+
+        let label_prefix = string_option(&option, "clnzapper_label_prefix");
+
+        let comment_filter = CommentFilter {
+            max_len: int_option(&option, "clnzapper_comment_max_len")?
+                .map_or(DEFAULT_COMMENT_MAX_LEN, |max_len| max_len as usize),
+            strip_urls: matches!(
+                option("clnzapper_comment_strip_urls"),
+                Some(Value::Boolean(true))
+            ),
+        };
+
+        let max_receipt_tags = int_option(&option, "clnzapper_max_receipt_tags")?
+            .map_or(DEFAULT_MAX_RECEIPT_TAGS, |max| max as usize);
+
+        let receipt_ttl_secs =
+            int_option(&option, "clnzapper_receipt_ttl_secs")?.filter(|ttl| *ttl > 0);
+
+        let extra_rpc_sockets = string_option(&option, "clnzapper_extra_rpc_sockets")
+            .map(|sockets| {
+                sockets
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|socket| !socket.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let fallback_rpc_socket =
+            string_option(&option, "clnzapper_fallback_rpc_socket").map(PathBuf::from);
+
+        let deterministic_signatures = matches!(
+            option("clnzapper_deterministic_signatures"),
+            Some(Value::Boolean(true))
+        );
+        let max_clock_skew =
+            int_option(&option, "clnzapper_max_clock_skew")?.unwrap_or(clock::DEFAULT_MAX_SKEW);
+
+        let relay_scheme_policy = match option("clnzapper_relay_scheme_policy") {
+            Some(Value::String(policy)) => policy.parse()?,
+            _ => RelaySchemePolicy::default(),
+        };
+
+        let republish_intervals = match string_option(&option, "clnzapper_republish_intervals") {
+            Some(intervals) => republish::parse_intervals(&intervals)?,
+            None => vec![],
+        };
+
+        let key_reload_interval =
+            int_option(&option, "clnzapper_key_reload_interval")?.filter(|interval| *interval > 0);
+
+        let status_file = string_option(&option, "clnzapper_status_file").map(PathBuf::from);
+        let reload_file = string_option(&option, "clnzapper_reload_file").map(PathBuf::from);
+        let status_file_interval = match int_option(&option, "clnzapper_status_file_interval")? {
+            Some(0) => return Err(anyhow!("clnzapper_status_file_interval must be positive")),
+            Some(interval) => interval,
+            None => status_file::DEFAULT_INTERVAL,
+        };
+
+        let summary_interval =
+            int_option(&option, "clnzapper_summary_interval")?.filter(|interval| *interval > 0);
+        let summary_relays = match string_option(&option, "clnzapper_summary_relays") {
+            Some(relays) => relays
+                .split(',')
+                .filter(|relay| !relay.trim().is_empty())
+                .map(RelayUrl::parse)
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+
+        let receipt_output = string_option(&option, "clnzapper_receipt_output")
+            .map(|target| ReceiptOutput::open(&target))
+            .transpose()?;
+
+        let non_zap_log_level = match string_option(&option, "clnzapper_non_zap_log_level") {
+            Some(level) => level.parse().map_err(|_| {
+                anyhow!("Invalid non zap log level {level}, expected debug, info or warn")
+            })?,
+            None => Level::Debug,
+        };
+
+        let event_json_log_level =
+            match string_option(&option, "clnzapper_event_json_log_level").as_deref() {
+                Some("off") => None,
+                Some("debug") => Some(Level::Debug),
+                Some("trace") | None => Some(Level::Trace),
+                Some(level) => {
+                    return Err(anyhow!(
+                        "Invalid event JSON log level {level}, expected debug, trace or off"
+                    ))
+                }
+            };
+
+        let send_overflow = match string_option(&option, "clnzapper_relay_send_overflow") {
+            Some(overflow) => overflow.parse()?,
+            None => Overflow::default(),
+        };
+        let send_buffers = int_option(&option, "clnzapper_relay_send_buffer")?
+            .filter(|size| *size > 0)
+            .map(|size| Arc::new(SendBuffers::new(size as usize, send_overflow)));
+
+        let alert_threshold =
+            int_option(&option, "clnzapper_alert_threshold")?.filter(|threshold| *threshold > 0);
+        let alert_relays: Vec<RelayUrl> = match string_option(&option, "clnzapper_alert_relays") {
+            Some(relays) => relays
+                .split(',')
+                .filter(|relay| !relay.trim().is_empty())
+                .map(RelayUrl::parse)
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+        let alerter = match alert_threshold {
+            // The zapper's relays are the ones failing, so alerts need relays of their own
+            Some(_) if alert_relays.is_empty() => {
+                return Err(anyhow!(
+                    "clnzapper_alert_threshold needs clnzapper_alert_relays to publish alerts to"
+                ))
+            }
+            Some(threshold) => Some(Arc::new(Alerter::new(threshold, alert_relays.clone()))),
+            None => None,
+        };
+
+        let recipient_pubkeys = match string_option(&option, "clnzapper_recipient_pubkeys") {
+            Some(pubkeys) => recipient::parse_pubkeys(&pubkeys)?,
+            None => vec![],
+        };
+        let payer_blocklist = match string_option(&option, "clnzapper_payer_blocklist") {
+            Some(blocklist) => blocklist::parse(&blocklist)?,
+            None => HashSet::new(),
+        };
+        let payer_blocklist = Arc::new(PayerBlocklist::new(payer_blocklist));
+        let coalescer = int_option(&option, "clnzapper_coalesce_window_secs")?
+            .filter(|window| *window > 0)
+            .map(|window| Arc::new(Coalescer::new(Duration::from_secs(window))));
+        let recipient_mismatch = match string_option(&option, "clnzapper_recipient_mismatch") {
+            Some(action) => action.parse()?,
+            None => MismatchAction::default(),
+        };
+        if recipient_mismatch == MismatchAction::Alert && alert_relays.is_empty() {
+            return Err(anyhow!(
+                "clnzapper_recipient_mismatch=alert needs clnzapper_alert_relays to publish alerts to"
+            ));
+        }
+
+        let metrics_addr = string_option(&option, "clnzapper_metrics_addr")
+            .map(|addr| {
+                addr.parse::<SocketAddr>()
+                    .map_err(|err| anyhow!("Invalid clnzapper_metrics_addr {addr}: {err}"))
+            })
+            .transpose()?;
+        let zap_totals = match int_option(&option, "clnzapper_zap_totals_recipients")? {
+            Some(0) => None,
+            Some(max_recipients) => Some(Arc::new(ZapTotals::new(max_recipients as usize))),
+            None => Some(Arc::new(ZapTotals::new(DEFAULT_MAX_RECIPIENTS))),
+        };
+        let metrics = metrics_addr.map(|_| Arc::new(Metrics::new(zap_totals.clone())));
+
+        let syslog = match option("clnzapper_syslog") {
+            Some(Value::Boolean(true)) => Some(Arc::new(SyslogSink::new(
+                string_option(&option, "clnzapper_syslog_socket")
+                    .unwrap_or_else(|| syslog::DEFAULT_SOCKET.to_string())
+                    .into(),
+                match string_option(&option, "clnzapper_syslog_facility") {
+                    Some(facility) => facility.parse()?,
+                    None => Facility::default(),
+                },
+                match string_option(&option, "clnzapper_syslog_severity") {
+                    Some(severity) => severity.parse()?,
+                    None => Severity::default(),
+                },
+                int_option(&option, "clnzapper_syslog_rate")?
+                    .filter(|rate| *rate > 0)
+                    .map(|rate| u32::try_from(rate).unwrap_or(u32::MAX)),
+            ))),
+            _ => None,
+        };
+
+        let health_addr = string_option(&option, "clnzapper_health_listen")
+            .map(|addr| {
+                addr.parse::<SocketAddr>()
+                    .map_err(|err| anyhow!("Invalid clnzapper_health_listen {addr}: {err}"))
+            })
+            .transpose()?;
+
+        let publish_jitter =
+            int_option(&option, "clnzapper_publish_jitter")?.filter(|jitter| *jitter > 0);
+
+        let max_total_relays = int_option(&option, "clnzapper_max_total_relays")?
+            .filter(|max| *max > 0)
+            .map(|max| max as usize);
+        let inbox_relay = string_option(&option, "clnzapper_inbox_relay")
+            .map(|relay| RelayUrl::parse(&relay))
+            .transpose()?;
+
+        let nip65_relays = matches!(option("clnzapper_nip65_relays"), Some(Value::Boolean(true)));
+        let nip65_markers = match option("clnzapper_nip65_markers") {
+            Some(Value::String(markers)) => markers.parse()?,
+            _ => Nip65Markers::default(),
+        };
+        let nip65_refresh =
+            int_option(&option, "clnzapper_nip65_refresh")?.filter(|refresh| *refresh > 0);
+
+        let backfill = match string_option(&option, "clnzapper_backfill") {
+            Some(backfill) => backfill.parse()?,
+            None => Backfill::default(),
+        };
+
+        Ok(Self {
+            relay_headers,
+            pay_index_migrate_from,
+            pay_index_conflict,
+            relay_kinds,
+            catchup_rate,
+            backfill,
+            per_zap_concurrency,
+            amount_field,
+            amount_mismatch,
+            max_amount_deviation_pct: max_amount_deviation_pct.into(),
+            sanity_range: sanity_range.into(),
+            archive,
+            archive_pending: Arc::new(Semaphore::new(archive::MAX_PENDING)),
+            published_log,
+            published_retention,
+            auditor,
+            simulate,
+            watchdog_timeout,
+            relays_tag,
+            description_hash_tag,
+            client_tag,
+            copy_e_tags,
+            decrypt_private_zaps,
+            compliance_mode,
+            max_zap_request_age: max_zap_request_age.into(),
+            startup_grace,
+            network_tag,
+            verify_delivery,
+            zapped_event_check,
+            invoice_source,
+            poll_interval,
+            max_inflight_zaps,
+            index_after_publish,
+            index_batching,
+            rpc_timeout,
+            log_relay_order,
+            label_prefix: label_prefix.into(),
+            comment_filter: comment_filter.into(),
+            max_receipt_tags,
+            receipt_ttl_secs,
+            extra_rpc_sockets,
+            fallback_rpc_socket,
+            deterministic_signatures,
+            max_clock_skew,
+            relay_scheme_policy,
+            republish_intervals,
+            summary_interval,
+            status_file,
+            reload_file,
+            status_file_interval,
+            key_reload_interval,
+            summary_relays,
+            receipt_output,
+            non_zap_log_level,
+            event_json_log_level,
+            send_buffers,
+            alerter,
+            alert_relays,
+            recipient_pubkeys: recipient_pubkeys.into(),
+            payer_blocklist,
+            coalescer,
+            recipient_mismatch,
+            mismatch_alerts: Arc::new(MismatchAlerts::default()),
+            metrics_addr,
+            metrics,
+            zap_totals,
+            syslog,
+            health_addr,
+            publish_jitter,
+            max_total_relays: max_total_relays.into(),
+            inbox_relay,
+            nip65_relays,
+            nip65_markers,
+            nip65_refresh,
+        })
+    }
+}
+
+/// Get a string option if set and not empty
+fn string_option<F>(option: &F, name: &str) -> Option<String>
+where
+    F: Fn(&str) -> Option<Value>,
+{
+    match option(name) {
+        Some(Value::String(s)) if !s.is_empty() => Some(s),
+        _ => None,
+    }
+}
+
+/// Get a non negative integer option if set
+fn int_option<F>(option: &F, name: &str) -> Result<Option<u64>>
+where
+    F: Fn(&str) -> Option<Value>,
+{
+    match option(name) {
+        Some(Value::Integer(i)) => u64::try_from(i)
+            .map(Some)
+            .map_err(|_| anyhow!("{name} must not be negative")),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,171 @@
+//! Control socket serving the zapper RPC methods over a unix socket
+//!
+//! Each line sent to the socket is a JSON-RPC 2.0 request such as
+//! `{"jsonrpc": "2.0", "id": 1, "method": "zapper-status", "params": {}}`
+//! and is answered with a single line JSON-RPC response.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::rpc;
+use crate::state::State;
+
+/// Bind the control socket and serve requests in the background
+pub async fn serve(path: PathBuf, state: State) -> Result<()> {
+    // Remove a stale socket left by a previous run, but nothing a mistyped path points at
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "Control socket path {path:?} exists and is not a socket"
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    // Only the user running the plugin may talk to it. The socket is created 0600 under a
+    // restrictive umask, so there's no window between bind and a chmod where others could
+    // SAFETY: umask only swaps the process file mode mask and can't fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    info!("Control socket listening on {path:?}");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &state).await {
+                            debug!("Control socket connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Control socket accept error: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_connection(stream: UnixStream, state: &State) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = handle_request(state, &line).await;
+        write.write_all(format!("{response}\n").as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(state: &State, line: &str) -> Value {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return error_response(Value::Null, -32700, &err.to_string()),
+    };
+
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error_response(id, -32600, "Missing method"),
+    };
+    let params = request.get("params").cloned().unwrap_or(json!({}));
+
+    match rpc::dispatch(state, method, params).await {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(err) => error_response(id, -32603, &err.to_string()),
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nostr::Keys;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::relay_url;
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let path = PathBuf::from("./test/control.sock");
+        fs::create_dir_all("./test").unwrap();
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("ws://localhost:8080")]),
+            Config::default(),
+        );
+        serve(path.clone(), state.clone()).await.unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        write
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"zapper-setrelays\",\"params\":{\"relays\":[\"wss://nos.lol\"]}}\n")
+            .await
+            .unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["relays"], json!(["wss://nos.lol"]));
+
+        write.write_all(b"not json\n").await.unwrap();
+        let response: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+
+        assert_eq!(
+            *state.relays.read().await,
+            HashSet::from([relay_url("wss://nos.lol")])
+        );
+
+        // Serving again replaces the socket left behind
+        serve(path.clone(), state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_control_socket_path_not_socket() {
+        let path = PathBuf::from("./test/control_not_socket");
+        fs::create_dir_all("./test").unwrap();
+        fs::write(&path, "keep").unwrap();
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("ws://localhost:8080")]),
+            Config::default(),
+        );
+
+        assert!(serve(path.clone(), state).await.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,127 @@
+//! Exit codes, so lightningd and supervisors can tell why the zapper stopped
+//!
+//! Codes follow `sysexits.h`:
+//! * `0`: a clean shutdown, from lightningd's `shutdown` notification, an interrupt
+//!   in standalone mode, or the invoice streams ending
+//! * `78` (`EX_CONFIG`): the options are invalid, e.g. a bad key or relay URL.
+//!   Restarting won't help until they are fixed
+//! * `75` (`EX_TEMPFAIL`): something outside the zapper failed, e.g. the rpc socket
+//!   is unreachable or another instance holds the pay index. Restarting may help
+//! * `1`: any other error
+//!
+//! Errors are tagged where their category is known with [`config`] and [`transient`],
+//! which keep their message. Untagged errors caused by I/O count as transient, as do
+//! those reading the options, e.g. a key file that can't be read yet.
+
+use std::fmt;
+use std::io;
+use std::process::ExitCode;
+
+/// Exit code of a clean shutdown
+pub const SUCCESS: u8 = 0;
+/// Exit code of errors in no other category
+pub const ERROR: u8 = 1;
+/// Exit code of errors that may go away on a restart, `EX_TEMPFAIL`
+pub const TRANSIENT: u8 = 75;
+/// Exit code of invalid options, `EX_CONFIG`
+pub const CONFIG: u8 = 78;
+
+/// An error tagged with the exit code it ends the zapper with
+#[derive(Debug)]
+struct Fatal {
+    code: u8,
+    err: anyhow::Error,
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.err, f)
+    }
+}
+
+impl std::error::Error for Fatal {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.err.source()
+    }
+}
+
+/// Tag `err` as caused by invalid options
+pub fn config(err: anyhow::Error) -> anyhow::Error {
+    Fatal { code: CONFIG, err }.into()
+}
+
+/// Tag `err` as a failure outside the zapper that a restart may fix
+pub fn transient(err: anyhow::Error) -> anyhow::Error {
+    Fatal {
+        code: TRANSIENT,
+        err,
+    }
+    .into()
+}
+
+/// Tag `err` from reading the options as invalid options, unless I/O caused it,
+/// e.g. a key file on a mount not up yet, which a restart may fix
+pub fn config_or_transient(err: anyhow::Error) -> anyhow::Error {
+    match is_io(&err) {
+        true => transient(err),
+        false => config(err),
+    }
+}
+
+fn is_io(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<io::Error>())
+}
+
+/// The exit code an error ends the zapper with
+pub fn code_of(err: &anyhow::Error) -> u8 {
+    if let Some(fatal) = err.downcast_ref::<Fatal>() {
+        return fatal.code;
+    }
+    if is_io(err) {
+        return TRANSIENT;
+    }
+    ERROR
+}
+
+/// Report how the zapper ended, printing any error as `main` returning it would
+pub fn exit(result: anyhow::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::from(SUCCESS),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(code_of(&err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let err = config(anyhow!("Invalid clnzapper_nostr_nsec"));
+        assert_eq!(err.to_string(), "Invalid clnzapper_nostr_nsec");
+        assert_eq!(code_of(&err), CONFIG);
+        // Still found under context added later
+        assert_eq!(code_of(&err.context("Could not start")), CONFIG);
+
+        assert_eq!(code_of(&transient(anyhow!("Index locked"))), TRANSIENT);
+
+        let unreachable: anyhow::Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(
+            code_of(&unreachable.context("Could not connect to lightning-rpc")),
+            TRANSIENT
+        );
+        assert_eq!(code_of(&anyhow!("Something else")), ERROR);
+
+        let bad_key = config_or_transient(anyhow!("Invalid clnzapper_nostr_nsec"));
+        assert_eq!(code_of(&bad_key), CONFIG);
+        let unreadable: anyhow::Error = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        let unreadable = config_or_transient(unreadable.context("Could not read the key file"));
+        assert_eq!(code_of(&unreadable), TRANSIENT);
+        assert_eq!(unreadable.to_string(), "Could not read the key file");
+    }
+}
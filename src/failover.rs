@@ -0,0 +1,355 @@
+//! Standby node read while the primary is down, by `clnzapper_fallback_rpc_socket`
+//!
+//! High availability setups run a standby CLN node that takes payments while the
+//! primary is down. With a fallback socket the zapper reads the primary as usual,
+//! reconnecting after any error, and once it can't reconnect reads the standby
+//! instead, going back to the primary as soon as it answers again. Each node
+//! resumes from its own pay index file, the standby's named after its socket as for
+//! extra nodes, so switching neither skips nor repeats invoices. As a plugin stops
+//! with its node, this is mostly of use running `standalone`. Extra nodes are read
+//! through the same reconnecting source, so one being down holds up no other.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Result;
+use cln_rpc::model::WaitanyinvoiceResponse;
+use futures::future::BoxFuture;
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::node::Node;
+use crate::source::{self, InvoiceSource};
+use crate::state::State;
+
+/// Whether the primary node is down, so the standby is read
+#[derive(Debug)]
+pub struct Failover {
+    primary_down: watch::Sender<bool>,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Self {
+            primary_down: watch::channel(false).0,
+        }
+    }
+}
+
+impl Failover {
+    /// Whether invoices are being read from the standby
+    pub fn on_fallback(&self) -> bool {
+        *self.primary_down.borrow()
+    }
+
+    fn set_primary_down(&self, down: bool) {
+        let changed = self
+            .primary_down
+            .send_if_modified(|current| std::mem::replace(current, down) != down);
+        match (changed, down) {
+            (true, true) => warn!("Primary node unreachable, reading invoices from the fallback"),
+            (true, false) => info!("Primary node is back, reading invoices from it again"),
+            (false, _) => (),
+        }
+    }
+
+    /// Wait until the primary is `down`, returning at once if it already is
+    async fn wait_primary(&self, down: bool) {
+        let mut primary_down = self.primary_down.subscribe();
+        while *primary_down.borrow_and_update() != down {
+            // The sender lives as long as `self`
+            if primary_down.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Opens the invoice source of a node, resuming after the pay index given
+type Connect = Box<dyn FnMut(u64) -> BoxFuture<'static, Result<Box<dyn InvoiceSource>>> + Send>;
+
+/// Invoices of a node, connecting on first use and again after any error
+struct NodeSource {
+    node: Node,
+    connect: Connect,
+    source: Option<Box<dyn InvoiceSource>>,
+}
+
+impl NodeSource {
+    fn new(node: Node, config: Arc<Config>) -> Self {
+        let socket = node.socket.clone();
+        let connect: Connect = Box::new(move |last_pay_index| {
+            let (socket, config) = (socket.clone(), config.clone());
+            Box::pin(async move { source::connect(&socket, Some(last_pay_index), &config).await })
+        });
+        Self {
+            node,
+            connect,
+            source: None,
+        }
+    }
+
+    /// Connect unless connected, resuming after the node's last pay index
+    async fn ensure_connected(&mut self) -> Result<()> {
+        if self.source.is_none() {
+            let last_pay_index = self.node.last_pay_index.load(Ordering::Relaxed);
+            self.source = Some((self.connect)(last_pay_index).await?);
+        }
+        Ok(())
+    }
+
+    async fn next_invoice(&mut self) -> Result<Option<WaitanyinvoiceResponse>> {
+        self.ensure_connected().await?;
+        let source = self.source.as_mut().expect("Connected above");
+        let invoice = source.next_invoice().await;
+        if invoice.is_err() {
+            self.source = None;
+        }
+        invoice
+    }
+}
+
+impl InvoiceSource for NodeSource {
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+        Box::pin(NodeSource::next_invoice(self))
+    }
+}
+
+/// The primary node, down whenever connecting to it fails
+struct Primary {
+    node: NodeSource,
+    failover: Arc<Failover>,
+}
+
+impl InvoiceSource for Primary {
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+        Box::pin(async move {
+            let connected = self.node.ensure_connected().await;
+            self.failover.set_primary_down(connected.is_err());
+            connected?;
+            self.node.next_invoice().await
+        })
+    }
+}
+
+/// The standby node, only read while the primary is down
+struct Standby {
+    node: NodeSource,
+    failover: Arc<Failover>,
+}
+
+impl InvoiceSource for Standby {
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+        Box::pin(async move {
+            self.failover.wait_primary(true).await;
+            tokio::select! {
+                invoice = self.node.next_invoice() => invoice,
+                _ = self.failover.wait_primary(false) => {
+                    // Given up on mid call, so its reply can't be taken for the next one's
+                    self.node.source = None;
+                    Ok(None)
+                }
+            }
+        })
+    }
+}
+
+/// Invoice sources of the primary node and its standby, each read by its own stream
+pub fn sources(
+    primary: &Node,
+    standby: &Node,
+    state: &State,
+) -> (Box<dyn InvoiceSource>, Box<dyn InvoiceSource>) {
+    (
+        Box::new(Primary {
+            node: NodeSource::new(primary.clone(), state.config.clone()),
+            failover: state.failover.clone(),
+        }),
+        Box::new(Standby {
+            node: NodeSource::new(standby.clone(), state.config.clone()),
+            failover: state.failover.clone(),
+        }),
+    )
+}
+
+/// Invoice source of an extra node, connecting on first use and again after any error
+pub fn reconnecting(node: &Node, state: &State) -> Box<dyn InvoiceSource> {
+    Box::new(NodeSource::new(node.clone(), state.config.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet, VecDeque};
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use futures::{Stream, StreamExt};
+
+    use super::*;
+    use crate::tests::{test_invoice, test_keys, ZAP_REQ};
+    use crate::{read_last_pay_index, zap_stream};
+
+    type Script = Arc<Mutex<VecDeque<Result<WaitanyinvoiceResponse>>>>;
+
+    /// Invoices pushed to the script, in order
+    struct Scripted(Script);
+
+    impl InvoiceSource for Scripted {
+        fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+            Box::pin(async move {
+                loop {
+                    if let Some(invoice) = self.0.lock().unwrap().pop_front() {
+                        return invoice.map(Some);
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        }
+    }
+
+    /// A node reached while `up`, reading `script`
+    fn scripted_node(node: &Node, up: Arc<AtomicBool>, script: Script) -> NodeSource {
+        NodeSource {
+            node: node.clone(),
+            connect: Box::new(move |_| {
+                let (up, script) = (up.load(Ordering::Relaxed), script.clone());
+                Box::pin(async move {
+                    match up {
+                        true => Ok(Box::new(Scripted(script)) as Box<dyn InvoiceSource>),
+                        false => Err(anyhow!("Connection refused")),
+                    }
+                })
+            }),
+            source: None,
+        }
+    }
+
+    fn invoice(pay_index: u64) -> Result<WaitanyinvoiceResponse> {
+        let mut invoice = test_invoice(ZAP_REQ);
+        invoice.label = format!("invoice-{pay_index}");
+        invoice.pay_index = Some(pay_index);
+        Ok(invoice)
+    }
+
+    async fn next_pay_index<S, A, B>(invoices: &mut S) -> Option<u64>
+    where
+        S: Stream<Item = (A, WaitanyinvoiceResponse, B)> + Unpin,
+    {
+        tokio::time::timeout(Duration::from_secs(10), invoices.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .1
+            .pay_index
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_standby() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-failover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let primary = Node::new(PathBuf::from("primary-rpc"), dir.join("primary"));
+        let standby = Node::new(PathBuf::from("standby-rpc"), dir.join("standby"));
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("primary-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        let (primary_up, primary_script, standby_script) = (
+            Arc::new(AtomicBool::new(true)),
+            Script::default(),
+            Script::default(),
+        );
+
+        let primary_source = Primary {
+            node: scripted_node(&primary, primary_up.clone(), primary_script.clone()),
+            failover: state.failover.clone(),
+        };
+        let standby_source = Standby {
+            node: scripted_node(
+                &standby,
+                Arc::new(AtomicBool::new(true)),
+                standby_script.clone(),
+            ),
+            failover: state.failover.clone(),
+        };
+        let mut invoices = futures::stream::select(
+            Box::pin(zap_stream(
+                Box::new(primary_source),
+                primary.clone(),
+                state.clone(),
+            )),
+            Box::pin(zap_stream(
+                Box::new(standby_source),
+                standby.clone(),
+                state.clone(),
+            )),
+        );
+
+        primary_script.lock().unwrap().push_back(invoice(1));
+        // Not read while the primary is up
+        standby_script.lock().unwrap().push_back(invoice(7));
+        assert_eq!(next_pay_index(&mut invoices).await, Some(1));
+        assert!(!state.failover.on_fallback());
+        assert_eq!(standby_script.lock().unwrap().len(), 1);
+
+        // The primary goes away, and the standby's invoices are read
+        primary_up.store(false, Ordering::Relaxed);
+        primary_script
+            .lock()
+            .unwrap()
+            .push_back(Err(anyhow!("Connection reset")));
+        assert_eq!(next_pay_index(&mut invoices).await, Some(7));
+        assert!(state.failover.on_fallback());
+
+        // The primary comes back and is read from where it was left
+        primary_up.store(true, Ordering::Relaxed);
+        primary_script.lock().unwrap().push_back(invoice(2));
+        assert_eq!(next_pay_index(&mut invoices).await, Some(2));
+        assert!(!state.failover.on_fallback());
+
+        // Each node kept its own index
+        assert_eq!(primary.last_pay_index.load(Ordering::Relaxed), 2);
+        assert_eq!(read_last_pay_index(&primary.pay_index_path).unwrap(), 2);
+        assert_eq!(standby.last_pay_index.load(Ordering::Relaxed), 7);
+        assert_eq!(read_last_pay_index(&standby.pay_index_path).unwrap(), 7);
+
+        // And the standby is left alone again
+        standby_script.lock().unwrap().push_back(invoice(8));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(standby_script.lock().unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_extra_node_reconnects() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-extra-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let extra = Node::new(PathBuf::from("extra-rpc"), dir.join("extra"));
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        let (up, script) = (Arc::new(AtomicBool::new(false)), Script::default());
+        script.lock().unwrap().push_back(invoice(3));
+
+        // Down at first, so reading it doesn't end or fail but keeps trying
+        let source = scripted_node(&extra, up.clone(), script);
+        let mut invoices = Box::pin(zap_stream(Box::new(source), extra.clone(), state));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), invoices.next())
+                .await
+                .is_err()
+        );
+
+        up.store(true, Ordering::Relaxed);
+        assert_eq!(next_pay_index(&mut invoices).await, Some(3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Health probes served over http, by `clnzapper_health_listen`
+//!
+//! For container orchestrators and load balancers rather than people: `GET /livez`
+//! answers 200 while the process is up, and `GET /readyz` answers 200 only if CLN's
+//! rpc socket accepts a connection and at least one default relay accepts a
+//! websocket, 503 otherwise. Both are checked on every request.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+use crate::relay::any_relay_up;
+use crate::relay_url::RelayUrl;
+use crate::state::State;
+
+/// How long a readiness check waits for a relay
+const RELAY_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Why the zapper isn't ready, `None` if it is
+async fn not_ready(state: &State) -> Option<&'static str> {
+    if UnixStream::connect(&state.rpc_socket).await.is_err() {
+        return Some("CLN rpc socket unreachable");
+    }
+    let relays: Vec<RelayUrl> = state.relays.read().await.iter().cloned().collect();
+    if !any_relay_up(&relays, &state.config.relay_headers, RELAY_CHECK_TIMEOUT).await {
+        return Some("no relay reachable");
+    }
+    None
+}
+
+/// Bind the health endpoint and serve it in the background, returning its address
+pub async fn serve(addr: SocketAddr, state: State) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    info!("Health probes listening on http://{addr}/livez and /readyz");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &state).await {
+                            debug!("Health connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Health accept error: {err}"),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn handle_connection(stream: TcpStream, state: &State) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let request = lines.next_line().await?.unwrap_or_default();
+    // The headers don't matter, but read them so the client isn't cut off mid request
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/livez"] => ("200 OK", "ok"),
+        ["GET", "/readyz"] => match not_ready(state).await {
+            None => ("200 OK", "ok"),
+            Some(reason) => ("503 Service Unavailable", reason),
+        },
+        _ => ("404 Not Found", ""),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    write.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nostr::Keys;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::mock_relay;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_probes() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rpc_socket = dir.join("lightning-rpc");
+        std::fs::remove_file(&rpc_socket).ok();
+        let (relay, _) = mock_relay(None, 1);
+        let state = State::new(
+            Keys::generate(),
+            rpc_socket.clone(),
+            HashSet::from([relay]),
+            Config::default(),
+        );
+        let addr = serve("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+        assert!(get(addr, "/livez").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = get(addr, "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.ends_with("CLN rpc socket unreachable"));
+
+        let _cln = UnixListener::bind(&rpc_socket).unwrap();
+        assert!(get(addr, "/readyz")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // The mock relay served its one connection
+        let response = get(addr, "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.ends_with("no relay reachable"));
+
+        assert!(get(addr, "/metrics").await.starts_with("HTTP/1.1 404"));
+    }
+}
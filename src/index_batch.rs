@@ -0,0 +1,180 @@
+//! Batching pay index writes, by `clnzapper_index_batch_size` and `clnzapper_index_batch_ms`
+//!
+//! Every invoice read normally writes its node's pay index file, a write per payment
+//! on a busy node. Batching holds the writes back until `clnzapper_index_batch_size`
+//! invoices advanced the index or `clnzapper_index_batch_ms` passed since the first
+//! one held, whichever comes first, and the index is always written on shutdown.
+//! The cost is replay on a crash: the invoices held, at most the batch size less one
+//! or those read within the interval, are read again on restart and their receipts
+//! published again, which relays already holding them take as duplicates.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::node::Node;
+use crate::write_last_pay_index;
+
+/// When held pay index writes are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Batching {
+    /// Invoices advancing the index before it is written
+    pub size: u64,
+    /// How long the index may be held, no limit if unset
+    pub interval: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    idx: Option<u64>,
+    count: u64,
+    since: Option<Instant>,
+}
+
+/// Writes of a node's pay index file, batched if configured
+#[derive(Debug, Default)]
+pub struct IndexWrites {
+    batching: Option<Batching>,
+    held: Mutex<Held>,
+}
+
+impl IndexWrites {
+    pub fn new(batching: Option<Batching>) -> Self {
+        Self {
+            batching,
+            held: Mutex::new(Held::default()),
+        }
+    }
+
+    /// Write `idx` to `path`, or hold it until the batch is due
+    pub fn write(&self, path: &Path, idx: u64) {
+        let mut held = self.held.lock().expect("Lock not poisoned");
+        let Some(batching) = self.batching else {
+            write(path, idx);
+            return;
+        };
+
+        held.idx = Some(idx);
+        held.count += 1;
+        let since = *held.since.get_or_insert_with(Instant::now);
+        let due = held.count >= batching.size
+            || batching
+                .interval
+                .is_some_and(|interval| since.elapsed() >= interval);
+        if due {
+            *held = Held::default();
+            write(path, idx);
+        }
+    }
+
+    /// Write the index held for longer than `interval`, if any
+    fn flush_due(&self, path: &Path, interval: Duration) {
+        let mut held = self.held.lock().expect("Lock not poisoned");
+        if let (Some(idx), Some(since)) = (held.idx, held.since) {
+            if since.elapsed() >= interval {
+                *held = Held::default();
+                write(path, idx);
+            }
+        }
+    }
+
+    /// Write `idx` now, dropping any index held
+    pub fn write_final(&self, path: &Path, idx: u64) -> anyhow::Result<()> {
+        let mut held = self.held.lock().expect("Lock not poisoned");
+        *held = Held::default();
+        write_last_pay_index(&path.to_path_buf(), idx)
+    }
+}
+
+fn write(path: &Path, idx: u64) {
+    if let Err(e) = write_last_pay_index(&path.to_path_buf(), idx) {
+        warn!("Could not write index tip: {e}");
+    }
+}
+
+/// Write indexes held past the batch interval, for nodes no invoice comes from for a while
+pub fn spawn_flush(nodes: Vec<Node>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            for node in &nodes {
+                node.index_writes.flush_due(&node.pay_index_path, interval);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::state::State;
+    use crate::tests::test_keys;
+    use crate::{advance_pay_index, read_last_pay_index, shutdown};
+
+    fn node(name: &str, batching: Batching) -> Node {
+        let dir =
+            std::env::temp_dir().join(format!("clnzapper-batch-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Node::new(PathBuf::from("lightning-rpc"), dir.join("last_pay_index"))
+            .batched(Some(batching))
+    }
+
+    #[test]
+    fn test_writes_coalesced() {
+        let node = node(
+            "size",
+            Batching {
+                size: 3,
+                interval: None,
+            },
+        );
+
+        advance_pay_index(&node, 1, true);
+        advance_pay_index(&node, 2, true);
+        assert!(read_last_pay_index(&node.pay_index_path).is_err());
+        advance_pay_index(&node, 3, true);
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 3);
+        advance_pay_index(&node, 4, true);
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 3);
+
+        // Always written on shutdown
+        let state = State::new(
+            test_keys(),
+            node.socket.clone(),
+            HashSet::new(),
+            Config::default(),
+        );
+        shutdown::finish(&state, std::slice::from_ref(&node));
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 4);
+        std::fs::remove_dir_all(node.pay_index_path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flushed_after_interval() {
+        let interval = Duration::from_millis(50);
+        let node = node(
+            "interval",
+            Batching {
+                size: u64::MAX,
+                interval: Some(interval),
+            },
+        );
+        spawn_flush(vec![node.clone()], interval);
+
+        advance_pay_index(&node, 1, true);
+        advance_pay_index(&node, 2, true);
+        assert!(read_last_pay_index(&node.pay_index_path).is_err());
+
+        // Nothing else comes, and the ticker writes it
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 2);
+        std::fs::remove_dir_all(node.pay_index_path.parent().unwrap()).unwrap();
+    }
+}
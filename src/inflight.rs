@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+/// Zaps published at once when `clnzapper_max_inflight_zaps` is not set
+pub const DEFAULT_MAX_INFLIGHT_ZAPS: usize = 16;
+
+/// Bounds the zaps being published at once
+///
+/// Each zap runs in its own task holding a slot. Spawning waits for a free slot,
+/// so a burst of zaps holds back reading the invoice stream instead of piling up
+/// tasks and relay connections.
+#[derive(Clone, Debug)]
+pub struct Inflight {
+    max: usize,
+    slots: Arc<Semaphore>,
+}
+
+impl Inflight {
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            slots: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// Wait for a free slot, then run `zap` in a task holding it
+    pub async fn spawn<F>(&self, zap: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let slot = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Slots are never closed");
+        tokio::spawn(async move {
+            zap.await;
+            drop(slot);
+        });
+    }
+
+    /// Wait for every zap in flight to finish
+    pub async fn wait_idle(&self) {
+        let _all = self
+            .slots
+            .acquire_many(self.max as u32)
+            .await
+            .expect("Slots are never closed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inflight_cap() {
+        let inflight = Inflight::new(4);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..100 {
+            let (active, max_active, done) = (active.clone(), max_active.clone(), done.clone());
+            inflight
+                .spawn(async move {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+                .await;
+        }
+        inflight.wait_idle().await;
+
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+        assert_eq!(max_active.load(Ordering::SeqCst), 4);
+    }
+}
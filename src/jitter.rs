@@ -0,0 +1,65 @@
+//! Random delay before publishing receipts, by `clnzapper_publish_jitter`
+//!
+//! A receipt published the moment its invoice is paid tells anyone watching the
+//! relays when the payer paid, to the second. Holding each receipt for a random
+//! time within the window blurs that. A held receipt takes an inflight slot like
+//! one being published, and is published at once when the zapper stops.
+
+use std::time::Duration;
+
+use nostr::secp256k1::rand::{thread_rng, Rng};
+
+use crate::shutdown::Shutdown;
+
+/// Random delay of at most `window`
+pub fn delay(window: Duration) -> Duration {
+    thread_rng().gen_range(Duration::ZERO..=window)
+}
+
+/// Wait a random delay of at most `window`, cut short once the zapper is stopping
+pub async fn hold(window: Duration, shutdown: &Shutdown) {
+    tokio::select! {
+        _ = tokio::time::sleep(delay(window)) => (),
+        _ = shutdown.requested() => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_delay_bounded() {
+        let window = Duration::from_secs(60);
+        let delays: Vec<Duration> = (0..1000).map(|_| delay(window)).collect();
+        assert!(delays.iter().all(|delay| *delay <= window));
+        // Spread over the window rather than stuck at either end
+        assert!(delays.iter().any(|delay| *delay < window / 4));
+        assert!(delays.iter().any(|delay| *delay > window * 3 / 4));
+
+        assert_eq!(delay(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_hold() {
+        let window = Duration::from_millis(200);
+        let shutdown = Shutdown::default();
+        let start = Instant::now();
+        let held = (0..20).map(|_| async {
+            hold(window, &shutdown).await;
+            start.elapsed()
+        });
+        let elapsed = futures::future::join_all(held).await;
+
+        assert!(elapsed.iter().all(|elapsed| *elapsed < window * 2));
+        assert!(elapsed.iter().any(|elapsed| *elapsed > window / 4));
+
+        // Stopping flushes held receipts rather than waiting them out
+        shutdown.request();
+        let start = Instant::now();
+        hold(Duration::from_secs(60), &shutdown).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
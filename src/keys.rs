@@ -0,0 +1,311 @@
+//! Loading signing keys from the scheme prefixed values of the key options
+//!
+//! * `file:<path>` reads the key from a file
+//! * `env:<name>` reads the key from an environment variable
+//! * `cmd:<command>` runs the command with `sh -c` and reads the key from its output
+//! * `bunker:<uri>` is reserved for remote signing over NIP-46, not supported yet
+//!
+//! Any other value is the key itself, as nsec or hex. Other secrets, such as the
+//! tokens of `clnzapper_relay_auth`, are read the same way.
+//!
+//! The receipt key can also be named by `clnzapper_nostr_nsec_env`, the environment
+//! variable to read it from, which takes precedence over `clnzapper_nostr_nsec` so a
+//! container can inject the key over whatever the config file says.
+//!
+//! The receipt key is read again from the same place by `zapper-reload-key`, and
+//! every `clnzapper_key_reload_interval` seconds if set, so a rotated key is picked
+//! up without a restart. Receipts are signed before they are broadcast, so one in
+//! flight keeps the key it was signed with.
+
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use nostr::key::FromSkStr;
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::Keys;
+
+/// Where a key is read from
+#[derive(Debug, PartialEq, Eq)]
+enum KeySource<'a> {
+    Literal(&'a str),
+    File(&'a str),
+    Env(&'a str),
+    Cmd(&'a str),
+    Bunker(&'a str),
+}
+
+impl<'a> KeySource<'a> {
+    fn parse(value: &'a str) -> Self {
+        match value.split_once(':') {
+            Some(("file", path)) => Self::File(path),
+            Some(("env", name)) => Self::Env(name),
+            Some(("cmd", command)) => Self::Cmd(command),
+            Some(("bunker", uri)) => Self::Bunker(uri),
+            _ => Self::Literal(value),
+        }
+    }
+}
+
+/// Read the secret an option value refers to, `option` naming it in errors
+pub fn read_secret(option: &str, value: &str) -> Result<String> {
+    let secret = match KeySource::parse(value) {
+        KeySource::Literal(secret) => secret.to_string(),
+        // The I/O error is kept as the cause, so a file not there yet exits as transient
+        KeySource::File(path) => std::fs::read_to_string(path).map_err(|err| {
+            let msg = format!("Could not read {option} from {path}: {err}");
+            anyhow::Error::new(err).context(msg)
+        })?,
+        KeySource::Env(name) => match std::env::var(name) {
+            Ok(secret) if secret.trim().is_empty() => {
+                return Err(anyhow!("Could not read {option} from ${name}: it is empty"))
+            }
+            Ok(secret) => secret,
+            Err(err) => return Err(anyhow!("Could not read {option} from ${name}: {err}")),
+        },
+        KeySource::Cmd(command) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .map_err(|err| {
+                    let msg = format!("Could not run the {option} command: {err}");
+                    anyhow::Error::new(err).context(msg)
+                })?;
+            if !output.status.success() {
+                return Err(anyhow!("The {option} command failed: {}", output.status));
+            }
+            String::from_utf8(output.stdout)
+                .map_err(|_| anyhow!("The {option} command did not print a key"))?
+        }
+        KeySource::Bunker(_) => {
+            return Err(anyhow!("{option}: bunker signing is not supported yet"))
+        }
+    };
+
+    Ok(secret.trim().to_string())
+}
+
+/// Load the keys an option value refers to, `option` naming it in errors
+pub fn load(option: &str, value: &str) -> Result<Keys> {
+    let secret = read_secret(option, value)?;
+    Keys::from_sk_str(&secret).map_err(|err| anyhow!("Invalid {option}: {err}"))
+}
+
+/// Load the receipt key from `clnzapper_nostr_nsec_env` if set, else `clnzapper_nostr_nsec`
+pub fn load_receipt_key(nsec: Option<String>, nsec_env: Option<String>) -> Result<ReceiptKeys> {
+    let (option, value) = match (nsec, nsec_env) {
+        (nsec, Some(name)) => {
+            if nsec.is_some() {
+                info!("clnzapper_nostr_nsec_env is set, ignoring clnzapper_nostr_nsec");
+            }
+            ("clnzapper_nostr_nsec_env", format!("env:{name}"))
+        }
+        (Some(nsec), None) => ("clnzapper_nostr_nsec", nsec),
+        (None, None) => return Err(anyhow!("clnzapper_nostr_nsec is not set")),
+    };
+    let keys = load(option, &value)?;
+
+    Ok(ReceiptKeys {
+        source: Some((option, value)),
+        keys: RwLock::new(keys),
+    })
+}
+
+/// The key zap receipts are signed with, reloadable from where it was read
+#[derive(Debug)]
+pub struct ReceiptKeys {
+    /// Option and value the key was loaded from, none if it was given directly
+    source: Option<(&'static str, String)>,
+    keys: RwLock<Keys>,
+}
+
+impl From<Keys> for ReceiptKeys {
+    fn from(keys: Keys) -> Self {
+        Self {
+            source: None,
+            keys: RwLock::new(keys),
+        }
+    }
+}
+
+impl ReceiptKeys {
+    /// The keys to sign the next receipt with
+    pub fn current(&self) -> Keys {
+        self.keys.read().expect("Lock not poisoned").clone()
+    }
+
+    pub fn public_key(&self) -> XOnlyPublicKey {
+        self.current().public_key()
+    }
+
+    /// Read the key again, keeping the current one if that fails, and return its pubkey
+    pub fn reload(&self) -> Result<XOnlyPublicKey> {
+        let (option, value) = self
+            .source
+            .as_ref()
+            .ok_or_else(|| anyhow!("The receipt key was not loaded from an option"))?;
+        let keys = load(option, value)?;
+        let public_key = keys.public_key();
+
+        let old = std::mem::replace(&mut *self.keys.write().expect("Lock not poisoned"), keys);
+        if old.public_key() != public_key {
+            info!(
+                "Receipt key changed from {} to {public_key}",
+                old.public_key()
+            );
+        }
+
+        Ok(public_key)
+    }
+}
+
+/// Reload the receipt key every `interval`, by `clnzapper_key_reload_interval`
+pub fn spawn_reload(keys: Arc<ReceiptKeys>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate and the key was just loaded
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            // Reading the key may run a command or read a file, so keep it off the async workers
+            let reload = tokio::task::spawn_blocking({
+                let keys = keys.clone();
+                move || keys.reload()
+            });
+            match reload.await {
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => {
+                    warn!("Could not reload the receipt key, keeping the current one: {err}")
+                }
+                Err(err) => warn!("Receipt key reload task failed: {err}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "505fd02741816952ec9a70204221acdd8458906d3e1e0604fef033876c811a8f";
+
+    #[test]
+    fn test_key_source_parse() {
+        assert_eq!(KeySource::parse(HEX), KeySource::Literal(HEX));
+        assert_eq!(
+            KeySource::parse("file:/etc/zapper/nsec"),
+            KeySource::File("/etc/zapper/nsec")
+        );
+        assert_eq!(
+            KeySource::parse("env:ZAPPER_NSEC"),
+            KeySource::Env("ZAPPER_NSEC")
+        );
+        assert_eq!(
+            KeySource::parse("cmd:pass show zapper"),
+            KeySource::Cmd("pass show zapper")
+        );
+        assert_eq!(
+            KeySource::parse("bunker://abc?relay=wss://relay.example"),
+            KeySource::Bunker("//abc?relay=wss://relay.example")
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let expected = Keys::from_sk_str(HEX).unwrap().public_key();
+        let public_key = |value: &str| load("clnzapper_nostr_nsec", value).unwrap().public_key();
+
+        assert_eq!(public_key(HEX), expected);
+
+        let path = std::env::temp_dir().join(format!("clnzapper-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{HEX}\n")).unwrap();
+        assert_eq!(public_key(&format!("file:{}", path.display())), expected);
+        std::fs::remove_file(&path).unwrap();
+        // A file that can't be read exits as transient, a bad key as invalid options
+        let missing = load("clnzapper_nostr_nsec", &format!("file:{}", path.display()));
+        let missing = crate::exit::config_or_transient(missing.unwrap_err());
+        assert_eq!(crate::exit::code_of(&missing), crate::exit::TRANSIENT);
+        let invalid =
+            crate::exit::config_or_transient(load("clnzapper_nostr_nsec", "nsecnope").unwrap_err());
+        assert_eq!(crate::exit::code_of(&invalid), crate::exit::CONFIG);
+
+        std::env::set_var("CLNZAPPER_TEST_KEY", HEX);
+        assert_eq!(public_key("env:CLNZAPPER_TEST_KEY"), expected);
+
+        assert_eq!(public_key(&format!("cmd:echo {HEX}")), expected);
+
+        assert!(load("clnzapper_nostr_nsec", "cmd:false").is_err());
+        assert!(load("clnzapper_nostr_nsec", "env:CLNZAPPER_TEST_KEY_UNSET").is_err());
+        assert!(load("clnzapper_nostr_nsec", "bunker://abc").is_err());
+        assert!(load("clnzapper_nostr_nsec", "nsecnope").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("clnzapper-reload-{}", std::process::id()));
+        std::fs::write(&path, HEX).unwrap();
+        let keys = load_receipt_key(Some(format!("file:{}", path.display())), None).unwrap();
+        let expected = Keys::from_sk_str(HEX).unwrap().public_key();
+        assert_eq!(keys.public_key(), expected);
+
+        let rotated = Keys::generate();
+        std::fs::write(
+            &path,
+            rotated.secret_key().unwrap().display_secret().to_string(),
+        )
+        .unwrap();
+        // Nothing changes until the key is reloaded
+        assert_eq!(keys.public_key(), expected);
+        assert_eq!(keys.reload().unwrap(), rotated.public_key());
+        assert_eq!(keys.public_key(), rotated.public_key());
+
+        // A key that can't be read keeps the current one
+        std::fs::write(&path, "nsecnope").unwrap();
+        assert!(keys.reload().is_err());
+        assert_eq!(keys.public_key(), rotated.public_key());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(ReceiptKeys::from(Keys::generate()).reload().is_err());
+    }
+
+    #[test]
+    fn test_load_receipt_key_from_env() {
+        let expected = Keys::from_sk_str(HEX).unwrap().public_key();
+        let env = || Some("CLNZAPPER_TEST_NSEC_ENV".to_string());
+
+        std::env::set_var("CLNZAPPER_TEST_NSEC_ENV", HEX);
+        assert_eq!(
+            load_receipt_key(None, env()).unwrap().public_key(),
+            expected
+        );
+        // Over the option's own value
+        let other = Keys::generate().secret_key().unwrap().display_secret();
+        let keys = load_receipt_key(Some(other.to_string()), env()).unwrap();
+        assert_eq!(keys.public_key(), expected);
+
+        std::env::set_var("CLNZAPPER_TEST_NSEC_ENV", " ");
+        let err = load_receipt_key(None, env()).unwrap_err().to_string();
+        assert!(
+            err.contains("$CLNZAPPER_TEST_NSEC_ENV: it is empty"),
+            "{err}"
+        );
+
+        std::env::remove_var("CLNZAPPER_TEST_NSEC_ENV");
+        let err = load_receipt_key(Some(HEX.to_string()), env()).unwrap_err();
+        assert!(
+            err.to_string().contains("$CLNZAPPER_TEST_NSEC_ENV"),
+            "{err}"
+        );
+
+        assert_eq!(
+            load_receipt_key(Some(HEX.to_string()), None)
+                .unwrap()
+                .public_key(),
+            expected
+        );
+        assert!(load_receipt_key(None, None).is_err());
+    }
+}
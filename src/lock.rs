@@ -0,0 +1,71 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+/// Advisory lock on the pay index, held for as long as the plugin runs
+///
+/// Two instances sharing an index file, e.g. on shared storage, would otherwise
+/// both write the tip. The lock is an `flock` on `<index>.lock` next to it, which
+/// Linux also honours on NFS, and is released when the process exits.
+#[derive(Debug)]
+pub struct IndexLock {
+    _file: File,
+}
+
+impl IndexLock {
+    /// Take the lock on the index at `index_path`, failing if another instance holds it
+    pub fn acquire(index_path: &Path) -> Result<Self> {
+        let path = lock_path(index_path);
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        // SAFETY: flock only reads the descriptor, which `file` keeps open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.kind() {
+                io::ErrorKind::WouldBlock => anyhow!(
+                    "Pay index {} is in use by another cln-zapper",
+                    index_path.display()
+                ),
+                _ => anyhow!("Could not lock {}: {err}", path.display()),
+            });
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+fn lock_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_lock() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-lock-{}", std::process::id()));
+        let index_path = dir.join("last_pay_index");
+
+        let lock = IndexLock::acquire(&index_path).unwrap();
+        assert!(dir.join("last_pay_index.lock").exists());
+        assert!(IndexLock::acquire(&index_path).is_err());
+
+        drop(lock);
+        assert!(IndexLock::acquire(&index_path).is_ok());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
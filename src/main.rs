@@ -1,18 +1,26 @@
 use anyhow::{anyhow, Result};
 use cln_plugin::options::{ConfigOption, Value};
 use cln_plugin::Plugin;
-use cln_rpc::model::{WaitanyinvoiceRequest, WaitanyinvoiceResponse};
+use cln_rpc::model::WaitanyinvoiceResponse;
+use cln_rpc::primitives::Sha256;
 use dirs::data_dir;
 use futures::{Stream, StreamExt};
-use log::{debug, warn};
-use serde::Serialize;
+use log::{debug, log, trace, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{stdin, stdout};
 
-use nostr::{event::Event, key::FromSkStr, ClientMessage, EventBuilder, Keys, Tag};
-
-use tungstenite::Message as WsMessage;
+use nostr::hashes::{sha256, Hash};
+use nostr::{
+    event::Event,
+    secp256k1::{Message, XOnlyPublicKey},
+    EventId, Keys, Kind, Tag, TagKind, Timestamp, UncheckedUrl, UnsignedEvent, SECP256K1,
+};
 
 use std::string::String;
 
@@ -22,34 +30,178 @@ use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{Read, Write};
 
+mod alert;
+mod amount;
+mod archive;
+mod audit;
+mod backfill;
+mod blocklist;
+mod bolt11;
+mod catchup;
+mod cln;
+mod clock;
+mod coalesce;
+mod comment;
+mod compliance;
+mod config;
+mod control;
+mod exit;
+mod failover;
+mod health;
+mod index_batch;
+mod inflight;
+mod jitter;
+mod keys;
+mod lock;
+mod metrics;
+mod migrate;
+mod nip65;
+mod node;
+mod output;
+mod pause;
+mod private_zap;
+mod published;
+mod recipient;
+mod relay;
+mod relay_auth;
+mod relay_kinds;
+mod relay_url;
+mod reload;
+mod republish;
+mod request_age;
+mod rpc;
+mod send_buffer;
+mod shutdown;
+mod skip;
+mod source;
+#[cfg(feature = "standalone")]
+mod standalone;
+mod state;
+mod status_file;
+mod summary;
+mod syslog;
+mod totals;
+mod validate;
+mod watchdog;
+mod window;
+mod zapped_event;
+
+use amount::MismatchPolicy;
+use catchup::CatchupPacer;
+use config::{Config, NetworkTag};
+use index_batch::Batching;
+use inflight::Inflight;
+use node::Node;
+use private_zap::ZapPrivacy;
+use published::{PublishedReceipts, CAPACITY};
+use relay::zap_relays;
+use relay_url::RelayUrl;
+use skip::SkipReason;
+use source::InvoiceSource;
+use state::State;
+use watchdog::Watchdog;
+use window::Pending;
+
+/// Relay used when `clnzapper_nostr_relay` is not set
+const DEFAULT_RELAY: &str = "ws://localhost:8080";
+
+/// Log level used when `clnzapper_log_level` is not set
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Longest invoice description decoded as a zap request
+const MAX_ZAP_REQUEST_LEN: usize = 64 * 1024;
+
+/// Characters of a description that isn't a zap request shown in its log line
+const DESCRIPTION_PREVIEW_LEN: usize = 64;
+
+/// Most distinct payer relays taken from a zap request
+const MAX_ZAP_REQUEST_RELAYS: usize = 100;
+
+/// Most optional receipt tags, and relays in its relays tag, when `clnzapper_max_receipt_tags` is not set
+pub const DEFAULT_MAX_RECEIPT_TAGS: usize = 20;
+
+/// Value of the client tag added by `clnzapper_client_tag`
+const CLIENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Env var cln-plugin reads its log filter from
+const LOG_FILTER_ENV: &str = "CLN_PLUGIN_LOG";
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let plugin = if let Some(plugin) = cln_plugin::Builder::new(stdin(), stdout())
-        .option(ConfigOption::new(
-            "clnzapper_nostr_nsec",
-            Value::String("".into()),
-            "Nsec for publishing nostr notes",
-        ))
-        // TODO: Would be better to be a list
-        .option(ConfigOption::new(
-            "clnzapper_nostr_relay",
-            Value::String("ws://localhost:8080".to_string()),
-            "Default relay to publish to",
-        ))
-        .option(ConfigOption::new(
-            "clnzapper_pay_index_path",
-            Value::OptString,
-            "Path to pay index",
-        ))
+async fn main() -> ExitCode {
+    if std::env::args().nth(1).as_deref() == Some("validate") {
+        return exit::exit(validate::run());
+    }
+    #[cfg(feature = "standalone")]
+    if std::env::args().nth(1).as_deref() == Some("standalone") {
+        return exit::exit(standalone::run().await);
+    }
+
+    exit::exit(run_plugin().await)
+}
+
+/// Run as a CLN plugin until lightningd shuts us down
+async fn run_plugin() -> Result<()> {
+    // cln-plugin builds its log filter from `CLN_PLUGIN_LOG` before we know our options,
+    // so let our own records through it and cap them with `clnzapper_log_level` below.
+    // An operator set `CLN_PLUGIN_LOG` still takes precedence.
+    if std::env::var_os(LOG_FILTER_ENV).is_none() {
+        std::env::set_var(LOG_FILTER_ENV, "info,cln_zapper=trace");
+    }
+
+    let builder = options().into_iter().fold(
+        cln_plugin::Builder::new(stdin(), stdout()),
+        |builder, option| builder.option(option),
+    );
+    let plugin = if let Some(plugin) = builder
+        .rpcmethod(
+            "zapper-setrelays",
+            "Replace the default relays zap receipts are published to",
+            rpc::set_relays,
+        )
+        .rpcmethod(
+            "zapper-status",
+            "Show the zapper's relays and progress",
+            rpc::status,
+        )
+        .rpcmethod(
+            "zapper-replay",
+            "Rebroadcast the zap receipt for the paid invoice with the given label",
+            rpc::replay,
+        )
+        .rpcmethod(
+            "zapper-export",
+            "Write every archived zap receipt to the given path as newline delimited JSON. Needs a directory clnzapper_archive",
+            rpc::export,
+        )
+        .rpcmethod(
+            "zapper-pause",
+            "Stop publishing zap receipts, holding paid zaps until zapper-resume",
+            rpc::pause,
+        )
+        .rpcmethod(
+            "zapper-resume",
+            "Publish the zaps held by zapper-pause and carry on",
+            rpc::resume,
+        )
+        .rpcmethod(
+            "zapper-reload-key",
+            "Read the key signing zap receipts again from where clnzapper_nostr_nsec or clnzapper_nostr_nsec_env says",
+            rpc::reload_key,
+        )
+        .rpcmethod(
+            "zapper-simulate",
+            "Dry run a zap request and amount_msat through the pipeline without a payment or broadcast. Needs clnzapper_simulate",
+            rpc::simulate,
+        )
         .subscribe("shutdown",
             // Handle CLN `shutdown` if it is sent 
-            |plugin: Plugin<()>, _: serde_json::Value| async move {
+            |plugin: Plugin<State>, _: serde_json::Value| async move {
             info!("Received \"shutdown\" notification from lightningd ... requesting cln_plugin shutdown");
             plugin.shutdown().ok();
             plugin.join().await
         })
         .dynamic()
-        .start(())
+        .configure()
         .await?
     {
         plugin
@@ -57,16 +209,763 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     };
 
-    let rpc_socket: PathBuf = plugin.configuration().rpc_file.parse()?;
+    // A `--help` probe from lightningd ends at the manifest, so nothing before here may
+    // touch the filesystem. Check the options before anything else does, and if they are
+    // bad tell lightningd why through the init response instead of just exiting.
+    let rpc_socket = PathBuf::from(&plugin.configuration().rpc_file);
+    let (state, pay_index_path) = match startup(|name| plugin.option(name), rpc_socket) {
+        Ok(startup) => startup,
+        Err(err) => {
+            plugin.disable(&err.to_string()).await?;
+            return Err(exit::config_or_transient(err));
+        }
+    };
+    let nodes = nodes(&state, pay_index_path);
+
+    // Held until we exit so a second instance can't write the same index
+    let _index_locks = match lock_indexes(&state.config, &nodes) {
+        Ok(locks) => locks,
+        Err(err) => {
+            plugin.disable(&err.to_string()).await?;
+            return Err(err);
+        }
+    };
 
-    let nostr_sec_key = plugin
-        .option("clnzapper_nostr_nsec")
-        .expect("Option is defined")
-        .as_str()
-        .expect("Option is a string")
-        .to_owned();
-    let nostr_relay = plugin
-        .option("clnzapper_nostr_relay")
+    if let Some(Value::String(path)) = plugin.option("clnzapper_control_socket") {
+        control::serve(PathBuf::from(path), state.clone()).await?;
+    }
+
+    if let (Some(addr), Some(metrics)) = (state.config.metrics_addr, &state.config.metrics) {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
+
+    if let Some(addr) = state.config.health_addr {
+        health::serve(addr, state.clone()).await?;
+    }
+
+    let reload_file = match &state.config.reload_file {
+        Some(path) => path.clone(),
+        None => PathBuf::from(&plugin.configuration().lightning_dir).join("config"),
+    };
+    reload::spawn(state.clone(), reload_file)?;
+
+    let plugin = plugin.start(state).await?;
+    let state = plugin.state().clone();
+
+    // The `shutdown` subscription stops the plugin, which ends `join`
+    let result =
+        shutdown::run_until(&state, run(state.clone(), nodes.clone()), plugin.join()).await;
+    shutdown::finish(&state, &nodes);
+    result
+}
+
+/// The node the zapper runs on, at `pay_index_path`, its fallback and any extra nodes
+fn nodes(state: &State, pay_index_path: PathBuf) -> Vec<Node> {
+    let own_node = Node {
+        last_pay_index: state.last_pay_index.clone(),
+        ..Node::new(state.rpc_socket.clone(), pay_index_path).batched(state.config.index_batching)
+    };
+    std::iter::once(own_node)
+        .chain(state.fallback_node.clone())
+        .chain(state.extra_nodes.iter().cloned())
+        .collect()
+}
+
+/// Lock the pay index of every node, held until dropped, then take over our own
+/// node's from `clnzapper_pay_index_migrate_from`
+fn lock_indexes(config: &Config, nodes: &[Node]) -> Result<Vec<lock::IndexLock>> {
+    let locks = nodes
+        .iter()
+        .map(|node| lock::IndexLock::acquire(&node.pay_index_path))
+        .collect::<Result<Vec<_>>>()
+        .map_err(exit::transient)?;
+
+    if let (Some(from), Some(own_node)) = (&config.pay_index_migrate_from, nodes.first()) {
+        migrate::migrate(from, &own_node.pay_index_path, config.pay_index_conflict)
+            .map_err(exit::config_or_transient)?;
+    }
+
+    Ok(locks)
+}
+
+/// Issue receipts for the invoices paid on `nodes` until their streams end
+async fn run(state: State, nodes: Vec<Node>) -> Result<()> {
+    if let (true, Some(refresh)) = (state.config.nip65_relays, state.config.nip65_refresh) {
+        nip65::spawn_refresh(state.clone(), Duration::from_secs(refresh));
+    }
+
+    if let Some(interval) = state.config.key_reload_interval {
+        keys::spawn_reload(state.keys.clone(), Duration::from_secs(interval));
+    }
+
+    if let Some(Batching {
+        interval: Some(interval),
+        ..
+    }) = state.config.index_batching
+    {
+        index_batch::spawn_flush(nodes.clone(), interval);
+    }
+
+    if let Some(path) = &state.config.status_file {
+        status_file::spawn(
+            state.clone(),
+            path.clone(),
+            Duration::from_secs(state.config.status_file_interval),
+        );
+    }
+
+    if let Some(interval) = state.config.summary_interval {
+        summary::spawn(
+            state.clone(),
+            Duration::from_secs(interval),
+            state.config.summary_relays.clone(),
+        );
+    }
+
+    // Extra nodes with no pay index yet that can't be reached to pick one are left out
+    let mut unstarted = HashSet::new();
+    for (i, node) in nodes.iter().enumerate() {
+        let last_pay_index = match read_last_pay_index(&node.pay_index_path) {
+            Ok(idx) => idx,
+            Err(e) => {
+                warn!("Could not read last pay index: {e}");
+                let start = state
+                    .config
+                    .backfill
+                    .start_index(
+                        node.socket.clone(),
+                        state.config.rpc_timeout.map(Duration::from_secs),
+                    )
+                    .await;
+                let idx = match start {
+                    Ok(idx) => idx,
+                    Err(err)
+                        if i > 0
+                            && state.config.fallback_rpc_socket.as_ref() != Some(&node.socket) =>
+                    {
+                        error!(
+                            "Could not start {}, not reading it until restarted: {err}",
+                            node.socket.display()
+                        );
+                        unstarted.insert(node.socket.clone());
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                info!("No last pay index, starting after pay index {idx} by clnzapper_backfill");
+                if let Err(e) = write_last_pay_index(&node.pay_index_path, idx) {
+                    warn!("Write error: {e}");
+                }
+                idx
+            }
+        };
+        match i {
+            0 => info!("Starting at pay index: {last_pay_index}"),
+            _ => info!(
+                "Starting {} at pay index: {last_pay_index}",
+                node.socket.display()
+            ),
+        }
+        node.last_pay_index.store(last_pay_index, Ordering::Relaxed);
+        node.index_window.reset(last_pay_index);
+    }
+
+    if let Some(grace) = state.config.startup_grace {
+        let relays: Vec<RelayUrl> = state.relays.read().await.iter().cloned().collect();
+        info!("Waiting up to {grace}s for relays to accept connections");
+        let unreachable = relay::await_relays(
+            &relays,
+            &state.config.relay_headers,
+            Duration::from_secs(grace),
+        )
+        .await;
+        if !unreachable.is_empty() {
+            warn!("Relays still unreachable after startup grace period: {unreachable:?}");
+        }
+    }
+
+    let watchdog = state
+        .config
+        .watchdog_timeout
+        .map(|timeout| Watchdog::new(Duration::from_secs(timeout), state.stream_heartbeat.clone()));
+    let mut catchup_pacer = CatchupPacer::new(state.config.catchup_rate);
+    let inflight = Inflight::new(state.config.max_inflight_zaps);
+    loop {
+        // One stream per node, each resuming from the last invoice seen from it, which is
+        // where a restarted stream picks up
+        let (own_node, other_nodes) = nodes.split_first().expect("Our own node is always read");
+        let mut streams = match &state.fallback_node {
+            Some(fallback) => {
+                let (primary, standby) = failover::sources(own_node, fallback, &state);
+                vec![
+                    zap_stream(primary, own_node.clone(), state.clone()).boxed_local(),
+                    zap_stream(standby, fallback.clone(), state.clone()).boxed_local(),
+                ]
+            }
+            None => vec![invoice_stream(own_node.clone(), state.clone())
+                .await?
+                .boxed_local()],
+        };
+        // Each reconnects on its own, so one node being down leaves the others be
+        streams.extend(
+            other_nodes
+                .iter()
+                .filter(|node| state.config.fallback_rpc_socket.as_ref() != Some(&node.socket))
+                .filter(|node| !unstarted.contains(&node.socket))
+                .map(|node| {
+                    let source = failover::reconnecting(node, &state);
+                    zap_stream(source, node.clone(), state.clone()).boxed_local()
+                }),
+        );
+        let mut invoices = futures::stream::select_all(streams);
+
+        loop {
+            // Checked before reading on, as a zap read is past the pay index
+            if state.pause.is_paused() {
+                info!("Paused, holding zaps until zapper-resume");
+                tokio::select! {
+                    _ = state.pause.wait_resumed() => info!("Resumed"),
+                    // The streams end at once, ending the loop below
+                    _ = state.shutdown.requested() => {}
+                }
+            }
+
+            let next = match &watchdog {
+                Some(watchdog) => {
+                    // Time spent processing the last zap isn't the stream's fault
+                    watchdog.touch();
+                    tokio::select! {
+                        next = invoices.next() => next,
+                        stalled = watchdog.stalled() => {
+                            // A stream holding an invoice for the pause isn't stuck
+                            if state.pause.is_paused() {
+                                continue;
+                            }
+                            error!("Invoice stream made no progress for {}s, restarting it", stalled.as_secs());
+                            break;
+                        }
+                    }
+                }
+                None => invoices.next().await,
+            };
+
+            let Some((zap_request_info, invoice, pending)) = next else {
+                inflight.wait_idle().await;
+                return Ok(());
+            };
+
+            catchup_pacer.wait(&invoice).await;
+            let state = state.clone();
+            // Held receipts take a slot, so they can't pile up while reading goes on
+            inflight
+                .spawn(async move {
+                    if let Some(window) = state.config.publish_jitter {
+                        jitter::hold(Duration::from_secs(window), &state.shutdown).await;
+                    }
+                    let (zap_request_id, paid_at) =
+                        (zap_request_info.zap_request.id, coalesce::paid_at(&invoice));
+                    let processed = zapped_event::check(&state, &zap_request_info).await
+                        && match process_zap(&state, zap_request_info, invoice).await {
+                            Ok(_) => true,
+                            Err(err) => {
+                                error!("{err}");
+                                false
+                            }
+                        };
+                    // No receipt was published, so a retry shouldn't be coalesced with it
+                    if let (false, Some(coalescer)) = (processed, &state.config.coalescer) {
+                        coalescer.forget(zap_request_id, paid_at);
+                    }
+                    drop(pending);
+                })
+                .await;
+        }
+    }
+}
+
+/// Options of the plugin, with their defaults and descriptions
+fn options() -> Vec<ConfigOption> {
+    vec![
+        // cln-plugin does not yet forward option metadata such as `secret` or
+        // `deprecated` to lightningd, so secrets are registered without a default
+        // to keep them out of the manifest and `listconfigs` defaults.
+        ConfigOption::new(
+            "clnzapper_nostr_nsec",
+            Value::OptString,
+            "Nostr secret key (nsec or hex, or file:, env: or cmd: to read it from elsewhere) used to sign zap receipts. Required unless clnzapper_nostr_nsec_env is set. Secret: do not share",
+        ),
+        ConfigOption::new(
+            "clnzapper_nostr_nsec_env",
+            Value::OptString,
+            "Environment variable to read the key signing zap receipts from, over clnzapper_nostr_nsec",
+        ),
+        ConfigOption::new(
+            "clnzapper_reload_file",
+            Value::OptString,
+            "Config file whose relay, blocklist and zap check lines are applied on SIGHUP. Defaults to config in lightningd's network directory",
+        ),
+        ConfigOption::new(
+            "clnzapper_key_reload_interval",
+            Value::Integer(0),
+            "Seconds between reading the key signing zap receipts again, to pick up a rotated key. 0 only reloads on zapper-reload-key",
+        ),
+        // TODO: Would be better to be a list
+        ConfigOption::new(
+            "clnzapper_nostr_relay",
+            Value::String(DEFAULT_RELAY.to_string()),
+            "Relay that every zap receipt is published to, in addition to the relays in the zap request",
+        ),
+        ConfigOption::new(
+            "clnzapper_pay_index_path",
+            Value::OptString,
+            "Path of the file storing the last processed pay index. Defaults to <data dir>/cln-zapper/last_pay_index",
+        ),
+        ConfigOption::new(
+            "clnzapper_pay_index_migrate_from",
+            Value::OptString,
+            "Pay index file of a previous install to take over on startup. It is renamed to <file>.migrated once taken",
+        ),
+        ConfigOption::new(
+            "clnzapper_pay_index_conflict",
+            Value::String("current".to_string()),
+            "Which index to keep when both clnzapper_pay_index_migrate_from and the current pay index file hold one: current, highest, or error to refuse to start",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_headers",
+            Value::OptString,
+            "JSON object of extra websocket handshake headers per relay, e.g. {\"wss://relay.example\": {\"Sec-WebSocket-Protocol\": \"nostr\"}}",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_auth",
+            Value::OptString,
+            "JSON object of the Authorization header value per paid relay, e.g. {\"wss://relay.example\": \"L402 <macaroon>:<preimage>\"}. Values are secret and may be file:, env: or cmd:",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_kinds",
+            Value::OptString,
+            "JSON object of the event kinds relays accept, e.g. {\"wss://relay.example\": [1, 7]}. Listed relays are only sent those kinds, so one without 9735 gets no receipts",
+        ),
+        ConfigOption::new(
+            "clnzapper_learn_relay_kinds",
+            Value::Boolean(false),
+            "Stop sending a kind to a relay that rejects it with a reason naming its kind, until restart",
+        ),
+        ConfigOption::new(
+            "clnzapper_catchup_rate",
+            Value::OptInteger,
+            "Max zap receipts per second for invoices paid while the plugin was not running. Unlimited if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_backfill",
+            Value::String("all".to_string()),
+            "Paid invoices given receipts on the first start, with no pay index saved: all, none for only new ones, or a pay index to start after",
+        ),
+        ConfigOption::new(
+            "clnzapper_per_zap_concurrency",
+            Value::Integer(relay::DEFAULT_PER_ZAP_CONCURRENCY as i64),
+            "Max relays contacted at once when publishing a single zap receipt",
+        ),
+        ConfigOption::new(
+            "clnzapper_log_level",
+            Value::String(DEFAULT_LOG_LEVEL.to_string()),
+            "Log level of the zapper: error, warn, info, debug or trace. lightningd's log-level still applies",
+        ),
+        ConfigOption::new(
+            "clnzapper_amount_field",
+            Value::String("requested".to_string()),
+            "Invoice amount a zap request's amount must equal: requested (amount_msat) or received (amount_received_msat)",
+        ),
+        ConfigOption::new(
+            "clnzapper_amount_mismatch",
+            Value::String("skip".to_string()),
+            "What a zap whose amount doesn't match the invoice gets: skip, reject to also warn and count it apart, or warn_and_broadcast to warn and still publish the receipt",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_amount_deviation_pct",
+            Value::OptInteger,
+            "Skip zaps whose received amount differs from the invoice amount by more than this percent. Unchecked if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_archive",
+            Value::OptString,
+            "Directory to write each zap receipt to as <event id>.json, or http:// or https:// endpoint to POST each receipt to. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_status_file",
+            Value::OptString,
+            "File to write the zapper's pid, start time, last pay index and relay count to as JSON, for supervisors. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_status_file_interval",
+            Value::Integer(status_file::DEFAULT_INTERVAL as i64),
+            "Seconds between writes of clnzapper_status_file",
+        ),
+        ConfigOption::new(
+            "clnzapper_published_log",
+            Value::OptString,
+            "File to keep the ids of published zap receipts in across restarts, compacted as it grows, so they are still ignored when read back from relays. In memory only if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_published_keep",
+            Value::Integer(CAPACITY as i64),
+            "Most ids of published zap receipts kept, the newest, in memory and in clnzapper_published_log",
+        ),
+        ConfigOption::new(
+            "clnzapper_published_keep_days",
+            Value::Integer(0),
+            "Days the ids of published zap receipts are kept in clnzapper_published_log. 0 for no limit",
+        ),
+        ConfigOption::new(
+            "clnzapper_audit_nsec",
+            Value::OptString,
+            "Nostr secret key signing an attestation of every zap receipt. Requires clnzapper_audit_relay. Secret: do not share",
+        ),
+        ConfigOption::new(
+            "clnzapper_audit_relay",
+            Value::OptString,
+            "Internal relay attestations are published to. Requires clnzapper_audit_nsec",
+        ),
+        ConfigOption::new(
+            "clnzapper_simulate",
+            Value::Boolean(false),
+            "Enable the zapper-simulate dry run RPC method for testing",
+        ),
+        ConfigOption::new(
+            "clnzapper_watchdog_timeout",
+            Value::OptInteger,
+            "Seconds the invoice stream may make no progress before it is restarted. Unwatched if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_relays_tag",
+            Value::Boolean(false),
+            "Add a relays tag listing the relays each zap receipt is published to",
+        ),
+        ConfigOption::new(
+            "clnzapper_description_hash_tag",
+            Value::Boolean(false),
+            "Add a description_hash tag with the sha256 of each zap receipt's description, the zap request JSON",
+        ),
+        ConfigOption::new(
+            "clnzapper_client_tag",
+            Value::Boolean(false),
+            "Add a client tag naming the zapper software and version to each zap receipt",
+        ),
+        ConfigOption::new(
+            "clnzapper_decrypt_private_zaps",
+            Value::Boolean(false),
+            "Decrypt private zaps for the receipt key's pubkey, logging their sender and filtered comment at debug",
+        ),
+        ConfigOption::new(
+            "clnzapper_copy_e_tags",
+            Value::Boolean(false),
+            "Accept zap requests with several e tags, copying them all into the receipt as sent",
+        ),
+        ConfigOption::new(
+            "clnzapper_compliance_mode",
+            Value::String("lenient".to_string()),
+            "How strictly zap requests are held to NIP-57: strict or lenient",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_zap_request_age",
+            Value::OptInteger,
+            "Skip zap requests created more than this many seconds before their invoice was paid, as likely replays. Unchecked if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_sanity_min_msat",
+            Value::OptInteger,
+            "Skip invoices paid for less than this many msat, whatever the zap request asked for. Unbounded if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_sanity_max_msat",
+            Value::OptInteger,
+            "Skip invoices paid for more than this many msat, whatever the zap request asked for. Unbounded if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_coalesce_window_secs",
+            Value::Integer(0),
+            "Seconds after an invoice for a zap request is paid during which further invoices for it, such as wallet retries, get no receipt. 0 to disable",
+        ),
+        ConfigOption::new(
+            "clnzapper_startup_grace",
+            Value::OptInteger,
+            "Seconds, at most 300, to wait at startup for the default relays to accept connections before processing invoices. Skipped if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_network_tag",
+            Value::OptString,
+            "Add a network tag to zap receipts: auto to take the network from the invoice, or the network name to use. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_verify_delivery",
+            Value::Boolean(false),
+            "After a relay accepts a zap receipt, request it back to confirm the relay stored it",
+        ),
+        ConfigOption::new(
+            "clnzapper_verify_zapped_event",
+            Value::Boolean(false),
+            "Look up the event a zap request's e tag names on the default relays before publishing its receipt",
+        ),
+        ConfigOption::new(
+            "clnzapper_verify_zapped_event_timeout",
+            Value::Integer(zapped_event::DEFAULT_TIMEOUT as i64),
+            "Seconds to wait for a relay to return the zapped event",
+        ),
+        ConfigOption::new(
+            "clnzapper_zapped_event_missing",
+            Value::String("skip".to_string()),
+            "What to do with a zap whose event no relay returned in time: skip, or broadcast its receipt anyway",
+        ),
+        ConfigOption::new(
+            "clnzapper_invoice_source",
+            Value::String("wait".to_string()),
+            "Where paid invoices come from: wait blocks on waitanyinvoice, poll asks listinvoices every clnzapper_poll_interval seconds",
+        ),
+        ConfigOption::new(
+            "clnzapper_poll_interval",
+            Value::Integer(source::DEFAULT_POLL_INTERVAL as i64),
+            "Seconds between polls once caught up with clnzapper_invoice_source=poll",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_inflight_zaps",
+            Value::Integer(inflight::DEFAULT_MAX_INFLIGHT_ZAPS as i64),
+            "Max zaps being published at once. Further paid invoices wait until one finishes",
+        ),
+        ConfigOption::new(
+            "clnzapper_index_after_publish",
+            Value::Boolean(false),
+            "Only save the pay index past invoices done with, so receipts still being published when stopped are published on restart",
+        ),
+        ConfigOption::new(
+            "clnzapper_index_batch_size",
+            Value::Integer(1),
+            "Write the pay index file once this many invoices advanced it rather than on every one. A crash replays up to this many less one",
+        ),
+        ConfigOption::new(
+            "clnzapper_index_batch_ms",
+            Value::OptInteger,
+            "Write a pay index held by clnzapper_index_batch_size at most this many milliseconds after the first invoice held. A crash replays the invoices read in that time",
+        ),
+        ConfigOption::new(
+            "clnzapper_rpc_timeout",
+            Value::Integer(0),
+            "Seconds a CLN rpc call may take before the connection is dropped and remade. 0 to disable",
+        ),
+        ConfigOption::new(
+            "clnzapper_log_relay_order",
+            Value::String("sorted".to_string()),
+            "How relays of a zap are listed in logs: sorted, or own-first for the default relays before the payer's. Publishing is unaffected",
+        ),
+        ConfigOption::new(
+            "clnzapper_event_json_log_level",
+            Value::String("trace".to_string()),
+            "Level the full JSON of each zap receipt is logged at, debug or trace, or off to never log it",
+        ),
+        ConfigOption::new(
+            "clnzapper_non_zap_log_level",
+            Value::String("debug".to_string()),
+            "Level paid invoices whose description isn't a zap request are logged at, e.g. info to look into odd descriptions",
+        ),
+        ConfigOption::new(
+            "clnzapper_label_prefix",
+            Value::OptString,
+            "Only issue receipts for invoices whose label starts with this, for nodes shared with other applications. All invoices if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_comment_max_len",
+            Value::Integer(comment::DEFAULT_COMMENT_MAX_LEN as i64),
+            "Most characters of a payer's zap comment shown in logs and RPC output",
+        ),
+        ConfigOption::new(
+            "clnzapper_comment_strip_urls",
+            Value::Boolean(false),
+            "Replace links in zap comments shown in logs and RPC output with [link]",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_receipt_tags",
+            Value::Integer(DEFAULT_MAX_RECEIPT_TAGS as i64),
+            "Most optional tags in a receipt, and relays in its relays tag",
+        ),
+        ConfigOption::new(
+            "clnzapper_receipt_ttl_secs",
+            Value::Integer(0),
+            "Seconds after payment receipts expire by NIP-40, 0 for never",
+        ),
+        ConfigOption::new(
+            "clnzapper_extra_rpc_sockets",
+            Value::OptString,
+            "Comma separated rpc socket paths of other CLN nodes to also issue zap receipts for",
+        ),
+        ConfigOption::new(
+            "clnzapper_fallback_rpc_socket",
+            Value::OptString,
+            "Rpc socket path of a standby CLN node to read paid invoices from while the primary can't be reached",
+        ),
+        ConfigOption::new(
+            "clnzapper_deterministic_signatures",
+            Value::Boolean(false),
+            "Sign receipts without random nonce data, so the same receipt always gets the same signature",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_clock_skew",
+            Value::Integer(clock::DEFAULT_MAX_SKEW as i64),
+            "Max seconds a receipt's created_at, the invoice's paid_at, may be from the local clock before it is clamped",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_scheme_policy",
+            Value::String("keep".to_string()),
+            "With a relay listed as both ws:// and wss://, keep both or prefer-wss to only publish over wss",
+        ),
+        ConfigOption::new(
+            "clnzapper_republish_intervals",
+            Value::OptString,
+            "Comma separated seconds after the first publish to publish each receipt again, e.g. 0,60,3600",
+        ),
+        ConfigOption::new(
+            "clnzapper_summary_interval",
+            Value::Integer(0),
+            "Seconds between signed notes summarizing the zaps processed, 0 to never publish them",
+        ),
+        ConfigOption::new(
+            "clnzapper_summary_relays",
+            Value::OptString,
+            "Comma separated relays summaries are published to. Defaults to the zapper's relays",
+        ),
+        ConfigOption::new(
+            "clnzapper_receipt_output",
+            Value::OptString,
+            "File, named pipe or fd:N every receipt is also written to as newline delimited JSON. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_send_buffer",
+            Value::Integer(0),
+            "Events queued per relay, each relay's queue sent in order by a single writer. 0 to send directly",
+        ),
+        ConfigOption::new(
+            "clnzapper_relay_send_overflow",
+            Value::String("block".to_string()),
+            "What a full relay send buffer does with another event: block until there is room, or drop-oldest",
+        ),
+        ConfigOption::new(
+            "clnzapper_alert_threshold",
+            Value::Integer(0),
+            "Receipts in a row no relay accepts before an alert note is published to clnzapper_alert_relays. 0 to never alert",
+        ),
+        ConfigOption::new(
+            "clnzapper_alert_relays",
+            Value::OptString,
+            "Comma separated relays alerts are published to, best kept separate from the zapper's relays",
+        ),
+        ConfigOption::new(
+            "clnzapper_recipient_pubkeys",
+            Value::OptString,
+            "Comma separated npub or hex pubkeys zap requests may be for. Zaps for anyone else get no receipt. Any recipient if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_payer_blocklist",
+            Value::OptString,
+            "Comma separated npub or hex pubkeys of payers whose zap requests get no receipt, or file:<path> of a file with one per line",
+        ),
+        ConfigOption::new(
+            "clnzapper_recipient_mismatch",
+            Value::String("warn".to_string()),
+            "What a zap request for a recipient not in clnzapper_recipient_pubkeys does besides getting no receipt: skip, warn, or alert to clnzapper_alert_relays",
+        ),
+        ConfigOption::new(
+            "clnzapper_metrics_addr",
+            Value::OptString,
+            "Address such as 127.0.0.1:9090 to serve Prometheus metrics on at /metrics. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_zap_totals_recipients",
+            Value::Integer(totals::DEFAULT_MAX_RECIPIENTS as i64),
+            "Most recipients to keep hourly and daily zap totals of, for zapper-status and /metrics. The least recently zapped are dropped first. 0 to keep none",
+        ),
+        ConfigOption::new(
+            "clnzapper_syslog",
+            Value::Boolean(false),
+            "Record every zap receipt issued to syslog with structured fields, apart from the plugin's logs",
+        ),
+        ConfigOption::new(
+            "clnzapper_syslog_socket",
+            Value::String(syslog::DEFAULT_SOCKET.to_string()),
+            "Unix socket of the local syslog daemon receipt records are sent to",
+        ),
+        ConfigOption::new(
+            "clnzapper_syslog_facility",
+            Value::String("local0".to_string()),
+            "Syslog facility of receipt records: user, daemon, auth, authpriv or local0 to local7",
+        ),
+        ConfigOption::new(
+            "clnzapper_syslog_severity",
+            Value::String("info".to_string()),
+            "Syslog severity of receipt records, emerg to debug",
+        ),
+        ConfigOption::new(
+            "clnzapper_syslog_rate",
+            Value::Integer(0),
+            "Most receipt records sent to syslog per second, the rest counted and dropped. 0 for no limit",
+        ),
+        ConfigOption::new(
+            "clnzapper_health_listen",
+            Value::OptString,
+            "Address such as 0.0.0.0:8080 to serve /livez and /readyz health probes on. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_publish_jitter",
+            Value::Integer(0),
+            "Most seconds each receipt is held for, at random, before it is published, so its timing doesn't reveal when the payer paid. 0 to publish right away",
+        ),
+        ConfigOption::new(
+            "clnzapper_max_total_relays",
+            Value::Integer(0),
+            "Most relays a zap receipt is published to, counting the zapper's and the payer's, the zapper's kept first. 0 for no limit",
+        ),
+        ConfigOption::new(
+            "clnzapper_inbox_relay",
+            Value::OptString,
+            "The operator's own relay every zap receipt is also published to, whatever the payer's relays and not counted by clnzapper_max_total_relays. Disabled if unset",
+        ),
+        ConfigOption::new(
+            "clnzapper_nip65_relays",
+            Value::Boolean(false),
+            "Also publish receipts to the relays the recipient reads from, by their NIP-65 relay list on the default relays",
+        ),
+        ConfigOption::new(
+            "clnzapper_nip65_markers",
+            Value::String("read".to_string()),
+            "Which relays of the recipient's NIP-65 list receipts go to: read, or all to include their write relays",
+        ),
+        ConfigOption::new(
+            "clnzapper_nip65_refresh",
+            Value::Integer(3600),
+            "Seconds after which recipients' NIP-65 relay lists are fetched again. 0 to keep the first list fetched",
+        ),
+        ConfigOption::new(
+            "clnzapper_control_socket",
+            Value::OptString,
+            "Path of a unix socket serving the zapper RPC methods to tools that can't use lightning-cli. Disabled if unset",
+        ),
+    ]
+}
+
+/// Parse and check the options, looked up by name, into the shared state and the pay index path
+fn startup<F>(option: F, rpc_socket: PathBuf) -> Result<(State, PathBuf)>
+where
+    F: Fn(&str) -> Option<Value>,
+{
+    let log_level = match option("clnzapper_log_level") {
+        Some(Value::String(level)) => parse_log_level(&level)?,
+        _ => LevelFilter::Info,
+    };
+    log::set_max_level(log_level);
+
+    let string_option = |name| match option(name) {
+        Some(Value::String(value)) if !value.is_empty() => Some(value),
+        _ => None,
+    };
+    let nostr_sec_key = string_option("clnzapper_nostr_nsec");
+    let nostr_sec_key_env = string_option("clnzapper_nostr_nsec_env");
+    let nostr_relay = option("clnzapper_nostr_relay")
         .expect("Option is defined")
         .as_str()
         .expect("Option is a string")
@@ -74,7 +973,7 @@ async fn main() -> anyhow::Result<()> {
 
     // Get pay index file path from cln config if set
     // if not set to default
-    let pay_index_path = match plugin.option("clnzapper_pay_index_path") {
+    let pay_index_path = match option("clnzapper_pay_index_path") {
         Some(Value::String(path)) => PathBuf::from(path),
         Some(Value::OptString) => index_file_path()?,
         _ => {
@@ -86,96 +985,312 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Pay index path: {pay_index_path:?}");
 
-    let mut relays = HashSet::new();
-    relays.insert(nostr_relay);
+    let nostr_relay = RelayUrl::parse(&nostr_relay)?;
 
-    let keys = Keys::from_sk_str(&nostr_sec_key)?;
+    let config = Config::from_options(&option)?;
 
-    let last_pay_index = match read_last_pay_index(&pay_index_path) {
-        Ok(idx) => idx,
-        Err(e) => {
-            warn!("Could not read last pay index: {e}");
-            if let Err(e) = write_last_pay_index(&pay_index_path, 0) {
-                warn!("Write error: {e}");
-            }
-            0
+    let keys = keys::load_receipt_key(nostr_sec_key, nostr_sec_key_env)?;
+
+    let mut extra_nodes = vec![];
+    for socket in &config.extra_rpc_sockets {
+        if *socket == rpc_socket || extra_nodes.iter().any(|node: &Node| node.socket == *socket) {
+            return Err(anyhow!(
+                "clnzapper_extra_rpc_sockets lists {} more than once",
+                socket.display()
+            ));
         }
+        let pay_index_path = node::extra_index_path(&pay_index_path, socket);
+        info!("Pay index path of {}: {pay_index_path:?}", socket.display());
+        extra_nodes.push(Node::new(socket.clone(), pay_index_path).batched(config.index_batching));
+    }
+
+    let fallback_node = match &config.fallback_rpc_socket {
+        Some(socket) if *socket == rpc_socket || config.extra_rpc_sockets.contains(socket) => {
+            return Err(anyhow!(
+                "clnzapper_fallback_rpc_socket {} is already read",
+                socket.display()
+            ));
+        }
+        Some(socket) => {
+            let pay_index_path = node::extra_index_path(&pay_index_path, socket);
+            info!(
+                "Pay index path of fallback {}: {pay_index_path:?}",
+                socket.display()
+            );
+            Some(Node::new(socket.clone(), pay_index_path).batched(config.index_batching))
+        }
+        None => None,
     };
-    info!("Starting at pay index: {last_pay_index}");
 
-    let mut invoices = invoice_stream(&rpc_socket, pay_index_path, Some(last_pay_index)).await?;
-    while let Some((zap_request_info, invoice)) = invoices.next().await {
-        let zap_note = match create_zap_note(&keys, zap_request_info.clone(), invoice) {
-            Ok(note) => note,
-            Err(err) => {
-                error!("Error while creating zap note: {}", err);
-                continue;
-            }
-        };
+    let state = State {
+        extra_nodes: Arc::new(extra_nodes),
+        fallback_node,
+        ..State::new(
+            keys.current(),
+            rpc_socket,
+            HashSet::from([nostr_relay]),
+            config,
+        )
+    };
+    let mut state = State {
+        keys: Arc::new(keys),
+        ..state
+    };
+    if let Some(path) = &state.config.published_log {
+        let published = PublishedReceipts::load(path, state.config.published_retention)?;
+        state.published = Arc::new(Mutex::new(published));
+    }
 
-        debug!("Zap Note: {}", zap_note.as_json());
+    Ok((state, pay_index_path))
+}
 
-        let mut relays = relays.clone();
-        relays.extend(zap_request_info.relays);
+/// Create the zap note for a paid invoice and broadcast it
+async fn process_zap(
+    state: &State,
+    zap_request_info: ZapRequestInfo,
+    invoice: WaitanyinvoiceResponse,
+) -> Result<EventId> {
+    let default_relays = state.relays.read().await.clone();
+    let mut payer_relays = zap_request_info.relays.clone();
+    if let (true, Tag::PubKey(recipient, _)) = (state.config.nip65_relays, &zap_request_info.p) {
+        let query: Vec<RelayUrl> = default_relays.iter().cloned().collect();
+        let own = state.own_receipts();
+        payer_relays.extend(state.nip65.relays(*recipient, query, &state.config, &own));
+    }
+    let relays = relay::cap_relays(
+        relay::apply_scheme_policy(
+            zap_relays(&default_relays, &payer_relays),
+            state.config.relay_scheme_policy,
+        ),
+        &default_relays,
+        state.config.max_total_relays.get(),
+    );
+    let extra_tags = receipt_tags(&state.config, &relays, &invoice);
+    let relays = relay::without_inbox(relays, state.config.inbox_relay.as_ref());
+    let msat = invoice
+        .amount_received_msat
+        .or(invoice.amount_msat)
+        .map_or(0, |amount| amount.msat());
+    let label = invoice.label.clone();
+    let paid_at = coalesce::paid_at(&invoice);
+    let zap_note = create_zap_note(
+        &state.keys.current(),
+        zap_request_info.clone(),
+        invoice,
+        &extra_tags,
+        state.config.deterministic_signatures,
+        state.config.max_clock_skew,
+    )
+    .map_err(|err| anyhow!("Error while creating zap note: {}", err))?;
+    // Verified once here for both the zap's relays and the inbox; an invalid note is our
+    // own bug, so nothing is written or sent
+    if let Err(err) = zap_note.verify() {
+        state.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+        return Err(anyhow!(
+            "Not publishing invalid note {}: {err}",
+            zap_note.id
+        ));
+    }
 
-        let zap_note_id = zap_note.id.to_hex();
-        if let Err(err) = broadcast_zap_note(&relays, zap_note).await {
-            warn!("Error while broadcasting zap note: {}", err);
-        };
-        info!("Broadcasted: {}", zap_note_id);
-        // info!("To relays: {:?}", relays);
+    // The full note carries the payer's comment, so it only goes to the logs if asked
+    // for, and then with the comment filtered
+    debug!(
+        "Zap note {} for invoice {label}, {msat} msat, {} tags",
+        zap_note.id.to_hex(),
+        zap_note.tags.len()
+    );
+    if let Some(level) = state.config.event_json_log_level {
+        log!(
+            level,
+            "Zap note: {}",
+            state.config.comment_filter.get().redact_receipt(&zap_note)
+        );
+    }
+    if let Some(comment) = state
+        .config
+        .comment_filter
+        .get()
+        .apply(&zap_request_info.zap_request.content)
+    {
+        debug!("Zap comment on {}: {comment}", zap_note.id.to_hex());
+    }
+    match &zap_request_info.privacy {
+        ZapPrivacy::Public => (),
+        ZapPrivacy::Anonymous => debug!(
+            "Zap request {} is anonymous",
+            zap_request_info.zap_request.id.to_hex()
+        ),
+        ZapPrivacy::Private { msg } => {
+            let keys = state.keys.current();
+            let ours = matches!(&zap_request_info.p, Tag::PubKey(recipient, _) if *recipient == keys.public_key());
+            if state.config.decrypt_private_zaps && ours {
+                match private_zap::decrypt(msg, &keys, &zap_request_info.zap_request.pubkey) {
+                    Ok(private) => debug!(
+                        "Private zap on {} from {}: {}",
+                        zap_note.id.to_hex(),
+                        private.pubkey,
+                        state
+                            .config
+                            .comment_filter
+                            .get()
+                            .apply(&private.content)
+                            .unwrap_or_default()
+                    ),
+                    Err(err) => warn!(
+                        "Could not decrypt private zap request {}: {err}",
+                        zap_request_info.zap_request.id.to_hex()
+                    ),
+                }
+            } else {
+                debug!(
+                    "Zap request {} is private, message not decrypted",
+                    zap_request_info.zap_request.id.to_hex()
+                );
+            }
+        }
     }
 
-    Ok(())
-}
+    debug!(
+        "Publishing {} to relays: {:?}",
+        zap_note.id.to_hex(),
+        relay::log_order(&relays, &default_relays, state.config.log_relay_order)
+    );
 
-async fn broadcast_zap_note(relays: &HashSet<String>, zap_note: Event) -> Result<()> {
-    // Create new client
-    zap_note.verify()?;
-    // info!("Note to broadcast {}", zap_note.as_json());
+    if let Some(output) = &state.config.receipt_output {
+        output.write(&zap_note);
+    }
+
+    // Archive in the background so a slow or failing archive never holds up publishing
+    if let Some(archive) = &state.config.archive {
+        archive.spawn_store(&zap_note, &state.config.archive_pending);
+    }
 
-    for relay in relays {
-        let mut socket = match tungstenite::connect(relay) {
-            Ok((s, _)) => s,
-            // TODO: the mutiny relay returns an http 200 its getting logged as an error
+    let attestation = match &state.config.auditor {
+        Some(auditor) => match auditor.attestation(&zap_note) {
+            Ok(attestation) => Some((auditor, attestation)),
             Err(err) => {
-                warn!("Error connecting to {relay}: {err}");
-                continue;
+                warn!("Error while creating attestation: {err}");
+                None
             }
-        };
+        },
+        None => None,
+    };
 
-        // Send msg
-        let msg = ClientMessage::new_event(zap_note.clone()).as_json();
-        socket
-            .write_message(WsMessage::Text(msg))
-            .expect("Impossible to send message");
+    let zap_note_id = zap_note.id;
+    let created_at = zap_note.created_at.as_u64();
+    let republish = (!state.config.republish_intervals.is_empty()).then(|| zap_note.clone());
+    // The inbox is sent to alongside the zap's relays but not counted with them, so it
+    // never hides that no relay a payer would look at took the receipt
+    let inbox = async {
+        let Some(inbox) = &state.config.inbox_relay else {
+            return;
+        };
+        if relay::send_to_inbox(inbox, &zap_note, &state.config).await {
+            debug!("Inbox relay {inbox} accepted {}", zap_note_id.to_hex());
+        } else {
+            warn!(
+                "Inbox relay {inbox} did not accept {}",
+                zap_note_id.to_hex()
+            );
+        }
+    };
+    let (accepted, ()) = tokio::join!(
+        relay::broadcast_verified(&relays, zap_note.clone(), &state.config),
+        inbox
+    );
+    if accepted == 0 {
+        state.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(alerter) = &state.config.alerter {
+        alerter.observe(accepted, &state.keys.current(), &state.config);
+    }
+    if let Some(zap_note) = republish {
+        republish::schedule(
+            relays,
+            zap_note,
+            state.config.republish_intervals.clone(),
+            state.config.clone(),
+        );
+    }
+    if let Some((auditor, attestation)) = attestation {
+        if let Err(err) = auditor.publish(attestation, &state.config).await {
+            warn!("Error while publishing attestation: {err}");
+        }
+    }
+    state
+        .published
+        .lock()
+        .expect("Lock not poisoned")
+        .insert(zap_note_id);
+    // A receipt no relay took hasn't reached anyone, and is counted in broadcast_failures.
+    // A retry of its zap request isn't coalesced with it, so may still get one through
+    if accepted > 0 {
+        state.zaps_broadcast.fetch_add(1, Ordering::Relaxed);
+        state.msat_broadcast.fetch_add(msat, Ordering::Relaxed);
+    } else if let Some(coalescer) = &state.config.coalescer {
+        coalescer.forget(zap_request_info.zap_request.id, paid_at);
+    }
+    if let (Some(zap_totals), Tag::PubKey(recipient, _)) =
+        (&state.config.zap_totals, &zap_request_info.p)
+    {
+        zap_totals.record(*recipient, msat, created_at, Timestamp::now().as_u64());
     }
+    if let Some(syslog) = &state.config.syslog {
+        syslog.record(&syslog::ReceiptRecord {
+            id: zap_note_id,
+            recipient: match &zap_request_info.p {
+                Tag::PubKey(recipient, _) => Some(*recipient),
+                _ => None,
+            },
+            payer: zap_request_info.zap_request.pubkey,
+            msat,
+            label: &label,
+            created_at,
+            accepted,
+        });
+    }
+    info!("Broadcasted: {}", zap_note_id.to_hex());
 
-    Ok(())
+    Ok(zap_note_id)
 }
 
 async fn invoice_stream(
-    socket_addr: &PathBuf,
-    pay_index_path: PathBuf,
-    last_pay_index: Option<u64>,
-) -> Result<impl Stream<Item = (ZapRequestInfo, WaitanyinvoiceResponse)>> {
-    let cln_client = cln_rpc::ClnRpc::new(&socket_addr).await?;
-
-    Ok(futures::stream::unfold(
-        (cln_client, pay_index_path, last_pay_index),
-        |(mut cln_client, pay_index_path, mut last_pay_idx)| async move {
+    node: Node,
+    state: State,
+) -> Result<impl Stream<Item = (ZapRequestInfo, WaitanyinvoiceResponse, Option<Pending>)>> {
+    let last_pay_index = Some(node.last_pay_index.load(Ordering::Relaxed));
+    let source = source::connect(&node.socket, last_pay_index, &state.config).await?;
+
+    Ok(zap_stream(source, node, state))
+}
+
+/// Zap requests of the invoices `source` returns, counting those skipped and
+/// advancing the node's pay index past every invoice seen
+///
+/// With `clnzapper_index_after_publish` each zap comes with its pending pay index,
+/// to be dropped once the zap is done with.
+fn zap_stream(
+    source: Box<dyn InvoiceSource>,
+    node: Node,
+    state: State,
+) -> impl Stream<Item = (ZapRequestInfo, WaitanyinvoiceResponse, Option<Pending>)> {
+    futures::stream::unfold(
+        (source, node, state),
+        |(mut source, node, state)| async move {
             // We loop here since some invoices aren't zaps, in which case we wait for the next one and don't yield
             loop {
-                // info!("Waiting for index: {last_pay_idx:?}");
-                let invoice_res = cln_client
-                    .call(cln_rpc::Request::WaitAnyInvoice(WaitanyinvoiceRequest {
-                        timeout: None,
-                        lastpay_index: last_pay_idx,
-                    }))
-                    .await;
+                let invoice_res = tokio::select! {
+                    invoice_res = source.next_invoice() => invoice_res,
+                    _ = state.shutdown.requested() => {
+                        debug!("Stopping, no more invoices from {}", node.socket.display());
+                        return None;
+                    }
+                };
+                watchdog::beat(&state.stream_heartbeat);
 
-                let invoice: WaitanyinvoiceResponse = match invoice_res {
-                    Ok(invoice) => invoice,
+                let invoice = match invoice_res {
+                    Ok(Some(invoice)) => invoice,
+                    Ok(None) => continue,
                     Err(e) => {
                         warn!("Error fetching invoice: {e}");
                         // Let's not spam CLN with requests on failure
@@ -183,52 +1298,173 @@ async fn invoice_stream(
                         // Retry same request
                         continue;
                     }
+                };
+
+                // Paid as zaps were paused, so held before the pay index moves past it
+                // and read again should the plugin restart while paused
+                if state.pause.is_paused() {
+                    tokio::select! {
+                        _ = state.pause.wait_resumed() => {}
+                        _ = state.shutdown.requested() => return None,
+                    }
                 }
-                .try_into()
-                .expect("Wrong response from CLN");
 
-                last_pay_idx = invoice.pay_index;
-                if let Some(idx) = last_pay_idx {
-                    if let Err(e) = write_last_pay_index(&pay_index_path, idx) {
-                        warn!("Could not write index tip: {e}");
+                // Dropped on skipping the invoice below, or yielded along with its zap
+                let pending = match invoice.pay_index {
+                    Some(idx) if state.config.index_after_publish => {
+                        advance_pay_index(&node, idx, false).then(|| Pending::open(&node, idx))
                     }
+                    Some(idx) => {
+                        advance_pay_index(&node, idx, true);
+                        None
+                    }
+                    None => None,
                 };
 
-                match decode_zap_req(&invoice.description) {
+                if !claimed_invoice(&invoice, state.config.label_prefix.get().as_deref()) {
+                    trace!(
+                        "Skipping invoice {}: {}",
+                        invoice.label,
+                        SkipReason::NotOurs
+                    );
+                    state.skipped.count(SkipReason::NotOurs);
+                    continue;
+                }
+
+                if let Some(reason) = not_zap_invoice(&invoice) {
+                    // CLN should always give one or the other, so this is worth a look
+                    if reason == SkipReason::NoInvoice {
+                        warn!("Skipping invoice {}: {reason}", invoice.label);
+                    } else {
+                        trace!("Skipping invoice {}: {reason}", invoice.label);
+                    }
+                    state.skipped.count(reason);
+                    continue;
+                }
+
+                match decode_zap_req_with(&invoice.description, state.config.copy_e_tags) {
                     Ok(zap) => {
-                        let pay_idx = invoice.pay_index;
+                        if state.config.payer_blocklist.is_blocked(&zap) {
+                            info!(
+                                "Skipping zap request {} for invoice {}: {} is on clnzapper_payer_blocklist",
+                                zap.zap_request.id.to_hex(),
+                                invoice.label,
+                                zap.zap_request.pubkey
+                            );
+                            state.skipped.count(SkipReason::BlockedPayer);
+                            continue;
+                        }
 
-                        // If there is an amount tag present in zap request check it matches invoice
-                        if let (Some(zap_request_amount), Some(invoice_amount)) =
-                            (zap.amount, invoice.amount_msat)
+                        if let Err(err) = state.config.sanity_range.get().check(&invoice) {
+                            warn!(
+                                "Skipping zap request {} for invoice {}: {err}",
+                                zap.zap_request.id.to_hex(),
+                                invoice.label
+                            );
+                            state.skipped.count(SkipReason::AmountOutOfRange);
+                            continue;
+                        }
+
+                        if let Err(err) = amount::check_zap_amount(
+                            zap.amount,
+                            &invoice,
+                            state.config.amount_field,
+                            state.config.max_amount_deviation_pct.get(),
+                        ) {
+                            let policy = state.config.amount_mismatch;
+                            let action = match policy {
+                                MismatchPolicy::Skip => "Skipping",
+                                MismatchPolicy::Reject => "Rejecting",
+                                MismatchPolicy::WarnAndBroadcast => "Broadcasting anyway",
+                            };
+                            log!(
+                                policy.log_level(),
+                                "{action} zap request {} for invoice {}: {err}",
+                                zap.zap_request.id.to_hex(),
+                                invoice.label
+                            );
+                            match policy.skip_reason() {
+                                Some(reason) => {
+                                    state.skipped.count(reason);
+                                    // Don't yield wait for next invoice
+                                    continue;
+                                }
+                                None => {
+                                    state.amount_mismatches.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                        }
+
+                        if let Err(err) =
+                            compliance::check(state.config.compliance_mode, &zap, &invoice)
+                        {
+                            info!(
+                                "Skipping zap request {} for invoice {}: {err}",
+                                zap.zap_request.id.to_hex(),
+                                invoice.label
+                            );
+                            state.skipped.count(SkipReason::NonCompliant);
+                            continue;
+                        }
+
+                        if let Err(err) =
+                            request_age::check(state.config.max_zap_request_age.get(), &zap, &invoice)
                         {
-                            if zap_request_amount.ne(&invoice_amount.msat()) {
+                            warn!(
+                                "Skipping zap request {} for invoice {}: {err}",
+                                zap.zap_request.id.to_hex(),
+                                invoice.label
+                            );
+                            state.skipped.count(SkipReason::StaleRequest);
+                            continue;
+                        }
+
+                        if !recipient::is_expected(&state.config.recipient_pubkeys.get(), &zap) {
+                            recipient::mismatch(&state, &zap, &invoice.label);
+                            state.skipped.count(SkipReason::WrongRecipient);
+                            continue;
+                        }
+
+                        if let Some(coalescer) = &state.config.coalescer {
+                            // Keyed on the payment, so retries read together on catch up
+                            // are coalesced as they would have been live
+                            let paid_at = coalesce::paid_at(&invoice);
+                            if coalescer.is_retry(zap.zap_request.id, paid_at) {
                                 info!(
-                                    "Zap request {} amount does not equal invoice amount {}",
-                                    zap.zap_request.id.to_hex(),
-                                    invoice.label
+                                    "Coalescing invoice {} with an earlier invoice for zap request {}",
+                                    invoice.label,
+                                    zap.zap_request.id.to_hex()
                                 );
-                                // Don't yield wait for next invoice
+                                state.skipped.count(SkipReason::Coalesced);
                                 continue;
                             }
                         }
 
                         // yield zap
-                        break Some(((zap, invoice), (cln_client, pay_index_path, pay_idx)));
+                        break Some(((zap, invoice, pending), (source, node, state)));
+                    }
+                    // A json object description that isn't a valid zap request is a zap gone wrong
+                    Err(e) if invoice.description.trim_start().starts_with('{') => {
+                        warn!("Malformed zap request in invoice {}: {e}", invoice.label);
+                        state.skipped.count(SkipReason::Malformed);
+                        continue;
                     }
                     Err(e) => {
                         // Process next invoice without yielding anything
-                        debug!(
-                            "Error while decoding zap (likely just not a zap invoice): {}",
-                            e
+                        log!(
+                            state.config.non_zap_log_level,
+                            "Error while decoding zap (likely just not a zap invoice) {}: {e}, description {}",
+                            invoice.label,
+                            description_preview(&invoice.description)
                         );
+                        state.skipped.count(SkipReason::NotZap);
                         continue;
                     }
                 }
             }
         },
     )
-    .boxed())
+    .boxed()
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -237,16 +1473,103 @@ struct ZapRequestInfo {
     zap_request: Event,
     /// p tag of zap request
     p: Tag,
-    /// E tag of zap request if related to event
-    e: Option<Tag>,
-    /// Relays in zap request
-    relays: HashSet<String>,
+    /// e tag of zap request if related to an event, or all of them as sent by `clnzapper_copy_e_tags`
+    e: Vec<Tag>,
+    /// a tag of zap request if related to a parameterized replaceable event, as sent
+    a: Option<Tag>,
+    /// Relays in zap request that are valid relay urls
+    relays: HashSet<RelayUrl>,
     /// Amount
     amount: Option<u64>,
+    /// Whether the zap request is signed by its payer, by its anon tag
+    privacy: ZapPrivacy,
+}
+
+/// Just the tags of a zap request, as sent
+#[derive(Deserialize)]
+struct RawZapRequest {
+    tags: Vec<Vec<String>>,
+}
+
+/// Why a paid invoice can't be a zap without looking at its description, if it can't
+///
+/// Keysend payments show up in `waitanyinvoice` with a placeholder description
+/// and no bolt11, and a zap receipt needs the bolt11 the zap request was hashed into.
+fn not_zap_invoice(invoice: &WaitanyinvoiceResponse) -> Option<SkipReason> {
+    if invoice.label.starts_with("keysend-") {
+        return Some(SkipReason::Keysend);
+    }
+    match invoice_bolt11(invoice) {
+        Ok(_) => None,
+        Err(InvoiceError::Missing) => Some(SkipReason::NoInvoice),
+        Err(InvoiceError::Bolt12) => Some(SkipReason::NotBolt11),
+    }
+}
+
+/// Why a paid invoice has no bolt11 for a zap receipt to reference
+#[derive(Debug, PartialEq, Eq)]
+enum InvoiceError {
+    /// Neither a bolt11 nor a bolt12
+    Missing,
+    /// Only a bolt12, which NIP-57 receipts don't carry yet
+    Bolt12,
+}
+
+impl fmt::Display for InvoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "invoice has neither a bolt11 nor a bolt12"),
+            Self::Bolt12 => write!(f, "invoice is bolt12, receipts need a bolt11"),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceError {}
+
+/// The bolt11 of the invoice
+fn invoice_bolt11(invoice: &WaitanyinvoiceResponse) -> Result<&str, InvoiceError> {
+    match (&invoice.bolt11, &invoice.bolt12) {
+        (Some(bolt11), _) => Ok(bolt11),
+        (None, Some(_)) => Err(InvoiceError::Bolt12),
+        (None, None) => Err(InvoiceError::Missing),
+    }
+}
+
+/// Whether the zapper should handle the invoice, created with `label_prefix` if one is set
+///
+/// On a node shared with other applications this keeps receipts to the invoices
+/// created for zaps, e.g. by an lnurl server labelling them with a known prefix.
+fn claimed_invoice(invoice: &WaitanyinvoiceResponse, label_prefix: Option<&str>) -> bool {
+    label_prefix.is_none_or(|prefix| invoice.label.starts_with(prefix))
+}
+
+/// Start of a description, escaped so binary or odd text is safe to log
+fn description_preview(description: &str) -> String {
+    let preview: String = description.chars().take(DESCRIPTION_PREVIEW_LEN).collect();
+    if preview.len() < description.len() {
+        format!("{preview:?}…")
+    } else {
+        format!("{preview:?}")
+    }
 }
 
-/// Decode str of JSON zap note
+/// Decode str of JSON zap note, refusing more than one e tag
 fn decode_zap_req(description: &str) -> Result<ZapRequestInfo> {
+    decode_zap_req_with(description, false)
+}
+
+/// Decode str of JSON zap note, keeping every e tag as sent if `copy_e_tags`
+///
+/// NIP-57 has a zap request reference at most one event, but some clients send the
+/// root and reply e tags of a thread and expect the receipt to carry them all.
+fn decode_zap_req_with(description: &str, copy_e_tags: bool) -> Result<ZapRequestInfo> {
+    // Parsing allocates in proportion to the description, so bound it before parsing
+    if description.len() > MAX_ZAP_REQUEST_LEN {
+        return Err(anyhow!(
+            "Zap request is {} bytes, more than {MAX_ZAP_REQUEST_LEN}",
+            description.len()
+        ));
+    }
     let zap_request: Event = Event::from_json(description)?;
 
     // info!("{:?}", zap_request.as_json());
@@ -273,27 +1596,57 @@ fn decode_zap_req(description: &str) -> Result<ZapRequestInfo> {
         .cloned()
         .collect();
 
-    // Check there is 0 or 1 e tag
+    // nostr rebuilds `a` tags from their parts, so take them from the json as sent to
+    // keep the coordinate and relay hint exactly as the client wrote them
+    let raw: RawZapRequest = serde_json::from_str(description)?;
+    let raw_tags = |name: &str, kind: TagKind| -> Vec<Tag> {
+        raw.tags
+            .iter()
+            .filter(|tag| tag.first().map(String::as_str) == Some(name))
+            .map(|tag| Tag::Generic(kind.clone(), tag[1..].to_vec()))
+            .collect()
+    };
+
+    // Check there is 0 or 1 e tag, unless copying them all
     let e_tag = match e_tags.len() {
-        0 => None,
-        1 => Some(e_tags[0].clone()),
+        0 => vec![],
+        _ if copy_e_tags => raw_tags("e", TagKind::E),
+        1 => e_tags,
         _ => return Err(anyhow!("Too many e tags")),
     };
 
-    let relays: HashSet<String> = zap_request
-        .tags
-        .iter()
-        .filter_map(|tag| match tag {
-            Tag::Relays(values) => Some(
-                values
-                    .iter()
-                    .map(|value| value.to_string())
-                    .collect::<Vec<String>>(),
+    let a_tags = raw_tags("a", TagKind::A);
+
+    // Check there is 0 or 1 a tag
+    let a_tag = match a_tags.len() {
+        0 => None,
+        1 => Some(a_tags[0].clone()),
+        _ => return Err(anyhow!("Too many a tags")),
+    };
+
+    let mut relays: HashSet<RelayUrl> = HashSet::new();
+    let payer_relays = zap_request.tags.iter().flat_map(|tag| match tag {
+        Tag::Relays(values) => values.as_slice(),
+        _ => &[],
+    });
+    for relay in payer_relays {
+        if relays.len() == MAX_ZAP_REQUEST_RELAYS {
+            debug!(
+                "Zap request {} lists more than {MAX_ZAP_REQUEST_RELAYS} relays, ignoring the rest",
+                zap_request.id.to_hex()
+            );
+            break;
+        }
+        match RelayUrl::parse(&relay.to_string()) {
+            Ok(relay) => {
+                relays.insert(relay);
+            }
+            Err(err) => debug!(
+                "Ignoring relay of zap request {}: {err}",
+                zap_request.id.to_hex()
             ),
-            _ => None,
-        })
-        .flatten()
-        .collect();
+        }
+    }
 
     let amount = zap_request.tags.iter().find_map(|tag| {
         if let Tag::Amount(a) = tag {
@@ -302,35 +1655,82 @@ fn decode_zap_req(description: &str) -> Result<ZapRequestInfo> {
         None
     });
 
+    // The receipt for a private or anonymous zap is the same as for a public one
+    let privacy = ZapPrivacy::from_tags(&zap_request.tags);
+
     Ok(ZapRequestInfo {
         zap_request,
         p: p_tag,
         e: e_tag,
+        a: a_tag,
         relays,
         amount,
+        privacy,
     })
 }
 
-/// Create zap note
+/// Create zap note, with any optional `extra_tags` after the NIP-57 ones, at the
+/// invoice's paid_at within `max_clock_skew` of now
 fn create_zap_note(
     keys: &Keys,
     zap_request_info: ZapRequestInfo,
     invoice: WaitanyinvoiceResponse,
+    extra_tags: &[Tag],
+    deterministic: bool,
+    max_clock_skew: u64,
 ) -> Result<Event> {
-    let mut tags = if zap_request_info.e.is_some() {
-        vec![zap_request_info.p, zap_request_info.e.unwrap()]
-    } else {
-        vec![zap_request_info.p]
-    };
+    let created_at = clock::receipt_created_at(invoice.paid_at, Timestamp::now(), max_clock_skew);
+    let zap_note = unsigned_zap_note(
+        keys.public_key(),
+        zap_request_info,
+        invoice,
+        extra_tags,
+        created_at,
+    )?;
+
+    sign_zap_note(zap_note, keys, deterministic)
+}
 
-    // Check there is a bolt11
-    let bolt11 = match invoice.bolt11 {
-        Some(bolt11) => bolt11,
-        None => return Err(anyhow!("No bolt 11")),
-    };
+/// Sign the zap note, with a nonce derived only from the key and id if `deterministic`
+///
+/// BIP-340 nonces already depend on the key and message; the random aux data nostr
+/// adds by default only hardens against side channels. Without it the same note and
+/// key always give the same signature, which reproducible tests and audits want.
+fn sign_zap_note(zap_note: UnsignedEvent, keys: &Keys, deterministic: bool) -> Result<Event> {
+    if !deterministic {
+        return Ok(zap_note.sign(keys)?);
+    }
 
-    // Add bolt11 tag
-    tags.push(Tag::Bolt11(bolt11));
+    let message = Message::from_slice(zap_note.id.as_bytes())?;
+    let sig = SECP256K1.sign_schnorr_no_aux_rand(&message, &keys.key_pair()?);
+    Ok(Event {
+        id: zap_note.id,
+        pubkey: zap_note.pubkey,
+        created_at: zap_note.created_at,
+        kind: zap_note.kind,
+        tags: zap_note.tags,
+        content: zap_note.content,
+        sig,
+    })
+}
+
+/// Zap note created at `created_at`, the same for the same inputs
+fn unsigned_zap_note(
+    pubkey: XOnlyPublicKey,
+    zap_request_info: ZapRequestInfo,
+    invoice: WaitanyinvoiceResponse,
+    extra_tags: &[Tag],
+    created_at: Timestamp,
+) -> Result<UnsignedEvent> {
+    let mut tags: Vec<Tag> = [Some(zap_request_info.p)]
+        .into_iter()
+        .chain(zap_request_info.e.into_iter().map(Some))
+        .chain([zap_request_info.a])
+        .flatten()
+        .collect();
+
+    // Add bolt11 tag, which there must be for the receipt to reference the invoice
+    tags.push(Tag::Bolt11(invoice_bolt11(&invoice)?.to_string()));
 
     // Add description tag
     // description of bolt11 invoice a JSON encoded zap request
@@ -339,10 +1739,158 @@ fn create_zap_note(
     // Add preimage tag if set
     // Pre image is optional according to the spec
     if let Some(pre_image) = invoice.payment_preimage {
-        tags.push(Tag::Preimage(hex::encode(pre_image.to_vec())));
+        let pre_image = pre_image.to_vec();
+        // Don't embed a preimage clients will fail to validate
+        if preimage_matches(&pre_image, &invoice.payment_hash) {
+            tags.push(Tag::Preimage(hex::encode(pre_image)));
+        } else {
+            warn!(
+                "Preimage of invoice {} does not match its payment hash, omitting preimage tag",
+                invoice.label
+            );
+        }
+    }
+
+    tags.extend_from_slice(extra_tags);
+    // Stable, so tags of the same kind such as copied e tags keep their order
+    tags.sort_by_key(tag_rank);
+
+    let kind = Kind::ZapReceipt;
+    let content = String::new();
+    Ok(UnsignedEvent {
+        id: EventId::new(&pubkey, created_at, &kind, &tags, &content),
+        pubkey,
+        created_at,
+        kind,
+        tags,
+        content,
+    })
+}
+
+/// Receipt tags in their canonical order, NIP-57's then the optional ones
+const RECEIPT_TAG_ORDER: [&str; 13] = [
+    "p",
+    "P",
+    "e",
+    "a",
+    "k",
+    "bolt11",
+    "description",
+    "preimage",
+    "relays",
+    "network",
+    "expiration",
+    "description_hash",
+    "client",
+];
+
+/// Position of the tag in a receipt, tags of unknown kinds going last
+fn tag_rank(tag: &Tag) -> usize {
+    let kind = tag.kind().to_string();
+    RECEIPT_TAG_ORDER
+        .iter()
+        .position(|known| *known == kind)
+        .unwrap_or(RECEIPT_TAG_ORDER.len())
+}
+
+/// Optional receipt tags enabled in the config
+///
+/// Capped by `clnzapper_max_receipt_tags`, as is the number of relays in the relays
+/// tag: a zap request listing many relays would otherwise bloat the receipt until
+/// some relays reject it. The tags NIP-57 requires are never dropped, and the
+/// request's a tag, and e tag unless `clnzapper_copy_e_tags` is set, is limited to one.
+fn receipt_tags(
+    config: &Config,
+    relays: &[RelayUrl],
+    invoice: &WaitanyinvoiceResponse,
+) -> Vec<Tag> {
+    let mut tags = Vec::new();
+
+    if config.relays_tag && !relays.is_empty() {
+        if relays.len() > config.max_receipt_tags {
+            warn!(
+                "Receipt for invoice {} would list {} relays, keeping the first {}",
+                invoice.label,
+                relays.len(),
+                config.max_receipt_tags
+            );
+        }
+        tags.push(Tag::Relays(
+            relays
+                .iter()
+                .take(config.max_receipt_tags)
+                .cloned()
+                .map(UncheckedUrl::from)
+                .collect(),
+        ));
+    }
+
+    let network = match &config.network_tag {
+        Some(NetworkTag::FromInvoice) => invoice.bolt11.as_deref().and_then(bolt11::network),
+        Some(NetworkTag::Fixed(network)) => Some(network.as_str()),
+        None => None,
+    };
+    if let Some(network) = network {
+        tags.push(Tag::Generic(
+            TagKind::Custom("network".to_string()),
+            vec![network.to_string()],
+        ));
+    }
+
+    // NIP-40, so relays may prune the receipt once it is `clnzapper_receipt_ttl_secs` old
+    if let Some(ttl) = config.receipt_ttl_secs {
+        let paid_at = invoice.paid_at.unwrap_or_else(|| Timestamp::now().as_u64());
+        tags.push(Tag::Expiration(Timestamp::from(
+            paid_at.saturating_add(ttl),
+        )));
+    }
+
+    // The sha256 of the description tag, for clients caching zap requests by hash
+    if config.description_hash_tag {
+        let hash = sha256::Hash::hash(invoice.description.as_bytes());
+        tags.push(Tag::Generic(
+            TagKind::Custom("description_hash".to_string()),
+            vec![hash.to_string()],
+        ));
+    }
+
+    // NIP-89, naming the software that issued the receipt
+    if config.client_tag {
+        tags.push(Tag::Generic(
+            TagKind::Custom("client".to_string()),
+            vec![CLIENT.to_string()],
+        ));
+    }
+
+    if tags.len() > config.max_receipt_tags {
+        warn!(
+            "Receipt for invoice {} would carry {} optional tags, dropping all but {}",
+            invoice.label,
+            tags.len(),
+            config.max_receipt_tags
+        );
+        tags.truncate(config.max_receipt_tags);
     }
 
-    Ok(EventBuilder::new(nostr::Kind::ZapReceipt, "".to_string(), &tags).to_event(keys)?)
+    tags
+}
+
+/// Check that the preimage hashes to the invoice payment hash
+fn preimage_matches(pre_image: &[u8], payment_hash: &Sha256) -> bool {
+    let pre_image_hash = sha256::Hash::hash(pre_image);
+    AsRef::<[u8]>::as_ref(&pre_image_hash) == AsRef::<[u8]>::as_ref(payment_hash)
+}
+
+/// Parse the `clnzapper_log_level` option
+fn parse_log_level(level: &str) -> Result<LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" => Ok(LevelFilter::Trace),
+        _ => Err(anyhow!("Invalid log level: {level}")),
+    }
 }
 
 /// Default file path for last pay index tip
@@ -358,6 +1906,27 @@ fn index_file_path() -> Result<PathBuf> {
     Ok(file_path)
 }
 
+/// Record the pay index of an invoice from the node, writing it if it advanced and `persist`
+///
+/// CLN hands out pay indices in increasing order, so one not past the last seen
+/// means an invoice came round twice.
+fn advance_pay_index(node: &Node, idx: u64, persist: bool) -> bool {
+    let last = node.last_pay_index.load(Ordering::Relaxed);
+    if idx <= last {
+        warn!(
+            "Pay index {idx} from {} is not past the last one seen, {last}, not writing it",
+            node.socket.display()
+        );
+        return false;
+    }
+
+    if persist {
+        node.write_pay_index(idx);
+    }
+    node.last_pay_index.store(idx, Ordering::Relaxed);
+    true
+}
+
 /// Read last pay index tip from file
 fn read_last_pay_index(file_path: &PathBuf) -> Result<u64> {
     let mut file = File::open(file_path)?;
@@ -374,19 +1943,38 @@ fn write_last_pay_index(file_path: &PathBuf, last_pay_index: u64) -> Result<()>
         fs::create_dir_all(parent_dir)?;
     }
 
-    let mut file = File::create(file_path)?;
+    // Write then rename so a crash never leaves a partial index behind
+    let tmp = file_path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
     file.write_all(&last_pay_index.to_ne_bytes())?;
+    fs::rename(&tmp, file_path)?;
     Ok(())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 
+    use std::collections::VecDeque;
     use std::str::FromStr;
 
     use cln_rpc::primitives::Amount;
+    use nostr::key::FromSkStr;
+    use nostr::{ClientMessage, EventBuilder, RelayMessage};
 
     use super::*;
+    use crate::relay::tests::{mock_relay_replying, relay_url};
+    use crate::relay_kinds::RelayKinds;
+
+    #[test]
+    fn test_parse_log_level() {
+        assert_eq!(parse_log_level("info").unwrap(), LevelFilter::Info);
+        assert_eq!(parse_log_level("DEBUG").unwrap(), LevelFilter::Debug);
+        assert!(parse_log_level("verbose").is_err());
+
+        // The "likely just not a zap" message is logged at debug
+        assert!(log::Level::Debug > parse_log_level("info").unwrap());
+        assert!(log::Level::Debug <= parse_log_level("debug").unwrap());
+    }
 
     #[test]
     fn test_save_last_pay_index() {
@@ -405,24 +1993,1044 @@ mod tests {
         assert_eq!(plus, read_last_pay_index(&path).unwrap());
     }
 
-    #[test]
-    fn test_create_zap_note() {
-        use cln_rpc::primitives::Sha256;
-        use nostr::prelude::*;
-        use nostr::Keys;
+    /// Returns the scripted invoices in order, then nothing ever again
+    struct ScriptedSource(VecDeque<WaitanyinvoiceResponse>);
+
+    impl InvoiceSource for ScriptedSource {
+        fn next_invoice(
+            &mut self,
+        ) -> futures::future::BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+            Box::pin(async move {
+                match self.0.pop_front() {
+                    Some(invoice) => Ok(Some(invoice)),
+                    None => futures::future::pending().await,
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zap_stream() {
+        let invoice = |description: &str, pay_index: u64| {
+            let mut invoice = test_invoice(description);
+            invoice.label = format!("invoice-{pay_index}");
+            invoice.pay_index = Some(pay_index);
+            invoice
+        };
+        let mut keysend = invoice(ZAP_REQ, 1);
+        keysend.label = "keysend-1".to_string();
+        let wrong_amount = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[
+                Tag::PubKey(test_keys().public_key(), None),
+                Tag::Amount(1000),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+
+        let script = VecDeque::from([
+            keysend,
+            invoice("Lunch with Bob", 2),
+            invoice("{\"kind\": 9734", 3),
+            invoice(&wrong_amount, 4),
+            invoice(ZAP_REQ, 5),
+        ]);
+
+        fs::create_dir_all("./test/stream").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/stream/last_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(script)),
+            node.clone(),
+            state.clone(),
+        );
+        let (zap, invoice, pending) = tokio::time::timeout(Duration::from_secs(5), zaps.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.label, "invoice-5");
+        assert_eq!(
+            zap.zap_request.as_json(),
+            Event::from_json(ZAP_REQ).unwrap().as_json()
+        );
+        assert_eq!(node.last_pay_index.load(Ordering::Relaxed), 5);
+        assert!(pending.is_none());
+
+        let skipped = state.skipped.snapshot();
+        assert_eq!(skipped["keysend"], 1);
+        assert_eq!(skipped["not-a-zap"], 1);
+        assert_eq!(skipped["malformed"], 1);
+        assert_eq!(skipped["amount-mismatch"], 1);
+
+        // Nothing else is yielded
+        assert!(tokio::time::timeout(Duration::from_millis(50), zaps.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_on_shutdown() {
+        fs::create_dir_all("./test/shutdown").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/shutdown/last_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        let mut completed = test_invoice(ZAP_REQ);
+        completed.pay_index = Some(3);
+
+        // Waits forever after the first invoice, like a quiet node
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(VecDeque::from([completed]))),
+            node.clone(),
+            state.clone(),
+        );
+        assert!(zaps.next().await.is_some());
+
+        let waiting = tokio::spawn(async move { zaps.next().await.is_none() });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.shutdown.request();
+        assert!(tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap());
+
+        shutdown::finish(&state, std::slice::from_ref(&node));
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_paused_invoice_not_past_pay_index() {
+        fs::create_dir_all("./test/paused").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/paused/last_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        node.last_pay_index.store(2, Ordering::Relaxed);
+        let mut paid = test_invoice(ZAP_REQ);
+        paid.pay_index = Some(3);
+
+        state.pause.pause();
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(VecDeque::from([paid]))),
+            node.clone(),
+            state.clone(),
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(50), zaps.next())
+            .await
+            .is_err());
+        assert_eq!(node.last_pay_index.load(Ordering::Relaxed), 2);
+
+        state.pause.resume();
+        let (_, invoice, _) = tokio::time::timeout(Duration::from_secs(1), zaps.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.pay_index, Some(3));
+        assert_eq!(node.last_pay_index.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_amount_mismatch_policy() {
+        let wrong_amount = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[
+                Tag::PubKey(test_keys().public_key(), None),
+                Tag::Amount(1000),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+
+        fs::create_dir_all("./test/mismatch").unwrap();
+        for (policy, skipped_as) in [
+            (MismatchPolicy::Skip, Some("amount-mismatch")),
+            (MismatchPolicy::Reject, Some("amount-rejected")),
+            (MismatchPolicy::WarnAndBroadcast, None),
+        ] {
+            let broadcast = skipped_as.is_none();
+            let mut mismatched = test_invoice(&wrong_amount);
+            mismatched.pay_index = Some(1);
+            let mut matched = test_invoice(ZAP_REQ);
+            matched.pay_index = Some(2);
+
+            let node = Node::new(
+                PathBuf::from("lightning-rpc"),
+                PathBuf::from(format!("./test/mismatch/{policy:?}")),
+            );
+            let state = State::new(
+                test_keys(),
+                PathBuf::from("lightning-rpc"),
+                HashSet::new(),
+                Config {
+                    amount_mismatch: policy,
+                    ..Config::default()
+                },
+            );
+            let mut zaps = zap_stream(
+                Box::new(ScriptedSource(VecDeque::from([mismatched, matched]))),
+                node,
+                state.clone(),
+            );
+
+            let (zap, invoice, _) = tokio::time::timeout(Duration::from_secs(5), zaps.next())
+                .await
+                .unwrap()
+                .unwrap();
+            if broadcast {
+                assert_eq!(invoice.pay_index, Some(1), "{policy:?}");
+                assert_eq!(zap.amount, Some(1000));
+            } else {
+                assert_eq!(invoice.pay_index, Some(2), "{policy:?}");
+            }
+            let skipped = state.skipped.snapshot();
+            for reason in ["amount-mismatch", "amount-rejected"] {
+                assert_eq!(
+                    skipped[reason],
+                    u64::from(skipped_as == Some(reason)),
+                    "{policy:?}"
+                );
+            }
+            assert_eq!(
+                state.amount_mismatches.load(Ordering::Relaxed),
+                u64::from(broadcast),
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocklisted_payer_skipped() {
+        let zap_request = |keys: &Keys| {
+            EventBuilder::new(
+                nostr::Kind::ZapRequest,
+                "",
+                &[Tag::PubKey(test_keys().public_key(), None)],
+            )
+            .to_event(keys)
+            .unwrap()
+            .as_json()
+        };
+        let (abuser, payer) = (Keys::generate(), Keys::generate());
+        let mut blocked = test_invoice(&zap_request(&abuser));
+        blocked.pay_index = Some(1);
+        let mut allowed = test_invoice(&zap_request(&payer));
+        allowed.pay_index = Some(2);
+
+        fs::create_dir_all("./test/blocklist").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/blocklist/last_pay_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config {
+                payer_blocklist: Arc::new(blocklist::PayerBlocklist::new(HashSet::from([
+                    abuser.public_key()
+                ]))),
+                ..Config::default()
+            },
+        );
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(VecDeque::from([blocked, allowed]))),
+            node,
+            state.clone(),
+        );
+
+        let (zap, invoice, _) = tokio::time::timeout(Duration::from_secs(5), zaps.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.pay_index, Some(2));
+        assert_eq!(zap.zap_request.pubkey, payer.public_key());
+        assert_eq!(state.skipped.snapshot()["blocked-payer"], 1);
+    }
 
-        let keys =
-            Keys::from_sk_str("505fd02741816952ec9a70204221acdd8458906d3e1e0604fef033876c811a8f")
+    #[tokio::test]
+    async fn test_retried_zap_coalesced() {
+        let (relay, received) = mock_relay_replying(None, 3, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let paid_at = 1_700_000_000;
+        let invoice = |description: &str, pay_index: u64, paid_after: u64| {
+            let mut invoice = test_invoice(description);
+            invoice.label = format!("invoice-{pay_index}");
+            invoice.pay_index = Some(pay_index);
+            invoice.paid_at = Some(paid_at + paid_after);
+            invoice
+        };
+        let other = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[Tag::PubKey(test_keys().public_key(), None)],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+
+        fs::create_dir_all("./test/coalesce").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/coalesce/last_pay_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay]),
+            Config {
+                coalescer: Some(Arc::new(coalesce::Coalescer::new(Duration::from_secs(60)))),
+                ..Config::default()
+            },
+        );
+        // The same zap request paid twice, then another, then the first again past the
+        // window. All are read at once, so only their payment times tell them apart
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(VecDeque::from([
+                invoice(ZAP_REQ, 1, 0),
+                invoice(ZAP_REQ, 2, 30),
+                invoice(&other, 3, 30),
+                invoice(ZAP_REQ, 4, 90),
+            ]))),
+            node,
+            state.clone(),
+        );
+
+        for pay_index in [1, 3, 4] {
+            let (zap, invoice, _) = tokio::time::timeout(Duration::from_secs(5), zaps.next())
+                .await
+                .unwrap()
                 .unwrap();
-        // let zap_req = "{\"content\":\"\",\"created_at\":1678734288,\"id\":\"c93b75ff70b07d28287059d750756f93281ac779cd780e7d61b781f9862c5a81\",\"kind\":9734,\"pubkey\":\"04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5\",\"sig\":\"512d0a3ec6b9797810272b9dc05cadb7f6d271ff72a183350f643fa761bc37820e877563ddc1c5ef30a549a63115a6e907412a60de1dbe35dd7ea3b431a534ba\",\"tags\":[[\"e\",\"d07f03815931a3767ea91ee9cb3920758cd6dcb4e206ef0f1061f7e3c51f338e\"],[\"p\",\"00003687cecf074d81949ce8b95a860789e2be03925f3d3860ae27573fdc2218\"],[\"relays\",\"wss://nostr.wine\",\"wss://relay.damus.io\",\"wss://relay.orangepill.dev\",\"wss://dublin.saoirse.dev\",\"wss://relay.utxo.one\",\"wss://relay.nostr.band\",\"wss://nostr-pub.wellorder.net\",\"wss://nostr.milou.lol\",\"wss://nostr.oxtr.dev\",\"wss://eden.nostr.land\",\"wss://mutinywallet.com\",\"wss://nostr.zebedee.cloud\",\"wss://brb.io\"],[\"amount\",\"50000\"]]}";
+            assert_eq!(invoice.pay_index, Some(pay_index));
+            process_zap(&state, zap, invoice).await.unwrap();
+        }
+        assert_eq!(state.skipped.snapshot()["coalesced"], 1);
+
+        // One receipt for each window of the retried zap request, none for the retry
+        let retried = decode_zap_req(ZAP_REQ).unwrap().zap_request.id.to_hex();
+        let receipts: Vec<String> = (0..3)
+            .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(
+            receipts.iter().filter(|msg| msg.contains(&retried)).count(),
+            2
+        );
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_of_rejected_zap_not_coalesced() {
+        // Rejects the first receipt, accepts the rest
+        fn reply(msg: &str) -> Option<String> {
+            static REJECTED: std::sync::atomic::AtomicBool =
+                std::sync::atomic::AtomicBool::new(false);
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            let accepted = REJECTED.swap(true, Ordering::Relaxed);
+            Some(RelayMessage::new_ok(event.id, accepted, "").as_json())
+        }
+        let (relay, received) = mock_relay_replying(None, 2, reply);
+        let invoice = |pay_index: u64, paid_at: u64| {
+            let mut invoice = test_invoice(ZAP_REQ);
+            invoice.label = format!("invoice-{pay_index}");
+            invoice.pay_index = Some(pay_index);
+            invoice.paid_at = Some(paid_at);
+            invoice
+        };
+
+        fs::create_dir_all("./test/coalesce_rejected").unwrap();
+        let node = Node::new(
+            PathBuf::from("lightning-rpc"),
+            PathBuf::from("./test/coalesce_rejected/last_pay_index"),
+        );
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay]),
+            Config {
+                coalescer: Some(Arc::new(coalesce::Coalescer::new(Duration::from_secs(60)))),
+                ..Config::default()
+            },
+        );
+        let mut zaps = zap_stream(
+            Box::new(ScriptedSource(VecDeque::from([
+                invoice(1, 1_700_000_000),
+                invoice(2, 1_700_000_030),
+            ]))),
+            node,
+            state.clone(),
+        );
+
+        for pay_index in [1, 2] {
+            let (zap, invoice, _) = tokio::time::timeout(Duration::from_secs(5), zaps.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(invoice.pay_index, Some(pay_index));
+            process_zap(&state, zap, invoice).await.unwrap();
+        }
+
+        assert_eq!(state.skipped.snapshot()["coalesced"], 0);
+        assert_eq!(state.broadcast_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(state.zaps_broadcast.load(Ordering::Relaxed), 1);
+        for _ in 0..2 {
+            received.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receipts_signed_with_reloaded_key() {
+        let (relay, received) = mock_relay_replying(None, 2, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let zap_request = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[Tag::PubKey(test_keys().public_key(), None)],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+
+        let path = std::env::temp_dir().join(format!("clnzapper-rotate-{}", std::process::id()));
+        let write_key = |keys: &Keys| {
+            fs::write(
+                &path,
+                keys.secret_key().unwrap().display_secret().to_string(),
+            )
+            .unwrap()
+        };
+        let old = Keys::generate();
+        write_key(&old);
+        let keys = keys::load_receipt_key(Some(format!("file:{}", path.display())), None).unwrap();
+        let state = State {
+            keys: Arc::new(keys),
+            ..State::new(
+                old.clone(),
+                PathBuf::from("lightning-rpc"),
+                HashSet::from([relay]),
+                Config::default(),
+            )
+        };
+        // The mock relay runs on its own thread, so waiting here can't hold it up
+        let next_pubkey = || {
+            let msg = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            let ClientMessage::Event(note) = ClientMessage::from_json(msg).unwrap() else {
+                panic!("Expected an event");
+            };
+            note.pubkey
+        };
+
+        let zap = || decode_zap_req(&zap_request).unwrap();
+        process_zap(&state, zap(), test_invoice(&zap_request))
+            .await
+            .unwrap();
+        assert_eq!(next_pubkey(), old.public_key());
+
+        // Rotated mid-run, the next receipt uses the new key
+        let new = Keys::generate();
+        write_key(&new);
+        rpc::handle_reload_key(&state).await.unwrap();
+        process_zap(&state, zap(), test_invoice(&zap_request))
+            .await
+            .unwrap();
+        assert_eq!(next_pubkey(), new.public_key());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inbox_relay_gets_capped_zap() {
+        fn reply(msg: &str, accepted: bool) -> Option<String> {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, accepted, "").as_json())
+        }
+        let (default_relay, _) = mock_relay_replying(None, 1, |msg| reply(msg, false));
+        let (inbox, inbox_received) = mock_relay_replying(None, 1, |msg| reply(msg, true));
+        let zap_request = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[
+                Tag::PubKey(test_keys().public_key(), None),
+                Tag::Relays(vec![UncheckedUrl::from("wss://payer.example")]),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+        // Only room for the default relay, so the payer's is dropped
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([default_relay]),
+            Config {
+                max_total_relays: Some(1).into(),
+                // Not a kind the inbox is said to take, but it still gets every receipt
+                relay_kinds: RelayKinds::parse(Some(&format!(r#"{{"{inbox}": [1]}}"#)), false)
+                    .unwrap()
+                    .into(),
+                inbox_relay: Some(inbox),
+                ..Config::default()
+            },
+        );
+
+        let id = process_zap(
+            &state,
+            decode_zap_req(&zap_request).unwrap(),
+            test_invoice(&zap_request),
+        )
+        .await
+        .unwrap();
+        let msg = inbox_received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(msg.contains(&id.to_hex()));
+        // The inbox accepting it doesn't make up for the zap's relays rejecting it
+        assert_eq!(state.broadcast_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(state.zaps_broadcast.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_accepted_receipts_counted() {
+        let (relay, _) = mock_relay_replying(None, 1, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let zap_request = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            &[Tag::PubKey(test_keys().public_key(), None)],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+        let zap = || decode_zap_req(&zap_request).unwrap();
+
+        // Nothing listens on the discard port, so no relay takes it
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("ws://127.0.0.1:9")]),
+            Config::default(),
+        );
+        process_zap(&state, zap(), test_invoice(&zap_request))
+            .await
+            .unwrap();
+        assert_eq!(state.zaps_broadcast.load(Ordering::Relaxed), 0);
+        assert_eq!(state.msat_broadcast.load(Ordering::Relaxed), 0);
+        assert_eq!(state.broadcast_failures.load(Ordering::Relaxed), 1);
+
+        *state.relays.write().await = HashSet::from([relay]);
+        process_zap(&state, zap(), test_invoice(&zap_request))
+            .await
+            .unwrap();
+        assert_eq!(state.zaps_broadcast.load(Ordering::Relaxed), 1);
+        assert_eq!(state.msat_broadcast.load(Ordering::Relaxed), 50000);
+        assert_eq!(state.broadcast_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_index_written_on_increase() {
+        let path = PathBuf::from("./test/advance/last_index");
+        fs::create_dir_all("./test/advance").unwrap();
+        let node = Node::new(PathBuf::from("lightning-rpc"), path.clone());
+
+        assert!(advance_pay_index(&node, 5, true));
+        assert_eq!(read_last_pay_index(&path).unwrap(), 5);
+
+        // Neither an equal nor a lower index touches the file
+        fs::remove_file(&path).unwrap();
+        assert!(!advance_pay_index(&node, 5, true));
+        assert!(!advance_pay_index(&node, 3, true));
+        assert!(!path.exists());
+
+        assert!(advance_pay_index(&node, 6, true));
+        assert_eq!(read_last_pay_index(&path).unwrap(), 6);
+        assert_eq!(node.last_pay_index.load(Ordering::Relaxed), 6);
+    }
+
+    pub const ZAP_REQ: &str = "{\"content\":\"\",\"created_at\":1680535967,\"id\":\"0237c32a241cbbdb6d8c7984befbd04428643669007f5d12efb7806863ac746e\",\"kind\":9734,\"pubkey\":\"1abbe81befdec27c7b571df65e5f96f41fac32233698290dee4c5b09fb57d6bb\",\"sig\":\"3e5fd2d74972b9aba7519e5c239b413f78cb8b1dd9f1349f883d6c1edf6619e36ca423524a99d3d8739fde095557a287e7690e1fca1a5ecea16e846035499e39\",\"tags\":[[\"e\",\"9b8e5879b8f895b229c97a87deb1232d96499d746209625284dd8de65ebb52e3\"],[\"p\",\"3036e986c4cef0b2615e6bcf2d6d411310c73872f30c99b19ab7ba58a2df9f98\"],[\"relays\",\"wss://relay.damus.io\",\"wss://eden.nostr.land\",\"wss://nos.lol\",\"wss://nostr.mutinywallet.com/\",\"wss://offchain.pub\",\"wss://relay.damus.io/\",\"wss://relay.current.fyi\",\"wss://relay.snort.social\",\"wss://nostr.btcmp.com\",\"wss://adult.18plus.social/\"]]}";
+
+    pub fn test_keys() -> Keys {
+        Keys::from_sk_str("505fd02741816952ec9a70204221acdd8458906d3e1e0604fef033876c811a8f")
+            .unwrap()
+    }
+
+    /// Receipt for `ZAP_REQ` and `test_invoice` created at its paid_at with `test_keys`
+    const GOLDEN_ZAP_NOTE: &str = include_str!("../testdata/zap_note.json");
+
+    /// The same note signed with `clnzapper_deterministic_signatures`
+    const GOLDEN_SIGNED_ZAP_NOTE: &str = include_str!("../testdata/zap_note_signed.json");
+
+    pub fn test_invoice(zap_req: &str) -> WaitanyinvoiceResponse {
+        WaitanyinvoiceResponse { label: "c15c98b0-81fe-4864-a9c5-ffad716d466a".to_string(), description: zap_req.to_string(), payment_hash: Sha256::from_str("83f34c56502833b28dc64b382ef8462c2f5edb19c427fd5456d46bfc5c35914b").unwrap(), status: cln_rpc::model::WaitanyinvoiceStatus::PAID, expires_at: 1687338240, amount_msat: Some(Amount::from_msat(5000)), bolt11: Some("lnbc500n1pjq7u7jsp5n5jth3w6d4wjnjmup0nwlr2xfqthg8leru8yj8cyqf3sszapfxeqpp5s0e5c4js9qem9rwxfvuza7zx9sh4akcecsnl64zk634lchp4j99shp5ctnx2g7vddpve39pa35f70d4yua7fypfqjepcygq938ev86ekd7sxqyjw5qcqpjrzjqvhxqvs0ulx0mf5gp6x2vw047capck4pxqnsjv0gg8a4zaegej6gxzlgzuqqttgqqyqqqqqqqqqqqqqqyg9qyysgqs80g00rantwaay8g6wwev33v7xgtu8qkmq4hflgs93ygrxccry6qlhksdd0497pusvlsx3emk0hj5ghecxf6pw84tgxf99r5jg7mjrgpammhml".to_string()), bolt12: None, pay_index: Some(1), amount_received_msat: Some(Amount::from_msat(50000)), paid_at: Some(1687251840), payment_preimage: None}
+    }
+
+    #[test]
+    fn test_keysend_not_zap() {
+        assert_eq!(not_zap_invoice(&test_invoice(ZAP_REQ)), None);
+
+        let mut keysend = test_invoice("keysend");
+        keysend.label = "keysend-1687251840.123456789".to_string();
+        keysend.bolt11 = None;
+        assert_eq!(not_zap_invoice(&keysend), Some(SkipReason::Keysend));
+
+        let mut bolt12 = test_invoice(ZAP_REQ);
+        bolt12.bolt11 = None;
+        bolt12.bolt12 = Some("lni1placeholder".to_string());
+        assert_eq!(not_zap_invoice(&bolt12), Some(SkipReason::NotBolt11));
+
+        let mut neither = test_invoice(ZAP_REQ);
+        neither.bolt11 = None;
+        assert_eq!(not_zap_invoice(&neither), Some(SkipReason::NoInvoice));
+    }
+
+    #[test]
+    fn test_zap_note_without_invoice() {
+        let mut invoice = test_invoice(ZAP_REQ);
+        invoice.bolt11 = None;
+        let err = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice.clone(),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&InvoiceError::Missing));
+
+        invoice.bolt12 = Some("lni1placeholder".to_string());
+        let err = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice,
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&InvoiceError::Bolt12));
+    }
+
+    #[test]
+    fn test_claimed_invoice() {
+        let invoices: Vec<WaitanyinvoiceResponse> = ["clnurl-1", "other-app-2", "clnurl-3", ""]
+            .into_iter()
+            .map(|label| WaitanyinvoiceResponse {
+                label: label.to_string(),
+                ..test_invoice(ZAP_REQ)
+            })
+            .collect();
+        let claimed = |prefix| -> Vec<&str> {
+            invoices
+                .iter()
+                .filter(|invoice| claimed_invoice(invoice, prefix))
+                .map(|invoice| invoice.label.as_str())
+                .collect()
+        };
+
+        assert_eq!(claimed(Some("clnurl-")), vec!["clnurl-1", "clnurl-3"]);
+        assert_eq!(claimed(None).len(), 4);
+    }
+
+    #[test]
+    fn test_private_zap() {
+        assert_eq!(decode_zap_req(ZAP_REQ).unwrap().privacy, ZapPrivacy::Public);
+
+        let recipient = test_keys();
+        let payer = Keys::generate();
+        let (anon, throwaway) =
+            private_zap::tests::private_zap(&payer, recipient.public_key(), "Secret thanks");
+        let Tag::Anon { msg: Some(msg) } = anon.clone() else {
+            panic!("Expected an anon tag");
+        };
+        let tags = [Tag::PubKey(recipient.public_key(), None), anon];
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&throwaway)
+            .unwrap()
+            .as_json();
+
+        let zap_req_info = decode_zap_req(&zap_request).unwrap();
+        assert_eq!(
+            zap_req_info.privacy,
+            ZapPrivacy::Private { msg: msg.clone() }
+        );
+        let private =
+            private_zap::decrypt(&msg, &recipient, &zap_req_info.zap_request.pubkey).unwrap();
+        assert_eq!(private.pubkey, payer.public_key());
+        assert_eq!(private.content, "Secret thanks");
+
+        // Still gets a standard receipt
+        let zap_note = create_zap_note(
+            &test_keys(),
+            zap_req_info,
+            test_invoice(&zap_request),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        assert!(zap_note
+            .tags
+            .contains(&Tag::Description(zap_request.clone())));
+    }
 
-        let zap_req = "{\"content\":\"\",\"created_at\":1680535967,\"id\":\"0237c32a241cbbdb6d8c7984befbd04428643669007f5d12efb7806863ac746e\",\"kind\":9734,\"pubkey\":\"1abbe81befdec27c7b571df65e5f96f41fac32233698290dee4c5b09fb57d6bb\",\"sig\":\"3e5fd2d74972b9aba7519e5c239b413f78cb8b1dd9f1349f883d6c1edf6619e36ca423524a99d3d8739fde095557a287e7690e1fca1a5ecea16e846035499e39\",\"tags\":[[\"e\",\"9b8e5879b8f895b229c97a87deb1232d96499d746209625284dd8de65ebb52e3\"],[\"p\",\"3036e986c4cef0b2615e6bcf2d6d411310c73872f30c99b19ab7ba58a2df9f98\"],[\"relays\",\"wss://relay.damus.io\",\"wss://eden.nostr.land\",\"wss://nos.lol\",\"wss://nostr.mutinywallet.com/\",\"wss://offchain.pub\",\"wss://relay.damus.io/\",\"wss://relay.current.fyi\",\"wss://relay.snort.social\",\"wss://nostr.btcmp.com\",\"wss://adult.18plus.social/\"]]}";
+    #[test]
+    fn test_receipt_relays_tag() {
+        let relays = vec![
+            relay_url("wss://nos.lol"),
+            relay_url("wss://relay.damus.io"),
+        ];
+        let relays_tag = Tag::Relays(vec![
+            UncheckedUrl::from("wss://nos.lol"),
+            UncheckedUrl::from("wss://relay.damus.io"),
+        ]);
+
+        // Off by default
+        let invoice = test_invoice(ZAP_REQ);
+        assert!(receipt_tags(&Config::default(), &relays, &invoice).is_empty());
+
+        let config = Config {
+            relays_tag: true,
+            ..Config::default()
+        };
+        let tags = receipt_tags(&config, &relays, &invoice);
+        assert_eq!(tags, vec![relays_tag.clone()]);
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            test_invoice(ZAP_REQ),
+            &tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        zap_note.verify().unwrap();
+        assert!(zap_note.tags.contains(&relays_tag));
+    }
+
+    #[test]
+    fn test_description_hash_tag() {
+        let config = Config {
+            description_hash_tag: true,
+            ..Config::default()
+        };
+        let tags = receipt_tags(&config, &[], &test_invoice(ZAP_REQ));
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            test_invoice(ZAP_REQ),
+            &tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        zap_note.verify().unwrap();
+
+        let tag = |kind: &str| {
+            zap_note
+                .tags
+                .iter()
+                .map(Tag::as_vec)
+                .find(|tag| tag[0] == kind)
+                .unwrap()
+        };
+        let description = tag("description");
+        let hash = sha256::Hash::hash(description[1].as_bytes()).to_string();
+        assert_eq!(
+            tag("description_hash"),
+            vec!["description_hash".to_string(), hash]
+        );
+    }
+
+    #[test]
+    fn test_client_tag() {
+        let invoice = test_invoice(ZAP_REQ);
+        assert!(receipt_tags(&Config::default(), &[], &invoice).is_empty());
+
+        let config = Config {
+            client_tag: true,
+            ..Config::default()
+        };
+        let tags = receipt_tags(&config, &[], &invoice);
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice,
+            &tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        zap_note.verify().unwrap();
+
+        let client = format!("cln-zapper/{}", env!("CARGO_PKG_VERSION"));
+        assert!(zap_note
+            .tags
+            .iter()
+            .any(|tag| tag.as_vec() == ["client".to_string(), client.clone()]));
+        // After the NIP-57 tags, which are left as they were
+        let kinds: Vec<String> = zap_note
+            .tags
+            .iter()
+            .map(|tag| tag.kind().to_string())
+            .collect();
+        assert_eq!(kinds, ["p", "e", "bolt11", "description", "client"]);
+    }
+
+    #[test]
+    fn test_undecodable_description() {
+        let binary = String::from_utf8_lossy(&[0xff, 0xfe, b'{', 0x00, 0x9f]).to_string();
+        for description in [
+            binary.as_str(),
+            "",
+            "\u{0}\u{1b}[2J",
+            "{\"kind\": 9734,",
+            "[1, 2, 3]",
+            "Lunch with Bob",
+        ] {
+            assert!(decode_zap_req(description).is_err());
+            assert_eq!(description_preview(description), format!("{description:?}"));
+        }
+
+        assert_eq!(description_preview("\u{0}\u{1b}[2J"), "\"\\0\\u{1b}[2J\"");
+        assert_eq!(description_preview("a\nb"), "\"a\\nb\"");
+        let long = "x".repeat(1000);
+        assert_eq!(
+            description_preview(&long),
+            format!("\"{}\"…", "x".repeat(DESCRIPTION_PREVIEW_LEN))
+        );
+        // Cut by characters, not bytes
+        let wide = "é".repeat(DESCRIPTION_PREVIEW_LEN + 1);
+        assert_eq!(
+            description_preview(&wide),
+            format!("\"{}\"…", "é".repeat(DESCRIPTION_PREVIEW_LEN))
+        );
+    }
+
+    #[test]
+    fn test_zap_request_invalid_relays_dropped() {
+        let tags = [
+            Tag::PubKey(test_keys().public_key(), None),
+            Tag::Relays(
+                [
+                    "wss://nos.lol",
+                    "not a relay",
+                    "https://relay.example",
+                    "wss://",
+                    "",
+                    "ws://relay.example:7777/",
+                ]
+                .into_iter()
+                .map(UncheckedUrl::from)
+                .collect(),
+            ),
+        ];
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+
+        let zap_req_info = decode_zap_req(&zap_request).unwrap();
+        assert_eq!(
+            zap_req_info.relays,
+            HashSet::from([
+                relay_url("wss://nos.lol"),
+                relay_url("ws://relay.example:7777")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_zap_request_relays_capped() {
+        let zap_request = |relays: usize| {
+            let tags = [
+                Tag::PubKey(test_keys().public_key(), None),
+                Tag::Relays(
+                    (0..relays)
+                        .map(|i| UncheckedUrl::from(format!("ws://r{i}")))
+                        .collect(),
+                ),
+            ];
+            EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+                .to_event(&Keys::generate())
+                .unwrap()
+                .as_json()
+        };
+
+        let zap_req_info = decode_zap_req(&zap_request(3000)).unwrap();
+        assert_eq!(zap_req_info.relays.len(), MAX_ZAP_REQUEST_RELAYS);
+
+        // Too big to parse at all
+        let zap_request = zap_request(10000);
+        assert!(zap_request.len() > MAX_ZAP_REQUEST_LEN);
+        assert!(decode_zap_req(&zap_request).is_err());
+    }
+
+    #[test]
+    fn test_receipt_tags_capped() {
+        let tags: Vec<Tag> = (0..500)
+            .map(|i| Tag::Relays(vec![UncheckedUrl::from(format!("ws://r{i}"))]))
+            .chain([Tag::PubKey(test_keys().public_key(), None)])
+            .collect();
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+        let zap_req_info = decode_zap_req(&zap_request).unwrap();
+        let relays = zap_relays(&HashSet::new(), &zap_req_info.relays);
+
+        let config = Config {
+            relays_tag: true,
+            network_tag: Some(NetworkTag::FromInvoice),
+            max_receipt_tags: 1,
+            ..Config::default()
+        };
+        let invoice = test_invoice(&zap_request);
+        let extra_tags = receipt_tags(&config, &relays, &invoice);
+        assert_eq!(
+            extra_tags,
+            vec![Tag::Relays(vec![UncheckedUrl::from(relays[0].as_str())])]
+        );
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            zap_req_info,
+            invoice,
+            &extra_tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        // p, bolt11 and description are always kept
+        assert_eq!(zap_note.tags.len(), 4);
+    }
+
+    #[test]
+    fn test_receipt_expiration_tag() {
+        let invoice = test_invoice(ZAP_REQ);
+        let config = Config {
+            receipt_ttl_secs: Some(86400),
+            ..Config::default()
+        };
+        let tags = receipt_tags(&config, &[], &invoice);
+        assert_eq!(tags, vec![Tag::Expiration(Timestamp::from(1687338240))]);
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice,
+            &tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        zap_note.verify().unwrap();
+        // Added after the NIP-57 tags
+        assert_eq!(zap_note.tags.last(), tags.last());
+
+        // A huge ttl saturates rather than overflowing
+        let config = Config {
+            receipt_ttl_secs: Some(u64::MAX),
+            ..Config::default()
+        };
+        assert_eq!(
+            receipt_tags(&config, &[], &test_invoice(ZAP_REQ)),
+            vec![Tag::Expiration(Timestamp::from(u64::MAX))]
+        );
+    }
+
+    #[test]
+    fn test_receipt_network_tag() {
+        let network_tag = |network: &str| {
+            Tag::Generic(
+                TagKind::Custom("network".to_string()),
+                vec![network.to_string()],
+            )
+        };
+        let mut invoice = test_invoice(ZAP_REQ);
+
+        // Off by default
+        assert!(receipt_tags(&Config::default(), &[], &invoice).is_empty());
+
+        let config = Config {
+            network_tag: Some(NetworkTag::FromInvoice),
+            ..Config::default()
+        };
+        assert_eq!(
+            receipt_tags(&config, &[], &invoice),
+            vec![network_tag("bitcoin")]
+        );
+        invoice.bolt11 = Some("lntbs10u1placeholder".to_string());
+        assert_eq!(
+            receipt_tags(&config, &[], &invoice),
+            vec![network_tag("signet")]
+        );
+
+        let config = Config {
+            network_tag: Some(NetworkTag::Fixed("testnet".to_string())),
+            ..Config::default()
+        };
+        assert_eq!(
+            receipt_tags(&config, &[], &invoice),
+            vec![network_tag("testnet")]
+        );
+    }
+
+    #[test]
+    fn test_create_zap_note() {
+        let keys = test_keys();
+        let zap_req = ZAP_REQ;
 
         let zap_req_info = decode_zap_req(zap_req).unwrap();
 
-        let invoice = WaitanyinvoiceResponse { label: "c15c98b0-81fe-4864-a9c5-ffad716d466a".to_string(), description: zap_req.to_string(), payment_hash: Sha256::from_str("83f34c56502833b28dc64b382ef8462c2f5edb19c427fd5456d46bfc5c35914b").unwrap(), status: cln_rpc::model::WaitanyinvoiceStatus::PAID, expires_at: 1687338240, amount_msat: Some(Amount::from_msat(5000)), bolt11: Some("lnbc500n1pjq7u7jsp5n5jth3w6d4wjnjmup0nwlr2xfqthg8leru8yj8cyqf3sszapfxeqpp5s0e5c4js9qem9rwxfvuza7zx9sh4akcecsnl64zk634lchp4j99shp5ctnx2g7vddpve39pa35f70d4yua7fypfqjepcygq938ev86ekd7sxqyjw5qcqpjrzjqvhxqvs0ulx0mf5gp6x2vw047capck4pxqnsjv0gg8a4zaegej6gxzlgzuqqttgqqyqqqqqqqqqqqqqqyg9qyysgqs80g00rantwaay8g6wwev33v7xgtu8qkmq4hflgs93ygrxccry6qlhksdd0497pusvlsx3emk0hj5ghecxf6pw84tgxf99r5jg7mjrgpammhml".to_string()), bolt12: None, pay_index: Some(1), amount_received_msat: Some(Amount::from_msat(50000)), paid_at: Some(1687251840), payment_preimage: None};
+        let invoice = test_invoice(zap_req);
 
-        let zap_note = create_zap_note(&keys, zap_req_info, invoice.clone()).unwrap();
+        let zap_note = create_zap_note(
+            &keys,
+            zap_req_info,
+            invoice.clone(),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
 
         zap_note.verify().unwrap();
 
@@ -436,4 +3044,226 @@ mod tests {
 
         assert_eq!(zap_req_hash, invoice_des_has);
     }
+
+    #[test]
+    fn test_zap_note_golden() {
+        let zap_note = unsigned_zap_note(
+            test_keys().public_key(),
+            decode_zap_req(ZAP_REQ).unwrap(),
+            test_invoice(ZAP_REQ),
+            &[],
+            Timestamp::from(1687251840),
+        )
+        .unwrap();
+
+        assert_eq!(serde_json::to_string(&zap_note).unwrap(), GOLDEN_ZAP_NOTE);
+        zap_note.sign(&test_keys()).unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn test_signed_zap_note_golden() {
+        let zap_note = || {
+            unsigned_zap_note(
+                test_keys().public_key(),
+                decode_zap_req(ZAP_REQ).unwrap(),
+                test_invoice(ZAP_REQ),
+                &[],
+                Timestamp::from(1687251840),
+            )
+            .unwrap()
+        };
+
+        let signed = sign_zap_note(zap_note(), &test_keys(), true).unwrap();
+        signed.verify().unwrap();
+        assert_eq!(signed.as_json(), GOLDEN_SIGNED_ZAP_NOTE);
+
+        // Randomized by default
+        let first = sign_zap_note(zap_note(), &test_keys(), false).unwrap();
+        let second = sign_zap_note(zap_note(), &test_keys(), false).unwrap();
+        assert_eq!(first.id, signed.id);
+        assert_ne!(first.sig, second.sig);
+    }
+
+    #[test]
+    fn test_receipt_tag_order() {
+        let coordinate = format!("30023:{}:my-article", test_keys().public_key());
+        let tags = [
+            Tag::Amount(5000),
+            Tag::Generic(TagKind::A, vec![coordinate]),
+            Tag::Event(EventId::all_zeros(), None, None),
+            Tag::PubKey(test_keys().public_key(), None),
+        ];
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+        let mut invoice = test_invoice(&zap_request);
+        invoice.payment_hash = Sha256::from_str(&sha256::Hash::hash(&[1; 32]).to_string()).unwrap();
+        invoice.payment_preimage = Some([1u8; 32].to_vec().try_into().unwrap());
+        let config = Config {
+            relays_tag: true,
+            network_tag: Some(NetworkTag::FromInvoice),
+            receipt_ttl_secs: Some(60),
+            ..Config::default()
+        };
+        // Optional tags given out of order, with one of a kind we don't know
+        let mut extra_tags = receipt_tags(&config, &[relay_url("wss://relay.example")], &invoice);
+        extra_tags.reverse();
+        extra_tags.insert(1, Tag::Generic(TagKind::Custom("x".to_string()), vec![]));
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(&zap_request).unwrap(),
+            invoice,
+            &extra_tags,
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        let kinds: Vec<String> = zap_note
+            .tags
+            .iter()
+            .map(|tag| tag.kind().to_string())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "p",
+                "e",
+                "a",
+                "bolt11",
+                "description",
+                "preimage",
+                "relays",
+                "network",
+                "expiration",
+                "x"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copy_e_tags() {
+        let tags = [
+            Tag::PubKey(test_keys().public_key(), None),
+            Tag::Generic(
+                TagKind::E,
+                vec![
+                    EventId::all_zeros().to_hex(),
+                    "wss://relay.example".to_string(),
+                    "root".to_string(),
+                ],
+            ),
+            Tag::Generic(
+                TagKind::E,
+                vec![
+                    "1".repeat(64),
+                    "wss://relay.example".to_string(),
+                    "reply".to_string(),
+                ],
+            ),
+        ];
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+
+        assert!(decode_zap_req(&zap_request).is_err());
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req_with(&zap_request, true).unwrap(),
+            test_invoice(&zap_request),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        let e_tags = |json: &str| -> Vec<serde_json::Value> {
+            let event: serde_json::Value = serde_json::from_str(json).unwrap();
+            event["tags"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|tag| tag[0] == "e")
+                .cloned()
+                .collect()
+        };
+        assert_eq!(e_tags(&zap_note.as_json()).len(), 2);
+        assert_eq!(e_tags(&zap_note.as_json()), e_tags(&zap_request));
+    }
+
+    #[test]
+    fn test_a_tag_copied_verbatim() {
+        let coordinate = format!("30023:{}:my-article", test_keys().public_key());
+        let a_tag = Tag::Generic(
+            TagKind::A,
+            vec![coordinate, "wss://relay.example/".to_string()],
+        );
+        let tags = [Tag::PubKey(test_keys().public_key(), None), a_tag.clone()];
+        let zap_request = EventBuilder::new(nostr::Kind::ZapRequest, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+            .as_json();
+
+        let zap_note = create_zap_note(
+            &test_keys(),
+            decode_zap_req(&zap_request).unwrap(),
+            test_invoice(&zap_request),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+
+        let sent: serde_json::Value = serde_json::from_str(&zap_request).unwrap();
+        let receipt: serde_json::Value = serde_json::from_str(&zap_note.as_json()).unwrap();
+        let find_a = |event: &serde_json::Value| {
+            event["tags"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|tag| tag[0] == "a")
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(find_a(&receipt), find_a(&sent));
+        assert_eq!(zap_note.tags[1], a_tag);
+    }
+
+    #[test]
+    fn test_preimage_checked_against_payment_hash() {
+        let keys = test_keys();
+        let pre_image = [7u8; 32];
+        let has_preimage = |note: &Event| note.tags.iter().any(|t| matches!(t, Tag::Preimage(_)));
+
+        let mut invoice = test_invoice(ZAP_REQ);
+        invoice.payment_hash =
+            Sha256::from_str(&sha256::Hash::hash(&pre_image).to_string()).unwrap();
+        invoice.payment_preimage = Some(pre_image.to_vec().try_into().unwrap());
+        let zap_note = create_zap_note(
+            &keys,
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice.clone(),
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        assert!(zap_note
+            .tags
+            .contains(&Tag::Preimage(hex::encode(pre_image))));
+
+        invoice.payment_preimage = Some([8u8; 32].to_vec().try_into().unwrap());
+        let zap_note = create_zap_note(
+            &keys,
+            decode_zap_req(ZAP_REQ).unwrap(),
+            invoice,
+            &[],
+            false,
+            clock::DEFAULT_MAX_SKEW,
+        )
+        .unwrap();
+        assert!(!has_preimage(&zap_note));
+    }
 }
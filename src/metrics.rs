@@ -0,0 +1,248 @@
+//! Prometheus metrics served over http, by `clnzapper_metrics_addr`
+//!
+//! `GET /metrics` answers in the Prometheus text format with
+//! `zapper_broadcast_failures_total`, the events relays did not accept, labelled by
+//! `relay` and `reason`. Reasons are a fixed set, and relays past the first
+//! `MAX_RELAY_SERIES` seen are counted under `relay="other"`, so payers listing
+//! many relays can't grow the series without bound.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write as _};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use nostr::Timestamp;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::relay_url::RelayUrl;
+use crate::totals::ZapTotals;
+
+/// Most relays with series of their own
+const MAX_RELAY_SERIES: usize = 200;
+
+/// Relay label of failures at relays past `MAX_RELAY_SERIES`
+const OTHER_RELAY: &str = "other";
+
+/// Why a relay did not accept an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureReason {
+    /// No connection or acknowledgement in time
+    Timeout,
+    Refused,
+    Tls,
+    /// The relay rejected the event or the websocket upgrade
+    Rejected,
+    /// The relay wants NIP-42 authentication or an authorized key
+    Auth,
+    /// Any other failure, such as DNS
+    Other,
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Timeout => "timeout",
+            Self::Refused => "refused",
+            Self::Tls => "tls",
+            Self::Rejected => "rejected",
+            Self::Auth => "auth",
+            Self::Other => "other",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// Failures by relay label and reason
+    broadcast_failures: BTreeMap<(String, FailureReason), u64>,
+    /// Relays with series of their own
+    relays: BTreeSet<String>,
+}
+
+/// Counters served on `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+    /// Zap totals served alongside the counters, by `clnzapper_zap_totals_recipients`
+    zap_totals: Option<Arc<ZapTotals>>,
+}
+
+impl Metrics {
+    pub fn new(zap_totals: Option<Arc<ZapTotals>>) -> Self {
+        Self {
+            zap_totals,
+            ..Self::default()
+        }
+    }
+
+    /// Count an event the relay did not accept
+    pub fn broadcast_failed(&self, relay: &RelayUrl, reason: FailureReason) {
+        let mut counters = self.counters.lock().expect("Lock not poisoned");
+        let relay = relay.as_str().to_string();
+        let label = if counters.relays.contains(&relay) {
+            relay
+        } else if counters.relays.len() < MAX_RELAY_SERIES {
+            counters.relays.insert(relay.clone());
+            relay
+        } else {
+            OTHER_RELAY.to_string()
+        };
+        *counters
+            .broadcast_failures
+            .entry((label, reason))
+            .or_default() += 1;
+    }
+
+    /// The counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().expect("Lock not poisoned");
+        let mut out = String::from(
+            "# HELP zapper_broadcast_failures_total Events relays did not accept, by relay and reason\n\
+             # TYPE zapper_broadcast_failures_total counter\n",
+        );
+        for ((relay, reason), count) in &counters.broadcast_failures {
+            writeln!(
+                out,
+                "zapper_broadcast_failures_total{{relay=\"{}\",reason=\"{reason}\"}} {count}",
+                escape_label(relay)
+            )
+            .expect("Writing to a string can't fail");
+        }
+        if let Some(zap_totals) = &self.zap_totals {
+            zap_totals.render(Timestamp::now().as_u64(), &mut out);
+        }
+        out
+    }
+}
+
+/// Escape a label value as the text format requires
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Bind the metrics endpoint and serve it in the background, returning its address
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    info!("Metrics listening on http://{addr}/metrics");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_connection(stream, &metrics).await {
+                            debug!("Metrics connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Metrics accept error: {err}"),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn handle_connection(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let request = lines.next_line().await?.unwrap_or_default();
+    // The headers don't matter, but read them so the client isn't cut off mid request
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    write.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::broadcast_zap_note;
+    use crate::relay::tests::relay_url;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_failures_scraped() {
+        let metrics = Arc::new(Metrics::default());
+        let addr = serve("127.0.0.1:0".parse().unwrap(), metrics.clone())
+            .await
+            .unwrap();
+
+        // Nothing listens on a port just freed
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay = relay_url(&format!("ws://127.0.0.1:{port}"));
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let config = Config {
+            metrics: Some(metrics),
+            ..Config::default()
+        };
+        broadcast_zap_note(std::slice::from_ref(&relay), zap_note, &config)
+            .await
+            .unwrap();
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!(
+            "zapper_broadcast_failures_total{{relay=\"{relay}\",reason=\"refused\"}} 1\n"
+        )));
+
+        assert!(get(addr, "/").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_relay_series_capped() {
+        let metrics = Metrics::default();
+        for i in 0..MAX_RELAY_SERIES + 10 {
+            metrics.broadcast_failed(&relay_url(&format!("ws://r{i}")), FailureReason::Timeout);
+        }
+        // Relays with a series keep it
+        metrics.broadcast_failed(&relay_url("ws://r0"), FailureReason::Timeout);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("{relay=\"other\",reason=\"timeout\"} 10\n"));
+        assert!(rendered.contains("{relay=\"ws://r0\",reason=\"timeout\"} 2\n"));
+        assert_eq!(rendered.lines().count(), MAX_RELAY_SERIES + 3);
+    }
+}
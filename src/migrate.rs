@@ -0,0 +1,198 @@
+//! Moving the pay index from a previous install, by `clnzapper_pay_index_migrate_from`
+//!
+//! An upgrade that moves the zapper's data leaves the last pay index behind, and
+//! starting without it would reprocess or, with `clnzapper_backfill`, skip
+//! invoices. Naming the old file has the zapper take it over on startup: its index
+//! is written to the current pay index file and the old file is renamed to
+//! `<file>.migrated`, so it is only ever taken once. If the current file already
+//! holds an index, `clnzapper_pay_index_conflict` decides which one wins.
+//!
+//! The pay index file has only ever had one format, 8 bytes of the index, so only
+//! its location needs migrating.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+
+use crate::lock::IndexLock;
+use crate::{read_last_pay_index, write_last_pay_index};
+
+/// Which index to keep when both the old and current pay index files hold one,
+/// set by `clnzapper_pay_index_conflict`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexConflict {
+    /// Keep the current file's index
+    #[default]
+    Current,
+    /// Keep the higher of the two, reprocessing nothing
+    Highest,
+    /// Refuse to start until the operator removes one
+    Error,
+}
+
+impl FromStr for IndexConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "current" => Ok(Self::Current),
+            "highest" => Ok(Self::Highest),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow!(
+                "Invalid pay index conflict {s}, expected current, highest or error"
+            )),
+        }
+    }
+}
+
+/// Where the old file goes once migrated
+fn migrated_path(from: &Path) -> PathBuf {
+    let mut file_name = from.file_name().unwrap_or_default().to_os_string();
+    file_name.push(OsString::from(".migrated"));
+    from.with_file_name(file_name)
+}
+
+/// Take over the pay index at `from` into `to`, returning the index now at `to` if
+/// there was one to migrate
+///
+/// `to` must already be locked by the caller. The old index is locked too, so one
+/// still in use by an old instance is left alone.
+pub fn migrate(from: &Path, to: &Path, conflict: IndexConflict) -> Result<Option<u64>> {
+    if from == to || !from.exists() {
+        debug!("No pay index to migrate at {}", from.display());
+        return Ok(None);
+    }
+
+    let _lock = IndexLock::acquire(from)?;
+    let old = read_last_pay_index(&from.to_path_buf()).map_err(|err| {
+        anyhow!(
+            "Could not read pay index to migrate {}: {err}",
+            from.display()
+        )
+    })?;
+    let current = match to.exists() {
+        true => Some(read_last_pay_index(&to.to_path_buf())?),
+        false => None,
+    };
+
+    let idx = match (current, conflict) {
+        (None, _) => old,
+        (Some(current), IndexConflict::Current) => current,
+        (Some(current), IndexConflict::Highest) => current.max(old),
+        (Some(current), IndexConflict::Error) => {
+            return Err(anyhow!(
+                "Both {} ({old}) and {} ({current}) hold a pay index, remove one or set clnzapper_pay_index_conflict",
+                from.display(),
+                to.display()
+            ))
+        }
+    };
+    if current != Some(idx) {
+        write_last_pay_index(&to.to_path_buf(), idx)?;
+    }
+
+    let migrated = migrated_path(from);
+    std::fs::rename(from, &migrated)?;
+    info!(
+        "Migrated pay index {old} from {} to {}, now at {idx}; the old file is kept as {}",
+        from.display(),
+        to.display(),
+        migrated.display()
+    );
+
+    Ok(Some(idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("clnzapper-migrate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_upgrade_migrated() {
+        let dir = dir("upgrade");
+        let (from, to) = (
+            dir.join("old/last_pay_index"),
+            dir.join("new/last_pay_index"),
+        );
+        write_last_pay_index(&from, 42).unwrap();
+
+        assert_eq!(
+            migrate(&from, &to, IndexConflict::default()).unwrap(),
+            Some(42)
+        );
+        assert_eq!(read_last_pay_index(&to).unwrap(), 42);
+        assert!(!from.exists());
+        assert_eq!(read_last_pay_index(&migrated_path(&from)).unwrap(), 42);
+
+        // Only ever taken once
+        write_last_pay_index(&to, 50).unwrap();
+        assert_eq!(migrate(&from, &to, IndexConflict::default()).unwrap(), None);
+        assert_eq!(read_last_pay_index(&to).unwrap(), 50);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_conflict() {
+        let dir = dir("conflict");
+        let (from, to) = (dir.join("old"), dir.join("new"));
+        let both = |old, current| {
+            write_last_pay_index(&from, old).unwrap();
+            write_last_pay_index(&to, current).unwrap();
+        };
+
+        both(40, 30);
+        assert!(migrate(&from, &to, IndexConflict::Error).is_err());
+        // Nothing touched
+        assert_eq!(read_last_pay_index(&from).unwrap(), 40);
+        assert_eq!(read_last_pay_index(&to).unwrap(), 30);
+
+        assert_eq!(
+            migrate(&from, &to, IndexConflict::Current).unwrap(),
+            Some(30)
+        );
+        assert_eq!(read_last_pay_index(&to).unwrap(), 30);
+
+        both(40, 30);
+        assert_eq!(
+            migrate(&from, &to, IndexConflict::Highest).unwrap(),
+            Some(40)
+        );
+        assert_eq!(read_last_pay_index(&to).unwrap(), 40);
+        assert!(!from.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_old_index_in_use() {
+        let dir = dir("in-use");
+        let (from, to) = (dir.join("old"), dir.join("new"));
+        write_last_pay_index(&from, 42).unwrap();
+
+        let _old_instance = IndexLock::acquire(&from).unwrap();
+        assert!(migrate(&from, &to, IndexConflict::default()).is_err());
+        assert!(!to.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index_conflict_from_str() {
+        assert_eq!(
+            IndexConflict::from_str("highest").unwrap(),
+            IndexConflict::Highest
+        );
+        assert!(IndexConflict::from_str("newest").is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Recipient relays from NIP-65 relay lists, by `clnzapper_nip65_relays`
+//!
+//! With it set, receipts also go to the relays the zap's recipient reads from, as
+//! listed in their newest kind 10002 event on the default relays. Lists are cached
+//! per recipient, and with `clnzapper_nip65_refresh` set a task fetches each list
+//! again once it is that old, so relays the recipient drops stop getting receipts
+//! and relays they add start to. A failed fetch keeps the list we had.
+//!
+//! Fetching never holds up a zap: a recipient not cached yet gets the receipt on the
+//! other relays while their list is fetched in the background for their next zap.
+//! The cache keeps the `CAPACITY` recipients zapped most recently.
+//!
+//! For a zap on an event the recipient is the event's author. By default only their
+//! read relays are used, where they look for events about them; with
+//! `clnzapper_nip65_markers` set to `all` their write relays are used too, where
+//! others following them look.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::{Event, Filter, Kind, RelayMetadata, Tag};
+
+use crate::config::Config;
+use crate::published::OwnReceipts;
+use crate::relay::fetch_events;
+use crate::relay_url::RelayUrl;
+use crate::state::State;
+
+/// Most time between checks for lists due a refresh
+const REFRESH_CHECK: Duration = Duration::from_secs(60);
+
+/// Most recipients whose lists are cached
+const CAPACITY: usize = 1_000;
+
+/// Which relays of a relay list receipts go to, set by `clnzapper_nip65_markers`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Nip65Markers {
+    /// Relays marked read, or not marked which means both
+    #[default]
+    Read,
+    /// Every relay listed, read or write
+    All,
+}
+
+impl FromStr for Nip65Markers {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Self::Read),
+            "all" => Ok(Self::All),
+            _ => Err(anyhow!("Invalid NIP-65 markers {s}, expected read or all")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RelayList {
+    relays: HashSet<RelayUrl>,
+    fetched_at: Instant,
+    /// When a zap last took these relays
+    used_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    lists: HashMap<XOnlyPublicKey, RelayList>,
+    /// Recipients whose first list is being fetched
+    fetching: HashSet<XOnlyPublicKey>,
+}
+
+/// Cached relay lists, by recipient, forgetting the least recently used
+#[derive(Debug)]
+pub struct Nip65Relays {
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+impl Default for Nip65Relays {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+impl Nip65Relays {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// The recipient's cached relays, none if not cached, in which case their list is
+    /// fetched from `query` in the background
+    pub fn relays(
+        self: &Arc<Self>,
+        recipient: XOnlyPublicKey,
+        query: Vec<RelayUrl>,
+        config: &Arc<Config>,
+        own: &OwnReceipts,
+    ) -> HashSet<RelayUrl> {
+        let mut cache = self.cache.lock().expect("Lock not poisoned");
+        if let Some(list) = cache.lists.get_mut(&recipient) {
+            list.used_at = Instant::now();
+            return list.relays.clone();
+        }
+
+        if cache.fetching.insert(recipient) {
+            let (nip65, config, own) = (self.clone(), config.clone(), own.clone());
+            tokio::spawn(async move {
+                nip65.update(recipient, &query, &config, &own).await;
+            });
+        }
+        HashSet::new()
+    }
+
+    /// Fetch every list at least `max_age` old again from `query`
+    pub async fn refresh(
+        &self,
+        max_age: Duration,
+        query: &[RelayUrl],
+        config: &Config,
+        own: &OwnReceipts,
+    ) {
+        let stale: Vec<XOnlyPublicKey> = self
+            .cache
+            .lock()
+            .expect("Lock not poisoned")
+            .lists
+            .iter()
+            .filter(|(_, list)| list.fetched_at.elapsed() >= max_age)
+            .map(|(recipient, _)| *recipient)
+            .collect();
+
+        for recipient in stale {
+            self.update(recipient, query, config, own).await;
+        }
+    }
+
+    /// Fetch the recipient's list and cache it, returning the relays now cached
+    async fn update(
+        &self,
+        recipient: XOnlyPublicKey,
+        query: &[RelayUrl],
+        config: &Config,
+        own: &OwnReceipts,
+    ) -> HashSet<RelayUrl> {
+        let fetched = fetch(recipient, query, config, own).await;
+        let mut cache = self.cache.lock().expect("Lock not poisoned");
+        cache.fetching.remove(&recipient);
+
+        let old = cache.lists.get(&recipient);
+        let relays = match (fetched, old) {
+            (Some(relays), Some(old)) => {
+                let added: BTreeSet<_> = relays.difference(&old.relays).collect();
+                let removed: BTreeSet<_> = old.relays.difference(&relays).collect();
+                if !added.is_empty() || !removed.is_empty() {
+                    info!(
+                        "NIP-65 relays of {recipient} changed, added {added:?}, removed {removed:?}"
+                    );
+                }
+                relays
+            }
+            (Some(relays), None) => {
+                debug!("NIP-65 relays of {recipient}: {relays:?}");
+                relays
+            }
+            (None, old) => {
+                warn!("Could not fetch the NIP-65 relay list of {recipient}, keeping the last one");
+                // Cached even if empty so it is retried on refresh rather than every zap
+                old.map(|old| old.relays.clone()).unwrap_or_default()
+            }
+        };
+
+        let now = Instant::now();
+        let used_at = old.map_or(now, |old| old.used_at);
+        if old.is_none() && cache.lists.len() >= self.capacity {
+            let least_used = cache
+                .lists
+                .iter()
+                .min_by_key(|(_, list)| list.used_at)
+                .map(|(recipient, _)| *recipient);
+            if let Some(least_used) = least_used {
+                cache.lists.remove(&least_used);
+            }
+        }
+        cache.lists.insert(
+            recipient,
+            RelayList {
+                relays: relays.clone(),
+                fetched_at: now,
+                used_at,
+            },
+        );
+        relays
+    }
+}
+
+/// Relays in the recipient's newest relay list on any of `query`, `None` if none answered
+async fn fetch(
+    recipient: XOnlyPublicKey,
+    query: &[RelayUrl],
+    config: &Config,
+    own: &OwnReceipts,
+) -> Option<HashSet<RelayUrl>> {
+    let filter = Filter::new()
+        .author(recipient.to_string())
+        .kind(Kind::RelayList);
+    let fetches = query.iter().cloned().map(|relay| {
+        let headers = config.relay_headers.get(&relay).cloned();
+        let (filter, own) = (filter.clone(), own.clone());
+        // tungstenite is blocking so keep it off the async workers
+        tokio::task::spawn_blocking(move || {
+            fetch_events(&relay, headers.as_ref(), filter, &own)
+                .map_err(|err| debug!("Could not fetch relay list from {relay}: {err}"))
+                .ok()
+        })
+    });
+
+    let answers: Vec<Vec<Event>> = futures::future::join_all(fetches)
+        .await
+        .into_iter()
+        .filter_map(|answer| answer.ok().flatten())
+        .collect();
+    if answers.is_empty() {
+        return None;
+    }
+
+    let newest = answers
+        .into_iter()
+        .flatten()
+        .filter(|event| event.pubkey == recipient && event.kind == Kind::RelayList)
+        .max_by_key(|event| event.created_at);
+    Some(
+        newest
+            .map(|event| list_relays(&event, config.nip65_markers))
+            .unwrap_or_default(),
+    )
+}
+
+/// Relays of a relay list with the markers wanted
+fn list_relays(relay_list: &Event, markers: Nip65Markers) -> HashSet<RelayUrl> {
+    relay_list
+        .tags
+        .iter()
+        .filter_map(|tag| match (tag, markers) {
+            (Tag::RelayMetadata(url, None | Some(RelayMetadata::Read)), _)
+            | (Tag::RelayMetadata(url, Some(RelayMetadata::Write)), Nip65Markers::All) => {
+                RelayUrl::parse(&url.to_string()).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Refresh cached relay lists once `max_age` old, from the default relays
+pub fn spawn_refresh(state: State, max_age: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(max_age.min(REFRESH_CHECK));
+        // The first tick is immediate, with nothing cached yet
+        ticks.tick().await;
+
+        loop {
+            ticks.tick().await;
+            let query: Vec<RelayUrl> = state.relays.read().await.iter().cloned().collect();
+            let own = state.own_receipts();
+            state
+                .nip65
+                .refresh(max_age, &query, &state.config, &own)
+                .await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use nostr::{
+        ClientMessage, EventBuilder, EventId, Keys, RelayMessage, Timestamp, UncheckedUrl,
+        UnsignedEvent,
+    };
+
+    use super::*;
+    use crate::decode_zap_req;
+    use crate::published::tests::no_receipts;
+    use crate::relay::tests::{mock_relay_replying, mock_stored_relay, relay_url};
+    use crate::tests::test_invoice;
+
+    /// Relay list of `keys` created at `created_at`
+    fn relay_list(keys: &Keys, relays: &[(&str, Option<RelayMetadata>)], created_at: u64) -> Event {
+        let tags: Vec<Tag> = relays
+            .iter()
+            .map(|(url, metadata)| Tag::RelayMetadata(UncheckedUrl::from(*url), metadata.clone()))
+            .collect();
+        let (pubkey, created_at, kind) = (
+            keys.public_key(),
+            Timestamp::from(created_at),
+            Kind::RelayList,
+        );
+        UnsignedEvent {
+            id: EventId::new(&pubkey, created_at, &kind, &tags, ""),
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content: String::new(),
+        }
+        .sign(keys)
+        .unwrap()
+    }
+
+    /// The recipient's cached relays
+    fn cached(nip65: &Nip65Relays, recipient: XOnlyPublicKey) -> Option<HashSet<RelayUrl>> {
+        let cache = nip65.cache.lock().unwrap();
+        cache.lists.get(&recipient).map(|list| list.relays.clone())
+    }
+
+    #[test]
+    fn test_list_relays() {
+        let list = relay_list(
+            &Keys::generate(),
+            &[
+                ("wss://both.example", None),
+                ("wss://read.example", Some(RelayMetadata::Read)),
+                ("wss://write.example", Some(RelayMetadata::Write)),
+                ("not a relay", None),
+            ],
+            1000,
+        );
+
+        assert_eq!(
+            list_relays(&list, Nip65Markers::Read),
+            HashSet::from([
+                relay_url("wss://both.example"),
+                relay_url("wss://read.example")
+            ])
+        );
+        assert_eq!(
+            list_relays(&list, Nip65Markers::All),
+            HashSet::from([
+                relay_url("wss://both.example"),
+                relay_url("wss://read.example"),
+                relay_url("wss://write.example")
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receipt_sent_to_author_relays() {
+        let (outbox, received) = mock_relay_replying(None, 1, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let author = Keys::generate();
+        let stored = Arc::new(Mutex::new(vec![relay_list(
+            &author,
+            &[(outbox.as_str(), Some(RelayMetadata::Write))],
+            1000,
+        )]));
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([mock_stored_relay(stored)]),
+            Config {
+                nip65_relays: true,
+                nip65_markers: Nip65Markers::All,
+                ..Config::default()
+            },
+        );
+        let zap_request = EventBuilder::new(
+            Kind::ZapRequest,
+            "",
+            &[
+                Tag::PubKey(author.public_key(), None),
+                Tag::Event(EventId::all_zeros(), None, None),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap()
+        .as_json();
+
+        let zap = || {
+            crate::process_zap(
+                &state,
+                decode_zap_req(&zap_request).unwrap(),
+                test_invoice(&zap_request),
+            )
+        };
+
+        // Not cached yet, so the first zap doesn't wait for the list to be fetched
+        zap().await.unwrap();
+        let recipient = author.public_key();
+        while cached(&state.nip65, recipient).is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let id = zap().await.unwrap();
+        let msg = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        let ClientMessage::Event(note) = ClientMessage::from_json(msg).unwrap() else {
+            panic!("Expected an event");
+        };
+        assert_eq!(note.id, id);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_follows_list() {
+        let recipient = Keys::generate();
+        let stored = Arc::new(Mutex::new(vec![relay_list(
+            &recipient,
+            &[("wss://a.example", None), ("wss://b.example", None)],
+            2000,
+        )]));
+        let query = [mock_stored_relay(stored.clone())];
+        let config = Config::default();
+        let nip65 = Nip65Relays::default();
+
+        let relays = nip65
+            .update(recipient.public_key(), &query, &config, &no_receipts())
+            .await;
+        assert_eq!(
+            relays,
+            HashSet::from([relay_url("wss://a.example"), relay_url("wss://b.example")])
+        );
+
+        // The recipient drops a relay and adds another, an older list lingering
+        *stored.lock().unwrap() = vec![
+            relay_list(
+                &recipient,
+                &[("wss://b.example", None), ("wss://c.example", None)],
+                3000,
+            ),
+            relay_list(&recipient, &[("wss://a.example", None)], 1000),
+        ];
+
+        // Cached until refreshed
+        nip65
+            .refresh(Duration::from_secs(60), &query, &config, &no_receipts())
+            .await;
+        assert_eq!(cached(&nip65, recipient.public_key()), Some(relays));
+
+        nip65
+            .refresh(Duration::ZERO, &query, &config, &no_receipts())
+            .await;
+        assert_eq!(
+            cached(&nip65, recipient.public_key()),
+            Some(HashSet::from([
+                relay_url("wss://b.example"),
+                relay_url("wss://c.example")
+            ]))
+        );
+
+        // A relay that can't be reached keeps the list
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let down = [relay_url(&format!("ws://127.0.0.1:{port}"))];
+        nip65
+            .refresh(Duration::ZERO, &down, &config, &no_receipts())
+            .await;
+        assert_eq!(
+            cached(&nip65, recipient.public_key()),
+            Some(HashSet::from([
+                relay_url("wss://b.example"),
+                relay_url("wss://c.example")
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_forgotten() {
+        let recipients: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+        let stored = Arc::new(Mutex::new(
+            recipients
+                .iter()
+                .map(|keys| relay_list(keys, &[("wss://a.example", None)], 1000))
+                .collect(),
+        ));
+        let query = vec![mock_stored_relay(stored)];
+        let config = Arc::new(Config::default());
+        let nip65 = Arc::new(Nip65Relays::new(2));
+        let [a, b, c] = [0, 1, 2].map(|i| recipients[i].public_key());
+
+        nip65.update(a, &query, &config, &no_receipts()).await;
+        nip65.update(b, &query, &config, &no_receipts()).await;
+        // a is zapped again, so b is the one forgotten for c
+        assert_eq!(
+            nip65
+                .relays(a, query.clone(), &config, &no_receipts())
+                .len(),
+            1
+        );
+        nip65.update(c, &query, &config, &no_receipts()).await;
+
+        assert!(cached(&nip65, a).is_some());
+        assert!(cached(&nip65, b).is_none());
+        assert!(cached(&nip65, c).is_some());
+    }
+}
@@ -0,0 +1,85 @@
+//! CLN nodes paid invoices are taken from
+//!
+//! The zapper always reads the node it runs on. `clnzapper_extra_rpc_sockets` adds
+//! other nodes, each read by its own invoice stream and resumed from its own pay
+//! index file, all feeding the same broadcast pipeline.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use crate::index_batch::{Batching, IndexWrites};
+use crate::window::IndexWindow;
+
+/// A node and how far its invoices have been read
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// Path to the node's rpc socket
+    pub socket: PathBuf,
+    /// File storing the last pay index processed from the node
+    pub pay_index_path: PathBuf,
+    /// Last pay index seen from the node
+    pub last_pay_index: Arc<AtomicU64>,
+    /// Invoices being processed, by `clnzapper_index_after_publish`
+    pub index_window: Arc<IndexWindow>,
+    /// Writes of `pay_index_path`, batched by `clnzapper_index_batch_size`
+    pub index_writes: Arc<IndexWrites>,
+}
+
+impl Node {
+    pub fn new(socket: PathBuf, pay_index_path: PathBuf) -> Self {
+        Self {
+            socket,
+            pay_index_path,
+            last_pay_index: Arc::new(AtomicU64::new(0)),
+            index_window: Arc::new(IndexWindow::default()),
+            index_writes: Arc::new(IndexWrites::default()),
+        }
+    }
+
+    /// The node with its pay index writes batched
+    pub fn batched(self, batching: Option<Batching>) -> Self {
+        Self {
+            index_writes: Arc::new(IndexWrites::new(batching)),
+            ..self
+        }
+    }
+
+    /// Write the pay index file, or hold it for the batch
+    pub fn write_pay_index(&self, idx: u64) {
+        self.index_writes.write(&self.pay_index_path, idx);
+    }
+}
+
+/// Pay index file of an extra node: next to our own node's, named after its socket
+pub fn extra_index_path(own_index_path: &Path, socket: &Path) -> PathBuf {
+    let socket: String = socket
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut file_name = own_index_path
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    file_name.push(format!("-{socket}"));
+    own_index_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extra_index_path() {
+        let own = Path::new("/data/cln-zapper/last_pay_index");
+        assert_eq!(
+            extra_index_path(own, Path::new("/home/bob/.lightning/bitcoin/lightning-rpc")),
+            Path::new("/data/cln-zapper/last_pay_index-_home_bob__lightning_bitcoin_lightning_rpc")
+        );
+        assert_ne!(
+            extra_index_path(own, Path::new("/a/lightning-rpc")),
+            extra_index_path(own, Path::new("/b/lightning-rpc"))
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Receipts streamed as newline delimited JSON, by `clnzapper_receipt_output`
+//!
+//! Each receipt is written as its full signed event on one line, for piping into
+//! other nostr tooling as zaps come in. The target is a file, a named pipe, or an
+//! inherited file descriptor given as `fd:N`. The plugin's stdin and stdout carry
+//! the CLN plugin protocol, so they are refused as targets.
+//!
+//! Writing happens on its own thread: opening a named pipe blocks until a reader
+//! shows up, and a slow reader must never hold up receipts. Receipts that don't fit
+//! in the queue are dropped with a warning.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use nostr::Event;
+
+/// Receipts queued for the writer before new ones are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// Where receipts are streamed to
+#[derive(Clone, Debug)]
+pub struct ReceiptOutput {
+    path: PathBuf,
+    queue: SyncSender<String>,
+}
+
+impl ReceiptOutput {
+    /// Check `target` and start the writer, which opens it on the first receipt
+    pub fn open(target: &str) -> Result<Self> {
+        let path = target_path(target)?;
+        if is_protocol_stream(&path) {
+            return Err(anyhow!(
+                "Receipt output {target} is the plugin's stdin or stdout, which carry the CLN plugin protocol"
+            ));
+        }
+
+        let (queue, lines) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        let writer_path = path.clone();
+        thread::spawn(move || {
+            let mut out: Option<File> = None;
+            for line in lines {
+                let file = match &mut out {
+                    Some(file) => file,
+                    None => match open_append(&writer_path) {
+                        Ok(file) => out.insert(file),
+                        Err(err) => {
+                            warn!(
+                                "Error while opening receipt output {}: {err}",
+                                writer_path.display()
+                            );
+                            continue;
+                        }
+                    },
+                };
+                if let Err(err) = file.write_all(line.as_bytes()) {
+                    warn!(
+                        "Error while writing to receipt output {}: {err}",
+                        writer_path.display()
+                    );
+                    // Reopened for the next receipt, a named pipe may have a new reader by then
+                    out = None;
+                }
+            }
+        });
+
+        Ok(Self { path, queue })
+    }
+
+    /// Queue the receipt to be written
+    pub fn write(&self, zap_note: &Event) {
+        let line = format!("{}\n", zap_note.as_json());
+        match self.queue.try_send(line) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!(
+                "Receipt output {} is not keeping up, dropping {}",
+                self.path.display(),
+                zap_note.id.to_hex()
+            ),
+            Err(TrySendError::Disconnected(_)) => warn!(
+                "Receipt output {} writer stopped, dropping {}",
+                self.path.display(),
+                zap_note.id.to_hex()
+            ),
+        }
+    }
+}
+
+/// Path `target` is opened at, `fd:N` being the inherited descriptor `N`
+fn target_path(target: &str) -> Result<PathBuf> {
+    if target.is_empty() {
+        return Err(anyhow!("Receipt output path is empty"));
+    }
+    let Some(fd) = target.strip_prefix("fd:") else {
+        return Ok(PathBuf::from(target));
+    };
+
+    let fd: u32 = fd
+        .parse()
+        .map_err(|_| anyhow!("Invalid receipt output {target}, expected fd:N"))?;
+    if fd <= 2 {
+        return Err(anyhow!(
+            "Receipt output fd {fd} is a standard stream, which the CLN plugin protocol and logging use"
+        ));
+    }
+    Ok(PathBuf::from(format!("/dev/fd/{fd}")))
+}
+
+/// Whether `path` is the same file as our stdin or stdout, however it is written
+fn is_protocol_stream(path: &Path) -> bool {
+    let Ok(target) = std::fs::metadata(path) else {
+        // Doesn't exist yet, so it will be created as a new file
+        return false;
+    };
+    let stdin = io::stdin().as_fd().try_clone_to_owned();
+    let stdout = io::stdout().as_fd().try_clone_to_owned();
+
+    [stdin, stdout]
+        .into_iter()
+        .filter_map(|fd| File::from(fd.ok()?).metadata().ok())
+        .any(|stream| stream.dev() == target.dev() && stream.ino() == target.ino())
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use nostr::{EventBuilder, Keys, Kind};
+
+    use super::*;
+
+    #[test]
+    fn test_receipts_written_as_ndjson() {
+        std::fs::create_dir_all("./test").unwrap();
+        let path = "./test/receipts.ndjson";
+        let _ = std::fs::remove_file(path);
+
+        let keys = Keys::generate();
+        let zap_notes: Vec<Event> = (0..2)
+            .map(|i| {
+                EventBuilder::new(Kind::ZapReceipt, format!("{i}"), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+
+        let output = ReceiptOutput::open(path).unwrap();
+        for zap_note in &zap_notes {
+            output.write(zap_note);
+        }
+
+        let start = Instant::now();
+        let written = loop {
+            let written = std::fs::read_to_string(path).unwrap_or_default();
+            if written.lines().count() == 2 || start.elapsed() > Duration::from_secs(5) {
+                break written;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let lines: Vec<Event> = written
+            .lines()
+            .map(|line| Event::from_json(line).unwrap())
+            .collect();
+        assert_eq!(lines, zap_notes);
+    }
+
+    #[test]
+    fn test_protocol_streams_refused() {
+        assert!(ReceiptOutput::open("fd:1").is_err());
+        assert!(ReceiptOutput::open("fd:0").is_err());
+        assert!(ReceiptOutput::open("fd:stdout").is_err());
+        assert!(ReceiptOutput::open("").is_err());
+        assert_eq!(target_path("fd:5").unwrap(), Path::new("/dev/fd/5"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+/// Whether publishing is paused by `zapper-pause`
+///
+/// Paused zaps are queued, not skipped: the processing loop stops reading the
+/// invoice stream until `zapper-resume`, then carries on from where it stopped. An
+/// invoice the stream was already waiting on is held before the pay index moves
+/// past it, so a restart while paused reads it again.
+#[derive(Debug, Default)]
+pub struct Pause {
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl Pause {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once not paused
+    pub async fn wait_resumed(&self) {
+        loop {
+            // Created before the check so a resume in between is not missed
+            let resumed = self.resumed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        let pause = Arc::new(Pause::default());
+        pause.wait_resumed().await;
+
+        pause.pause();
+        let waiting = tokio::spawn({
+            let pause = pause.clone();
+            async move { pause.wait_resumed().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        pause.resume();
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Private and anonymous zaps, marked by NIP-57's anon tag
+//!
+//! Both are signed with a throwaway key instead of the payer's. An anonymous zap's
+//! anon tag is empty. A private zap's carries the payer's own zap request, a kind
+//! 9733 event with their comment, encrypted by NIP-04 between the throwaway key and
+//! the recipient as `pzap1<ciphertext>_iv1<iv>`, each bech32. Receipts for either
+//! are the same as for public zaps. With `clnzapper_decrypt_private_zaps` the zapper
+//! decrypts private zaps sent to its own receipt key, logging who sent them and
+//! their filtered comment.
+
+use anyhow::{anyhow, Result};
+use base64::engine::{general_purpose, Engine};
+use nostr::bech32::{self, FromBase32};
+use nostr::nips::nip04;
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::{Event, Keys, Tag};
+use serde::Serialize;
+
+/// Kind of the encrypted zap request inside a private zap
+pub const PRIVATE_ZAP_REQUEST_KIND: u64 = 9733;
+
+/// Who a zap request shows as its sender
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ZapPrivacy {
+    /// Signed by the payer
+    Public,
+    /// Signed by a throwaway key, with no sender inside
+    Anonymous,
+    /// Signed by a throwaway key, with the payer's zap request encrypted in `msg`
+    Private { msg: String },
+}
+
+impl ZapPrivacy {
+    /// Privacy of a zap request with these tags
+    pub fn from_tags(tags: &[Tag]) -> Self {
+        let anon = tags.iter().find_map(|tag| match tag {
+            Tag::Anon { msg } => Some(msg.clone()),
+            _ => None,
+        });
+        match anon {
+            None => Self::Public,
+            Some(Some(msg)) if !msg.is_empty() => Self::Private { msg },
+            Some(_) => Self::Anonymous,
+        }
+    }
+}
+
+/// Bytes of a bech32 string with the human readable part `hrp`
+fn bech32_bytes(s: &str, hrp: &str) -> Result<Vec<u8>> {
+    let (found, data, _) = bech32::decode(s)?;
+    if found != hrp {
+        return Err(anyhow!("Expected {hrp} but found {found}"));
+    }
+    Ok(Vec::<u8>::from_base32(&data)?)
+}
+
+/// The payer's zap request encrypted in a private zap's `msg`, sent to `recipient`
+/// from `pubkey`, the throwaway key the outer zap request is signed with
+pub fn decrypt(msg: &str, recipient: &Keys, pubkey: &XOnlyPublicKey) -> Result<Event> {
+    let (ciphertext, iv) = msg
+        .split_once('_')
+        .ok_or_else(|| anyhow!("Private zap message is not pzap1.._iv1.."))?;
+    let ciphertext = bech32_bytes(ciphertext, "pzap")?;
+    let iv = bech32_bytes(iv, "iv")?;
+    // nostr's decryption panics on an iv that isn't a block long
+    if iv.len() != 16 {
+        return Err(anyhow!("Private zap iv is {} bytes, not 16", iv.len()));
+    }
+
+    // Decrypted by nostr's NIP-04, which takes the message as base64
+    let content = format!(
+        "{}?iv={}",
+        general_purpose::STANDARD.encode(ciphertext),
+        general_purpose::STANDARD.encode(iv)
+    );
+    let plaintext = nip04::decrypt(&recipient.secret_key()?, pubkey, content)?;
+    let zap_request = Event::from_json(plaintext)?;
+    zap_request.verify()?;
+    if zap_request.kind.as_u64() != PRIVATE_ZAP_REQUEST_KIND {
+        return Err(anyhow!(
+            "Private zap request is kind {}, not {PRIVATE_ZAP_REQUEST_KIND}",
+            zap_request.kind.as_u64()
+        ));
+    }
+
+    Ok(zap_request)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use nostr::bech32::{ToBase32, Variant};
+    use nostr::{EventBuilder, Kind};
+
+    use super::*;
+
+    /// Anon tag of a private zap from `payer` to `recipient` with `comment`, and the
+    /// throwaway keys to sign the outer zap request with
+    pub fn private_zap(payer: &Keys, recipient: XOnlyPublicKey, comment: &str) -> (Tag, Keys) {
+        let zap_request = EventBuilder::new(
+            Kind::from(PRIVATE_ZAP_REQUEST_KIND),
+            comment,
+            &[Tag::PubKey(recipient, None)],
+        )
+        .to_event(payer)
+        .unwrap();
+        let throwaway = Keys::generate();
+        let content = nip04::encrypt(
+            &throwaway.secret_key().unwrap(),
+            &recipient,
+            zap_request.as_json(),
+        )
+        .unwrap();
+        let (ciphertext, iv) = content.split_once("?iv=").unwrap();
+        let ciphertext = general_purpose::STANDARD.decode(ciphertext).unwrap();
+        let iv = general_purpose::STANDARD.decode(iv).unwrap();
+        let msg = format!(
+            "{}_{}",
+            bech32::encode("pzap", ciphertext.to_base32(), Variant::Bech32).unwrap(),
+            bech32::encode("iv", iv.to_base32(), Variant::Bech32).unwrap()
+        );
+        (Tag::Anon { msg: Some(msg) }, throwaway)
+    }
+
+    #[test]
+    fn test_from_tags() {
+        let p = Tag::PubKey(Keys::generate().public_key(), None);
+        assert_eq!(
+            ZapPrivacy::from_tags(std::slice::from_ref(&p)),
+            ZapPrivacy::Public
+        );
+        assert_eq!(
+            ZapPrivacy::from_tags(&[p.clone(), Tag::Anon { msg: None }]),
+            ZapPrivacy::Anonymous
+        );
+        assert_eq!(
+            ZapPrivacy::from_tags(&[
+                p,
+                Tag::Anon {
+                    msg: Some("pzap1x_iv1y".to_string())
+                }
+            ]),
+            ZapPrivacy::Private {
+                msg: "pzap1x_iv1y".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let (payer, recipient) = (Keys::generate(), Keys::generate());
+        let (Tag::Anon { msg: Some(msg) }, throwaway) =
+            private_zap(&payer, recipient.public_key(), "Great post")
+        else {
+            panic!("Expected an anon tag");
+        };
+
+        let zap_request = decrypt(&msg, &recipient, &throwaway.public_key()).unwrap();
+        assert_eq!(zap_request.pubkey, payer.public_key());
+        assert_eq!(zap_request.content, "Great post");
+
+        // Only the recipient can read it
+        assert!(decrypt(&msg, &Keys::generate(), &throwaway.public_key()).is_err());
+        assert!(decrypt("pzap1qqqq", &recipient, &throwaway.public_key()).is_err());
+        let short_iv = bech32::encode("iv", [9; 8].to_base32(), Variant::Bech32).unwrap();
+        let (ciphertext, _) = msg.split_once('_').unwrap();
+        assert!(decrypt(
+            &format!("{ciphertext}_{short_iv}"),
+            &recipient,
+            &throwaway.public_key()
+        )
+        .is_err());
+        let (ciphertext, iv) = msg.split_once('_').unwrap();
+        assert!(decrypt(
+            &format!("{iv}_{ciphertext}"),
+            &recipient,
+            &throwaway.public_key()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,364 @@
+//! Ids of the receipts we published, optionally kept on disk by `clnzapper_published_log`
+//!
+//! The log is a file of fixed width records, the unix time a receipt was published
+//! as a big endian u64 then its 32 byte id, so it's 40 bytes a receipt rather than a
+//! JSON line of around 100. New ids are appended, and once the file holds twice the
+//! ids kept it is compacted down to those the retention keeps, as it is on startup:
+//! the last `clnzapper_published_keep`, and only those from the last
+//! `clnzapper_published_keep_days` days if set. Memory and disk stay bounded by the same
+//! number of ids.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::{Event, EventId, Kind, Timestamp};
+
+/// Most receipt ids remembered when `clnzapper_published_keep` is not set
+pub const CAPACITY: usize = 10_000;
+
+/// Bytes of a record in the log
+const RECORD_LEN: usize = 8 + 32;
+
+/// Which ids are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    /// Most ids kept, the newest
+    pub keep: usize,
+    /// Seconds after which an id is dropped, none to keep ids of any age
+    pub max_age: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep: CAPACITY,
+            max_age: None,
+        }
+    }
+}
+
+impl Retention {
+    /// The records of `records`, oldest first, this keeps at `now`
+    fn apply(&self, records: Vec<(u64, EventId)>, now: u64) -> Vec<(u64, EventId)> {
+        let mut records: Vec<(u64, EventId)> = records
+            .into_iter()
+            .filter(|(published_at, _)| {
+                self.max_age
+                    .is_none_or(|max_age| now.saturating_sub(*published_at) <= max_age)
+            })
+            .collect();
+        let excess = records.len().saturating_sub(self.keep);
+        records.drain(..excess);
+        records
+    }
+}
+
+/// Append only file of published receipt ids
+#[derive(Debug)]
+struct IdLog {
+    path: PathBuf,
+    retention: Retention,
+    /// Records in the file
+    records: usize,
+}
+
+impl IdLog {
+    /// Open the log, compacting it, and return the ids it keeps, oldest first
+    fn open(path: &Path, retention: Retention, now: u64) -> Result<(Self, Vec<EventId>)> {
+        let mut log = Self {
+            path: path.to_path_buf(),
+            retention,
+            records: 0,
+        };
+        let kept = log.compact(now)?;
+        Ok((log, kept.into_iter().map(|(_, id)| id).collect()))
+    }
+
+    fn read(&self) -> Result<Vec<(u64, EventId)>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(anyhow!("Could not read {}: {err}", self.path.display())),
+        };
+        // A partial record at the end is from a crash mid append, and dropped
+        bytes
+            .chunks_exact(RECORD_LEN)
+            .map(|record| {
+                let (published_at, id) = record.split_at(8);
+                Ok((
+                    u64::from_be_bytes(published_at.try_into().expect("8 bytes")),
+                    EventId::from_slice(id)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Rewrite the log with only the records the retention keeps, returning them
+    fn compact(&mut self, now: u64) -> Result<Vec<(u64, EventId)>> {
+        let records = self.read()?;
+        let read = records.len();
+        let kept = self.retention.apply(records, now);
+
+        let mut bytes = Vec::with_capacity(kept.len() * RECORD_LEN);
+        for (published_at, id) in &kept {
+            bytes.extend_from_slice(&published_at.to_be_bytes());
+            bytes.extend_from_slice(id.as_bytes());
+        }
+        // Write then rename so a crash never loses the log
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &self.path)?;
+
+        if kept.len() < read {
+            info!(
+                "Compacted {} from {read} to {} receipt ids",
+                self.path.display(),
+                kept.len()
+            );
+        }
+        self.records = kept.len();
+        Ok(kept)
+    }
+
+    fn append(&mut self, id: EventId, now: u64) -> Result<()> {
+        let mut record = [0; RECORD_LEN];
+        record[..8].copy_from_slice(&now.to_be_bytes());
+        record[8..].copy_from_slice(id.as_bytes());
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&record)?;
+        self.records += 1;
+
+        if self.records >= self.retention.keep.max(1) * 2 {
+            self.compact(now)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ids of the receipts we recently published
+///
+/// Anything reading events back from relays checks here first, so our own
+/// receipts are never taken as input and published again in a loop. Only delivery
+/// verification, which asks a relay for one receipt of ours by id, doesn't.
+#[derive(Debug)]
+pub struct PublishedReceipts {
+    /// Most ids remembered, the oldest are forgotten first
+    capacity: usize,
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+    /// Where the ids are also kept, by `clnzapper_published_log`
+    log: Option<IdLog>,
+}
+
+impl PublishedReceipts {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            log: None,
+        }
+    }
+
+    /// Remember the ids kept in the log at `path`, and log new ones to it
+    pub fn load(path: &Path, retention: Retention) -> Result<Self> {
+        let (log, ids) = IdLog::open(path, retention, Timestamp::now().as_u64())?;
+        let mut published = Self::new(retention.keep);
+        for id in ids {
+            published.remember(id);
+        }
+        published.log = Some(log);
+        Ok(published)
+    }
+
+    /// Remember a receipt we published
+    pub fn insert(&mut self, id: EventId) {
+        self.insert_at(id, Timestamp::now().as_u64());
+    }
+
+    fn insert_at(&mut self, id: EventId, now: u64) {
+        if !self.remember(id) {
+            return;
+        }
+        if let Some(log) = &mut self.log {
+            if let Err(err) = log.append(id, now) {
+                warn!("Could not log receipt {}: {err}", id.to_hex());
+            }
+        }
+    }
+
+    /// Remember the id in memory, returning whether it's new
+    fn remember(&mut self, id: EventId) -> bool {
+        if self.capacity == 0 || !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &EventId) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Whether an event seen on a relay is a receipt of ours, remembered or signed by `pubkey`
+    pub fn is_own(&self, event: &Event, pubkey: &XOnlyPublicKey) -> bool {
+        self.contains(&event.id) || (event.kind == Kind::ZapReceipt && &event.pubkey == pubkey)
+    }
+}
+
+/// The receipts we published and the key signing new ones, for the blocking relay
+/// reads to check fetched events against
+#[derive(Clone, Debug)]
+pub struct OwnReceipts {
+    pub published: Arc<Mutex<PublishedReceipts>>,
+    pub pubkey: XOnlyPublicKey,
+}
+
+impl OwnReceipts {
+    pub fn is_own(&self, event: &Event) -> bool {
+        self.published
+            .lock()
+            .expect("Lock not poisoned")
+            .is_own(event, &self.pubkey)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use nostr::hashes::{sha256, Hash};
+    use nostr::{EventBuilder, Keys};
+
+    use super::*;
+
+    /// Receipts of a fresh key that published none yet
+    pub fn no_receipts() -> OwnReceipts {
+        OwnReceipts {
+            published: Arc::new(Mutex::new(PublishedReceipts::new(CAPACITY))),
+            pubkey: Keys::generate().public_key(),
+        }
+    }
+
+    fn receipt(keys: &Keys, content: &str) -> Event {
+        EventBuilder::new(Kind::ZapReceipt, content, &[])
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_published_receipts() {
+        let keys = Keys::generate();
+        let mut published = PublishedReceipts::new(2);
+
+        let ours = receipt(&keys, "");
+        let theirs = receipt(&Keys::generate(), "");
+        assert!(published.is_own(&ours, &keys.public_key()));
+        assert!(!published.is_own(&theirs, &keys.public_key()));
+
+        // e.g. a receipt published under a previous key
+        published.insert(theirs.id);
+        published.insert(theirs.id);
+        assert_eq!(published.order.len(), 1);
+        assert!(published.is_own(&theirs, &keys.public_key()));
+
+        // Oldest forgotten first
+        let (a, b) = (receipt(&keys, "a").id, receipt(&keys, "b").id);
+        published.insert(a);
+        published.insert(b);
+        assert!(!published.contains(&theirs.id));
+        assert!(published.contains(&a) && published.contains(&b));
+    }
+
+    fn id(i: u32) -> EventId {
+        EventId::from(sha256::Hash::hash(&i.to_be_bytes()))
+    }
+
+    #[test]
+    fn test_log_compacted() {
+        let path = std::env::temp_dir().join(format!("clnzapper-published-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let retention = Retention {
+            keep: 100,
+            max_age: None,
+        };
+
+        let mut published = PublishedReceipts::load(&path, retention).unwrap();
+        for i in 0..1_000 {
+            published.insert_at(id(i), 1_000);
+            assert!(fs::metadata(&path).unwrap().len() < (2 * 100 * RECORD_LEN) as u64);
+        }
+        // Compacted back to 100 every time it reaches 200, the last time on the 1000th
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (100 * RECORD_LEN) as u64
+        );
+        assert!(published.ids.contains(&id(999)) && !published.ids.contains(&id(899)));
+
+        // Kept across a restart
+        let published = PublishedReceipts::load(&path, retention).unwrap();
+        assert_eq!(published.order.len(), 100);
+        assert!(published.ids.contains(&id(900)) && published.ids.contains(&id(999)));
+        assert!(!published.ids.contains(&id(899)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_max_age() {
+        let path =
+            std::env::temp_dir().join(format!("clnzapper-published-age-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let retention = Retention {
+            keep: 10,
+            max_age: Some(60),
+        };
+        let (mut log, ids) = IdLog::open(&path, retention, 0).unwrap();
+        assert!(ids.is_empty());
+
+        log.append(id(0), 100).unwrap();
+        log.append(id(1), 200).unwrap();
+        // A torn record from a crash mid append
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[0; 12])
+            .unwrap();
+
+        let (_, ids) = IdLog::open(&path, retention, 250).unwrap();
+        assert_eq!(ids, vec![id(1)]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), RECORD_LEN as u64);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_own_across_restarts() {
+        let path =
+            std::env::temp_dir().join(format!("clnzapper-published-own-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        // Published under a key since rotated away from
+        let old = receipt(&Keys::generate(), "");
+        let pubkey = Keys::generate().public_key();
+
+        PublishedReceipts::load(&path, Retention::default())
+            .unwrap()
+            .insert(old.id);
+        let restarted = PublishedReceipts::load(&path, Retention::default()).unwrap();
+        assert!(restarted.is_own(&old, &pubkey));
+        // Without the log, it is taken for someone else's
+        assert!(!PublishedReceipts::new(CAPACITY).is_own(&old, &pubkey));
+        fs::remove_file(&path).unwrap();
+    }
+}
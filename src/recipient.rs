@@ -0,0 +1,248 @@
+//! Checking zap requests are for the expected recipients, by `clnzapper_recipient_pubkeys`
+//!
+//! A receipt vouches, signed with the zapper's key, that the `p` tag of its zap
+//! request was paid. A zap request naming someone the node doesn't take zaps for
+//! is someone spoofing zaps to them through our invoices. Such zaps get no receipt,
+//! and `clnzapper_recipient_mismatch` decides who hears about it:
+//! * `skip` only logs it at trace
+//! * `warn` (the default) logs a warning
+//! * `alert` also publishes an alert note to the `clnzapper_alert_relays`, at most
+//!   one every `ALERT_INTERVAL`, the next counting the mismatches held back
+
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{log, Level};
+use nostr::key::FromPkStr;
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::{Keys, Tag};
+
+use crate::alert;
+use crate::state::State;
+use crate::ZapRequestInfo;
+
+/// Least time between two alerts of mismatches
+const ALERT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Value of `clnzapper_recipient_mismatch`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MismatchAction {
+    Skip,
+    #[default]
+    Warn,
+    Alert,
+}
+
+impl FromStr for MismatchAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "warn" => Ok(Self::Warn),
+            "alert" => Ok(Self::Alert),
+            _ => Err(anyhow!(
+                "Invalid recipient mismatch action {s}, expected skip, warn or alert"
+            )),
+        }
+    }
+}
+
+impl MismatchAction {
+    /// Level a mismatch is logged at
+    pub fn log_level(&self) -> Level {
+        match self {
+            Self::Skip => Level::Trace,
+            Self::Warn | Self::Alert => Level::Warn,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Alerted {
+    /// When the last alert went out
+    last: Option<Instant>,
+    /// Mismatches since then that weren't alerted
+    held: u64,
+}
+
+/// Spaces out mismatch alerts, so a spoofer can't flood the alert relays
+#[derive(Debug)]
+pub struct MismatchAlerts {
+    interval: Duration,
+    alerted: Mutex<Alerted>,
+}
+
+impl Default for MismatchAlerts {
+    fn default() -> Self {
+        Self::new(ALERT_INTERVAL)
+    }
+}
+
+impl MismatchAlerts {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            alerted: Mutex::new(Alerted::default()),
+        }
+    }
+
+    /// Count a mismatch at `now`, returning the mismatches held back since the
+    /// last alert if this one is to be alerted
+    pub fn record(&self, now: Instant) -> Option<u64> {
+        let mut alerted = self.alerted.lock().expect("Lock not poisoned");
+        if alerted
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < self.interval)
+        {
+            alerted.held += 1;
+            return None;
+        }
+        alerted.last = Some(now);
+        Some(std::mem::take(&mut alerted.held))
+    }
+}
+
+/// Parse the comma separated npub or hex `clnzapper_recipient_pubkeys`
+pub fn parse_pubkeys(pubkeys: &str) -> Result<Vec<XOnlyPublicKey>> {
+    pubkeys
+        .split(',')
+        .map(str::trim)
+        .filter(|pubkey| !pubkey.is_empty())
+        .map(|pubkey| {
+            Keys::from_pk_str(pubkey)
+                .map(|keys| keys.public_key())
+                .map_err(|err| anyhow!("Invalid recipient pubkey {pubkey}: {err}"))
+        })
+        .collect()
+}
+
+/// Whether the zap request is for one of the `expected` recipients, any if none are
+pub fn is_expected(expected: &[XOnlyPublicKey], zap: &ZapRequestInfo) -> bool {
+    match &zap.p {
+        Tag::PubKey(pubkey, _) => expected.is_empty() || expected.contains(pubkey),
+        _ => false,
+    }
+}
+
+/// Report a zap request for an unexpected recipient as `clnzapper_recipient_mismatch` says
+pub fn mismatch(state: &State, zap: &ZapRequestInfo, label: &str) {
+    let action = state.config.recipient_mismatch;
+    let recipient = match &zap.p {
+        Tag::PubKey(pubkey, _) => pubkey.to_string(),
+        _ => "none".to_string(),
+    };
+    log!(
+        action.log_level(),
+        "Skipping zap request {} for invoice {label}: recipient {recipient} is not expected",
+        zap.zap_request.id.to_hex()
+    );
+
+    if action != MismatchAction::Alert {
+        return;
+    }
+    if let Some(held) = state.config.mismatch_alerts.record(Instant::now()) {
+        let mut content = format!(
+            "cln-zapper alert: invoice {label} paid a zap request for unexpected recipient {recipient}"
+        );
+        if held > 0 {
+            content.push_str(&format!(", and {held} more since the last alert"));
+        }
+        alert::publish(
+            content,
+            &state.keys.current(),
+            state.config.alert_relays.clone(),
+            &state.config,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use nostr::ClientMessage;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::decode_zap_req;
+    use crate::relay::tests::mock_relay;
+    use crate::tests::{test_keys, ZAP_REQ};
+
+    #[test]
+    fn test_is_expected() {
+        let zap = decode_zap_req(ZAP_REQ).unwrap();
+        let recipient = "3036e986c4cef0b2615e6bcf2d6d411310c73872f30c99b19ab7ba58a2df9f98";
+
+        assert!(is_expected(&[], &zap));
+        assert!(is_expected(&parse_pubkeys(recipient).unwrap(), &zap));
+        let other = test_keys().public_key();
+        assert!(!is_expected(&[other], &zap));
+
+        assert_eq!(
+            parse_pubkeys(&format!("{other}, {recipient},"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(parse_pubkeys("npubnope").is_err());
+    }
+
+    #[test]
+    fn test_mismatch_alerts_spaced_out() {
+        let alerts = MismatchAlerts::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert_eq!(alerts.record(start), Some(0));
+        assert_eq!(alerts.record(start + Duration::from_secs(1)), None);
+        assert_eq!(alerts.record(start + Duration::from_secs(59)), None);
+        // The next alert counts those held back
+        assert_eq!(alerts.record(start + Duration::from_secs(60)), Some(2));
+        assert_eq!(alerts.record(start + Duration::from_secs(61)), None);
+        assert_eq!(alerts.record(start + Duration::from_secs(200)), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_mismatch_actions() {
+        let zap = decode_zap_req(ZAP_REQ).unwrap();
+        let (relay, received) = mock_relay(None, 1);
+        let state = |action| {
+            State::new(
+                test_keys(),
+                PathBuf::from("lightning-rpc"),
+                HashSet::new(),
+                Config {
+                    recipient_pubkeys: vec![test_keys().public_key()].into(),
+                    recipient_mismatch: action,
+                    alert_relays: vec![relay.clone()],
+                    ..Config::default()
+                },
+            )
+        };
+
+        assert_eq!(MismatchAction::Skip.log_level(), Level::Trace);
+        assert_eq!(MismatchAction::Warn.log_level(), Level::Warn);
+        assert_eq!(MismatchAction::Alert.log_level(), Level::Warn);
+
+        // Only alert publishes anything
+        for action in [MismatchAction::Skip, MismatchAction::Warn] {
+            mismatch(&state(action), &zap, "invoice-1");
+        }
+        mismatch(&state(MismatchAction::Alert), &zap, "invoice-2");
+
+        let msg =
+            tokio::task::spawn_blocking(move || received.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap()
+                .unwrap();
+        let ClientMessage::Event(note) = ClientMessage::from_json(msg).unwrap() else {
+            panic!("Expected an event");
+        };
+        assert_eq!(note.pubkey, test_keys().public_key());
+        assert!(note.content.contains("invoice-2"));
+    }
+}
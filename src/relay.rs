@@ -0,0 +1,1341 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, warn};
+use nostr::{ClientMessage, Event, EventId, Filter, RelayMessage, SubscriptionId};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::HandshakeError;
+use tungstenite::http::header::WWW_AUTHENTICATE;
+use tungstenite::http::{HeaderName, HeaderValue, StatusCode};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+use crate::config::Config;
+use crate::metrics::{FailureReason, Metrics};
+use crate::published::OwnReceipts;
+use crate::relay_url::RelayUrl;
+
+/// Relays contacted at once per zap when `clnzapper_per_zap_concurrency` is not set
+pub const DEFAULT_PER_ZAP_CONCURRENCY: usize = 8;
+
+/// How long to wait for a relay to acknowledge an event
+const OK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait between connection attempts to relays not yet up at startup
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Extra websocket handshake headers, keyed by relay url
+pub type RelayHeaders = HashMap<RelayUrl, HashMap<String, String>>;
+
+/// Parse the `clnzapper_relay_headers` JSON object of `{relay: {header: value}}`
+pub fn parse_relay_headers(json: &str) -> Result<RelayHeaders> {
+    let relay_headers: HashMap<String, HashMap<String, String>> = serde_json::from_str(json)?;
+
+    relay_headers
+        .into_iter()
+        .map(|(relay, headers)| {
+            // Catch bad headers at startup instead of on every connect
+            for (name, value) in &headers {
+                HeaderName::from_bytes(name.as_bytes())?;
+                HeaderValue::from_str(value)?;
+            }
+            Ok((RelayUrl::parse(&relay)?, headers))
+        })
+        .collect()
+}
+
+/// Sorted, deduplicated relays a zap is published to: the configured ones plus the payer's
+pub fn zap_relays(
+    default_relays: &HashSet<RelayUrl>,
+    payer_relays: &HashSet<RelayUrl>,
+) -> Vec<RelayUrl> {
+    default_relays
+        .iter()
+        .chain(payer_relays)
+        .cloned()
+        .collect::<BTreeSet<RelayUrl>>()
+        .into_iter()
+        .collect()
+}
+
+/// What to do with a relay listed as both `ws://` and `wss://`, set by
+/// `clnzapper_relay_scheme_policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RelaySchemePolicy {
+    /// Publish to both, as they may really be different endpoints
+    #[default]
+    Keep,
+    /// Treat them as one relay and only publish over `wss://`
+    PreferWss,
+}
+
+impl FromStr for RelaySchemePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "prefer-wss" => Ok(Self::PreferWss),
+            _ => Err(anyhow!(
+                "Invalid relay scheme policy {s}, expected keep or prefer-wss"
+            )),
+        }
+    }
+}
+
+/// Apply the scheme policy to the relays of a zap
+///
+/// Two urls are the same relay if only their scheme differs: same host, same
+/// explicit port if any, and same path ignoring a trailing slash. An operator's
+/// `wss://relay.example` and a payer's `ws://relay.example/` collide, while
+/// `ws://relay.example:8080` stays its own relay.
+pub fn apply_scheme_policy(relays: Vec<RelayUrl>, policy: RelaySchemePolicy) -> Vec<RelayUrl> {
+    if policy == RelaySchemePolicy::Keep {
+        return relays;
+    }
+
+    let endpoint = |relay: &RelayUrl| {
+        let url = relay.url();
+        (
+            url.host_str().unwrap_or_default().to_string(),
+            url.port(),
+            url.path().trim_end_matches('/').to_string(),
+        )
+    };
+    let secure: HashSet<_> = relays
+        .iter()
+        .filter(|relay| relay.is_secure())
+        .map(endpoint)
+        .collect();
+
+    relays
+        .into_iter()
+        .filter(|relay| {
+            let insecure_duplicate = !relay.is_secure() && secure.contains(&endpoint(relay));
+            if insecure_duplicate {
+                debug!("Not publishing to {relay}, it is also listed over wss");
+            }
+            !insecure_duplicate
+        })
+        .collect()
+}
+
+/// Keep at most `max` of a zap's sorted relays, the default relays first, warning if any are dropped
+///
+/// Payer relays are only dropped once the default relays are all kept, and each
+/// group is kept in sorted order, so the same zap always keeps the same relays.
+/// The relays kept stay sorted.
+pub fn cap_relays(
+    relays: Vec<RelayUrl>,
+    default_relays: &HashSet<RelayUrl>,
+    max: Option<usize>,
+) -> Vec<RelayUrl> {
+    let Some(max) = max.filter(|max| relays.len() > *max) else {
+        return relays;
+    };
+
+    let (mut kept, payer): (Vec<RelayUrl>, Vec<RelayUrl>) = relays
+        .into_iter()
+        .partition(|relay| default_relays.contains(relay));
+    kept.extend(payer);
+    let dropped = kept.split_off(max.min(kept.len()));
+    warn!(
+        "Zap has {} relays, more than clnzapper_max_total_relays, not publishing to {:?}",
+        kept.len() + dropped.len(),
+        dropped.iter().map(RelayUrl::as_str).collect::<Vec<_>>()
+    );
+    kept.sort();
+    kept
+}
+
+/// Take the operator's inbox relay out of a zap's relays, after any caps and policies
+///
+/// The inbox is sent every receipt by `send_to_inbox`, so it is left out here to not
+/// be sent it twice, or counted as one of the zap's relays.
+pub fn without_inbox(mut relays: Vec<RelayUrl>, inbox: Option<&RelayUrl>) -> Vec<RelayUrl> {
+    if let Some(inbox) = inbox {
+        relays.retain(|relay| relay != inbox);
+    }
+    relays
+}
+
+/// How relays are listed in logs, set by `clnzapper_log_relay_order`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogRelayOrder {
+    #[default]
+    Sorted,
+    /// The configured relays, then the payer's
+    OwnFirst,
+}
+
+impl FromStr for LogRelayOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sorted" => Ok(Self::Sorted),
+            "own-first" => Ok(Self::OwnFirst),
+            _ => Err(anyhow!(
+                "Invalid log relay order {s}, expected sorted or own-first"
+            )),
+        }
+    }
+}
+
+/// The sorted relays of a zap arranged for logging only, broadcasting ignores this order
+pub fn log_order<'a>(
+    relays: &'a [RelayUrl],
+    default_relays: &HashSet<RelayUrl>,
+    order: LogRelayOrder,
+) -> Vec<&'a str> {
+    let mut relays: Vec<&RelayUrl> = relays.iter().collect();
+    if order == LogRelayOrder::OwnFirst {
+        // Stable, so each group stays sorted
+        relays.sort_by_key(|relay| !default_relays.contains(*relay));
+    }
+    relays.into_iter().map(RelayUrl::as_str).collect()
+}
+
+/// Why a relay connection failed, told apart so operators can act on the cause
+#[derive(Debug)]
+enum ConnectError {
+    /// The relay host did not resolve
+    Dns(io::Error),
+    Refused,
+    Timeout,
+    /// Any other failure to open the TCP connection
+    Unreachable(io::Error),
+    Tls(String),
+    /// The relay answered the handshake over HTTP instead of upgrading to a websocket
+    Rejected(StatusCode),
+    /// The relay answered `402 Payment Required`, with its `WWW-Authenticate` challenge if any
+    PaymentRequired(Option<String>),
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns(err) => write!(f, "DNS lookup failed: {err}"),
+            Self::Refused => write!(f, "connection refused"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Unreachable(err) => write!(f, "could not connect: {err}"),
+            Self::Tls(err) => write!(f, "TLS handshake failed: {err}"),
+            Self::Rejected(status) => write!(f, "websocket upgrade rejected with HTTP {status}"),
+            Self::PaymentRequired(challenge) => {
+                write!(f, "relay requires payment (HTTP 402)")?;
+                if let Some(challenge) = challenge {
+                    write!(f, ", challenge {challenge}")?;
+                }
+                write!(f, "; pay it and set the token in clnzapper_relay_auth")
+            }
+            Self::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<tungstenite::Error> for ConnectError {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Http(response)
+                if response.status() == StatusCode::PAYMENT_REQUIRED =>
+            {
+                let challenge = response
+                    .headers()
+                    .get(WWW_AUTHENTICATE)
+                    .and_then(|challenge| challenge.to_str().ok())
+                    .map(str::to_string);
+                Self::PaymentRequired(challenge)
+            }
+            tungstenite::Error::Http(response) => Self::Rejected(response.status()),
+            tungstenite::Error::Tls(err) => Self::Tls(err.to_string()),
+            // rustls reports a failed handshake as invalid data on the stream
+            tungstenite::Error::Io(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Self::Tls(err.to_string())
+            }
+            tungstenite::Error::Io(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Self::Timeout
+            }
+            err => Self::Other(err.into()),
+        }
+    }
+}
+
+impl ConnectError {
+    fn failure_reason(&self) -> FailureReason {
+        match self {
+            Self::Refused => FailureReason::Refused,
+            Self::Timeout => FailureReason::Timeout,
+            Self::Tls(_) => FailureReason::Tls,
+            Self::Rejected(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+            | Self::PaymentRequired(_) => FailureReason::Auth,
+            Self::Rejected(_) => FailureReason::Rejected,
+            Self::Dns(_) | Self::Unreachable(_) | Self::Other(_) => FailureReason::Other,
+        }
+    }
+}
+
+/// Open a websocket to the relay, sending any extra headers configured for it
+///
+/// Each step is done here rather than by `tungstenite::connect`, which folds every
+/// TCP failure into one error, so a failure says which step it was. Redirects are
+/// not followed.
+fn connect(
+    relay: &RelayUrl,
+    headers: Option<&HashMap<String, String>>,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, ConnectError> {
+    let mut request = relay.as_str().into_client_request()?;
+
+    for (name, value) in headers.into_iter().flatten() {
+        request.headers_mut().insert(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| ConnectError::Other(e.into()))?,
+            HeaderValue::from_str(value).map_err(|e| ConnectError::Other(e.into()))?,
+        );
+    }
+
+    let uri = request.uri();
+    let host = uri
+        .host()
+        .ok_or_else(|| ConnectError::Other(anyhow!("No host in {relay}")))?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        });
+    let addrs = (host, port).to_socket_addrs().map_err(ConnectError::Dns)?;
+
+    let mut last_err = None;
+    let stream = addrs
+        .into_iter()
+        .find_map(|addr| {
+            TcpStream::connect_timeout(&addr, OK_TIMEOUT)
+                .map_err(|err| last_err = Some(err))
+                .ok()
+        })
+        .ok_or_else(|| match last_err {
+            Some(err) if err.kind() == io::ErrorKind::ConnectionRefused => ConnectError::Refused,
+            Some(err) if err.kind() == io::ErrorKind::TimedOut => ConnectError::Timeout,
+            Some(err) => ConnectError::Unreachable(err),
+            None => ConnectError::Dns(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} has no addresses"),
+            )),
+        })?;
+
+    // Don't wait forever on a relay that never answers
+    stream
+        .set_read_timeout(Some(OK_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(OK_TIMEOUT)))
+        .and_then(|()| stream.set_nodelay(true))
+        .map_err(ConnectError::Unreachable)?;
+
+    match tungstenite::client_tls(request, stream) {
+        Ok((socket, _)) => Ok(socket),
+        Err(HandshakeError::Failure(err)) => Err(err.into()),
+        // A blocking stream only stops short of the handshake when the read timed out
+        Err(HandshakeError::Interrupted(_)) => Err(ConnectError::Timeout),
+    }
+}
+
+/// What a relay said about an event we sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ack {
+    Accepted,
+    Rejected(String),
+    /// An OK for an event we didn't send
+    WrongId(EventId),
+    /// No OK before the relay closed or timed out
+    Missing,
+}
+
+impl Ack {
+    /// Why the relay did not accept the event, `None` if it did
+    fn failure_reason(&self) -> Option<FailureReason> {
+        match self {
+            Self::Accepted => None,
+            // NIP-42 prefixes, for relays wanting us to authenticate or an allowed key
+            Self::Rejected(reason)
+                if reason.starts_with("auth-required:") || reason.starts_with("restricted:") =>
+            {
+                Some(FailureReason::Auth)
+            }
+            Self::Rejected(_) => Some(FailureReason::Rejected),
+            Self::WrongId(_) => Some(FailureReason::Other),
+            Self::Missing => Some(FailureReason::Timeout),
+        }
+    }
+}
+
+/// Next text frame from the relay, answering pings and skipping other frames
+///
+/// Relays may send pings, and some send binary frames, in between the text frames
+/// nostr messages come in. tungstenite queues the pong for a ping itself; it is
+/// flushed here rather than on the next read, as a relay may wait for it.
+fn read_text(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<String> {
+    loop {
+        match socket.read_message()? {
+            WsMessage::Text(msg) => return Ok(msg),
+            WsMessage::Ping(_) => socket.write_pending()?,
+            WsMessage::Binary(data) => debug!("Ignoring {} byte binary frame", data.len()),
+            WsMessage::Pong(_) | WsMessage::Close(_) | WsMessage::Frame(_) => (),
+        }
+    }
+}
+
+/// Read until the relay sends an OK frame, checking it is for the event we sent
+fn read_ack(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, sent: &EventId) -> Ack {
+    loop {
+        let Ok(msg) = read_text(socket) else {
+            return Ack::Missing;
+        };
+
+        match RelayMessage::from_json(&msg) {
+            Ok(RelayMessage::Ok { event_id, .. }) if &event_id != sent => {
+                return Ack::WrongId(event_id)
+            }
+            Ok(RelayMessage::Ok { status: true, .. }) => return Ack::Accepted,
+            // The relay already stores the event, as is common on republish
+            Ok(RelayMessage::Ok { message, .. }) if is_duplicate(&message) => {
+                debug!("Relay already has the event: {message}");
+                return Ack::Accepted;
+            }
+            Ok(RelayMessage::Ok { message, .. }) => return Ack::Rejected(message),
+            Ok(RelayMessage::Notice { message }) => debug!("Relay notice: {message}"),
+            // NIP-42 isn't supported, a relay requiring it rejects the event next
+            Ok(RelayMessage::Auth { .. }) => debug!("Relay asked to authenticate, not supported"),
+            _ => (),
+        }
+    }
+}
+
+/// Whether an OK message says the relay already has the event, by NIP-01's `duplicate:` prefix
+fn is_duplicate(message: &str) -> bool {
+    message.trim_start().starts_with("duplicate:")
+}
+
+/// Ask the relay for the event back, true if it returns it before the end of stored events
+fn read_back(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, id: &EventId) -> Result<bool> {
+    let subscription_id = SubscriptionId::generate();
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![Filter::new().id(id.to_hex())]);
+    socket.write_message(WsMessage::Text(req.as_json()))?;
+
+    let found = loop {
+        let msg = read_text(socket)?;
+
+        match RelayMessage::from_json(&msg) {
+            Ok(RelayMessage::Event {
+                subscription_id: sub,
+                event,
+            }) if sub == subscription_id && &event.id == id => break event.verify().is_ok(),
+            Ok(RelayMessage::EndOfStoredEvents(sub)) if sub == subscription_id => break false,
+            _ => (),
+        }
+    };
+
+    let close = ClientMessage::close(subscription_id);
+    socket.write_message(WsMessage::Text(close.as_json())).ok();
+
+    Ok(found)
+}
+
+/// Verified events the relay has stored matching the filter, apart from our own receipts
+pub fn fetch_events(
+    relay: &RelayUrl,
+    headers: Option<&HashMap<String, String>>,
+    filter: Filter,
+    own: &OwnReceipts,
+) -> Result<Vec<Event>> {
+    let mut socket = connect(relay, headers).map_err(|err| anyhow!("{err}"))?;
+    let subscription_id = SubscriptionId::generate();
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![filter]);
+    socket.write_message(WsMessage::Text(req.as_json()))?;
+
+    let mut events = vec![];
+    loop {
+        let msg = read_text(&mut socket)?;
+
+        match RelayMessage::from_json(&msg) {
+            Ok(RelayMessage::Event {
+                subscription_id: sub,
+                event,
+            }) if sub == subscription_id => match event.verify() {
+                Ok(()) if own.is_own(&event) => {
+                    debug!("Ignoring our own receipt {} from {relay}", event.id)
+                }
+                Ok(()) => events.push(*event),
+                Err(err) => debug!("Ignoring invalid event {} from {relay}: {err}", event.id),
+            },
+            Ok(RelayMessage::EndOfStoredEvents(sub)) if sub == subscription_id => break,
+            _ => (),
+        }
+    }
+
+    let close = ClientMessage::close(subscription_id);
+    socket.write_message(WsMessage::Text(close.as_json())).ok();
+    socket.close(None).ok();
+
+    Ok(events)
+}
+
+/// Whether any of the relays accepts a websocket connection within `timeout`
+pub async fn any_relay_up(
+    relays: &[RelayUrl],
+    relay_headers: &RelayHeaders,
+    timeout: Duration,
+) -> bool {
+    let mut attempts: FuturesUnordered<_> = relays
+        .iter()
+        .cloned()
+        .map(|relay| {
+            let headers = relay_headers.get(&relay).cloned();
+            tokio::task::spawn_blocking(move || match connect(&relay, headers.as_ref()) {
+                Ok(mut socket) => {
+                    socket.close(None).ok();
+                    true
+                }
+                Err(err) => {
+                    debug!("{relay} is not reachable: {err}");
+                    false
+                }
+            })
+        })
+        .collect();
+
+    let any_up = async {
+        while let Some(up) = attempts.next().await {
+            if matches!(up, Ok(true)) {
+                return true;
+            }
+        }
+        false
+    };
+    tokio::time::timeout(timeout, any_up).await.unwrap_or(false)
+}
+
+/// Send the event message to a single relay, returning its acknowledgement if it was sent
+///
+/// Failures are counted in `metrics`, if given.
+pub fn send_event(
+    relay: &RelayUrl,
+    headers: Option<&HashMap<String, String>>,
+    msg: String,
+    id: &EventId,
+    verify_delivery: bool,
+    metrics: Option<&Metrics>,
+) -> Option<Ack> {
+    let failed = |reason| {
+        if let Some(metrics) = metrics {
+            metrics.broadcast_failed(relay, reason);
+        }
+    };
+
+    let mut socket = match connect(relay, headers) {
+        Ok(s) => s,
+        // Some relays, e.g. mutiny's, answer the handshake with a plain 200 page when
+        // they aren't up. Nothing can be published on it, but it's not a network error
+        Err(err @ ConnectError::Rejected(StatusCode::OK)) => {
+            debug!("{relay} answered with HTTP 200 instead of a websocket, not sending");
+            failed(err.failure_reason());
+            return None;
+        }
+        Err(err) => {
+            warn!("Error connecting to {relay}: {err}");
+            failed(err.failure_reason());
+            return None;
+        }
+    };
+
+    // Send msg
+    if let Err(err) = socket.write_message(WsMessage::Text(msg)) {
+        warn!("Error sending to {relay}: {err}");
+        failed(ConnectError::from(err).failure_reason());
+        return None;
+    }
+
+    let ack = read_ack(&mut socket, id);
+    if let Some(reason) = ack.failure_reason() {
+        failed(reason);
+    }
+    match &ack {
+        Ack::Accepted => debug!("{relay} accepted {}", id.to_hex()),
+        Ack::Rejected(reason) => warn!("{relay} rejected {}: {reason}", id.to_hex()),
+        Ack::WrongId(other) => warn!(
+            "{relay} acknowledged {} when sent {}, ignoring",
+            other.to_hex(),
+            id.to_hex()
+        ),
+        Ack::Missing => debug!("{relay} did not acknowledge {}", id.to_hex()),
+    }
+
+    if verify_delivery && ack == Ack::Accepted {
+        match read_back(&mut socket, id) {
+            Ok(true) => debug!("{relay} returned {}", id.to_hex()),
+            Ok(false) => warn!("{relay} accepted {} but does not return it", id.to_hex()),
+            Err(err) => warn!("Could not read {} back from {relay}: {err}", id.to_hex()),
+        }
+    }
+    socket.close(None).ok();
+
+    Some(ack)
+}
+
+/// Wait up to `grace` for every relay to accept a connection, returning those that never did
+pub async fn await_relays(
+    relays: &[RelayUrl],
+    relay_headers: &RelayHeaders,
+    grace: Duration,
+) -> Vec<RelayUrl> {
+    let deadline = tokio::time::Instant::now() + grace;
+    let mut pending = relays.to_vec();
+
+    while !pending.is_empty() {
+        let attempts = pending.iter().cloned().map(|relay| {
+            let headers = relay_headers.get(&relay).cloned();
+            async move {
+                let attempt = tokio::task::spawn_blocking({
+                    let relay = relay.clone();
+                    move || match connect(&relay, headers.as_ref()) {
+                        Ok(mut socket) => {
+                            socket.close(None).ok();
+                            true
+                        }
+                        Err(err) => {
+                            debug!("{relay} is not up yet: {err}");
+                            false
+                        }
+                    }
+                });
+                let up = matches!(
+                    tokio::time::timeout_at(deadline, attempt).await,
+                    Ok(Ok(true))
+                );
+                (relay, up)
+            }
+        });
+
+        pending = join_all(attempts)
+            .await
+            .into_iter()
+            .filter_map(|(relay, up)| (!up).then_some(relay))
+            .collect();
+
+        if pending.is_empty() || tokio::time::Instant::now() + RETRY_INTERVAL > deadline {
+            break;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+
+    pending
+}
+
+/// Publish the zap note to every relay, contacting at most `per_zap_concurrency` at once,
+/// returning how many relays accepted it
+///
+/// The note is verified once before anything is sent; an invalid note is our own
+/// bug, so it fails the whole broadcast rather than any one relay.
+pub async fn broadcast_zap_note(
+    relays: &[RelayUrl],
+    zap_note: Event,
+    config: &Config,
+) -> Result<usize> {
+    zap_note
+        .verify()
+        .map_err(|err| anyhow!("Not broadcasting invalid note {}: {err}", zap_note.id))?;
+
+    Ok(broadcast_verified(relays, zap_note, config).await)
+}
+
+/// `broadcast_zap_note` for a note the caller has already verified
+pub async fn broadcast_verified(relays: &[RelayUrl], zap_note: Event, config: &Config) -> usize {
+    let id = zap_note.id;
+    let kind = zap_note.kind;
+    let msg = ClientMessage::new_event(zap_note).as_json();
+    let relays = config.relay_kinds.filter(relays, kind);
+
+    futures::stream::iter(relays)
+        .map(|relay| {
+            let msg = msg.clone();
+            async move {
+                let ack = send_to(&relay, msg, id, config).await;
+                if let Some(Ack::Rejected(reason)) = &ack {
+                    config.relay_kinds.rejected(&relay, kind, reason);
+                }
+                ack
+            }
+        })
+        .buffer_unordered(config.per_zap_concurrency.max(1))
+        .filter(|ack| futures::future::ready(*ack == Some(Ack::Accepted)))
+        .count()
+        .await
+}
+
+/// Send a zap note the caller has already verified to the operator's inbox relay,
+/// returning whether it accepted it
+///
+/// The inbox gets every receipt, so unlike `broadcast_verified` it is sent whatever
+/// `clnzapper_relay_kinds` knows of it.
+pub async fn send_to_inbox(inbox: &RelayUrl, zap_note: &Event, config: &Config) -> bool {
+    let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+
+    send_to(inbox, msg, zap_note.id, config).await == Some(Ack::Accepted)
+}
+
+/// Send an event message to one relay, through its send buffer if there are any
+async fn send_to(relay: &RelayUrl, msg: String, id: EventId, config: &Config) -> Option<Ack> {
+    let headers = config.relay_headers.get(relay).cloned();
+    let verify_delivery = config.verify_delivery;
+    let metrics = config.metrics.clone();
+    match &config.send_buffers {
+        Some(send_buffers) => {
+            send_buffers
+                .send(relay, headers, msg, id, verify_delivery, metrics)
+                .await
+        }
+        // tungstenite is blocking so keep it off the async workers
+        None => {
+            let relay = relay.clone();
+            tokio::task::spawn_blocking(move || {
+                send_event(
+                    &relay,
+                    headers.as_ref(),
+                    msg,
+                    &id,
+                    verify_delivery,
+                    metrics.as_deref(),
+                )
+            })
+            .await
+            .ok()
+            .flatten()
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use nostr::{EventBuilder, Keys, Kind};
+    use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+
+    use super::*;
+
+    pub fn relay_url(relay: &str) -> RelayUrl {
+        RelayUrl::parse(relay).unwrap()
+    }
+
+    /// Start a relay on localhost serving `connections` connections that forwards
+    /// the first text message of each, rejecting handshakes without `required_header`
+    pub fn mock_relay(
+        required_header: Option<(&'static str, &'static str)>,
+        connections: usize,
+    ) -> (RelayUrl, mpsc::Receiver<String>) {
+        mock_relay_replying(required_header, connections, |_| None)
+    }
+
+    /// Relay answering every request with the events in `stored`
+    pub fn mock_stored_relay(stored: Arc<Mutex<Vec<Event>>>) -> RelayUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut socket = tungstenite::accept(stream.unwrap()).unwrap();
+                let Ok(WsMessage::Text(msg)) = socket.read_message() else {
+                    continue;
+                };
+                let Ok(ClientMessage::Req {
+                    subscription_id, ..
+                }) = ClientMessage::from_json(msg)
+                else {
+                    continue;
+                };
+                for event in stored.lock().unwrap().iter() {
+                    let msg = RelayMessage::new_event(subscription_id.clone(), event.clone());
+                    socket.write_message(WsMessage::Text(msg.as_json())).ok();
+                }
+                let eose = RelayMessage::new_eose(subscription_id);
+                socket.write_message(WsMessage::Text(eose.as_json())).ok();
+                socket.read_message().ok();
+            }
+        });
+
+        url
+    }
+
+    /// Like `mock_relay`, answering the first text message with `reply` of it if any
+    // The handshake callback signature is fixed by tungstenite
+    #[allow(clippy::result_large_err)]
+    pub fn mock_relay_replying(
+        required_header: Option<(&'static str, &'static str)>,
+        connections: usize,
+        reply: fn(&str) -> Option<String>,
+    ) -> (RelayUrl, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let callback = |request: &Request, response: Response| match required_header {
+                    Some((name, value))
+                        if request.headers().get(name).map(|v| v.as_bytes())
+                            != Some(value.as_bytes()) =>
+                    {
+                        let mut err = ErrorResponse::new(Some("missing header".into()));
+                        *err.status_mut() = tungstenite::http::StatusCode::UNAUTHORIZED;
+                        Err(err)
+                    }
+                    _ => Ok(response),
+                };
+                let Ok(mut socket) = tungstenite::accept_hdr(stream.unwrap(), callback) else {
+                    continue;
+                };
+                if let Ok(WsMessage::Text(msg)) = socket.read_message() {
+                    if let Some(reply) = reply(&msg) {
+                        socket.write_message(WsMessage::Text(reply)).ok();
+                    }
+                    sender.send(msg).unwrap();
+                }
+            }
+        });
+
+        (url, receiver)
+    }
+
+    #[test]
+    fn test_zap_relays() {
+        let default_relays = HashSet::from([relay_url("wss://relay.damus.io")]);
+        let payer_relays = HashSet::from([
+            relay_url("wss://nos.lol"),
+            relay_url("wss://relay.damus.io"),
+            relay_url("wss://eden.nostr.land"),
+        ]);
+
+        assert_eq!(
+            zap_relays(&default_relays, &payer_relays),
+            vec![
+                "wss://eden.nostr.land",
+                "wss://nos.lol",
+                "wss://relay.damus.io"
+            ]
+        );
+        assert_eq!(
+            zap_relays(&default_relays, &HashSet::new()),
+            vec!["wss://relay.damus.io"]
+        );
+    }
+
+    #[test]
+    fn test_scheme_policy() {
+        let default_relays = HashSet::from([relay_url("wss://relay.example")]);
+        let payer_relays = HashSet::from([
+            relay_url("ws://relay.example/"),
+            relay_url("ws://relay.example:8080"),
+            relay_url("ws://nos.lol"),
+        ]);
+        let relays = zap_relays(&default_relays, &payer_relays);
+
+        assert_eq!(
+            apply_scheme_policy(relays.clone(), RelaySchemePolicy::Keep),
+            relays
+        );
+        assert_eq!(
+            apply_scheme_policy(relays, RelaySchemePolicy::PreferWss),
+            vec![
+                "ws://nos.lol",
+                "ws://relay.example:8080",
+                "wss://relay.example"
+            ]
+        );
+        assert!("prefer-ws".parse::<RelaySchemePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_cap_relays() {
+        let default_relays = HashSet::from([
+            relay_url("wss://relay.example"),
+            relay_url("wss://zz.example"),
+        ]);
+        let payer_relays = HashSet::from([
+            relay_url("wss://eden.nostr.land"),
+            relay_url("wss://nos.lol"),
+            relay_url("wss://aa.example"),
+        ]);
+        let relays = zap_relays(&default_relays, &payer_relays);
+
+        // Default relays first, then the payer's in order
+        assert_eq!(
+            cap_relays(relays.clone(), &default_relays, Some(3)),
+            vec![
+                "wss://aa.example",
+                "wss://relay.example",
+                "wss://zz.example"
+            ]
+        );
+        assert_eq!(
+            cap_relays(relays.clone(), &default_relays, Some(1)),
+            vec!["wss://relay.example"]
+        );
+        assert_eq!(cap_relays(relays.clone(), &default_relays, Some(5)), relays);
+        assert_eq!(cap_relays(relays.clone(), &default_relays, None), relays);
+    }
+
+    #[test]
+    fn test_log_order() {
+        let default_relays = HashSet::from([relay_url("wss://relay.damus.io")]);
+        let payer_relays = HashSet::from([
+            relay_url("wss://nos.lol"),
+            relay_url("wss://eden.nostr.land"),
+        ]);
+        let relays = zap_relays(&default_relays, &payer_relays);
+
+        assert_eq!(
+            log_order(&relays, &default_relays, LogRelayOrder::Sorted),
+            vec![
+                "wss://eden.nostr.land",
+                "wss://nos.lol",
+                "wss://relay.damus.io"
+            ]
+        );
+        assert_eq!(
+            log_order(&relays, &default_relays, LogRelayOrder::OwnFirst),
+            vec![
+                "wss://relay.damus.io",
+                "wss://eden.nostr.land",
+                "wss://nos.lol"
+            ]
+        );
+        // Only logging is affected
+        assert_eq!(relays, zap_relays(&default_relays, &payer_relays));
+    }
+
+    #[test]
+    fn test_without_inbox() {
+        let inbox = relay_url("ws://localhost:7777");
+        let relays = vec![relay_url("wss://default.example"), inbox.clone()];
+
+        assert_eq!(
+            without_inbox(relays.clone(), Some(&inbox)),
+            vec![relay_url("wss://default.example")]
+        );
+        assert_eq!(without_inbox(relays.clone(), None), relays);
+    }
+
+    #[test]
+    fn test_parse_relay_headers() {
+        let headers =
+            parse_relay_headers(r#"{"wss://relay.example": {"Sec-WebSocket-Protocol": "nostr"}}"#)
+                .unwrap();
+        assert_eq!(
+            headers[&relay_url("wss://relay.example")]["Sec-WebSocket-Protocol"],
+            "nostr"
+        );
+
+        assert!(parse_relay_headers(r#"{"nope": {}}"#).is_err());
+        assert!(parse_relay_headers(r#"{"wss://relay.example": {"Bad Header": "x"}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_headers_sent_on_connect() {
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let (relay, received) = mock_relay(Some(("x-relay-token", "letmein")), 2);
+        let relays = vec![relay.clone()];
+
+        // Without the header the handshake is rejected
+        broadcast_zap_note(&relays, zap_note.clone(), &Config::default())
+            .await
+            .unwrap();
+
+        let config = Config {
+            relay_headers: RelayHeaders::from([(
+                relay,
+                HashMap::from([("x-relay-token".to_string(), "letmein".to_string())]),
+            )]),
+            ..Config::default()
+        };
+        broadcast_zap_note(&relays, zap_note.clone(), &config)
+            .await
+            .unwrap();
+
+        let msg = received.recv().unwrap();
+        assert!(msg.contains(&zap_note.id.to_hex()));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_ack_id_checked() {
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+
+        // Echo the id we were sent
+        let (relay, _) = mock_relay_replying(None, 1, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        assert_eq!(
+            send_event(&relay, None, msg.clone(), &zap_note.id, false, None),
+            Some(Ack::Accepted)
+        );
+
+        // Acknowledge some other event
+        let (relay, _) = mock_relay_replying(None, 1, |_| {
+            let id = EventId::from_hex("00".repeat(32)).unwrap();
+            Some(RelayMessage::new_ok(id, true, "").as_json())
+        });
+        assert_eq!(
+            send_event(&relay, None, msg.clone(), &zap_note.id, false, None),
+            Some(Ack::WrongId(EventId::from_hex("00".repeat(32)).unwrap()))
+        );
+
+        // Refuse the event as one it already has
+        let (relay, _) = mock_relay_replying(None, 1, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(
+                RelayMessage::new_ok(event.id, false, "duplicate: already have this event")
+                    .as_json(),
+            )
+        });
+        assert_eq!(
+            send_event(&relay, None, msg.clone(), &zap_note.id, false, None),
+            Some(Ack::Accepted)
+        );
+        assert!(!is_duplicate("blocked: not a duplicate: really"));
+
+        // Relay closes without answering
+        let (relay, _) = mock_relay(None, 1);
+        assert_eq!(
+            send_event(&relay, None, msg, &zap_note.id, false, None),
+            Some(Ack::Missing)
+        );
+    }
+
+    #[test]
+    fn test_plain_http_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .ok();
+            }
+        });
+
+        assert!(matches!(
+            connect(&relay, None),
+            Err(ConnectError::Rejected(StatusCode::OK))
+        ));
+
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+        assert_eq!(
+            send_event(&relay, None, msg, &zap_note.id, false, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_payment_required() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                stream
+                    .write_all(b"HTTP/1.1 402 Payment Required\r\nWWW-Authenticate: L402 macaroon=\"mac\", invoice=\"lnbc1\"\r\nContent-Length: 0\r\n\r\n")
+                    .ok();
+            }
+        });
+
+        let err = connect(&relay, None).unwrap_err();
+        assert!(matches!(
+            &err,
+            ConnectError::PaymentRequired(Some(challenge)) if challenge.starts_with("L402 macaroon=")
+        ));
+        assert_eq!(err.failure_reason(), FailureReason::Auth);
+        assert!(err.to_string().contains("clnzapper_relay_auth"));
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        // Nothing listens on a port just freed
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(matches!(
+            connect(&relay_url(&format!("ws://127.0.0.1:{port}")), None),
+            Err(ConnectError::Refused)
+        ));
+
+        assert!(matches!(
+            connect(&relay_url("ws://relay.invalid"), None),
+            Err(ConnectError::Dns(_))
+        ));
+
+        // Answering a TLS client hello with plain HTTP fails the TLS handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").ok();
+        });
+        let err = connect(&relay_url(&format!("wss://localhost:{port}")), None).unwrap_err();
+        assert!(matches!(err, ConnectError::Tls(_)), "{err:?}");
+    }
+
+    /// Relay accepting one event then answering a request for it, with the event if `stores`
+    fn mock_storing_relay(stores: bool) -> RelayUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let read = |socket: &mut WebSocket<TcpStream>| match socket.read_message() {
+                Ok(WsMessage::Text(msg)) => ClientMessage::from_json(msg).unwrap(),
+                msg => panic!("Unexpected {msg:?}"),
+            };
+
+            let ClientMessage::Event(event) = read(&mut socket) else {
+                panic!("Expected an event");
+            };
+            let ok = RelayMessage::new_ok(event.id, true, "");
+            socket.write_message(WsMessage::Text(ok.as_json())).unwrap();
+
+            let ClientMessage::Req {
+                subscription_id, ..
+            } = read(&mut socket)
+            else {
+                panic!("Expected a request");
+            };
+            if stores {
+                let msg = RelayMessage::new_event(subscription_id.clone(), *event);
+                socket
+                    .write_message(WsMessage::Text(msg.as_json()))
+                    .unwrap();
+            }
+            let eose = RelayMessage::new_eose(subscription_id);
+            socket
+                .write_message(WsMessage::Text(eose.as_json()))
+                .unwrap();
+            socket.read_message().ok();
+        });
+
+        url
+    }
+
+    #[test]
+    fn test_non_text_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+        let (pong_sender, pong) = mpsc::channel();
+
+        // Ping and send junk before answering, and only answer once ponged
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let Ok(WsMessage::Text(msg)) = socket.read_message() else {
+                panic!("Expected an event");
+            };
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                panic!("Expected an event");
+            };
+            socket
+                .write_message(WsMessage::Ping(b"are you there".to_vec()))
+                .unwrap();
+            socket
+                .write_message(WsMessage::Binary(vec![0xff, 0x00]))
+                .unwrap();
+            socket
+                .write_message(WsMessage::Text(RelayMessage::new_notice("hello").as_json()))
+                .unwrap();
+            match socket.read_message().unwrap() {
+                WsMessage::Pong(data) => pong_sender.send(data).unwrap(),
+                msg => panic!("Unexpected {msg:?}"),
+            }
+            let ok = RelayMessage::new_ok(event.id, true, "");
+            socket.write_message(WsMessage::Text(ok.as_json())).unwrap();
+            socket.read_message().ok();
+        });
+
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+        assert_eq!(
+            send_event(&relay, None, msg, &zap_note.id, false, None),
+            Some(Ack::Accepted)
+        );
+        assert_eq!(pong.try_recv().unwrap(), b"are you there");
+    }
+
+    #[test]
+    fn test_read_back() {
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        for stores in [true, false] {
+            let mut socket = connect(&mock_storing_relay(stores), None).unwrap();
+            let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+            socket.write_message(WsMessage::Text(msg)).unwrap();
+            assert_eq!(read_ack(&mut socket, &zap_note.id), Ack::Accepted);
+            assert_eq!(read_back(&mut socket, &zap_note.id).unwrap(), stores);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_await_relays() {
+        let (up, _) = mock_relay(None, 1);
+        // Bind then drop to get a port nothing listens on
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            relay_url(&format!("ws://{}", listener.local_addr().unwrap()))
+        };
+
+        let start = tokio::time::Instant::now();
+        let unreachable = await_relays(
+            &[up.clone(), down.clone()],
+            &RelayHeaders::new(),
+            Duration::from_millis(800),
+        )
+        .await;
+        assert_eq!(unreachable, vec![down]);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Returns as soon as every relay is up
+        let (up, _) = mock_relay(None, 1);
+        let start = tokio::time::Instant::now();
+        assert!(
+            await_relays(&[up], &RelayHeaders::new(), Duration::from_secs(10))
+                .await
+                .is_empty()
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_note_not_sent() {
+        let mut zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        zap_note.content = "tampered".to_string();
+
+        let (relay, received) = mock_relay(None, 1);
+        let (other, other_received) = mock_relay(None, 1);
+        assert!(
+            broadcast_zap_note(&[relay, other], zap_note, &Config::default())
+                .await
+                .is_err()
+        );
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(other_received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_per_zap_concurrency() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+
+        let relays: Vec<RelayUrl> = (0..6)
+            .map(|_| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let url = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+                let (active, max_active) = (active.clone(), max_active.clone());
+                // Hold the handshake open so the client's connections overlap
+                thread::spawn(move || {
+                    let (stream, _) = listener.accept().unwrap();
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    let mut socket = tungstenite::accept(stream).unwrap();
+                    socket.read_message().ok();
+                });
+                url
+            })
+            .collect();
+
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let config = Config {
+            per_zap_concurrency: 2,
+            ..Config::default()
+        };
+        broadcast_zap_note(&relays, zap_note, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_own_receipts_not_fetched() {
+        let keys = Keys::generate();
+        let receipt = |keys: &Keys, content: &str| {
+            EventBuilder::new(Kind::ZapReceipt, content, &[])
+                .to_event(keys)
+                .unwrap()
+        };
+        let ours = receipt(&keys, "ours");
+        // e.g. one published under a previous key
+        let remembered = receipt(&Keys::generate(), "remembered");
+        let theirs = receipt(&Keys::generate(), "theirs");
+        let relay = mock_stored_relay(Arc::new(Mutex::new(vec![
+            ours,
+            remembered.clone(),
+            theirs.clone(),
+        ])));
+
+        let own = crate::published::tests::no_receipts();
+        let own = OwnReceipts {
+            pubkey: keys.public_key(),
+            ..own
+        };
+        own.published.lock().unwrap().insert(remembered.id);
+
+        let events = fetch_events(&relay, None, Filter::new(), &own).unwrap();
+        assert_eq!(events, vec![theirs]);
+    }
+}
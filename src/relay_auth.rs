@@ -0,0 +1,113 @@
+//! Credentials for paid relays, by `clnzapper_relay_auth`
+//!
+//! Paid relays only take events from subscribers, and some check an `Authorization`
+//! header on the websocket handshake, such as an L402 token. `clnzapper_relay_auth`
+//! maps each such relay to the header value to send, e.g. `L402 <macaroon>:<preimage>`
+//! or `Bearer <token>`. Like the key options, a value can be read from elsewhere with
+//! `file:`, `env:` or `cmd:`, and it is sent alongside any `clnzapper_relay_headers`
+//! of the relay.
+//!
+//! The zapper never pays for access itself. A relay answering the handshake with
+//! `402 Payment Required` is logged with its challenge, so the operator can pay it
+//! and configure the resulting token.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use tungstenite::http::header::AUTHORIZATION;
+use tungstenite::http::HeaderValue;
+
+use crate::keys;
+use crate::relay::RelayHeaders;
+use crate::relay_url::RelayUrl;
+
+/// Add the `Authorization` header of each relay in the `clnzapper_relay_auth` JSON
+/// object of `{relay: value}` to `relay_headers`
+pub fn apply(json: &str, relay_headers: &mut RelayHeaders) -> Result<()> {
+    let auth: HashMap<String, String> = serde_json::from_str(json)?;
+
+    for (relay, value) in auth {
+        let relay = RelayUrl::parse(&relay)?;
+        let value = keys::read_secret("clnzapper_relay_auth", &value)?;
+        // Checked here, but the value itself stays out of the error
+        HeaderValue::from_str(&value)
+            .map_err(|_| anyhow!("clnzapper_relay_auth for {relay} is not a valid header value"))?;
+
+        let headers = relay_headers.entry(relay.clone()).or_default();
+        if headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case(AUTHORIZATION.as_str()))
+        {
+            return Err(anyhow!(
+                "{relay} has an Authorization header in both clnzapper_relay_headers and clnzapper_relay_auth"
+            ));
+        }
+        headers.insert(AUTHORIZATION.to_string(), value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::broadcast_zap_note;
+    use crate::relay::tests::{mock_relay, relay_url};
+
+    #[test]
+    fn test_apply() {
+        let mut headers = RelayHeaders::new();
+        std::env::set_var("CLNZAPPER_TEST_RELAY_TOKEN", "Bearer sekrit\n");
+        apply(
+            r#"{"wss://paid.example": "L402 mac:preimage", "wss://other.example": "env:CLNZAPPER_TEST_RELAY_TOKEN"}"#,
+            &mut headers,
+        )
+        .unwrap();
+        assert_eq!(
+            headers[&relay_url("wss://paid.example")]["authorization"],
+            "L402 mac:preimage"
+        );
+        assert_eq!(
+            headers[&relay_url("wss://other.example")]["authorization"],
+            "Bearer sekrit"
+        );
+
+        // Only one place to set it
+        assert!(apply(r#"{"wss://paid.example": "Bearer x"}"#, &mut headers).is_err());
+        assert!(apply(
+            r#"{"wss://bad.example": "line\nbreak"}"#,
+            &mut RelayHeaders::new()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_sent() {
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let (relay, received) = mock_relay(Some(("authorization", "L402 mac:preimage")), 2);
+        let relays = vec![relay.clone()];
+
+        // Without the token the handshake is refused
+        broadcast_zap_note(&relays, zap_note.clone(), &Config::default())
+            .await
+            .unwrap();
+
+        let mut config = Config::default();
+        apply(
+            &format!(r#"{{"{relay}": "L402 mac:preimage"}}"#),
+            &mut config.relay_headers,
+        )
+        .unwrap();
+        broadcast_zap_note(&relays, zap_note.clone(), &config)
+            .await
+            .unwrap();
+
+        assert!(received.recv().unwrap().contains(&zap_note.id.to_hex()));
+        assert!(received.try_recv().is_err());
+    }
+}
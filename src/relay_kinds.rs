@@ -0,0 +1,198 @@
+//! Event kinds relays accept, by `clnzapper_relay_kinds` and `clnzapper_learn_relay_kinds`
+//!
+//! Some relays only take a few kinds and reject zap receipts every time. A relay
+//! listed in `clnzapper_relay_kinds` is only sent events of the kinds listed for it,
+//! and with `clnzapper_learn_relay_kinds` a relay that rejects an event with a reason
+//! naming its kind, e.g. `blocked: kind 9735 not allowed`, is not sent that kind
+//! again until restart. NIP-11 documents have no standard field for accepted kinds,
+//! so nothing is learned from them. `clnzapper_relay_kinds` is reloaded on SIGHUP,
+//! keeping what was learned.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+use anyhow::Result;
+use log::{debug, info};
+use nostr::Kind;
+
+use crate::relay_url::RelayUrl;
+
+/// Kinds each listed relay accepts
+pub type KindsByRelay = HashMap<RelayUrl, HashSet<u64>>;
+
+/// What each relay is known to accept
+#[derive(Debug, Default)]
+pub struct RelayKinds {
+    /// Kinds each listed relay accepts, from `clnzapper_relay_kinds`
+    configured: RwLock<KindsByRelay>,
+    /// Whether to learn from rejections
+    learn: bool,
+    /// Kinds relays rejected for being of that kind
+    learned: Mutex<HashSet<(RelayUrl, u64)>>,
+}
+
+impl RelayKinds {
+    /// Parse the `clnzapper_relay_kinds` JSON object of `{relay: [kind, ...]}`
+    pub fn parse(json: Option<&str>, learn: bool) -> Result<Self> {
+        let configured = match json {
+            Some(json) => parse_configured(json)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            configured: RwLock::new(configured),
+            learn,
+            learned: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Replace the kinds listed relays accept, keeping what was learned from rejections
+    pub fn replace(&self, configured: KindsByRelay) {
+        *self.configured.write().expect("Lock not poisoned") = configured;
+    }
+
+    /// Whether the relay is not known to reject events of `kind`
+    pub fn accepts(&self, relay: &RelayUrl, kind: Kind) -> bool {
+        let kind = kind.as_u64();
+        let configured = self
+            .configured
+            .read()
+            .expect("Lock not poisoned")
+            .get(relay)
+            .is_none_or(|kinds| kinds.contains(&kind));
+        configured
+            && !self
+                .learned
+                .lock()
+                .expect("Lock not poisoned")
+                .contains(&(relay.clone(), kind))
+    }
+
+    /// The relays not known to reject events of `kind`
+    pub fn filter(&self, relays: &[RelayUrl], kind: Kind) -> Vec<RelayUrl> {
+        relays
+            .iter()
+            .filter(|relay| {
+                let accepts = self.accepts(relay, kind);
+                if !accepts {
+                    debug!(
+                        "Not publishing kind {} to {relay}, it doesn't accept it",
+                        kind.as_u64()
+                    );
+                }
+                accepts
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Note the relay rejected an event of `kind`, learning if `reason` says it was for its kind
+    pub fn rejected(&self, relay: &RelayUrl, kind: Kind, reason: &str) {
+        if !self.learn || !reason.to_lowercase().contains("kind") {
+            return;
+        }
+        let new = self
+            .learned
+            .lock()
+            .expect("Lock not poisoned")
+            .insert((relay.clone(), kind.as_u64()));
+        if new {
+            info!(
+                "Not publishing kind {} to {relay} again, it rejected one: {reason}",
+                kind.as_u64()
+            );
+        }
+    }
+}
+
+/// Parse a `clnzapper_relay_kinds` JSON object of `{relay: [kind, ...]}`
+pub fn parse_configured(json: &str) -> Result<KindsByRelay> {
+    serde_json::from_str::<HashMap<String, HashSet<u64>>>(json)?
+        .into_iter()
+        .map(|(relay, kinds)| Ok((RelayUrl::parse(&relay)?, kinds)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nostr::{ClientMessage, EventBuilder, Keys, RelayMessage};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::broadcast_zap_note;
+    use crate::relay::tests::{mock_relay, mock_relay_replying, relay_url};
+
+    fn zap_note() -> nostr::Event {
+        EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let kinds = RelayKinds::parse(Some(r#"{"wss://relay.example": [1, 7]}"#), false).unwrap();
+        assert!(!kinds.accepts(&relay_url("wss://relay.example"), Kind::ZapReceipt));
+        assert!(kinds.accepts(&relay_url("wss://relay.example"), Kind::TextNote));
+        // Relays not listed accept anything
+        assert!(kinds.accepts(&relay_url("wss://other.example"), Kind::ZapReceipt));
+
+        assert!(RelayKinds::parse(Some(r#"{"nope": [1]}"#), false).is_err());
+        assert!(RelayKinds::parse(Some(r#"{"wss://relay.example": 9735}"#), false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configured_relay_skipped() {
+        let (relay, received) = mock_relay(None, 1);
+        let json = format!(r#"{{"{relay}": [1]}}"#);
+        let config = Config {
+            relay_kinds: RelayKinds::parse(Some(&json), false).unwrap().into(),
+            ..Config::default()
+        };
+
+        let accepted = broadcast_zap_note(std::slice::from_ref(&relay), zap_note(), &config)
+            .await
+            .unwrap();
+        assert_eq!(accepted, 0);
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_learned_from_rejection() {
+        let (relay, received) = mock_relay_replying(None, 2, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, false, "blocked: kind 9735 not allowed").as_json())
+        });
+        let config = Config {
+            relay_kinds: RelayKinds::parse(None, true).unwrap().into(),
+            ..Config::default()
+        };
+
+        broadcast_zap_note(std::slice::from_ref(&relay), zap_note(), &config)
+            .await
+            .unwrap();
+        assert!(received.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!config.relay_kinds.accepts(&relay, Kind::ZapReceipt));
+        assert!(config.relay_kinds.accepts(&relay, Kind::TextNote));
+
+        broadcast_zap_note(std::slice::from_ref(&relay), zap_note(), &config)
+            .await
+            .unwrap();
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_other_rejections_not_learned() {
+        let relay = relay_url("wss://relay.example");
+        let kinds = RelayKinds::parse(None, true).unwrap();
+        kinds.rejected(&relay, Kind::ZapReceipt, "rate-limited: slow down");
+        assert!(kinds.accepts(&relay, Kind::ZapReceipt));
+
+        let kinds = RelayKinds::parse(None, false).unwrap();
+        kinds.rejected(&relay, Kind::ZapReceipt, "blocked: kind not allowed");
+        assert!(kinds.accepts(&relay, Kind::ZapReceipt));
+    }
+}
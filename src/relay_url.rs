@@ -0,0 +1,152 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use nostr::Url;
+use serde::{Deserialize, Serialize};
+
+/// A relay's websocket url, checked and normalized
+///
+/// Every relay the zapper knows of, configured or from a zap request, is one of these,
+/// so an url that can't be dialed is turned away where it comes in. Normalizing makes
+/// the same relay written differently compare equal: the scheme and host are
+/// lowercased, a default port is dropped, and so is the slash of an empty path, so
+/// `WSS://Relay.Damus.io:443/` is `wss://relay.damus.io`.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RelayUrl(String);
+
+impl RelayUrl {
+    pub fn parse(relay: &str) -> Result<Self> {
+        let relay = relay.trim();
+        let url = Url::parse(relay)?;
+
+        match url.scheme() {
+            "ws" | "wss" => (),
+            scheme => return Err(anyhow!("Unsupported relay scheme {scheme} in {relay}")),
+        }
+
+        if url.host_str().is_none() {
+            return Err(anyhow!("Relay {relay} has no host"));
+        }
+
+        let mut normalized = url.to_string();
+        if url.path() == "/" && url.query().is_none() && url.fragment().is_none() {
+            normalized.pop();
+        }
+        Ok(Self(normalized))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.0.starts_with("wss://")
+    }
+
+    /// The relay's url, now known to parse
+    pub fn url(&self) -> Url {
+        Url::parse(&self.0).expect("Checked on construction")
+    }
+}
+
+impl FromStr for RelayUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for RelayUrl {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::parse(&s)
+    }
+}
+
+impl From<RelayUrl> for String {
+    fn from(relay: RelayUrl) -> Self {
+        relay.0
+    }
+}
+
+impl fmt::Display for RelayUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<&str> for RelayUrl {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+// Logged as the plain url
+impl fmt::Debug for RelayUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl AsRef<str> for RelayUrl {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_relay_url() {
+        assert_eq!(
+            RelayUrl::parse(" wss://relay.damus.io ").unwrap().as_str(),
+            "wss://relay.damus.io"
+        );
+        assert!(RelayUrl::parse("ws://localhost:8080").is_ok());
+        assert!(RelayUrl::parse("https://relay.damus.io").is_err());
+        assert!(RelayUrl::parse("relay.damus.io").is_err());
+        assert!(RelayUrl::parse("").is_err());
+    }
+
+    #[test]
+    fn test_normalize_relay_url() {
+        let normalized = |relay: &str| RelayUrl::parse(relay).unwrap().to_string();
+
+        assert_eq!(normalized("wss://relay.damus.io/"), "wss://relay.damus.io");
+        assert_eq!(normalized("WSS://Relay.Damus.IO"), "wss://relay.damus.io");
+        assert_eq!(
+            normalized("wss://relay.damus.io:443"),
+            "wss://relay.damus.io"
+        );
+        assert_eq!(normalized("ws://relay.example:80/"), "ws://relay.example");
+        assert_eq!(normalized("ws://localhost:8080"), "ws://localhost:8080");
+        assert_eq!(
+            normalized("wss://relay.example/nostr/"),
+            "wss://relay.example/nostr/"
+        );
+        assert_eq!(
+            normalized("wss://relay.example/?key=a"),
+            "wss://relay.example/?key=a"
+        );
+
+        assert_eq!(
+            RelayUrl::parse("wss://nos.lol/").unwrap(),
+            RelayUrl::parse("wss://nos.lol").unwrap()
+        );
+        assert!(RelayUrl::parse("wss://nos.lol").unwrap().is_secure());
+        assert!(!RelayUrl::parse("ws://nos.lol").unwrap().is_secure());
+    }
+
+    #[test]
+    fn test_relay_url_serde() {
+        let relay: RelayUrl = serde_json::from_str("\"wss://nos.lol/\"").unwrap();
+        assert_eq!(serde_json::to_string(&relay).unwrap(), "\"wss://nos.lol\"");
+        assert!(serde_json::from_str::<RelayUrl>("\"https://nos.lol\"").is_err());
+    }
+}
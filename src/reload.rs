@@ -0,0 +1,442 @@
+//! Reloading settings on SIGHUP, from `clnzapper_reload_file`
+//!
+//! On SIGHUP the zapper reads the `clnzapper_*=value` lines of the reload file,
+//! lightningd's `config` in its network directory unless set, and applies the
+//! options it can change while running:
+//! * `clnzapper_nostr_relay` replaces the default relays, as `zapper-setrelays` does,
+//!   but only when it changed since it was last loaded, so relays set since stay
+//! * `clnzapper_payer_blocklist` replaces the blocklist, reading its file again
+//! * the checks a zap goes through, `HOT_RELOADABLE` listing them all, each held in
+//!   a `Reloadable` so a zap reads the value current when it is checked
+//! * the receipt key is read again from where it was loaded, only if
+//!   `clnzapper_key_reload_interval` is set
+//!
+//! Options missing from the file are left as they are, and every other option
+//! needs a restart, since it sets up something only built at startup. Everything is
+//! checked and the key read before anything is applied, so a file that doesn't
+//! parse or a key that can't be read changes nothing.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::amount::SanityRange;
+use crate::blocklist;
+use crate::comment::CommentFilter;
+use crate::recipient;
+use crate::relay_kinds;
+use crate::relay_url::RelayUrl;
+use crate::state::State;
+
+/// Options applied on reload
+const HOT_RELOADABLE: [&str; 12] = [
+    "clnzapper_nostr_relay",
+    "clnzapper_payer_blocklist",
+    "clnzapper_comment_max_len",
+    "clnzapper_comment_strip_urls",
+    "clnzapper_sanity_min_msat",
+    "clnzapper_sanity_max_msat",
+    "clnzapper_recipient_pubkeys",
+    "clnzapper_max_zap_request_age",
+    "clnzapper_relay_kinds",
+    "clnzapper_label_prefix",
+    "clnzapper_max_total_relays",
+    "clnzapper_max_amount_deviation_pct",
+];
+
+/// A setting `reload` can replace while the zapper runs, shared by every clone
+#[derive(Clone, Debug, Default)]
+pub struct Reloadable<T>(Arc<RwLock<T>>);
+
+impl<T> From<T> for Reloadable<T> {
+    fn from(value: T) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+}
+
+impl<T: Clone + PartialEq> Reloadable<T> {
+    /// The current value
+    pub fn get(&self) -> T {
+        self.0.read().expect("Lock not poisoned").clone()
+    }
+
+    /// Replace the value, returning whether it changed
+    pub fn replace(&self, value: T) -> bool {
+        let mut current = self.0.write().expect("Lock not poisoned");
+        let changed = *current != value;
+        *current = value;
+        changed
+    }
+}
+
+/// The `clnzapper_` options set in a lightningd style config file, the last line winning
+fn read_options(path: &Path) -> Result<HashMap<String, String>> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("Could not read {}: {err}", path.display()))?;
+
+    Ok(config
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("clnzapper_"))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect())
+}
+
+/// An integer option of the file, `None` if it isn't set there
+fn int_option(options: &HashMap<String, String>, name: &str) -> Result<Option<u64>> {
+    options
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| anyhow!("{name} must be a non negative integer, not {value}"))
+        })
+        .transpose()
+}
+
+/// A boolean option of the file, `None` if it isn't set there
+fn bool_option(options: &HashMap<String, String>, name: &str) -> Result<Option<bool>> {
+    options
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| anyhow!("{name} must be true or false, not {value}"))
+        })
+        .transpose()
+}
+
+/// Apply the hot reloadable options in `path` to `state`
+///
+/// `loaded_relays` are the default relays the option last set, at startup or by a
+/// reload, and are updated when it changes.
+pub async fn reload(
+    state: &State,
+    path: &Path,
+    loaded_relays: &mut HashSet<RelayUrl>,
+) -> Result<()> {
+    let options = read_options(path)?;
+    let config = &state.config;
+
+    // Check everything before applying anything
+    let relays = options
+        .get("clnzapper_nostr_relay")
+        .map(|relay| RelayUrl::parse(relay).map(|relay| HashSet::from([relay])))
+        .transpose()?;
+    let payer_blocklist = options
+        .get("clnzapper_payer_blocklist")
+        .map(|blocklist| blocklist::parse(blocklist))
+        .transpose()?;
+    let current = config.comment_filter.get();
+    let comment_filter = CommentFilter {
+        max_len: int_option(&options, "clnzapper_comment_max_len")?
+            .map_or(current.max_len, |max_len| max_len as usize),
+        strip_urls: bool_option(&options, "clnzapper_comment_strip_urls")?
+            .unwrap_or(current.strip_urls),
+    };
+    let current = config.sanity_range.get();
+    let sanity_range = SanityRange {
+        min_msat: int_option(&options, "clnzapper_sanity_min_msat")?.or(current.min_msat),
+        max_msat: int_option(&options, "clnzapper_sanity_max_msat")?.or(current.max_msat),
+    };
+    if let (Some(min), Some(max)) = (sanity_range.min_msat, sanity_range.max_msat) {
+        if min > max {
+            return Err(anyhow!(
+                "clnzapper_sanity_min_msat {min} is above clnzapper_sanity_max_msat {max}"
+            ));
+        }
+    }
+    let recipient_pubkeys = options
+        .get("clnzapper_recipient_pubkeys")
+        .map(|pubkeys| recipient::parse_pubkeys(pubkeys))
+        .transpose()?;
+    let max_zap_request_age = int_option(&options, "clnzapper_max_zap_request_age")?;
+    let relay_kinds = options
+        .get("clnzapper_relay_kinds")
+        .map(|json| relay_kinds::parse_configured(json))
+        .transpose()?;
+    let label_prefix = options
+        .get("clnzapper_label_prefix")
+        .map(|prefix| Some(prefix.clone()).filter(|prefix| !prefix.is_empty()));
+    let max_total_relays = int_option(&options, "clnzapper_max_total_relays")?
+        .map(|max| Some(max as usize).filter(|max| *max > 0));
+    let max_amount_deviation_pct = int_option(&options, "clnzapper_max_amount_deviation_pct")?;
+
+    // The key may come from a command, so don't hold up the runtime reading it. It is
+    // read before the rest is applied, so a key that can't be read changes nothing
+    if config.key_reload_interval.is_some() {
+        let keys = state.keys.clone();
+        tokio::task::spawn_blocking(move || keys.reload()).await??;
+    }
+
+    // Relays set by `zapper-setrelays` since are only replaced if the option changed
+    if let Some(relays) = relays.filter(|relays| relays != loaded_relays) {
+        info!("Default relays reloaded: {relays:?}");
+        *state.relays.write().await = relays.clone();
+        *loaded_relays = relays;
+    }
+    if let Some(payer_blocklist) = payer_blocklist {
+        info!(
+            "Payer blocklist reloaded, {} pubkeys",
+            payer_blocklist.len()
+        );
+        config.payer_blocklist.replace(payer_blocklist);
+    }
+    if config.comment_filter.replace(comment_filter.clone()) {
+        info!("Comment filter reloaded: {comment_filter:?}");
+    }
+    if config.sanity_range.replace(sanity_range) {
+        info!("Sanity range reloaded: {sanity_range:?}");
+    }
+    if let Some(recipient_pubkeys) = recipient_pubkeys {
+        if config.recipient_pubkeys.replace(recipient_pubkeys.clone()) {
+            info!(
+                "Recipient pubkeys reloaded, {} pubkeys",
+                recipient_pubkeys.len()
+            );
+        }
+    }
+    if let Some(age) = max_zap_request_age {
+        if config.max_zap_request_age.replace(Some(age)) {
+            info!("Max zap request age reloaded: {age} seconds");
+        }
+    }
+    if let Some(relay_kinds) = relay_kinds {
+        info!("Relay kinds reloaded, {} relays", relay_kinds.len());
+        config.relay_kinds.replace(relay_kinds);
+    }
+    if let Some(prefix) = label_prefix {
+        if config.label_prefix.replace(prefix.clone()) {
+            info!("Label prefix reloaded: {prefix:?}");
+        }
+    }
+    if let Some(max) = max_total_relays {
+        if config.max_total_relays.replace(max) {
+            info!("Max total relays reloaded: {max:?}");
+        }
+    }
+    if let Some(pct) = max_amount_deviation_pct {
+        if config.max_amount_deviation_pct.replace(Some(pct)) {
+            info!("Max amount deviation reloaded: {pct}%");
+        }
+    }
+
+    let restart: Vec<&str> = options
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !HOT_RELOADABLE.contains(name))
+        .collect();
+    if !restart.is_empty() {
+        info!("Changes to any of {restart:?} take effect on restart");
+    }
+
+    Ok(())
+}
+
+/// Reload from `path` on every SIGHUP
+pub fn spawn(state: State, path: PathBuf) -> Result<()> {
+    // Registered before returning, so a SIGHUP from here on is never fatal
+    let mut hangups = signal(SignalKind::hangup())?;
+    // Called at startup, before anything else could have changed the default relays
+    let mut loaded_relays = state
+        .relays
+        .try_read()
+        .map(|relays| relays.clone())
+        .unwrap_or_default();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP, reloading {}", path.display());
+            if let Err(err) = reload(&state, &path, &mut loaded_relays).await {
+                warn!("Could not reload {}: {err}", path.display());
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::relay_url;
+    use crate::tests::test_keys;
+
+    #[tokio::test]
+    async fn test_relays_reloaded_on_sighup() {
+        let path = std::env::temp_dir().join(format!("clnzapper-reload-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "network=regtest\nclnzapper_nostr_relay=wss://old.example\n",
+        )
+        .unwrap();
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("wss://old.example")]),
+            Config::default(),
+        );
+        spawn(state.clone(), path.clone()).unwrap();
+
+        std::fs::write(
+            &path,
+            "# edited\nclnzapper_nostr_relay = wss://new.example\nclnzapper_log_level=debug\n",
+        )
+        .unwrap();
+        unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+
+        let reloaded = HashSet::from([relay_url("wss://new.example")]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while *state.relays.read().await != reloaded {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // A bad file changes nothing
+        std::fs::write(
+            &path,
+            "clnzapper_nostr_relay=wss://newer.example\nclnzapper_payer_blocklist=npub1nope\n",
+        )
+        .unwrap();
+        let mut loaded_relays = reloaded.clone();
+        assert!(reload(&state, &path, &mut loaded_relays).await.is_err());
+        assert_eq!(*state.relays.read().await, reloaded);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_relays_kept_while_option_unchanged() {
+        let path = std::env::temp_dir().join(format!("clnzapper-setrelays-{}", std::process::id()));
+        std::fs::write(&path, "clnzapper_nostr_relay=wss://old.example\n").unwrap();
+        let mut loaded_relays = HashSet::from([relay_url("wss://old.example")]);
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            loaded_relays.clone(),
+            Config::default(),
+        );
+        // As `zapper-setrelays` would
+        let set = HashSet::from([relay_url("wss://a.example"), relay_url("wss://b.example")]);
+        *state.relays.write().await = set.clone();
+
+        reload(&state, &path, &mut loaded_relays).await.unwrap();
+        assert_eq!(*state.relays.read().await, set);
+
+        std::fs::write(&path, "clnzapper_nostr_relay=wss://new.example\n").unwrap();
+        reload(&state, &path, &mut loaded_relays).await.unwrap();
+        let new = HashSet::from([relay_url("wss://new.example")]);
+        assert_eq!(*state.relays.read().await, new);
+        assert_eq!(loaded_relays, new);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checks_reloaded() {
+        let path = std::env::temp_dir().join(format!("clnzapper-checks-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "clnzapper_comment_max_len=10\n\
+                 clnzapper_sanity_max_msat=5000\n\
+                 clnzapper_recipient_pubkeys={}\n\
+                 clnzapper_max_zap_request_age=60\n\
+                 clnzapper_relay_kinds={{\"wss://relay.example\": [1]}}\n\
+                 clnzapper_label_prefix=zap-\n\
+                 clnzapper_max_total_relays=3\n\
+                 clnzapper_max_amount_deviation_pct=5\n",
+                test_keys().public_key()
+            ),
+        )
+        .unwrap();
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config {
+                comment_filter: CommentFilter {
+                    max_len: 280,
+                    strip_urls: true,
+                }
+                .into(),
+                sanity_range: SanityRange {
+                    min_msat: Some(1000),
+                    max_msat: None,
+                }
+                .into(),
+                ..Config::default()
+            },
+        );
+
+        reload(&state, &path, &mut HashSet::new()).await.unwrap();
+        let config = &state.config;
+        // Options missing from the file are left as they are
+        assert_eq!(
+            config.comment_filter.get(),
+            CommentFilter {
+                max_len: 10,
+                strip_urls: true
+            }
+        );
+        assert_eq!(
+            config.sanity_range.get(),
+            SanityRange {
+                min_msat: Some(1000),
+                max_msat: Some(5000)
+            }
+        );
+        assert_eq!(
+            config.recipient_pubkeys.get(),
+            vec![test_keys().public_key()]
+        );
+        assert_eq!(config.max_zap_request_age.get(), Some(60));
+        assert!(!config
+            .relay_kinds
+            .accepts(&relay_url("wss://relay.example"), nostr::Kind::ZapReceipt));
+        assert_eq!(config.label_prefix.get().as_deref(), Some("zap-"));
+        assert_eq!(config.max_total_relays.get(), Some(3));
+        assert_eq!(config.max_amount_deviation_pct.get(), Some(5));
+
+        // Checked against what is kept, so this range is empty
+        std::fs::write(&path, "clnzapper_sanity_min_msat=6000\n").unwrap();
+        assert!(reload(&state, &path, &mut HashSet::new()).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_key_changes_nothing() {
+        let path = std::env::temp_dir().join(format!("clnzapper-badkey-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "clnzapper_nostr_relay=wss://new.example\nclnzapper_label_prefix=zap-\n",
+        )
+        .unwrap();
+        // Loaded directly, so there is nowhere to read it again from
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("wss://old.example")]),
+            Config {
+                key_reload_interval: Some(60),
+                ..Config::default()
+            },
+        );
+
+        let mut loaded_relays = HashSet::from([relay_url("wss://old.example")]);
+        assert!(reload(&state, &path, &mut loaded_relays).await.is_err());
+        assert_eq!(
+            *state.relays.read().await,
+            HashSet::from([relay_url("wss://old.example")])
+        );
+        assert_eq!(state.config.label_prefix.get(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
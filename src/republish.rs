@@ -0,0 +1,111 @@
+//! Receipts published again later, by `clnzapper_republish_intervals`
+//!
+//! Relays come and go, and some drop events, so a receipt published once may not
+//! survive. Each republish goes to the same relays as the first publish. Pending
+//! republishes are held in memory and lost if the plugin restarts.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use nostr::Event;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::relay::broadcast_zap_note;
+use crate::relay_url::RelayUrl;
+
+/// Parse a comma separated list of seconds after the first publish to publish again
+///
+/// `0` stands for the first publish, so `0,60,3600` and `60,3600` are the same.
+pub fn parse_intervals(intervals: &str) -> Result<Vec<Duration>> {
+    let mut offsets = intervals
+        .split(',')
+        .map(str::trim)
+        .filter(|offset| !offset.is_empty())
+        .map(|offset| {
+            offset
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid republish interval {offset}, expected seconds"))
+        })
+        .collect::<Result<Vec<u64>>>()?;
+    offsets.sort_unstable();
+    offsets.dedup();
+
+    Ok(offsets
+        .into_iter()
+        .filter(|offset| *offset > 0)
+        .map(Duration::from_secs)
+        .collect())
+}
+
+/// Publish the receipt again at each offset from now, in the background
+pub fn schedule(
+    relays: Vec<RelayUrl>,
+    zap_note: Event,
+    offsets: Vec<Duration>,
+    config: Arc<Config>,
+) {
+    if offsets.is_empty() {
+        return;
+    }
+
+    let start = Instant::now();
+    tokio::spawn(async move {
+        for offset in offsets {
+            tokio::time::sleep_until(start + offset).await;
+            debug!(
+                "Republishing {} {}s after its first publish",
+                zap_note.id.to_hex(),
+                offset.as_secs()
+            );
+            if let Err(err) = broadcast_zap_note(&relays, zap_note.clone(), &config).await {
+                warn!("Error while republishing zap note: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind};
+
+    use super::*;
+    use crate::relay::tests::mock_relay;
+
+    #[test]
+    fn test_parse_intervals() {
+        assert_eq!(
+            parse_intervals("0, 3600,60").unwrap(),
+            vec![Duration::from_secs(60), Duration::from_secs(3600)]
+        );
+        assert!(parse_intervals("0").unwrap().is_empty());
+        assert!(parse_intervals("60,soon").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_republished() {
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let (relay, received) = mock_relay(None, 2);
+
+        let offsets = vec![Duration::from_millis(50), Duration::from_millis(100)];
+        schedule(
+            vec![relay],
+            zap_note.clone(),
+            offsets,
+            Arc::new(Config::default()),
+        );
+
+        let msgs = tokio::task::spawn_blocking(move || {
+            (0..2)
+                .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+                .collect::<Vec<String>>()
+        })
+        .await
+        .unwrap();
+        assert!(msgs.iter().all(|msg| msg.contains(&zap_note.id.to_hex())));
+    }
+}
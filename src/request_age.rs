@@ -0,0 +1,58 @@
+//! Stale zap requests, by `clnzapper_max_zap_request_age`
+//!
+//! A wallet signs a zap request just before fetching its invoice, so one dated long
+//! before the payment may be an old request replayed against a new invoice. With a
+//! maximum age set, a zap request created more than that many seconds before the
+//! invoice's `paid_at` gets no receipt. It is a heuristic, so it is off by default.
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::WaitanyinvoiceResponse;
+
+use crate::ZapRequestInfo;
+
+/// Check the zap request was created at most `max_age` seconds before the invoice was paid
+pub fn check(
+    max_age: Option<u64>,
+    zap: &ZapRequestInfo,
+    invoice: &WaitanyinvoiceResponse,
+) -> Result<()> {
+    let (Some(max_age), Some(paid_at)) = (max_age, invoice.paid_at) else {
+        return Ok(());
+    };
+
+    let created_at = zap.zap_request.created_at.as_u64();
+    let age = paid_at.saturating_sub(created_at);
+    if age > max_age {
+        return Err(anyhow!(
+            "Zap request created at {created_at} is {age}s older than the payment, over {max_age}s"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_zap_req;
+    use crate::tests::{test_invoice, ZAP_REQ};
+
+    #[test]
+    fn test_stale_request_rejected() {
+        // ZAP_REQ was created at 1680535967 and test_invoice paid at 1687251840, 6716273s later
+        let zap = decode_zap_req(ZAP_REQ).unwrap();
+        let invoice = test_invoice(ZAP_REQ);
+
+        assert!(check(None, &zap, &invoice).is_ok());
+        assert!(check(Some(3600), &zap, &invoice).is_err());
+        assert!(check(Some(6_716_273), &zap, &invoice).is_ok());
+
+        // Requests after the payment aren't stale
+        let mut invoice = test_invoice(ZAP_REQ);
+        invoice.paid_at = Some(1680535967 - 60);
+        assert!(check(Some(3600), &zap, &invoice).is_ok());
+
+        invoice.paid_at = None;
+        assert!(check(Some(3600), &zap, &invoice).is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cln_plugin::{Error, Plugin};
+use cln_rpc::model::ListinvoicesRequest;
+use log::info;
+use nostr::{Keys, Timestamp};
+use serde_json::{json, Value};
+
+use crate::amount::check_zap_amount;
+use crate::cln::Rpc;
+use crate::compliance::ComplianceMode;
+use crate::relay::{apply_scheme_policy, cap_relays, without_inbox, zap_relays};
+use crate::relay_url::RelayUrl;
+use crate::source::paid_invoice;
+use crate::state::State;
+use crate::validate::synthesized_invoice;
+use crate::{create_zap_note, decode_zap_req_with, process_zap, receipt_tags};
+
+/// Dispatch a zapper RPC method by name
+///
+/// Shared by the CLN RPC methods and the control socket
+pub async fn dispatch(state: &State, method: &str, params: Value) -> Result<Value> {
+    match method {
+        "zapper-setrelays" => handle_set_relays(state, params).await,
+        "zapper-status" => handle_status(state).await,
+        "zapper-replay" => handle_replay(state, params).await,
+        "zapper-simulate" => handle_simulate(state, params).await,
+        "zapper-export" => handle_export(state, params).await,
+        "zapper-pause" => handle_pause(state).await,
+        "zapper-resume" => handle_resume(state).await,
+        "zapper-reload-key" => handle_reload_key(state).await,
+        _ => Err(anyhow!("Unknown method {method}")),
+    }
+}
+
+/// `zapper-setrelays`: replace the relays every zap receipt is published to
+pub async fn set_relays(plugin: Plugin<State>, params: Value) -> Result<Value, Error> {
+    handle_set_relays(plugin.state(), params).await
+}
+
+/// `zapper-status`: show the zapper's relays and progress
+pub async fn status(plugin: Plugin<State>, _params: Value) -> Result<Value, Error> {
+    handle_status(plugin.state()).await
+}
+
+/// `zapper-replay`: rebroadcast the zap receipt of a paid invoice
+pub async fn replay(plugin: Plugin<State>, params: Value) -> Result<Value, Error> {
+    handle_replay(plugin.state(), params).await
+}
+
+/// `zapper-simulate`: run a zap request through the pipeline without a payment or broadcast
+pub async fn simulate(plugin: Plugin<State>, params: Value) -> Result<Value, Error> {
+    handle_simulate(plugin.state(), params).await
+}
+
+/// `zapper-export`: write every archived receipt to a file
+pub async fn export(plugin: Plugin<State>, params: Value) -> Result<Value, Error> {
+    handle_export(plugin.state(), params).await
+}
+
+/// `zapper-pause`: hold zaps until `zapper-resume`
+pub async fn pause(plugin: Plugin<State>, _params: Value) -> Result<Value, Error> {
+    handle_pause(plugin.state()).await
+}
+
+/// `zapper-resume`: publish held zaps and carry on
+pub async fn resume(plugin: Plugin<State>, _params: Value) -> Result<Value, Error> {
+    handle_resume(plugin.state()).await
+}
+
+/// `zapper-reload-key`: read the receipt key again to pick up a rotated one
+pub async fn reload_key(plugin: Plugin<State>, _params: Value) -> Result<Value, Error> {
+    handle_reload_key(plugin.state()).await
+}
+
+pub async fn handle_set_relays(state: &State, params: Value) -> Result<Value> {
+    let relays = relays_param(&params)?
+        .iter()
+        .map(|relay| RelayUrl::parse(relay))
+        .collect::<Result<HashSet<RelayUrl>>>()?;
+
+    if relays.is_empty() {
+        return Err(anyhow!("At least one relay is required"));
+    }
+
+    info!("Setting relays to: {relays:?}");
+    *state.relays.write().await = relays.clone();
+
+    let mut relays: Vec<RelayUrl> = relays.into_iter().collect();
+    relays.sort();
+
+    Ok(json!({ "relays": relays }))
+}
+
+pub async fn handle_status(state: &State) -> Result<Value> {
+    let mut relays: Vec<RelayUrl> = state.relays.read().await.iter().cloned().collect();
+    relays.sort();
+
+    Ok(json!({
+        "pubkey": state.keys.public_key().to_string(),
+        "relays": relays,
+        "last_pay_index": state.last_pay_index.load(Ordering::Relaxed),
+        "zaps_broadcast": state.zaps_broadcast.load(Ordering::Relaxed),
+        "paused": state.pause.is_paused(),
+        "skipped": state.skipped.snapshot(),
+        "amount_mismatches_broadcast": state.amount_mismatches.load(Ordering::Relaxed),
+        "extra_nodes": state
+            .extra_nodes
+            .iter()
+            .map(|node| {
+                json!({
+                    "socket": node.socket,
+                    "last_pay_index": node.last_pay_index.load(Ordering::Relaxed),
+                })
+            })
+            .collect::<Vec<Value>>(),
+        "zap_totals": state
+            .config
+            .zap_totals
+            .as_ref()
+            .map(|zap_totals| zap_totals.snapshot(Timestamp::now().as_u64())),
+        "fallback_node": state.fallback_node.as_ref().map(|node| {
+            json!({
+                "socket": node.socket,
+                "last_pay_index": node.last_pay_index.load(Ordering::Relaxed),
+                "active": state.failover.on_fallback(),
+            })
+        }),
+    }))
+}
+
+pub async fn handle_export(state: &State, params: Value) -> Result<Value> {
+    let archive = state
+        .config
+        .archive
+        .as_ref()
+        .ok_or_else(|| anyhow!("zapper-export needs clnzapper_archive to be set"))?;
+    let path = string_param(&params, "path")?;
+
+    let count = archive.export(Path::new(&path)).await?;
+    info!("Exported {count} zap receipts to {path}");
+
+    Ok(json!({ "path": path, "count": count }))
+}
+
+pub async fn handle_pause(state: &State) -> Result<Value> {
+    info!("Pausing zap receipts");
+    state.pause.pause();
+    Ok(json!({ "paused": true }))
+}
+
+pub async fn handle_resume(state: &State) -> Result<Value> {
+    info!("Resuming zap receipts");
+    state.pause.resume();
+    Ok(json!({ "paused": false }))
+}
+
+pub async fn handle_reload_key(state: &State) -> Result<Value> {
+    let keys = state.keys.clone();
+    // The key may come from a command, so don't hold up the runtime reading it
+    let pubkey = tokio::task::spawn_blocking(move || keys.reload()).await??;
+    Ok(json!({ "pubkey": pubkey.to_string() }))
+}
+
+pub async fn handle_replay(state: &State, params: Value) -> Result<Value> {
+    let label = string_param(&params, "label")?;
+
+    let rpc_timeout = state.config.rpc_timeout.map(Duration::from_secs);
+    let mut rpc = Rpc::connect(state.rpc_socket.clone(), rpc_timeout).await?;
+    let request = ListinvoicesRequest {
+        label: Some(label.clone()),
+        invstring: None,
+        payment_hash: None,
+        offer_id: None,
+    };
+    let invoice = rpc
+        .call(request, Duration::ZERO)
+        .await?
+        .invoices
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No invoice with label {label}"))?;
+
+    let invoice = paid_invoice(invoice)?;
+    let zap_request_info = decode_zap_req_with(&invoice.description, state.config.copy_e_tags)?;
+    let zap_note_id = process_zap(state, zap_request_info, invoice).await?;
+
+    Ok(json!({ "id": zap_note_id.to_hex() }))
+}
+
+/// Dry run only: the receipt is signed with a throwaway key and never broadcast,
+/// so a simulated zap can't be passed off as a real one
+pub async fn handle_simulate(state: &State, params: Value) -> Result<Value> {
+    if !state.config.simulate {
+        return Err(anyhow!(
+            "zapper-simulate is disabled, set clnzapper_simulate to enable it"
+        ));
+    }
+
+    let zap_request = match param(&params, "zap_request", 0) {
+        Some(Value::String(zap_request)) => zap_request.clone(),
+        Some(zap_request @ Value::Object(_)) => zap_request.to_string(),
+        _ => return Err(anyhow!("Missing parameter zap_request")),
+    };
+    let amount_msat = param(&params, "amount_msat", 1)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("Missing integer parameter amount_msat"))?;
+
+    let zap_request_info = decode_zap_req_with(&zap_request, state.config.copy_e_tags)?;
+    let invoice = synthesized_invoice(&zap_request, Some(amount_msat), None)?;
+    check_zap_amount(
+        zap_request_info.amount,
+        &invoice,
+        state.config.amount_field,
+        state.config.max_amount_deviation_pct.get(),
+    )?;
+
+    // The synthesized invoice has no description hash for strict mode to check
+    if state.config.compliance_mode == ComplianceMode::Strict {
+        info!("zapper-simulate skips the strict compliance checks");
+    }
+
+    let comment = state
+        .config
+        .comment_filter
+        .get()
+        .apply(&zap_request_info.zap_request.content);
+    let default_relays = state.relays.read().await.clone();
+    let relays = cap_relays(
+        apply_scheme_policy(
+            zap_relays(&default_relays, &zap_request_info.relays),
+            state.config.relay_scheme_policy,
+        ),
+        &default_relays,
+        state.config.max_total_relays.get(),
+    );
+    let extra_tags = receipt_tags(&state.config, &relays, &invoice);
+    let relays = without_inbox(relays, state.config.inbox_relay.as_ref());
+    let zap_note = create_zap_note(
+        &Keys::generate(),
+        zap_request_info,
+        invoice,
+        &extra_tags,
+        false,
+        state.config.max_clock_skew,
+    )?;
+
+    Ok(json!({
+        "receipt": state.config.comment_filter.get().redact_receipt(&zap_note),
+        "comment": comment,
+        "relays": relays,
+        "inbox": state.config.inbox_relay,
+        "broadcast": false,
+    }))
+}
+
+/// Get a param by name from `{"name": ..}` params or by position from `[..]` params
+fn param<'a>(params: &'a Value, name: &str, index: usize) -> Option<&'a Value> {
+    match params {
+        Value::Object(obj) => obj.get(name),
+        Value::Array(arr) => arr.get(index),
+        _ => None,
+    }
+}
+
+/// Get a named string param from either `{"name": ..}` or `[..]` params
+fn string_param(params: &Value, name: &str) -> Result<String> {
+    param(params, name, 0)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Missing string parameter {name}"))
+}
+
+/// Get the relay list from either `{"relays": [..]}`, `[[..]]` or `[..]` params
+fn relays_param(params: &Value) -> Result<Vec<String>> {
+    let relays = match params {
+        Value::Object(obj) => obj.get("relays"),
+        Value::Array(arr) if matches!(arr.first(), Some(Value::Array(_))) => arr.first(),
+        Value::Array(_) => Some(params),
+        _ => None,
+    };
+
+    relays
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("Expected a list of relays"))?
+        .iter()
+        .map(|relay| {
+            relay
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Relay {relay} is not a string"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::relay_url;
+    use crate::tests::ZAP_REQ;
+
+    fn test_state() -> State {
+        State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("ws://localhost:8080")]),
+            Config::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        let state = test_state();
+
+        dispatch(&state, "zapper-pause", json!({})).await.unwrap();
+        let status = dispatch(&state, "zapper-status", json!({})).await.unwrap();
+        assert_eq!(status["paused"], true);
+        assert_eq!(status["skipped"]["not-a-zap"], 0);
+
+        dispatch(&state, "zapper-resume", json!([])).await.unwrap();
+        assert!(!state.pause.is_paused());
+    }
+
+    #[tokio::test]
+    async fn test_set_relays() {
+        let state = test_state();
+
+        let res = handle_set_relays(
+            &state,
+            json!({"relays": ["wss://relay.damus.io", "wss://nos.lol"]}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res,
+            json!({"relays": ["wss://nos.lol", "wss://relay.damus.io"]})
+        );
+        assert_eq!(state.relays.read().await.len(), 2);
+
+        handle_set_relays(&state, json!([["wss://nos.lol"]]))
+            .await
+            .unwrap();
+        assert_eq!(
+            *state.relays.read().await,
+            HashSet::from([relay_url("wss://nos.lol")])
+        );
+
+        // Invalid relays leave the set untouched
+        assert!(handle_set_relays(&state, json!(["wss://nos.lol", "nope"]))
+            .await
+            .is_err());
+        assert!(handle_set_relays(&state, json!({"relays": []}))
+            .await
+            .is_err());
+        assert_eq!(state.relays.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_simulate() {
+        let params = json!({"zap_request": ZAP_REQ, "amount_msat": 21000});
+
+        // Off unless enabled
+        assert!(handle_simulate(&test_state(), params.clone())
+            .await
+            .is_err());
+
+        let mut state = test_state();
+        state.config = Arc::new(Config {
+            simulate: true,
+            ..Config::default()
+        });
+
+        let res = dispatch(&state, "zapper-simulate", params).await.unwrap();
+        assert_eq!(res["broadcast"], false);
+        assert_eq!(res["receipt"]["kind"], 9735);
+        assert_eq!(res["comment"], Value::Null);
+        assert_ne!(
+            res["receipt"]["pubkey"],
+            state.keys.public_key().to_string()
+        );
+        assert!(res["relays"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("ws://localhost:8080")));
+
+        assert!(handle_simulate(&state, json!([ZAP_REQ])).await.is_err());
+        assert!(handle_simulate(&state, json!(["not json", 21000]))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let state = test_state();
+        state.last_pay_index.store(42, Ordering::Relaxed);
+
+        let status = dispatch(&state, "zapper-status", json!({})).await.unwrap();
+        assert_eq!(status["last_pay_index"], 42);
+        assert_eq!(status["relays"], json!(["ws://localhost:8080"]));
+
+        assert!(dispatch(&state, "zapper-replay", json!({})).await.is_err());
+        assert!(dispatch(&state, "zapper-nope", json!({})).await.is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Per-relay send buffers, by `clnzapper_relay_send_buffer`
+//!
+//! Without buffers every zap contacts its relays directly, so a burst of zaps has
+//! many tasks talking to the same relay at once. With them, each relay gets a queue
+//! drained by a single writer task, which sends one event at a time in the order
+//! they were queued. A full queue either makes the broadcast wait for room
+//! (`block`) or drops the oldest event still waiting (`drop-oldest`). A writer
+//! with nothing queued for `IDLE_TIMEOUT` ends and its buffer is dropped, so the
+//! payer relays of past zaps don't pile up.
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use nostr::EventId;
+use tokio::sync::{oneshot, Notify};
+
+use crate::metrics::Metrics;
+use crate::relay::{send_event, Ack};
+use crate::relay_url::RelayUrl;
+
+/// How long a relay's writer waits with nothing queued before it ends
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Buffers by relay
+type Buffers = Arc<Mutex<HashMap<RelayUrl, Arc<Buffer>>>>;
+
+/// What happens to an event sent to a relay whose buffer is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the writer to make room
+    #[default]
+    Block,
+    /// Drop the oldest event waiting to make room
+    DropOldest,
+}
+
+impl FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => Err(anyhow!(
+                "Invalid relay send overflow {s}, expected block or drop-oldest"
+            )),
+        }
+    }
+}
+
+/// An event waiting to be sent to a relay
+#[derive(Debug)]
+struct Job {
+    msg: String,
+    id: EventId,
+    headers: Option<HashMap<String, String>>,
+    verify_delivery: bool,
+    /// Where failures are counted, if anywhere
+    metrics: Option<Arc<Metrics>>,
+    /// Told the relay's acknowledgement once sent, dropped if the event is dropped
+    done: oneshot::Sender<Option<Ack>>,
+}
+
+/// Queue of one relay
+#[derive(Debug)]
+struct Buffer {
+    capacity: usize,
+    overflow: Overflow,
+    jobs: Mutex<VecDeque<Job>>,
+    /// Woken when a job is queued
+    pushed: Notify,
+    /// Woken when a job is taken, for senders blocked on a full queue
+    popped: Notify,
+}
+
+impl Buffer {
+    fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            overflow,
+            jobs: Mutex::new(VecDeque::new()),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        }
+    }
+
+    /// Queue the job if there is room, or drop the oldest for it under `DropOldest`
+    ///
+    /// Returns the job back if it has to wait for room, and the job dropped, if any.
+    fn try_push(&self, job: Job) -> (Option<Job>, Option<Job>) {
+        let mut jobs = self.jobs.lock().expect("Lock not poisoned");
+        let dropped = match (jobs.len() < self.capacity, self.overflow) {
+            (true, _) => None,
+            (false, Overflow::Block) => return (Some(job), None),
+            (false, Overflow::DropOldest) => jobs.pop_front(),
+        };
+        jobs.push_back(job);
+        self.pushed.notify_one();
+        (None, dropped)
+    }
+
+    async fn push(&self, relay: &RelayUrl, mut job: Job) {
+        loop {
+            let popped = self.popped.notified();
+            tokio::pin!(popped);
+            // Registered before trying so a pop in between still wakes us
+            popped.as_mut().enable();
+
+            match self.try_push(job) {
+                (None, dropped) => {
+                    if let Some(dropped) = dropped {
+                        warn!(
+                            "Send buffer of {relay} is full, dropped {}",
+                            dropped.id.to_hex()
+                        );
+                    }
+                    return;
+                }
+                (Some(waiting), _) => job = waiting,
+            }
+            popped.await;
+        }
+    }
+
+    async fn pop(&self) -> Job {
+        loop {
+            let job = self.jobs.lock().expect("Lock not poisoned").pop_front();
+            if let Some(job) = job {
+                self.popped.notify_waiters();
+                return job;
+            }
+            self.pushed.notified().await;
+        }
+    }
+}
+
+/// Send buffers of the relays recently sent to, created as relays are used
+#[derive(Debug)]
+pub struct SendBuffers {
+    capacity: usize,
+    overflow: Overflow,
+    buffers: Buffers,
+    /// How long a writer waits with nothing queued before it ends
+    idle_timeout: Duration,
+}
+
+impl SendBuffers {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            capacity,
+            overflow,
+            buffers: Buffers::default(),
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+
+    /// Queue the event message for the relay, returning its acknowledgement once sent,
+    /// `None` if it couldn't be sent or was dropped from a full buffer
+    pub async fn send(
+        &self,
+        relay: &RelayUrl,
+        headers: Option<HashMap<String, String>>,
+        msg: String,
+        id: EventId,
+        verify_delivery: bool,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Option<Ack> {
+        let (done, ack) = oneshot::channel();
+        let job = Job {
+            msg,
+            id,
+            headers,
+            verify_delivery,
+            metrics,
+            done,
+        };
+        self.buffer(relay).push(relay, job).await;
+        ack.await.ok().flatten()
+    }
+
+    /// The relay's buffer, starting its writer if it has none
+    fn buffer(&self, relay: &RelayUrl) -> Arc<Buffer> {
+        let mut buffers = self.buffers.lock().expect("Lock not poisoned");
+        if let Some(buffer) = buffers.get(relay) {
+            return buffer.clone();
+        }
+
+        let buffer = Arc::new(Buffer::new(self.capacity, self.overflow));
+        buffers.insert(relay.clone(), buffer.clone());
+        tokio::spawn(write(
+            relay.clone(),
+            buffer.clone(),
+            self.buffers.clone(),
+            self.idle_timeout,
+        ));
+        buffer
+    }
+}
+
+/// Send the relay's queued events one at a time, in order, ending once idle
+async fn write(relay: RelayUrl, buffer: Arc<Buffer>, buffers: Buffers, idle_timeout: Duration) {
+    loop {
+        let Ok(job) = tokio::time::timeout(idle_timeout, buffer.pop()).await else {
+            let mut buffers = buffers.lock().expect("Lock not poisoned");
+            // Held only by the map and this writer, so no event is on its way in, and
+            // none can be while the map is locked
+            let empty = buffer.jobs.lock().expect("Lock not poisoned").is_empty();
+            if empty && Arc::strong_count(&buffer) == 2 {
+                buffers.remove(&relay);
+                return;
+            }
+            continue;
+        };
+        let relay = relay.clone();
+        // tungstenite is blocking so keep it off the async workers
+        let ack = tokio::task::spawn_blocking(move || {
+            let ack = send_event(
+                &relay,
+                job.headers.as_ref(),
+                job.msg,
+                &job.id,
+                job.verify_delivery,
+                job.metrics.as_deref(),
+            );
+            job.done.send(ack).ok();
+        })
+        .await;
+        if let Err(err) = ack {
+            warn!("Send buffer writer task failed: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nostr::{ClientMessage, EventBuilder, Keys, Kind, RelayMessage};
+
+    use super::*;
+    use crate::relay::tests::mock_relay_replying;
+
+    fn job(id: EventId) -> (Job, oneshot::Receiver<Option<Ack>>) {
+        let (done, ack) = oneshot::channel();
+        let job = Job {
+            msg: String::new(),
+            id,
+            headers: None,
+            verify_delivery: false,
+            metrics: None,
+            done,
+        };
+        (job, ack)
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!("block".parse::<Overflow>().unwrap(), Overflow::Block);
+        assert_eq!(
+            "drop-oldest".parse::<Overflow>().unwrap(),
+            Overflow::DropOldest
+        );
+        assert!("drop-newest".parse::<Overflow>().is_err());
+
+        let ids: Vec<EventId> = (0..3u8)
+            .map(|i| EventId::from_hex(format!("{i:02x}").repeat(32)).unwrap())
+            .collect();
+
+        let buffer = Buffer::new(2, Overflow::DropOldest);
+        let mut acks = vec![];
+        for id in &ids {
+            let (job, ack) = job(*id);
+            assert!(buffer.try_push(job).0.is_none());
+            acks.push(ack);
+        }
+        let queued: Vec<EventId> = buffer.jobs.lock().unwrap().iter().map(|j| j.id).collect();
+        assert_eq!(queued, ids[1..]);
+        // The dropped job's sender is gone once it is dropped
+        assert!(acks[0].try_recv().is_err());
+
+        let buffer = Buffer::new(2, Overflow::Block);
+        for id in &ids[..2] {
+            assert!(buffer.try_push(job(*id).0).0.is_none());
+        }
+        assert!(buffer.try_push(job(ids[2]).0).0.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_under_concurrent_sends() {
+        let (relay, received) = mock_relay_replying(None, 10, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let keys = Keys::generate();
+        let zap_notes: Vec<_> = (0..10)
+            .map(|i| {
+                EventBuilder::new(Kind::ZapReceipt, format!("{i}"), &[])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+
+        let buffers = Arc::new(SendBuffers::new(4, Overflow::Block));
+        let sends = zap_notes.iter().map(|zap_note| {
+            let (buffers, relay) = (buffers.clone(), relay.clone());
+            let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+            let id = zap_note.id;
+            async move { buffers.send(&relay, None, msg, id, false, None).await }
+        });
+        let acks = futures::future::join_all(sends).await;
+        assert!(acks.iter().all(|ack| *ack == Some(Ack::Accepted)));
+
+        let order: Vec<EventId> = tokio::task::spawn_blocking(move || {
+            (0..10)
+                .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+                .map(|msg| match ClientMessage::from_json(msg).unwrap() {
+                    ClientMessage::Event(event) => event.id,
+                    _ => panic!("Expected an event"),
+                })
+                .collect()
+        })
+        .await
+        .unwrap();
+        let sent: Vec<EventId> = zap_notes.iter().map(|zap_note| zap_note.id).collect();
+        assert_eq!(order, sent);
+    }
+
+    #[tokio::test]
+    async fn test_idle_buffer_dropped() {
+        let (relay, _received) = mock_relay_replying(None, 2, |msg| {
+            let ClientMessage::Event(event) = ClientMessage::from_json(msg).unwrap() else {
+                return None;
+            };
+            Some(RelayMessage::new_ok(event.id, true, "").as_json())
+        });
+        let zap_note = EventBuilder::new(Kind::ZapReceipt, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let buffers = SendBuffers {
+            idle_timeout: Duration::from_millis(100),
+            ..SendBuffers::new(4, Overflow::Block)
+        };
+        let send = || {
+            let msg = ClientMessage::new_event(zap_note.clone()).as_json();
+            buffers.send(&relay, None, msg, zap_note.id, false, None)
+        };
+
+        assert_eq!(send().await, Some(Ack::Accepted));
+        assert_eq!(buffers.buffers.lock().unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(buffers.buffers.lock().unwrap().is_empty());
+
+        // And comes back when the relay is next sent to
+        assert_eq!(send().await, Some(Ack::Accepted));
+    }
+}
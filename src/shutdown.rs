@@ -0,0 +1,186 @@
+//! What the zapper does on its way out
+//!
+//! Once the invoice streams stop, from lightningd's `shutdown` notification or the
+//! streams ending, each node's last pay index is written out again so the next run
+//! resumes from it, then one line sums up the run for the operator's logs.
+//!
+//! Stopping sets the shared `Shutdown` token. The invoice streams wait on it
+//! alongside CLN, so they end at once rather than when `waitanyinvoice` next
+//! returns, and an invoice read but not yet handed on is left for the next run.
+//! Zaps already being published get `SHUTDOWN_GRACE` to finish.
+
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::node::Node;
+use crate::state::State;
+
+/// How long zaps being published when stopping get to finish
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Set once the zapper is stopping, so waits can end early
+#[derive(Debug)]
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    /// Wait until shutdown is requested, returning at once if it already was
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        while !*requested.borrow_and_update() {
+            // The sender lives as long as `self`
+            if requested.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Run the zapper until `stop` completes, then stop the invoice streams and give
+/// zaps being published up to `SHUTDOWN_GRACE` to finish
+pub async fn run_until<R, S>(state: &State, run: R, stop: S) -> Result<()>
+where
+    R: Future<Output = Result<()>>,
+    S: Future<Output = Result<()>>,
+{
+    tokio::pin!(run);
+    tokio::select! {
+        result = &mut run => result,
+        result = stop => {
+            state.shutdown.request();
+            match tokio::time::timeout(SHUTDOWN_GRACE, run).await {
+                Ok(Err(err)) => warn!("Error while stopping: {err}"),
+                Ok(Ok(())) => (),
+                Err(_) => warn!(
+                    "Zaps still being published after {}s, stopping anyway",
+                    SHUTDOWN_GRACE.as_secs()
+                ),
+            }
+            result
+        }
+    }
+}
+
+/// Flush the pay index of every node and log the session summary
+pub fn finish(state: &State, nodes: &[Node]) {
+    for node in nodes {
+        // Past invoices read but not done with, by `clnzapper_index_after_publish`
+        let idx = match state.config.index_after_publish {
+            true => node.index_window.persisted(),
+            false => node.last_pay_index.load(Ordering::Relaxed),
+        };
+        // Not read yet, and writing 0 would pass over `clnzapper_backfill` next start
+        if idx == 0 {
+            continue;
+        }
+        if let Err(err) = node.index_writes.write_final(&node.pay_index_path, idx) {
+            warn!(
+                "Could not write last pay index of {} on shutdown: {err}",
+                node.socket.display()
+            );
+        }
+    }
+    info!("{}", summary(state, nodes));
+}
+
+/// One line of `key=value` counters of the session
+fn summary(state: &State, nodes: &[Node]) -> String {
+    let zaps = state.zaps_broadcast.load(Ordering::Relaxed);
+    let failed = state.broadcast_failures.load(Ordering::Relaxed);
+    let pay_indexes: Vec<String> = nodes
+        .iter()
+        .map(|node| node.last_pay_index.load(Ordering::Relaxed).to_string())
+        .collect();
+
+    format!(
+        "Session summary: uptime_secs={} zaps_processed={zaps} broadcast_succeeded={} broadcast_failed={failed} last_pay_index={}",
+        state.started_at.elapsed().as_secs(),
+        zaps.saturating_sub(failed),
+        pay_indexes.join(","),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::read_last_pay_index;
+    use crate::tests::test_keys;
+
+    #[test]
+    fn test_finish() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-shutdown-{}", std::process::id()));
+        let node = Node::new(PathBuf::from("lightning-rpc"), dir.join("last_pay_index"));
+        let state = State::new(
+            test_keys(),
+            node.socket.clone(),
+            HashSet::new(),
+            Config::default(),
+        );
+
+        // Three zaps processed, one of which no relay accepted
+        state.zaps_broadcast.store(3, Ordering::Relaxed);
+        state.broadcast_failures.store(1, Ordering::Relaxed);
+        node.last_pay_index.store(42, Ordering::Relaxed);
+
+        finish(&state, std::slice::from_ref(&node));
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 42);
+
+        let summary = summary(&state, &[node]);
+        assert!(summary.starts_with("Session summary: uptime_secs=0 "));
+        assert!(summary.ends_with(
+            "zaps_processed=3 broadcast_succeeded=2 broadcast_failed=1 last_pay_index=42"
+        ));
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_run_until_stops_run() {
+        let state = State::new(
+            test_keys(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        // Stands in for `run`, which ends once its streams see the token
+        let run = {
+            let state = state.clone();
+            async move {
+                state.shutdown.requested().await;
+                Ok(())
+            }
+        };
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            run_until(&state, run, async { Ok(()) }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        // Already requested, so waiting again returns at once
+        state.shutdown.requested().await;
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why a paid invoice got no zap receipt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Not created for the zapper, by `clnzapper_label_prefix`
+    NotOurs,
+    Keysend,
+    NotBolt11,
+    /// Neither a bolt11 nor a bolt12, so a receipt would reference no invoice
+    NoInvoice,
+    /// The description isn't a zap request, e.g. a plain invoice
+    NotZap,
+    /// The description looks like a zap request but isn't a valid one
+    Malformed,
+    AmountMismatch,
+    /// An amount mismatch with `clnzapper_amount_mismatch=reject`
+    AmountRejected,
+    /// Fails a `clnzapper_compliance_mode` check
+    NonCompliant,
+    /// For a recipient not in `clnzapper_recipient_pubkeys`
+    WrongRecipient,
+    /// The zapped event wasn't found, by `clnzapper_verify_zapped_event`
+    ZappedEventMissing,
+    /// Created too long before the payment, by `clnzapper_max_zap_request_age`
+    StaleRequest,
+    /// Outside `clnzapper_sanity_min_msat` and `clnzapper_sanity_max_msat`
+    AmountOutOfRange,
+    /// Signed by a payer on `clnzapper_payer_blocklist`
+    BlockedPayer,
+    /// A retry of a zap request already read, by `clnzapper_coalesce_window_secs`
+    Coalesced,
+}
+
+impl SkipReason {
+    const ALL: [Self; 15] = [
+        Self::NotOurs,
+        Self::Keysend,
+        Self::NotBolt11,
+        Self::NoInvoice,
+        Self::NotZap,
+        Self::Malformed,
+        Self::AmountMismatch,
+        Self::AmountRejected,
+        Self::NonCompliant,
+        Self::WrongRecipient,
+        Self::ZappedEventMissing,
+        Self::StaleRequest,
+        Self::AmountOutOfRange,
+        Self::BlockedPayer,
+        Self::Coalesced,
+    ];
+
+    /// Key of the reason in `zapper-status`
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::NotOurs => "not-ours",
+            Self::Keysend => "keysend",
+            Self::NotBolt11 => "not-bolt11",
+            Self::NoInvoice => "no-invoice",
+            Self::NotZap => "not-a-zap",
+            Self::Malformed => "malformed",
+            Self::AmountMismatch => "amount-mismatch",
+            Self::AmountRejected => "amount-rejected",
+            Self::NonCompliant => "non-compliant",
+            Self::WrongRecipient => "wrong-recipient",
+            Self::ZappedEventMissing => "zapped-event-missing",
+            Self::StaleRequest => "stale-request",
+            Self::AmountOutOfRange => "amount-out-of-range",
+            Self::BlockedPayer => "blocked-payer",
+            Self::Coalesced => "coalesced",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotOurs => "label not from the zapper",
+            Self::Keysend => "keysend payment",
+            Self::NotBolt11 => "not a bolt11 invoice",
+            Self::NoInvoice => "neither a bolt11 nor a bolt12 invoice",
+            Self::NotZap => "not a zap request",
+            Self::Malformed => "malformed zap request",
+            Self::AmountMismatch => "amount mismatch",
+            Self::AmountRejected => "amount mismatch rejected",
+            Self::NonCompliant => "not compliant",
+            Self::WrongRecipient => "unexpected recipient",
+            Self::ZappedEventMissing => "zapped event not found",
+            Self::StaleRequest => "zap request older than the payment",
+            Self::AmountOutOfRange => "invoice amount out of the sanity range",
+            Self::BlockedPayer => "payer blocklisted",
+            Self::Coalesced => "retry of a zap request already read",
+        })
+    }
+}
+
+/// Invoices skipped since startup, by reason
+#[derive(Debug, Default)]
+pub struct SkipCounts {
+    counts: [AtomicU64; SkipReason::ALL.len()],
+}
+
+impl SkipCounts {
+    pub fn count(&self, reason: SkipReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        SkipReason::ALL
+            .iter()
+            .map(|reason| {
+                let count = self.counts[*reason as usize].load(Ordering::Relaxed);
+                (reason.key(), count)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_counts() {
+        let counts = SkipCounts::default();
+        counts.count(SkipReason::NotZap);
+        counts.count(SkipReason::NotZap);
+        counts.count(SkipReason::Malformed);
+
+        let snapshot = counts.snapshot();
+        assert_eq!(snapshot["not-a-zap"], 2);
+        assert_eq!(snapshot["malformed"], 1);
+        assert_eq!(snapshot["amount-mismatch"], 0);
+        assert_eq!(snapshot.len(), SkipReason::ALL.len());
+    }
+}
@@ -0,0 +1,396 @@
+//! Where paid invoices come from
+//!
+//! `wait` (the default) blocks on `waitanyinvoice`. `poll` is a fallback for nodes
+//! where `waitanyinvoice` is unreliable, periodically asking `listinvoices` for the
+//! invoices paid since the last pay index seen and returning them in pay index order.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::{
+    ListinvoicesInvoices, ListinvoicesInvoicesStatus, ListinvoicesRequest, WaitanyinvoiceRequest,
+    WaitanyinvoiceResponse, WaitanyinvoiceStatus,
+};
+use cln_rpc::primitives::RpcError;
+use futures::future::BoxFuture;
+use log::{trace, warn};
+use tokio::time::Instant;
+
+use crate::cln::Rpc;
+use crate::config::Config;
+use crate::watchdog;
+
+/// Seconds between polls when `clnzapper_poll_interval` is not set
+pub const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+/// Error code of `waitanyinvoice` when the timeout passes with nothing paid
+const WAIT_TIMED_OUT: i32 = 904;
+
+/// Value of `clnzapper_invoice_source`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceKind {
+    #[default]
+    Wait,
+    Poll,
+}
+
+impl FromStr for SourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wait" => Ok(Self::Wait),
+            "poll" => Ok(Self::Poll),
+            _ => Err(anyhow!("Invalid invoice source {s}, expected wait or poll")),
+        }
+    }
+}
+
+/// Paid invoices in pay index order
+pub trait InvoiceSource: Send {
+    /// Next invoice paid after the last one returned, `None` if nothing was paid for a while
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>>;
+}
+
+/// Connect the configured invoice source, resuming after `last_pay_index`
+pub async fn connect(
+    rpc_socket: &Path,
+    last_pay_index: Option<u64>,
+    config: &Config,
+) -> Result<Box<dyn InvoiceSource>> {
+    let rpc_timeout = config.rpc_timeout.map(Duration::from_secs);
+    let rpc = Rpc::connect(rpc_socket.to_path_buf(), rpc_timeout).await?;
+
+    Ok(match config.invoice_source {
+        SourceKind::Wait => {
+            // waitanyinvoice has to return within the rpc timeout or it would count as stuck
+            let timeout = match (
+                watchdog::wait_timeout(config.watchdog_timeout),
+                config.rpc_timeout,
+            ) {
+                (Some(wait), Some(rpc)) => Some(wait.min(rpc)),
+                (wait, rpc) => wait.or(rpc),
+            };
+            Box::new(WaitAnyInvoice {
+                rpc,
+                last_pay_index,
+                timeout,
+            })
+        }
+        SourceKind::Poll => Box::new(Poll::new(
+            rpc,
+            last_pay_index,
+            Duration::from_secs(config.poll_interval),
+        )),
+    })
+}
+
+/// Blocks on `waitanyinvoice`
+struct WaitAnyInvoice {
+    rpc: Rpc,
+    last_pay_index: Option<u64>,
+    /// `waitanyinvoice` timeout in seconds, if any
+    timeout: Option<u64>,
+}
+
+impl InvoiceSource for WaitAnyInvoice {
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+        Box::pin(async move {
+            let invoice =
+                wait_any_invoice(&mut self.rpc, self.last_pay_index, self.timeout).await?;
+            if let Some(invoice) = &invoice {
+                self.last_pay_index = next_pay_index(self.last_pay_index, invoice);
+            }
+            Ok(invoice)
+        })
+    }
+}
+
+/// Polls `listinvoices` every `interval`, returning the invoices paid since the last
+/// one returned before polling again
+struct Poll {
+    rpc: Rpc,
+    last_pay_index: Option<u64>,
+    interval: Duration,
+    /// Paid invoices from the last poll not returned yet, in pay index order
+    paid: VecDeque<WaitanyinvoiceResponse>,
+    /// When `listinvoices` was last called
+    polled_at: Option<Instant>,
+}
+
+impl Poll {
+    fn new(rpc: Rpc, last_pay_index: Option<u64>, interval: Duration) -> Self {
+        Self {
+            rpc,
+            last_pay_index,
+            interval,
+            paid: VecDeque::new(),
+            polled_at: None,
+        }
+    }
+
+    /// Invoices paid after the last pay index, in pay index order
+    async fn poll(&mut self) -> Result<VecDeque<WaitanyinvoiceResponse>> {
+        let request = ListinvoicesRequest {
+            label: None,
+            invstring: None,
+            payment_hash: None,
+            offer_id: None,
+        };
+        let tip = self.last_pay_index.unwrap_or_default();
+        let mut paid: Vec<ListinvoicesInvoices> = self
+            .rpc
+            .call(request, Duration::ZERO)
+            .await?
+            .invoices
+            .into_iter()
+            .filter(|invoice| matches!(invoice.status, ListinvoicesInvoicesStatus::PAID))
+            .filter(|invoice| invoice.pay_index.is_some_and(|pay_index| pay_index > tip))
+            .collect();
+        paid.sort_by_key(|invoice| invoice.pay_index);
+
+        paid.into_iter()
+            .map(|mut invoice| {
+                // Returned as an invoice that isn't a zap rather than listed again every poll
+                invoice.description.get_or_insert_with(String::new);
+                paid_invoice(invoice)
+            })
+            .collect()
+    }
+}
+
+impl InvoiceSource for Poll {
+    fn next_invoice(&mut self) -> BoxFuture<'_, Result<Option<WaitanyinvoiceResponse>>> {
+        Box::pin(async move {
+            if self.paid.is_empty() {
+                if let Some(polled_at) = self.polled_at {
+                    tokio::time::sleep_until(polled_at + self.interval).await;
+                }
+                self.polled_at = Some(Instant::now());
+                self.paid = self.poll().await?;
+            }
+
+            let invoice = self.paid.pop_front();
+            if let Some(invoice) = &invoice {
+                self.last_pay_index = next_pay_index(self.last_pay_index, invoice);
+            }
+            Ok(invoice)
+        })
+    }
+}
+
+/// The first invoice paid after `last_pay_index`, `None` if nothing was within `timeout` seconds
+async fn wait_any_invoice(
+    rpc: &mut Rpc,
+    last_pay_index: Option<u64>,
+    timeout: Option<u64>,
+) -> Result<Option<WaitanyinvoiceResponse>> {
+    let request = WaitanyinvoiceRequest {
+        timeout,
+        lastpay_index: last_pay_index,
+    };
+    let wait = Duration::from_secs(timeout.unwrap_or_default());
+    match rpc.call(request, wait).await {
+        Ok(invoice) => Ok(Some(invoice)),
+        // Nothing paid within the timeout
+        Err(e) if e.downcast_ref::<RpcError>().and_then(|e| e.code) == Some(WAIT_TIMED_OUT) => {
+            trace!("Nothing paid within the waitanyinvoice timeout");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Pay index to resume after once `invoice` is returned
+///
+/// A paid invoice always has a pay index, but should CLN ever leave it out, asking
+/// for invoices after no index would return every invoice ever paid, so the last
+/// known index is kept instead.
+fn next_pay_index(last_pay_index: Option<u64>, invoice: &WaitanyinvoiceResponse) -> Option<u64> {
+    match invoice.pay_index {
+        Some(pay_index) => Some(pay_index),
+        None => {
+            warn!(
+                "Paid invoice {} has no pay index, staying at pay index {last_pay_index:?}",
+                invoice.label
+            );
+            last_pay_index
+        }
+    }
+}
+
+/// Convert a `listinvoices` entry to the paid invoice `waitanyinvoice` would have returned
+pub fn paid_invoice(invoice: ListinvoicesInvoices) -> Result<WaitanyinvoiceResponse> {
+    if !matches!(invoice.status, ListinvoicesInvoicesStatus::PAID) {
+        return Err(anyhow!("Invoice {} is not paid", invoice.label));
+    }
+
+    Ok(WaitanyinvoiceResponse {
+        description: invoice
+            .description
+            .ok_or_else(|| anyhow!("Invoice {} has no description", invoice.label))?,
+        label: invoice.label,
+        payment_hash: invoice.payment_hash,
+        status: WaitanyinvoiceStatus::PAID,
+        expires_at: invoice.expires_at,
+        amount_msat: invoice.amount_msat,
+        bolt11: invoice.bolt11,
+        bolt12: invoice.bolt12,
+        pay_index: invoice.pay_index,
+        amount_received_msat: invoice.amount_received_msat,
+        paid_at: invoice.paid_at,
+        payment_preimage: invoice.payment_preimage,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    pub fn listed(label: &str, status: &str, pay_index: Option<u64>) -> Value {
+        json!({
+            "label": label,
+            "description": "",
+            "payment_hash": "83f34c56502833b28dc64b382ef8462c2f5edb19c427fd5456d46bfc5c35914b",
+            "status": status,
+            "expires_at": 1687338240,
+            "pay_index": pay_index,
+        })
+    }
+
+    #[test]
+    fn test_source_kind() {
+        assert_eq!("wait".parse::<SourceKind>().unwrap(), SourceKind::Wait);
+        assert_eq!("poll".parse::<SourceKind>().unwrap(), SourceKind::Poll);
+        assert!("listinvoices".parse::<SourceKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_poll_source() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-poll-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lightning-rpc");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Answers listinvoices with `listed`, counting the calls, and never answers
+        // waitanyinvoice, as on the nodes polling is for
+        let listed_now = Arc::new(Mutex::new(vec![
+            listed("c", "paid", Some(3)),
+            listed("unpaid", "unpaid", None),
+            listed("a", "paid", Some(1)),
+            listed("b", "paid", Some(2)),
+        ]));
+        let (polled, mut polls) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn({
+            let listed_now = listed_now.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let Ok(request) = serde_json::from_str::<Value>(&line) else {
+                        continue;
+                    };
+                    if request["method"] != "listinvoices" {
+                        continue;
+                    }
+                    polled.send(()).unwrap();
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": { "invoices": *listed_now.lock().unwrap() },
+                    });
+                    write
+                        .write_all(format!("{response}\n\n").as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let rpc = Rpc::connect(path, None).await.unwrap();
+        let mut source = Poll::new(rpc, Some(1), Duration::from_millis(50));
+        // Paid after the last pay index, in pay index order, from a single poll
+        let mut labels = vec![];
+        while let Some(invoice) = source.next_invoice().await.unwrap() {
+            labels.push(invoice.label);
+        }
+        assert_eq!(labels, vec!["b", "c"]);
+        polls.recv().await.unwrap();
+        polls.recv().await.unwrap();
+        assert!(polls.try_recv().is_err());
+
+        // The next poll waits out the interval, and only returns what was paid since
+        listed_now
+            .lock()
+            .unwrap()
+            .push(listed("d", "paid", Some(4)));
+        let start = Instant::now();
+        let invoice = source.next_invoice().await.unwrap().unwrap();
+        assert_eq!(invoice.label, "d");
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(source.next_invoice().await.unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_wait_source_missing_pay_index() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-wait-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lightning-rpc");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Answers waitanyinvoice with a paid invoice lacking its pay index, reporting
+        // the lastpay_index of every request
+        let (requested, mut requests) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(request) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                requested
+                    .send(request["params"]["lastpay_index"].as_u64())
+                    .unwrap();
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": listed("odd", "paid", None),
+                });
+                write
+                    .write_all(format!("{response}\n\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let rpc = Rpc::connect(path, None).await.unwrap();
+        let mut source = WaitAnyInvoice {
+            rpc,
+            last_pay_index: Some(7),
+            timeout: None,
+        };
+        for _ in 0..2 {
+            let invoice = source.next_invoice().await.unwrap().unwrap();
+            assert_eq!(invoice.pay_index, None);
+        }
+        assert_eq!(requests.recv().await.unwrap(), Some(7));
+        assert_eq!(requests.recv().await.unwrap(), Some(7));
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,160 @@
+//! Running the zapper without lightningd, behind the `standalone` feature
+//!
+//! `cln-zapper standalone <rpc socket>` runs the same pipeline the plugin does
+//! against the given rpc socket, which may be a real node's or a mock node serving
+//! the few CLN rpc methods the zapper calls, for end to end tests and development.
+//!
+//! Options are read from the environment, each from its name in upper case, e.g.
+//! `clnzapper_nostr_nsec` from `CLNZAPPER_NOSTR_NSEC`, with the plugin's defaults
+//! for any not set. Logs go to stderr. The zapper RPC methods are only served on
+//! `clnzapper_control_socket`, and it runs until interrupted.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use cln_plugin::options::{ConfigOption, Value};
+use log::{info, Log, Metadata, Record};
+
+use crate::{
+    control, exit, health, lock_indexes, metrics, nodes, options, reload, run as run_zapper,
+    shutdown, startup,
+};
+
+/// Writes log records to stderr
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            writeln!(
+                std::io::stderr(),
+                "{} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+            .ok();
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Run the zapper on the rpc socket given as the second argument
+pub async fn run() -> Result<()> {
+    let rpc_socket = std::env::args()
+        .nth(2)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Usage: cln-zapper standalone <rpc socket>"))?;
+
+    log::set_logger(&StderrLogger).map_err(|err| anyhow!("Could not set logger: {err}"))?;
+
+    let values = env_options(&options(), |name| std::env::var(name).ok()).map_err(exit::config)?;
+    let option = |name: &str| values.get(name).cloned();
+    let (state, pay_index_path) = startup(option, rpc_socket).map_err(exit::config_or_transient)?;
+    let nodes = nodes(&state, pay_index_path);
+    let _index_locks = lock_indexes(&state.config, &nodes)?;
+
+    if let Some(Value::String(path)) = option("clnzapper_control_socket") {
+        control::serve(PathBuf::from(path), state.clone()).await?;
+    }
+    if let (Some(addr), Some(metrics)) = (state.config.metrics_addr, &state.config.metrics) {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
+    if let Some(addr) = state.config.health_addr {
+        health::serve(addr, state.clone()).await?;
+    }
+    if let Some(path) = &state.config.reload_file {
+        reload::spawn(state.clone(), path.clone())?;
+    }
+
+    let interrupted = async {
+        tokio::signal::ctrl_c().await?;
+        info!("Interrupted, stopping");
+        Ok(())
+    };
+    let result = shutdown::run_until(
+        &state,
+        run_zapper(state.clone(), nodes.clone()),
+        interrupted,
+    )
+    .await;
+    shutdown::finish(&state, &nodes);
+    result
+}
+
+/// Value of each option, from the environment variable `env` returns for it or its default
+fn env_options<F>(options: &[ConfigOption], env: F) -> Result<HashMap<String, Value>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    options
+        .iter()
+        .map(|option| {
+            let name = option.name();
+            let var = name.to_uppercase();
+            let value = match (env(&var), option.default()) {
+                (None, default) => default.clone(),
+                (Some(value), Value::String(_) | Value::OptString) => Value::String(value),
+                (Some(value), Value::Integer(_) | Value::OptInteger) => Value::Integer(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("{var} must be an integer for {name}, not {value}"))?,
+                ),
+                (Some(value), Value::Boolean(_) | Value::OptBoolean) => {
+                    Value::Boolean(value.trim().parse().map_err(|_| {
+                        anyhow!("{var} must be true or false for {name}, not {value}")
+                    })?)
+                }
+            };
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_options() {
+        let env = HashMap::from([
+            ("CLNZAPPER_NOSTR_RELAY", "wss://relay.example"),
+            ("CLNZAPPER_RPC_TIMEOUT", "30"),
+            ("CLNZAPPER_SIMULATE", "true"),
+            ("CLNZAPPER_LABEL_PREFIX", "zap-"),
+        ]);
+        let values = env_options(&options(), |var| env.get(var).map(|v| v.to_string())).unwrap();
+
+        assert!(
+            matches!(&values["clnzapper_nostr_relay"], Value::String(relay) if relay == "wss://relay.example")
+        );
+        assert!(matches!(
+            values["clnzapper_rpc_timeout"],
+            Value::Integer(30)
+        ));
+        assert!(matches!(values["clnzapper_simulate"], Value::Boolean(true)));
+        assert!(
+            matches!(&values["clnzapper_label_prefix"], Value::String(prefix) if prefix == "zap-")
+        );
+        // Defaults for the rest
+        assert!(matches!(
+            values["clnzapper_poll_interval"],
+            Value::Integer(5)
+        ));
+        assert!(matches!(values["clnzapper_archive"], Value::OptString));
+
+        let bad = |var: &'static str, value: &'static str| {
+            env_options(&options(), |name| (name == var).then(|| value.to_string())).is_err()
+        };
+        assert!(bad("CLNZAPPER_RPC_TIMEOUT", "soon"));
+        assert!(bad("CLNZAPPER_SIMULATE", "yes"));
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use nostr::Keys;
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+use crate::failover::Failover;
+use crate::keys::ReceiptKeys;
+use crate::nip65::Nip65Relays;
+use crate::node::Node;
+use crate::pause::Pause;
+use crate::published::{OwnReceipts, PublishedReceipts};
+use crate::relay_url::RelayUrl;
+use crate::shutdown::Shutdown;
+use crate::skip::SkipCounts;
+
+/// State shared between the zap processing loop and the plugin's RPC methods
+#[derive(Clone, Debug)]
+pub struct State {
+    /// Keys used to sign zap receipts
+    pub keys: Arc<ReceiptKeys>,
+    /// Path to CLN's rpc socket
+    pub rpc_socket: PathBuf,
+    /// Relays every zap receipt is published to
+    pub relays: Arc<RwLock<HashSet<RelayUrl>>>,
+    /// Settings from the plugin options
+    pub config: Arc<Config>,
+    /// Last pay index seen from CLN
+    pub last_pay_index: Arc<AtomicU64>,
+    /// Number of zap receipts at least one relay accepted since startup
+    pub zaps_broadcast: Arc<AtomicU64>,
+    /// Msat the zap receipts counted in `zaps_broadcast` were for
+    pub msat_broadcast: Arc<AtomicU64>,
+    /// Receipts published despite an amount mismatch, by `clnzapper_amount_mismatch`
+    pub amount_mismatches: Arc<AtomicU64>,
+    /// Zap receipts no relay accepted since startup
+    pub broadcast_failures: Arc<AtomicU64>,
+    /// When the zapper started
+    pub started_at: Instant,
+    /// Unix time in milliseconds the invoice stream last made progress
+    pub stream_heartbeat: Arc<AtomicU64>,
+    /// Receipts we recently published, kept across restarts by `clnzapper_published_log`
+    pub published: Arc<Mutex<PublishedReceipts>>,
+    /// Set by `zapper-pause` to hold zaps for a maintenance window
+    pub pause: Arc<Pause>,
+    /// Invoices skipped since startup, by reason
+    pub skipped: Arc<SkipCounts>,
+    /// Other nodes invoices are read from, by `clnzapper_extra_rpc_sockets`
+    pub extra_nodes: Arc<Vec<Node>>,
+    /// Standby node read while ours is down, by `clnzapper_fallback_rpc_socket`
+    pub fallback_node: Option<Node>,
+    /// Whether the standby node is being read
+    pub failover: Arc<Failover>,
+    /// Recipients' relay lists, by `clnzapper_nip65_relays`
+    pub nip65: Arc<Nip65Relays>,
+    /// Set when the zapper is stopping
+    pub shutdown: Arc<Shutdown>,
+}
+
+impl State {
+    pub fn new(keys: Keys, rpc_socket: PathBuf, relays: HashSet<RelayUrl>, config: Config) -> Self {
+        let published = PublishedReceipts::new(config.published_retention.keep);
+        Self {
+            keys: Arc::new(ReceiptKeys::from(keys)),
+            rpc_socket,
+            relays: Arc::new(RwLock::new(relays)),
+            config: Arc::new(config),
+            last_pay_index: Arc::new(AtomicU64::new(0)),
+            zaps_broadcast: Arc::new(AtomicU64::new(0)),
+            msat_broadcast: Arc::new(AtomicU64::new(0)),
+            amount_mismatches: Arc::new(AtomicU64::new(0)),
+            broadcast_failures: Arc::new(AtomicU64::new(0)),
+            started_at: Instant::now(),
+            stream_heartbeat: Arc::new(AtomicU64::new(0)),
+            published: Arc::new(Mutex::new(published)),
+            pause: Arc::new(Pause::default()),
+            skipped: Arc::new(SkipCounts::default()),
+            extra_nodes: Arc::new(vec![]),
+            fallback_node: None,
+            failover: Arc::new(Failover::default()),
+            nip65: Arc::new(Nip65Relays::default()),
+            shutdown: Arc::new(Shutdown::default()),
+        }
+    }
+
+    /// Our receipts, to leave out of events read back from relays
+    pub fn own_receipts(&self) -> OwnReceipts {
+        OwnReceipts {
+            published: self.published.clone(),
+            pubkey: self.keys.public_key(),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Status file for process supervision, by `clnzapper_status_file`
+//!
+//! Every `clnzapper_status_file_interval` seconds the zapper writes a small JSON
+//! object to the file: its pid, when it started and when the file was last
+//! written, as unix times, the last pay index and the number of default relays.
+//! A supervisor can check the process is alive and the index moving without
+//! going through RPC. Each write replaces the file by renaming a temporary one
+//! over it, so a reader never sees it half written.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use nostr::Timestamp;
+use serde_json::json;
+
+use crate::state::State;
+
+/// Seconds between writes when `clnzapper_status_file_interval` is not set
+pub const DEFAULT_INTERVAL: u64 = 30;
+
+/// Write the status of the zapper to `path`
+async fn write(path: &Path, state: &State) -> Result<()> {
+    let now = Timestamp::now().as_u64();
+    let status = json!({
+        "pid": std::process::id(),
+        "started_at": now.saturating_sub(state.started_at.elapsed().as_secs()),
+        "updated_at": now,
+        "last_pay_index": state.last_pay_index.load(Ordering::Relaxed),
+        "relays": state.relays.read().await.len(),
+    });
+
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, format!("{status}\n")).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Write the status file now and then every `interval`
+pub fn spawn(state: State, path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if let Err(err) = write(&path, &state).await {
+                warn!("Could not write status file {}: {err}", path.display());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nostr::Keys;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::relay_url;
+
+    #[tokio::test]
+    async fn test_status_file_updated() {
+        let path = std::env::temp_dir().join(format!("clnzapper-status-{}", std::process::id()));
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay_url("wss://a.example"), relay_url("wss://b.example")]),
+            Config::default(),
+        );
+        state.last_pay_index.store(7, Ordering::Relaxed);
+
+        spawn(state.clone(), path.clone(), Duration::from_millis(50));
+
+        let read = || async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            serde_json::from_str::<Value>(&tokio::fs::read_to_string(&path).await.unwrap()).unwrap()
+        };
+        let status = read().await;
+        assert_eq!(status["pid"], std::process::id());
+        assert_eq!(status["last_pay_index"], 7);
+        assert_eq!(status["relays"], 2);
+        assert!(status["started_at"].as_u64().unwrap() <= status["updated_at"].as_u64().unwrap());
+
+        // Progress shows up in the next write
+        state.last_pay_index.store(8, Ordering::Relaxed);
+        assert_eq!(read().await["last_pay_index"], 8);
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
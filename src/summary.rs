@@ -0,0 +1,127 @@
+//! Periodic signed summaries of the zaps processed
+//!
+//! With `clnzapper_summary_interval` set, the zapper publishes a kind 1 note signed
+//! with its receipt key every interval, giving the number of receipts published
+//! and the sats they were for since the last summary. Anyone following the key can
+//! see the zapper is alive. Publishing runs on its own task, so a failure is
+//! logged and never holds up receipts.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, warn};
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind};
+
+use crate::relay::broadcast_zap_note;
+use crate::relay_url::RelayUrl;
+use crate::state::State;
+
+/// Hashtag summaries are published with, so they can be found
+const SUMMARY_HASHTAG: &str = "zapper-summary";
+
+/// Summary note of `zaps` receipts for `msat` published over the last `window`
+fn summary_note(keys: &Keys, zaps: u64, msat: u64, window: Duration) -> Result<Event> {
+    let content = format!(
+        "cln-zapper summary: {zaps} zap receipts published for {} sats in the last {}s",
+        msat / 1000,
+        window.as_secs()
+    );
+    let tags = [Tag::Generic(
+        TagKind::Custom("t".to_string()),
+        vec![SUMMARY_HASHTAG.to_string()],
+    )];
+
+    Ok(EventBuilder::new(Kind::TextNote, content, &tags).to_event(keys)?)
+}
+
+/// Publish a summary every `interval` to `relays`, or the default relays if empty
+pub fn spawn(state: State, interval: Duration, relays: Vec<RelayUrl>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick is immediate, with nothing to summarize
+        ticks.tick().await;
+        let (mut last_zaps, mut last_msat) = (0, 0);
+
+        loop {
+            ticks.tick().await;
+            let zaps = state.zaps_broadcast.load(Ordering::Relaxed);
+            let msat = state.msat_broadcast.load(Ordering::Relaxed);
+
+            let note = match summary_note(
+                &state.keys.current(),
+                zaps - last_zaps,
+                msat - last_msat,
+                interval,
+            ) {
+                Ok(note) => note,
+                Err(err) => {
+                    warn!("Error while creating summary: {err}");
+                    continue;
+                }
+            };
+            (last_zaps, last_msat) = (zaps, msat);
+
+            let relays = if relays.is_empty() {
+                state.relays.read().await.iter().cloned().collect()
+            } else {
+                relays.clone()
+            };
+            debug!("Publishing summary {}", note.id.to_hex());
+            if let Err(err) = broadcast_zap_note(&relays, note, &state.config).await {
+                warn!("Error while publishing summary: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use nostr::ClientMessage;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::relay::tests::mock_relay;
+
+    #[test]
+    fn test_summary_note() {
+        let keys = Keys::generate();
+        let note = summary_note(&keys, 3, 21_000, Duration::from_secs(3600)).unwrap();
+        note.verify().unwrap();
+        assert_eq!(note.kind, Kind::TextNote);
+        assert_eq!(note.pubkey, keys.public_key());
+        assert_eq!(
+            note.content,
+            "cln-zapper summary: 3 zap receipts published for 21 sats in the last 3600s"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summary_published() {
+        let (relay, received) = mock_relay(None, 1);
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::from([relay]),
+            Config::default(),
+        );
+        state.zaps_broadcast.store(2, Ordering::Relaxed);
+        state.msat_broadcast.store(5000, Ordering::Relaxed);
+
+        spawn(state.clone(), Duration::from_millis(100), vec![]);
+
+        let msg =
+            tokio::task::spawn_blocking(move || received.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap()
+                .unwrap();
+        let ClientMessage::Event(note) = ClientMessage::from_json(msg).unwrap() else {
+            panic!("Expected an event");
+        };
+        assert_eq!(note.pubkey, state.keys.public_key());
+        assert!(note.content.contains("2 zap receipts published for 5 sats"));
+    }
+}
@@ -0,0 +1,329 @@
+//! Audit records of issued receipts to syslog, by `clnzapper_syslog`
+//!
+//! Each receipt broadcast is recorded as an RFC 5424 message, formatted by the
+//! `syslog` crate, with its details in structured data, sent to the local syslog
+//! socket at the configured facility and severity. The crate only takes numeric
+//! message ids, so records carry MSGID `1`. Receipts no relay accepted are recorded
+//! too, with `accepted="0"`. This is separate from the plugin's own logs, so
+//! operators can route receipt records to their compliance store. Records are sent
+//! without blocking: if the socket is missing or full the record is dropped with a
+//! warning, and the broadcast carries on. `clnzapper_syslog_rate` caps records per
+//! second, with the number it dropped recorded ahead of the next record let through.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use nostr::secp256k1::XOnlyPublicKey;
+use nostr::EventId;
+use syslog::{Formatter5424, LogFormat, LoggerBackend};
+
+/// Socket records are sent to when `clnzapper_syslog_socket` is not set
+pub const DEFAULT_SOCKET: &str = "/dev/log";
+
+/// Structured data id of receipt records, under the enterprise number RFC 5424 reserves for examples
+const SD_ID: &str = "receipt@32473";
+
+const APP_NAME: &str = "cln-zapper";
+
+/// MSGID of receipt records
+const MSG_ID: u32 = 1;
+
+/// Syslog facility, by `clnzapper_syslog_facility`
+#[derive(Clone, Copy, Debug)]
+pub struct Facility(syslog::Facility);
+
+impl Default for Facility {
+    fn default() -> Self {
+        Self(syslog::Facility::LOG_LOCAL0)
+    }
+}
+
+impl FromStr for Facility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse().map(Self).map_err(|()| {
+            anyhow!("Invalid syslog facility {s}, expected a name such as user, daemon, authpriv or local0 to local7")
+        })
+    }
+}
+
+/// Syslog severity, by `clnzapper_syslog_severity`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Severity(u8);
+
+impl Default for Severity {
+    fn default() -> Self {
+        // info
+        Self(6)
+    }
+}
+
+impl FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        const NAMES: [&str; 8] = [
+            "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+        ];
+        NAMES
+            .iter()
+            .position(|name| *name == s)
+            .map(|code| Self(code as u8))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid syslog severity {s}, expected one of {}",
+                    NAMES.join(", ")
+                )
+            })
+    }
+}
+
+impl Severity {
+    fn level(self) -> syslog::Severity {
+        use syslog::Severity::*;
+        [
+            LOG_EMERG,
+            LOG_ALERT,
+            LOG_CRIT,
+            LOG_ERR,
+            LOG_WARNING,
+            LOG_NOTICE,
+            LOG_INFO,
+            LOG_DEBUG,
+        ][self.0 as usize]
+    }
+}
+
+/// What is recorded of an issued receipt
+#[derive(Debug)]
+pub struct ReceiptRecord<'a> {
+    pub id: EventId,
+    pub recipient: Option<XOnlyPublicKey>,
+    pub payer: XOnlyPublicKey,
+    pub msat: u64,
+    pub label: &'a str,
+    pub created_at: u64,
+    /// Relays that accepted the receipt
+    pub accepted: usize,
+}
+
+#[derive(Debug)]
+struct RateWindow {
+    start: Instant,
+    sent: u32,
+    dropped: u64,
+}
+
+/// Sends receipt records to syslog
+#[derive(Debug)]
+pub struct SyslogSink {
+    socket: PathBuf,
+    formatter: Formatter5424,
+    severity: Severity,
+    max_per_sec: Option<u32>,
+    window: Mutex<RateWindow>,
+}
+
+impl SyslogSink {
+    pub fn new(
+        socket: PathBuf,
+        facility: Facility,
+        severity: Severity,
+        max_per_sec: Option<u32>,
+    ) -> Self {
+        Self {
+            socket,
+            formatter: Formatter5424 {
+                facility: facility.0,
+                process: APP_NAME.to_string(),
+                pid: std::process::id(),
+                ..Formatter5424::default()
+            },
+            severity,
+            max_per_sec,
+            window: Mutex::new(RateWindow {
+                start: Instant::now(),
+                sent: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Record an issued receipt, unless over the rate limit
+    pub fn record(&self, receipt: &ReceiptRecord) {
+        let dropped = {
+            let mut window = self.window.lock().expect("Lock not poisoned");
+            let mut dropped = 0;
+            if window.start.elapsed() >= Duration::from_secs(1) {
+                dropped = std::mem::take(&mut window.dropped);
+                window.start = Instant::now();
+                window.sent = 0;
+            }
+            if self.max_per_sec.is_some_and(|max| window.sent >= max) {
+                window.dropped += 1;
+                return;
+            }
+            window.sent += 1;
+            dropped
+        };
+
+        if dropped > 0 {
+            self.send(
+                BTreeMap::from([("dropped".to_string(), dropped.to_string())]),
+                format!("{dropped} receipt records dropped by clnzapper_syslog_rate"),
+            );
+        }
+
+        let mut params = BTreeMap::from([
+            ("id".to_string(), receipt.id.to_hex()),
+            ("payer".to_string(), receipt.payer.to_string()),
+            ("msat".to_string(), receipt.msat.to_string()),
+            ("label".to_string(), receipt.label.to_string()),
+            ("created_at".to_string(), receipt.created_at.to_string()),
+            ("accepted".to_string(), receipt.accepted.to_string()),
+        ]);
+        if let Some(recipient) = receipt.recipient {
+            params.insert("recipient".to_string(), recipient.to_string());
+        }
+        self.send(
+            params,
+            format!("Zap receipt {} issued", receipt.id.to_hex()),
+        );
+    }
+
+    /// Send `params` under `SD_ID` and `msg` as an RFC 5424 message
+    fn send(&self, params: BTreeMap<String, String>, msg: String) {
+        let data = BTreeMap::from([(SD_ID.to_string(), params)]);
+        let sent =
+            syslog::unix_custom(self.formatter.clone(), &self.socket).and_then(|mut logger| {
+                // The crate's sockets block, and a busy syslog mustn't hold up receipts
+                match &logger.backend {
+                    LoggerBackend::Unix(socket) => socket.set_nonblocking(true)?,
+                    LoggerBackend::UnixStream(socket) => socket.get_ref().set_nonblocking(true)?,
+                    _ => {}
+                }
+                logger.formatter.format(
+                    &mut logger.backend,
+                    self.severity.level(),
+                    (MSG_ID, data, msg),
+                )
+            });
+        if let Err(err) = sent {
+            warn!(
+                "Could not send receipt record to syslog at {}: {err}",
+                self.socket.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use nostr::Keys;
+
+    use super::*;
+
+    fn listen(name: &str) -> (PathBuf, UnixDatagram) {
+        let path =
+            std::env::temp_dir().join(format!("clnzapper-syslog-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (path, socket)
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 2048];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    fn record(label: &str) -> ReceiptRecord<'_> {
+        ReceiptRecord {
+            id: EventId::all_zeros(),
+            recipient: Some(Keys::generate().public_key()),
+            payer: Keys::generate().public_key(),
+            msat: 21_000,
+            label,
+            created_at: 1_700_000_000,
+            accepted: 2,
+        }
+    }
+
+    #[test]
+    fn test_receipt_recorded() {
+        let (path, socket) = listen("record");
+        let sink = SyslogSink::new(
+            path.clone(),
+            "local3".parse().unwrap(),
+            "notice".parse().unwrap(),
+            None,
+        );
+
+        let receipt = record("zap-\"1\"]");
+        sink.record(&receipt);
+        let message = receive(&socket);
+        // local3 is 19, notice 5
+        assert!(message.starts_with("<157>1 "));
+        assert!(message.contains(&format!(
+            " cln-zapper {} 1 [receipt@32473 accepted=\"2\" created_at=\"1700000000\" id=\"{}\" label=\"zap-\\\"1\\\"\\]\" msat=\"21000\" payer=\"{}\" recipient=\"{}\"] Zap receipt {} issued",
+            std::process::id(),
+            receipt.id.to_hex(),
+            receipt.payer,
+            receipt.recipient.unwrap(),
+            receipt.id.to_hex()
+        )));
+        std::fs::remove_file(&path).unwrap();
+
+        // Nothing listening is only a warning
+        sink.record(&receipt);
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let (path, socket) = listen("rate");
+        let sink = SyslogSink::new(
+            path.clone(),
+            Facility::default(),
+            Severity::default(),
+            Some(2),
+        );
+
+        for i in 0..5 {
+            sink.record(&record(&format!("zap-{i}")));
+        }
+        assert!(receive(&socket).contains("label=\"zap-0\""));
+        assert!(receive(&socket).contains("label=\"zap-1\""));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        sink.record(&record("zap-5"));
+        assert!(receive(&socket).contains("dropped=\"3\""));
+        assert!(receive(&socket).contains("label=\"zap-5\""));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(
+            "local0".parse::<Facility>().unwrap().0,
+            syslog::Facility::LOG_LOCAL0
+        ));
+        assert!(matches!(
+            "user".parse::<Facility>().unwrap().0,
+            syslog::Facility::LOG_USER
+        ));
+        assert!("local8".parse::<Facility>().is_err());
+        assert_eq!("info".parse::<Severity>().unwrap(), Severity::default());
+        assert!("loud".parse::<Severity>().is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Rolling zap totals per recipient, by `clnzapper_zap_totals_recipients`
+//!
+//! Every receipt broadcast adds its amount to its recipient's totals, kept in
+//! `BUCKET_SECS` buckets for the last day. `zapper-status` and `/metrics` report
+//! each recipient's msat and zaps over the last hour and day, to the nearest bucket.
+//! Only the recipients zapped most recently are tracked, up to the configured
+//! number, so memory stays bounded however many recipients a service has.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use nostr::secp256k1::XOnlyPublicKey;
+use serde::Serialize;
+
+/// Recipients tracked when `clnzapper_zap_totals_recipients` is not set
+pub const DEFAULT_MAX_RECIPIENTS: usize = 1000;
+
+/// Seconds covered by each bucket
+const BUCKET_SECS: u64 = 300;
+
+/// Windows totals are reported over, by name
+const WINDOWS: [(&str, u64); 2] = [("hour", 3600), ("day", 86_400)];
+
+/// The longest window, past which buckets are dropped
+const MAX_WINDOW: u64 = 86_400;
+
+/// What was zapped over a window
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Total {
+    pub msat: u64,
+    pub zaps: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    start: u64,
+    total: Total,
+}
+
+#[derive(Debug, Default)]
+struct Recipient {
+    /// Oldest first
+    buckets: VecDeque<Bucket>,
+    /// When the recipient was last recorded, in record order, for evicting
+    last_used: u64,
+}
+
+impl Recipient {
+    fn prune(&mut self, now: u64) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.start + BUCKET_SECS + MAX_WINDOW <= now)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn total(&self, window: u64, now: u64) -> Total {
+        self.buckets
+            .iter()
+            .filter(|bucket| bucket.start + BUCKET_SECS + window > now)
+            .fold(Total::default(), |total, bucket| Total {
+                msat: total.msat + bucket.total.msat,
+                zaps: total.zaps + bucket.total.zaps,
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recipients {
+    by_pubkey: HashMap<XOnlyPublicKey, Recipient>,
+    records: u64,
+}
+
+/// Zap totals of the recipients zapped most recently
+#[derive(Debug)]
+pub struct ZapTotals {
+    max_recipients: usize,
+    recipients: Mutex<Recipients>,
+}
+
+impl ZapTotals {
+    pub fn new(max_recipients: usize) -> Self {
+        Self {
+            max_recipients,
+            recipients: Mutex::new(Recipients::default()),
+        }
+    }
+
+    /// Add a zap of `msat` to `recipient` made at `at`, both unix times
+    pub fn record(&self, recipient: XOnlyPublicKey, msat: u64, at: u64, now: u64) {
+        if at + MAX_WINDOW <= now {
+            return;
+        }
+
+        let mut recipients = self.recipients.lock().expect("Lock not poisoned");
+        recipients.records += 1;
+        let last_used = recipients.records;
+        if !recipients.by_pubkey.contains_key(&recipient)
+            && recipients.by_pubkey.len() >= self.max_recipients
+        {
+            let least_recent = recipients
+                .by_pubkey
+                .iter()
+                .min_by_key(|(_, recipient)| recipient.last_used)
+                .map(|(pubkey, _)| *pubkey);
+            if let Some(pubkey) = least_recent {
+                recipients.by_pubkey.remove(&pubkey);
+            }
+        }
+
+        let recipient = recipients.by_pubkey.entry(recipient).or_default();
+        recipient.last_used = last_used;
+        recipient.prune(now);
+
+        // Zaps caught up on after downtime can come after later ones
+        let start = at - at % BUCKET_SECS;
+        let zap = Total { msat, zaps: 1 };
+        match recipient
+            .buckets
+            .iter()
+            .position(|bucket| bucket.start >= start)
+        {
+            Some(i) if recipient.buckets[i].start == start => {
+                let total = &mut recipient.buckets[i].total;
+                total.msat += msat;
+                total.zaps += 1;
+            }
+            Some(i) => recipient.buckets.insert(i, Bucket { start, total: zap }),
+            None => recipient.buckets.push_back(Bucket { start, total: zap }),
+        }
+    }
+
+    /// Each recipient's totals over every window at `now`, by window name
+    pub fn snapshot(&self, now: u64) -> BTreeMap<String, BTreeMap<&'static str, Total>> {
+        let recipients = self.recipients.lock().expect("Lock not poisoned");
+        recipients
+            .by_pubkey
+            .iter()
+            .map(|(pubkey, recipient)| {
+                let totals = WINDOWS
+                    .iter()
+                    .map(|(name, window)| (*name, recipient.total(*window, now)))
+                    .collect();
+                (pubkey.to_string(), totals)
+            })
+            .collect()
+    }
+
+    /// The totals at `now` as Prometheus gauges, labelled by `recipient` and `window`
+    pub fn render(&self, now: u64, out: &mut String) {
+        let snapshot = self.snapshot(now);
+
+        for (metric, help, value) in [
+            (
+                "zapper_zapped_msat",
+                "Msat zapped to each recipient over the window",
+                (|total: &Total| total.msat) as fn(&Total) -> u64,
+            ),
+            (
+                "zapper_zaps",
+                "Zaps to each recipient over the window",
+                |total: &Total| total.zaps,
+            ),
+        ] {
+            writeln!(out, "# HELP {metric} {help}\n# TYPE {metric} gauge")
+                .expect("Writing to a string can't fail");
+            for (recipient, totals) in &snapshot {
+                for (window, _) in WINDOWS {
+                    writeln!(
+                        out,
+                        "{metric}{{recipient=\"{recipient}\",window=\"{window}\"}} {}",
+                        value(&totals[window])
+                    )
+                    .expect("Writing to a string can't fail");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::Keys;
+
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_totals() {
+        let totals = ZapTotals::new(DEFAULT_MAX_RECIPIENTS);
+        let (alice, bob) = (Keys::generate().public_key(), Keys::generate().public_key());
+
+        totals.record(alice, 1000, NOW - 60, NOW);
+        totals.record(alice, 2000, NOW - 30, NOW);
+        // Out of order, and only in the day
+        totals.record(alice, 5000, NOW - 7200, NOW);
+        totals.record(bob, 21_000, NOW - 600, NOW);
+        // Too old to count at all
+        totals.record(bob, 9000, NOW - 2 * 86_400, NOW);
+
+        let snapshot = totals.snapshot(NOW);
+        let alice_totals = &snapshot[&alice.to_string()];
+        assert_eq!(
+            alice_totals["hour"],
+            Total {
+                msat: 3000,
+                zaps: 2
+            }
+        );
+        assert_eq!(
+            alice_totals["day"],
+            Total {
+                msat: 8000,
+                zaps: 3
+            }
+        );
+        let bob_totals = &snapshot[&bob.to_string()];
+        assert_eq!(bob_totals["hour"], bob_totals["day"]);
+        assert_eq!(
+            bob_totals["day"],
+            Total {
+                msat: 21_000,
+                zaps: 1
+            }
+        );
+
+        // Zaps age out of the windows
+        let later = totals.snapshot(NOW + 2 * 86_400);
+        assert_eq!(later[&alice.to_string()]["day"], Total::default());
+    }
+
+    #[test]
+    fn test_least_recent_evicted() {
+        let totals = ZapTotals::new(2);
+        let keys: Vec<_> = (0..3).map(|_| Keys::generate().public_key()).collect();
+
+        totals.record(keys[0], 1000, NOW, NOW);
+        totals.record(keys[1], 1000, NOW, NOW);
+        // Zapped again, so the first is used more recently than the second
+        totals.record(keys[0], 1000, NOW, NOW);
+        totals.record(keys[2], 1000, NOW, NOW);
+
+        let snapshot = totals.snapshot(NOW);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[&keys[0].to_string()]["day"].zaps, 2);
+        assert!(!snapshot.contains_key(&keys[1].to_string()));
+        assert!(snapshot.contains_key(&keys[2].to_string()));
+    }
+
+    #[test]
+    fn test_render() {
+        let totals = ZapTotals::new(DEFAULT_MAX_RECIPIENTS);
+        let recipient = Keys::generate().public_key();
+        totals.record(recipient, 21_000, NOW, NOW);
+
+        let mut out = String::new();
+        totals.render(NOW, &mut out);
+        assert!(out.contains(&format!(
+            "zapper_zapped_msat{{recipient=\"{recipient}\",window=\"hour\"}} 21000\n"
+        )));
+        assert!(out.contains(&format!(
+            "zapper_zaps{{recipient=\"{recipient}\",window=\"day\"}} 1\n"
+        )));
+    }
+}
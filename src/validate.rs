@@ -0,0 +1,104 @@
+//! `cln-zapper validate [bolt11]`: check a zap request offline
+//!
+//! Reads a zap request JSON from stdin and prints the zap receipt the plugin
+//! would publish for it, or why it would be rejected, without touching CLN.
+//! The receipt is signed with a throwaway key and, unless a bolt11 is given,
+//! carries a placeholder invoice.
+
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use cln_rpc::model::{WaitanyinvoiceResponse, WaitanyinvoiceStatus};
+use cln_rpc::primitives::{Amount, Sha256};
+use nostr::{Event, Keys};
+
+use crate::clock;
+use crate::{create_zap_note, decode_zap_req};
+
+/// Stand in for the invoice when none is given
+const PLACEHOLDER_BOLT11: &str = "lnbc1placeholder";
+
+pub fn run() -> Result<()> {
+    let bolt11 = std::env::args().nth(2);
+
+    let mut zap_request = String::new();
+    io::stdin().read_to_string(&mut zap_request)?;
+
+    match validate(zap_request.trim(), bolt11, &Keys::generate()) {
+        Ok(zap_note) => {
+            println!("{}", zap_note.as_json());
+            Ok(())
+        }
+        Err(err) => Err(anyhow!("Zap request rejected: {err}")),
+    }
+}
+
+/// Decode the zap request and build the receipt for a synthesized paid invoice
+fn validate(zap_request: &str, bolt11: Option<String>, keys: &Keys) -> Result<Event> {
+    let zap_request_info = decode_zap_req(zap_request)?;
+    let invoice = synthesized_invoice(zap_request, zap_request_info.amount, bolt11)?;
+
+    create_zap_note(
+        keys,
+        zap_request_info,
+        invoice,
+        &[],
+        false,
+        clock::DEFAULT_MAX_SKEW,
+    )
+}
+
+/// Paid invoice for the zap request as `waitanyinvoice` would return it, without a payment
+pub fn synthesized_invoice(
+    zap_request: &str,
+    amount_msat: Option<u64>,
+    bolt11: Option<String>,
+) -> Result<WaitanyinvoiceResponse> {
+    Ok(WaitanyinvoiceResponse {
+        label: "validate".to_string(),
+        description: zap_request.to_string(),
+        payment_hash: Sha256::from_str(&"00".repeat(32))?,
+        status: WaitanyinvoiceStatus::PAID,
+        expires_at: 0,
+        amount_msat: amount_msat.map(Amount::from_msat),
+        bolt11: Some(bolt11.unwrap_or_else(|| PLACEHOLDER_BOLT11.to_string())),
+        bolt12: None,
+        pay_index: None,
+        amount_received_msat: amount_msat.map(Amount::from_msat),
+        paid_at: None,
+        payment_preimage: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::Tag;
+
+    use super::*;
+    use crate::tests::{test_keys, ZAP_REQ};
+
+    #[test]
+    fn test_validate() {
+        let zap_note = validate(ZAP_REQ, None, &test_keys()).unwrap();
+        zap_note.verify().unwrap();
+        assert!(zap_note
+            .tags
+            .contains(&Tag::Bolt11(PLACEHOLDER_BOLT11.to_string())));
+        assert!(zap_note
+            .tags
+            .contains(&Tag::Description(ZAP_REQ.to_string())));
+
+        let zap_note = validate(ZAP_REQ, Some("lnbc1given".to_string()), &test_keys()).unwrap();
+        assert!(zap_note
+            .tags
+            .contains(&Tag::Bolt11("lnbc1given".to_string())));
+
+        assert!(validate("not json", None, &test_keys()).is_err());
+        // A zap request needs a p tag
+        let zap_request = nostr::EventBuilder::new(nostr::Kind::ZapRequest, "", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(validate(&zap_request.as_json(), None, &test_keys()).is_err());
+    }
+}
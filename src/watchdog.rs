@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Notices when the invoice stream stops making progress
+///
+/// The stream beats the heartbeat every time `waitanyinvoice` returns, which with
+/// the watchdog enabled is at least every half `timeout` even on an idle node, so a
+/// heartbeat older than `timeout` means the stream is stuck.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// How long the stream may go without a heartbeat
+    timeout: Duration,
+    /// Unix time in milliseconds of the last heartbeat
+    heartbeat: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, heartbeat: Arc<AtomicU64>) -> Self {
+        Self { timeout, heartbeat }
+    }
+
+    /// Count now as progress, e.g. after time spent not polling the stream
+    pub fn touch(&self) {
+        beat(&self.heartbeat);
+    }
+
+    /// Resolve once there has been no heartbeat for `timeout`
+    pub async fn stalled(&self) -> Duration {
+        loop {
+            let elapsed = Duration::from_millis(
+                now_millis().saturating_sub(self.heartbeat.load(Ordering::Relaxed)),
+            );
+            if elapsed >= self.timeout {
+                return elapsed;
+            }
+            tokio::time::sleep(self.timeout - elapsed).await;
+        }
+    }
+}
+
+/// Record progress of the invoice stream
+pub fn beat(heartbeat: &AtomicU64) {
+    heartbeat.store(now_millis(), Ordering::Relaxed);
+}
+
+/// `waitanyinvoice` timeout in seconds keeping the heartbeat fresh for a watchdog timeout
+pub fn wait_timeout(watchdog_timeout: Option<u64>) -> Option<u64> {
+    watchdog_timeout.map(|timeout| (timeout / 2).max(1))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watchdog_stalled() {
+        let watchdog = Watchdog::new(Duration::from_millis(100), Arc::new(AtomicU64::new(0)));
+        watchdog.touch();
+
+        let start = Instant::now();
+        assert!(watchdog.stalled().await >= Duration::from_millis(100));
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_heartbeat() {
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let watchdog = Watchdog::new(Duration::from_millis(100), heartbeat.clone());
+        watchdog.touch();
+
+        let beating = tokio::spawn(async move {
+            for _ in 0..10 {
+                beat(&heartbeat);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        // Not stalled while beating
+        assert!(
+            tokio::time::timeout(Duration::from_millis(150), watchdog.stalled())
+                .await
+                .is_err()
+        );
+        beating.await.unwrap();
+        watchdog.stalled().await;
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        assert_eq!(wait_timeout(None), None);
+        assert_eq!(wait_timeout(Some(300)), Some(150));
+        assert_eq!(wait_timeout(Some(1)), Some(1));
+    }
+}
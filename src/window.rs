@@ -0,0 +1,146 @@
+//! Persisting the pay index only past invoices done with, by `clnzapper_index_after_publish`
+//!
+//! Receipts are published concurrently, up to `clnzapper_max_inflight_zaps`, and the
+//! pay index file normally advances as soon as an invoice is read, so a restart
+//! loses the receipts still being published. With this set the file only advances
+//! to the highest pay index with every invoice read up to it done with, published
+//! or skipped, like a sliding window. A restart can then publish a receipt again,
+//! which relays already holding it take as a duplicate.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::node::Node;
+
+#[derive(Debug, Default)]
+struct Window {
+    /// Highest pay index with every invoice read up to it done with
+    persisted: u64,
+    /// Pay indexes read and not yet done with
+    open: BTreeSet<u64>,
+    /// Pay indexes done with, past `persisted`
+    done: BTreeSet<u64>,
+}
+
+/// Invoices of a node being processed, by pay index
+#[derive(Debug, Default)]
+pub struct IndexWindow {
+    window: Mutex<Window>,
+}
+
+impl IndexWindow {
+    /// Start over from `persisted`, as read from the pay index file
+    pub fn reset(&self, persisted: u64) {
+        *self.window.lock().expect("Lock not poisoned") = Window {
+            persisted,
+            ..Window::default()
+        };
+    }
+
+    pub fn persisted(&self) -> u64 {
+        self.window.lock().expect("Lock not poisoned").persisted
+    }
+
+    fn open(&self, idx: u64) {
+        self.window
+            .lock()
+            .expect("Lock not poisoned")
+            .open
+            .insert(idx);
+    }
+
+    /// Mark the invoice done with, returning the pay index to persist if it advanced
+    fn close(&self, idx: u64) -> Option<u64> {
+        let mut window = self.window.lock().expect("Lock not poisoned");
+        window.open.remove(&idx);
+        window.done.insert(idx);
+
+        let advanced = match window.open.first() {
+            Some(first_open) => window.done.range(..first_open).next_back(),
+            None => window.done.last(),
+        }
+        .copied()
+        .filter(|idx| *idx > window.persisted)?;
+
+        window.persisted = advanced;
+        window.done = window.done.split_off(&(advanced + 1));
+        Some(advanced)
+    }
+}
+
+/// An invoice read from a node, done with once dropped
+///
+/// Dropping it, whether the invoice was skipped, published or its task failed,
+/// writes the node's pay index file if the window advanced.
+#[derive(Debug)]
+pub struct Pending {
+    node: Node,
+    idx: u64,
+}
+
+impl Pending {
+    pub fn open(node: &Node, idx: u64) -> Self {
+        node.index_window.open(idx);
+        Self {
+            node: node.clone(),
+            idx,
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(idx) = self.node.index_window.close(self.idx) {
+            self.node.write_pay_index(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::read_last_pay_index;
+
+    #[test]
+    fn test_out_of_order_completion() {
+        let window = IndexWindow::default();
+        window.reset(3);
+        for idx in [4, 5, 7, 8] {
+            window.open(idx);
+        }
+
+        // Done ahead of 4, so nothing can be persisted yet
+        assert_eq!(window.close(5), None);
+        assert_eq!(window.close(8), None);
+        assert_eq!(window.persisted(), 3);
+
+        // 4 and 5 are done, and 6 was never read, so up to 7 once it is done
+        assert_eq!(window.close(4), Some(5));
+        assert_eq!(window.close(7), Some(8));
+        assert_eq!(window.persisted(), 8);
+
+        // Done twice, or from before the window, changes nothing
+        assert_eq!(window.close(8), None);
+        assert_eq!(window.close(2), None);
+    }
+
+    #[test]
+    fn test_pending_writes_on_drop() {
+        let dir = std::env::temp_dir().join(format!("clnzapper-window-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let node = Node::new(PathBuf::from("lightning-rpc"), dir.join("last_pay_index"));
+        node.index_window.reset(1);
+
+        let first = Pending::open(&node, 2);
+        let second = Pending::open(&node, 3);
+        drop(second);
+        assert!(read_last_pay_index(&node.pay_index_path).is_err());
+
+        drop(first);
+        assert_eq!(read_last_pay_index(&node.pay_index_path).unwrap(), 3);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
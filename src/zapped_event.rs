@@ -0,0 +1,254 @@
+//! Looking up the zapped event before publishing, by `clnzapper_verify_zapped_event`
+//!
+//! A zap request's `e` tag can name any id, so a receipt may point at an event no
+//! relay has. With the check on, the event is requested by id from the default
+//! relays and the `e` tag's relay hint, and a receipt is only published once one of
+//! them returns it. If none does within `clnzapper_verify_zapped_event_timeout`,
+//! `clnzapper_zapped_event_missing` decides whether the zap is skipped or published
+//! anyway. Zaps of a profile have no `e` tag and are not checked.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use nostr::{EventId, Filter, Tag};
+
+use crate::config::Config;
+use crate::published::OwnReceipts;
+use crate::relay::fetch_events;
+use crate::relay_url::RelayUrl;
+use crate::skip::SkipReason;
+use crate::state::State;
+use crate::ZapRequestInfo;
+
+/// Seconds to wait for the zapped event when `clnzapper_verify_zapped_event_timeout` is not set
+pub const DEFAULT_TIMEOUT: u64 = 5;
+
+/// What to do with a zap whose event wasn't found, set by `clnzapper_zapped_event_missing`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingEvent {
+    #[default]
+    Skip,
+    Broadcast,
+}
+
+impl FromStr for MissingEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "broadcast" => Ok(Self::Broadcast),
+            _ => Err(anyhow!(
+                "Invalid missing zapped event policy {s}, expected skip or broadcast"
+            )),
+        }
+    }
+}
+
+/// How zapped events are checked, if they are
+#[derive(Clone, Copy, Debug)]
+pub struct ZappedEventCheck {
+    pub timeout: Duration,
+    pub missing: MissingEvent,
+}
+
+/// Whether any of `relays` returns the event within `timeout`
+async fn find(
+    id: EventId,
+    relays: HashSet<RelayUrl>,
+    config: &Config,
+    own: &OwnReceipts,
+    timeout: Duration,
+) -> bool {
+    let mut lookups: FuturesUnordered<_> = relays
+        .into_iter()
+        .map(|relay| {
+            let headers = config.relay_headers.get(&relay).cloned();
+            let own = own.clone();
+            // tungstenite is blocking so keep it off the async workers
+            tokio::task::spawn_blocking(move || {
+                let filter = Filter::new().id(id.to_hex());
+                match fetch_events(&relay, headers.as_ref(), filter, &own) {
+                    Ok(events) => events.iter().any(|event| event.id == id),
+                    Err(err) => {
+                        debug!("Could not look up {} on {relay}: {err}", id.to_hex());
+                        false
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let found = async {
+        while let Some(found) = lookups.next().await {
+            if matches!(found, Ok(true)) {
+                return true;
+            }
+        }
+        false
+    };
+    tokio::time::timeout(timeout, found).await.unwrap_or(false)
+}
+
+/// Whether to go on publishing the zap's receipt, counting it as skipped if not
+pub async fn check(state: &State, zap_request_info: &ZapRequestInfo) -> bool {
+    let Some(check) = state.config.zapped_event_check else {
+        return true;
+    };
+    // The zapped event is the first, any others are only copied with `clnzapper_copy_e_tags`
+    let Some(Tag::Event(id, hint, _)) = zap_request_info.e.first() else {
+        return true;
+    };
+
+    let mut relays = state.relays.read().await.clone();
+    relays.extend(
+        hint.as_ref()
+            .and_then(|hint| RelayUrl::parse(&hint.to_string()).ok()),
+    );
+    if find(
+        *id,
+        relays,
+        &state.config,
+        &state.own_receipts(),
+        check.timeout,
+    )
+    .await
+    {
+        debug!("Found zapped event {}", id.to_hex());
+        return true;
+    }
+
+    match check.missing {
+        MissingEvent::Skip => {
+            info!(
+                "Skipping zap request {}: {}",
+                zap_request_info.zap_request.id.to_hex(),
+                SkipReason::ZappedEventMissing
+            );
+            state.skipped.count(SkipReason::ZappedEventMissing);
+            false
+        }
+        MissingEvent::Broadcast => {
+            warn!(
+                "Zapped event {} not found, publishing the receipt of zap request {} anyway",
+                id.to_hex(),
+                zap_request_info.zap_request.id.to_hex()
+            );
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use nostr::{ClientMessage, Event, EventBuilder, Keys, Kind, RelayMessage};
+    use tungstenite::Message as WsMessage;
+
+    use super::*;
+    use crate::decode_zap_req;
+    use crate::relay::tests::relay_url;
+
+    /// Relay answering every request with `stored`
+    fn mock_event_relay(stored: Event) -> RelayUrl {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = relay_url(&format!("ws://{}", listener.local_addr().unwrap()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut socket = tungstenite::accept(stream.unwrap()).unwrap();
+                let Ok(WsMessage::Text(msg)) = socket.read_message() else {
+                    continue;
+                };
+                let Ok(ClientMessage::Req {
+                    subscription_id, ..
+                }) = ClientMessage::from_json(msg)
+                else {
+                    continue;
+                };
+                let msg = RelayMessage::new_event(subscription_id.clone(), stored.clone());
+                socket.write_message(WsMessage::Text(msg.as_json())).ok();
+                let eose = RelayMessage::new_eose(subscription_id);
+                socket.write_message(WsMessage::Text(eose.as_json())).ok();
+                socket.read_message().ok();
+            }
+        });
+
+        url
+    }
+
+    fn zap_of(id: EventId) -> ZapRequestInfo {
+        let keys = Keys::generate();
+        let zap_request = EventBuilder::new(
+            Kind::ZapRequest,
+            "",
+            &[
+                Tag::Event(id, None, None),
+                Tag::PubKey(keys.public_key(), None),
+            ],
+        )
+        .to_event(&keys)
+        .unwrap();
+        decode_zap_req(&zap_request.as_json()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lookup_decides() {
+        let note = EventBuilder::new_text_note("zap me", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let relay = mock_event_relay(note.clone());
+        let state = |missing| {
+            State::new(
+                Keys::generate(),
+                PathBuf::from("lightning-rpc"),
+                HashSet::from([relay.clone()]),
+                Config {
+                    zapped_event_check: Some(ZappedEventCheck {
+                        timeout: Duration::from_secs(5),
+                        missing,
+                    }),
+                    ..Config::default()
+                },
+            )
+        };
+        let other = EventId::from_slice(&[1; 32]).unwrap();
+
+        let skipping = state(MissingEvent::Skip);
+        assert!(check(&skipping, &zap_of(note.id)).await);
+        assert!(!check(&skipping, &zap_of(other)).await);
+        assert_eq!(skipping.skipped.snapshot()["zapped-event-missing"], 1);
+
+        let broadcasting = state(MissingEvent::Broadcast);
+        assert!(check(&broadcasting, &zap_of(other)).await);
+        assert_eq!(broadcasting.skipped.snapshot()["zapped-event-missing"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_unchecked() {
+        let state = State::new(
+            Keys::generate(),
+            PathBuf::from("lightning-rpc"),
+            HashSet::new(),
+            Config::default(),
+        );
+        assert!(check(&state, &zap_of(EventId::from_slice(&[1; 32]).unwrap())).await);
+    }
+
+    #[test]
+    fn test_missing_event_from_str() {
+        assert_eq!(
+            MissingEvent::from_str("broadcast").unwrap(),
+            MissingEvent::Broadcast
+        );
+        assert!(MissingEvent::from_str("publish").is_err());
+    }
+}